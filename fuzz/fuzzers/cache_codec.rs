@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libedgedns;
+
+use libedgedns::cache_codec;
+
+fuzz_target!(|data: &[u8]| {
+                 if data.len() > 65535 {
+                     return;
+                 }
+                 let compressed = cache_codec::compress(data);
+                 assert_eq!(cache_codec::decompress(&compressed).unwrap(), data);
+                 let _ = cache_codec::decompress(data);
+             });
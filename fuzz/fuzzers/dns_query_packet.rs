@@ -0,0 +1,23 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libedgedns;
+
+use libedgedns::dns;
+
+fuzz_target!(|data: &[u8]| {
+                 if let Ok(normalized_question) = dns::normalize(data, true) {
+                     let _ = format!("{}", normalized_question);
+                     let ecs = normalized_question
+                         .ecs
+                         .as_ref()
+                         .and_then(|ecs| dns::ecs_truncate(ecs, 24, 56));
+                     let _ = dns::build_query_packet(&normalized_question, false, None);
+                     let _ = dns::build_query_packet(
+                         &normalized_question,
+                         true,
+                         ecs.as_ref().map(|ecs| &ecs[..]),
+                     );
+                     let _ = dns::build_formerr_packet(data);
+                 }
+             });
@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libedgedns;
+
+use libedgedns::dns;
+
+fuzz_target!(|data: &[u8]| {
+                 let _ = dns::min_ttl(data, 1, 86400, 30);
+                 let mut packet = data.to_vec();
+                 let _ = dns::set_ttl(&mut packet, 42);
+                 let _ = dns::cap_answers(&mut data.to_vec(), 1);
+                 if let Ok(normalized_question) = dns::normalize(data, false) {
+                     let _ = format!("{}", normalized_question);
+                     dns::overwrite_qname(&mut packet, &normalized_question.qname);
+                 }
+             });
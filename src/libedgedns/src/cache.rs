@@ -11,23 +11,56 @@
 //! replaced with the `arc-cache` or `cart-cache` crates that expose a
 //! similar API (but might be subject to patents).
 //!
+//! Queries with the CD (Checking Disabled) bit are forwarded with that bit,
+//! and their responses are cached separately, since validating upstream
+//! resolvers can return data to them that other clients must not receive.
+//!
+//! Delegations extracted from referral responses can optionally be kept in a
+//! separate cache, indexed by zone, so that queries for other names of the
+//! same zone can be answered without contacting upstream servers.
+//!
 //! With a typical workload, it is expected that the vast majority of cached
 //! responses end up in the `frequent` section of the cache.
 //! The `test` and `recent` section act as a security valve when a spike of
 //! previously unknown queries is observed.
+//!
+//! Packets can optionally be stored compressed, using the codec from
+//! `cache_codec`, to reduce the memory footprint of large caches. Lookups
+//! then return a decompressed copy.
+//!
+//! The total size of the cached packets can also be capped with
+//! `cache.max_bytes`. Since entries evicted by the CLOCK-Pro algorithm are
+//! not reported, sizes are tracked separately, and the least recently used
+//! entries are dropped when the limit is exceeded. Sizes of entries evicted
+//! by the main cache are only forgotten later, so the estimate can be larger,
+//! but never lower, than the actual size.
+//!
+//! With the `shadow-cache` feature, insertions and lookups can also be
+//! mirrored to a `ShadowCache`, whose results are compared but never served.
 
+use cache_codec;
+use client_query::AnswerSource;
 use clockpro_cache::*;
 use coarsetime::{Duration, Instant};
 use config::Config;
-use dns::{NormalizedQuestion, NormalizedQuestionKey, DNS_CLASS_IN, DNS_RCODE_NXDOMAIN};
+use dns::{NormalizedQuestion, NormalizedQuestionKey, Referral, DNS_CLASS_IN,
+          DNS_RCODE_NXDOMAIN, DNS_RCODE_SERVFAIL, DNS_TYPE_DS};
 use dns;
 use parking_lot::Mutex;
+#[cfg(feature = "shadow-cache")]
+use shadow_cache::ShadowCache;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+#[cfg(feature = "shadow-cache")]
+use varz::Varz;
 
 #[derive(Clone, Debug)]
 pub struct CacheEntry {
+    pub inserted: Instant,
     pub expiration: Instant,
     pub packet: Vec<u8>,
+    pub synthesized: bool,
 }
 
 impl CacheEntry {
@@ -35,12 +68,127 @@ impl CacheEntry {
         let now = Instant::recent();
         now > self.expiration
     }
+
+    /// Checks that an entry expired less than `stale_ttl` seconds ago, and can
+    /// still be served stale.
+    pub fn is_within_stale_window(&self, stale_ttl: u32) -> bool {
+        let now = Instant::recent();
+        now <= self.expiration + Duration::from_secs(stale_ttl as u64) && !self.is_servfail()
+    }
+
+    pub fn is_servfail(&self) -> bool {
+        self.packet.len() >= dns::DNS_HEADER_SIZE && dns::rcode(&self.packet) == DNS_RCODE_SERVFAIL
+    }
+
+    pub fn source(&self) -> AnswerSource {
+        if self.synthesized {
+            AnswerSource::Synth
+        } else {
+            AnswerSource::Cache
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReferralEntry {
+    inserted: Instant,
+    expiration: Instant,
+    referral: Referral,
+}
+
+/// Sizes of the cached packets, and the order in which they were last used.
+///
+/// `lru` can contain outdated generations of a key, that are skipped, and
+/// removed when they start using too much memory.
+struct CacheBytes {
+    max_bytes: usize,
+    bytes: usize,
+    evicted: u64,
+    generation: u64,
+    entries: HashMap<NormalizedQuestionKey, (usize, u64)>,
+    lru: VecDeque<(NormalizedQuestionKey, u64)>,
+}
+
+impl CacheBytes {
+    fn new(max_bytes: usize) -> Self {
+        CacheBytes {
+            max_bytes: max_bytes,
+            bytes: 0,
+            evicted: 0,
+            generation: 0,
+            entries: HashMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+
+    /// Marks an entry as recently used.
+    fn touch(&mut self, normalized_question_key: &NormalizedQuestionKey) {
+        self.generation += 1;
+        match self.entries.get_mut(normalized_question_key) {
+            None => return,
+            Some(entry) => entry.1 = self.generation,
+        }
+        self.lru
+            .push_back((normalized_question_key.clone(), self.generation));
+        self.compact();
+    }
+
+    /// Records the size of a new entry, and returns the least recently used
+    /// entries that have to be evicted to stay within the limit.
+    fn insert(
+        &mut self,
+        normalized_question_key: NormalizedQuestionKey,
+        size: usize,
+    ) -> Vec<NormalizedQuestionKey> {
+        self.generation += 1;
+        if let Some((previous_size, _)) = self.entries
+            .insert(normalized_question_key.clone(), (size, self.generation))
+        {
+            self.bytes -= previous_size;
+        }
+        self.bytes += size;
+        self.lru
+            .push_back((normalized_question_key, self.generation));
+        let mut evicted = vec![];
+        while self.bytes > self.max_bytes {
+            let (normalized_question_key, generation) = match self.lru.pop_front() {
+                None => break,
+                Some(lru_entry) => lru_entry,
+            };
+            let size = match self.entries.get(&normalized_question_key) {
+                Some(&(size, entry_generation)) if entry_generation == generation => size,
+                _ => continue,
+            };
+            self.entries.remove(&normalized_question_key);
+            self.bytes -= size;
+            self.evicted += 1;
+            evicted.push(normalized_question_key);
+        }
+        self.compact();
+        evicted
+    }
+
+    fn compact(&mut self) {
+        if self.lru.len() <= self.entries.len() * 2 + 1024 {
+            return;
+        }
+        let entries = &self.entries;
+        self.lru.retain(|&(ref normalized_question_key, generation)| {
+            entries
+                .get(normalized_question_key)
+                .map_or(false, |entry| entry.1 == generation)
+        });
+    }
 }
 
 #[derive(Clone)]
 pub struct Cache {
     config: Config,
     arc_mx: Arc<Mutex<ClockProCache<NormalizedQuestionKey, CacheEntry>>>,
+    bytes_mx: Option<Arc<Mutex<CacheBytes>>>,
+    referrals_mx: Option<Arc<Mutex<ClockProCache<Vec<u8>, ReferralEntry>>>>,
+    #[cfg(feature = "shadow-cache")]
+    shadow: Option<ShadowCache>,
 }
 
 pub struct CacheStats {
@@ -49,26 +197,62 @@ pub struct CacheStats {
     pub test_len: usize,
     pub inserted: u64,
     pub evicted: u64,
+    /// Size of the cached packets, if `cache.max_bytes` is set
+    pub bytes: usize,
 }
 
 impl Cache {
     pub fn new(config: Config) -> Cache {
         let arc = ClockProCache::new(config.cache_size).unwrap();
         let arc_mx = Arc::new(Mutex::new(arc));
+        let bytes_mx = if config.max_cache_bytes > 0 {
+            Some(Arc::new(Mutex::new(CacheBytes::new(config.max_cache_bytes))))
+        } else {
+            None
+        };
+        let referrals_mx = if config.referrals_cache_size > 0 {
+            let referrals = ClockProCache::new(config.referrals_cache_size).unwrap();
+            Some(Arc::new(Mutex::new(referrals)))
+        } else {
+            None
+        };
         Cache {
             config: config,
             arc_mx: arc_mx,
+            bytes_mx: bytes_mx,
+            referrals_mx: referrals_mx,
+            #[cfg(feature = "shadow-cache")]
+            shadow: None,
         }
     }
 
+    /// Mirrors insertions and lookups to a shadow cache, if `cache.shadow`
+    /// is set.
+    #[cfg(feature = "shadow-cache")]
+    pub fn with_shadow(mut self, varz: Arc<Varz>) -> Cache {
+        if self.config.cache_shadow {
+            info!("Shadow cache enabled");
+            self.shadow = Some(ShadowCache::new(self.config.cache_size, varz));
+        }
+        self
+    }
+
     pub fn stats(&self) -> CacheStats {
         let cache = self.arc_mx.lock();
+        let (bytes, bytes_evicted) = match self.bytes_mx {
+            None => (0, 0),
+            Some(ref bytes_mx) => {
+                let cache_bytes = bytes_mx.lock();
+                (cache_bytes.bytes, cache_bytes.evicted)
+            }
+        };
         CacheStats {
             frequent_len: cache.frequent_len(),
             recent_len: cache.recent_len(),
             test_len: cache.test_len(),
             inserted: cache.inserted(),
-            evicted: cache.evicted(),
+            evicted: cache.evicted() + bytes_evicted,
+            bytes: bytes,
         }
     }
 
@@ -85,46 +269,184 @@ impl Cache {
         let now = Instant::recent();
         let duration = Duration::from_secs(ttl as u64);
         let expiration = now + duration;
-        let cache_entry = CacheEntry {
+        let mut cache_entry = CacheEntry {
+            inserted: now,
             expiration: expiration,
             packet: packet,
+            synthesized: false,
         };
+        #[cfg(feature = "shadow-cache")]
+        {
+            if let Some(ref shadow) = self.shadow {
+                shadow.insert(normalized_question_key.clone(), cache_entry.clone());
+            }
+        }
+        if self.config.cache_compress {
+            cache_entry.packet = cache_codec::compress(&cache_entry.packet);
+        }
         let mut cache = self.arc_mx.lock();
+        let bytes_mx = match self.bytes_mx {
+            None => return cache.insert(normalized_question_key, cache_entry),
+            Some(ref bytes_mx) => bytes_mx,
+        };
+        let size = cache_entry.packet.len();
+        if size > self.config.max_cache_bytes {
+            return false;
+        }
+        let evicted = bytes_mx
+            .lock()
+            .insert(normalized_question_key.clone(), size);
+        // The main cache can't remove entries, so evicted packets are dropped
+        // instead, and the remaining placeholders are ignored by lookups
+        for evicted_key in evicted {
+            if let Some(evicted_entry) = cache.get_mut(&evicted_key) {
+                evicted_entry.packet = Vec::new();
+            }
+        }
         cache.insert(normalized_question_key, cache_entry)
     }
 
+    /// Stores the delegation of a referral response, if the referrals cache
+    /// is enabled.
+    pub fn insert_referral(&mut self, referral: Referral, ttl: u32) -> bool {
+        let referrals_mx = match self.referrals_mx {
+            None => return false,
+            Some(ref referrals_mx) => referrals_mx,
+        };
+        let now = Instant::recent();
+        let referral_entry = ReferralEntry {
+            inserted: now,
+            expiration: now + Duration::from_secs(ttl as u64),
+            referral: referral,
+        };
+        let mut referrals = referrals_mx.lock();
+        referrals.insert(referral_entry.referral.zone.clone(), referral_entry)
+    }
+
+    /// Looks for a valid delegation covering the question name, starting with
+    /// the closest zone.
+    ///
+    /// The delegation is served for names that upstream servers were never
+    /// asked about, so its TTLs always reflect the time left before it expires.
+    fn get_referral(&self, normalized_question: &NormalizedQuestion) -> Option<CacheEntry> {
+        let referrals_mx = match self.referrals_mx {
+            None => return None,
+            Some(ref referrals_mx) => referrals_mx,
+        };
+        if normalized_question.dnssec || normalized_question.qtype == DNS_TYPE_DS {
+            return None;
+        }
+        let qname = dns::qname_lc(&normalized_question.qname);
+        let mut zone = &qname[..];
+        let mut referrals = referrals_mx.lock();
+        while !zone.is_empty() {
+            if let Some(referral_entry) = referrals.get_mut(zone) {
+                let now = Instant::recent();
+                if now <= referral_entry.expiration {
+                    debug!("Delegation cached");
+                    let mut packet =
+                        dns::build_referral_packet(normalized_question, &referral_entry.referral)
+                            .ok()?;
+                    let age = now.duration_since(referral_entry.inserted).as_secs();
+                    let remaining_ttl = referral_entry.expiration.duration_since(now).as_secs();
+                    let _ = dns::decrement_ttls(&mut packet, age as u32, remaining_ttl as u32);
+                    return Some(CacheEntry {
+                        inserted: referral_entry.inserted,
+                        expiration: referral_entry.expiration,
+                        packet: packet,
+                        synthesized: true,
+                    });
+                }
+            }
+            zone = dns::qname_shift(zone)?;
+        }
+        None
+    }
+
     pub fn get(&mut self, normalized_question_key: &NormalizedQuestionKey) -> Option<CacheEntry> {
         let mut cache = self.arc_mx.lock();
-        cache
+        let mut cache_entry = cache
             .get_mut(normalized_question_key)
-            .and_then(|res| Some(res.clone()))
+            .and_then(|res| Some(res.clone()));
+        if let Some(ref bytes_mx) = self.bytes_mx {
+            if cache_entry.as_ref().map_or(false, |x| x.packet.is_empty()) {
+                cache_entry = None;
+            } else if cache_entry.is_some() {
+                bytes_mx.lock().touch(normalized_question_key);
+            }
+        }
+        drop(cache);
+        if self.config.cache_compress {
+            cache_entry = cache_entry.and_then(|mut cache_entry| {
+                match cache_codec::decompress(&cache_entry.packet) {
+                    Ok(packet) => cache_entry.packet = packet,
+                    Err(e) => {
+                        warn!("Unable to decompress a cached packet: {}", e);
+                        return None;
+                    }
+                }
+                Some(cache_entry)
+            });
+        }
+        #[cfg(feature = "shadow-cache")]
+        {
+            if let Some(ref shadow) = self.shadow {
+                shadow.compare(normalized_question_key, cache_entry.as_ref());
+            }
+        }
+        cache_entry
     }
 
     /// get2() does a couple things before checking that a key is present in the cache.
     ///
-    /// It handles special queries (responses to `ANY` queries and `CHAOS TXT`) as if they
-    /// were cached, although they obviously don't need to actually use the cache.
+    /// It handles special queries (responses to `ANY` queries, `CHAOS TXT` and names of
+    /// the locally served zones) as if they were cached, although they obviously don't
+    /// need to actually use the cache.
     /// It also rejects queries that are not in the `IN` class, that we probably never
-    /// want to cache.
+    /// want to cache, zone transfers, that a caching forwarder must not proxy, as well
+    /// as queries using an EDNS version we don't support.
     ///
-    /// It then checks if a cached response is present and still valid.
+    /// It then checks if a cached response is present and still valid, unless the query
+    /// type is never cached.
     /// If `x.example.com` is not present, but `example.com` is cached with an `NXDOMAIN`
     /// response code, we assume that `x.example.com` doesn't exist either (RFC 8020).
     ///
+    /// Finally, if the cache of referrals is enabled and a delegation for a parent
+    /// zone of the name is cached, a referral response is built from it.
+    ///
     /// We are not checking additional cache entries for now. Both to be minimize
     /// possible incompatibilities with RFC 8020, and for speed.
     /// This might be revisited later.
     pub fn get2(&mut self, normalized_question: &NormalizedQuestion) -> Option<CacheEntry> {
-        if let Some(special_packet) = self.handle_special_queries(normalized_question) {
+        if normalized_question.edns_version > 0 {
             Some(CacheEntry {
+                inserted: Instant::recent(),
+                expiration: Instant::recent() + Duration::from_secs(self.config.max_ttl as u64),
+                packet: dns::build_badvers_packet(normalized_question).unwrap(),
+                synthesized: true,
+            })
+        } else if let Some(special_packet) = self.handle_special_queries(normalized_question) {
+            Some(CacheEntry {
+                inserted: Instant::recent(),
                 expiration: Instant::recent() + Duration::from_secs(self.config.max_ttl as u64),
                 packet: special_packet,
+                synthesized: true,
             })
-        } else if normalized_question.qclass != DNS_CLASS_IN {
+        } else if normalized_question.qclass != DNS_CLASS_IN ||
+            normalized_question.qtype == dns::DNS_TYPE_AXFR ||
+            normalized_question.qtype == dns::DNS_TYPE_IXFR
+        {
             Some(CacheEntry {
+                inserted: Instant::recent(),
                 expiration: Instant::recent() + Duration::from_secs(self.config.max_ttl as u64),
                 packet: dns::build_refused_packet(normalized_question).unwrap(),
+                synthesized: true,
             })
+        } else if self.config
+            .no_cache_qtypes
+            .contains(&normalized_question.qtype)
+        {
+            None
         } else {
             let normalized_question_key = normalized_question.key();
             let cache_entry = self.get(&normalized_question_key);
@@ -132,8 +454,13 @@ impl Cache {
                 if self.config.decrement_ttl {
                     let now = Instant::recent();
                     if now <= cache_entry.expiration {
+                        let age = now.duration_since(cache_entry.inserted).as_secs();
                         let remaining_ttl = cache_entry.expiration.duration_since(now).as_secs();
-                        let _ = dns::set_ttl(&mut cache_entry.packet, remaining_ttl as u32);
+                        let _ = dns::decrement_ttls(
+                            &mut cache_entry.packet,
+                            age as u32,
+                            remaining_ttl as u32,
+                        );
                     }
                 }
                 return Some(cache_entry);
@@ -151,15 +478,34 @@ impl Cache {
                             dns::rcode(&shifted_packet) == DNS_RCODE_NXDOMAIN
                         {
                             debug!("Shifted query returned NXDOMAIN");
+                            let now = Instant::recent();
+                            let remaining_ttl = if now <= shifted_cache_entry.expiration {
+                                shifted_cache_entry.expiration.duration_since(now).as_secs()
+                            } else {
+                                0
+                            };
+                            let ttl = cmp::min(
+                                remaining_ttl,
+                                self.config.synth_soa_minimum as u64,
+                            ) as u32;
+                            let packet = dns::build_nxdomain_packet_with_soa(
+                                normalized_question,
+                                &self.config.synth_soa_mname,
+                                &self.config.synth_soa_rname,
+                                self.config.synth_soa_minimum,
+                                ttl,
+                            ).unwrap();
                             return Some(CacheEntry {
+                                inserted: shifted_cache_entry.inserted,
                                 expiration: shifted_cache_entry.expiration,
-                                packet: dns::build_nxdomain_packet(normalized_question).unwrap(),
+                                packet: packet,
+                                synthesized: true,
                             });
                         }
                     }
                 }
             }
-            None
+            self.get_referral(normalized_question)
         }
     }
 
@@ -179,6 +525,46 @@ impl Cache {
                 dns::build_version_packet(normalized_question, self.config.max_ttl).unwrap();
             return Some(packet);
         }
+        if normalized_question.qclass == dns::DNS_CLASS_IN {
+            return self.handle_local_zones(normalized_question);
+        }
         None
     }
+
+    /// Answers queries for names within the locally served zones, so that
+    /// reverse queries for private and special-use addresses never reach
+    /// upstream servers (RFC 6303). Names are considered nonexistent, unless a
+    /// `PTR` record has been configured for them.
+    fn handle_local_zones(&self, normalized_question: &NormalizedQuestion) -> Option<Vec<u8>> {
+        if self.config.local_zones.is_empty() {
+            return None;
+        }
+        let qname_lc = dns::qname_lc(&normalized_question.qname);
+        let zone = self.config
+            .local_zones
+            .iter()
+            .find(|zone| dns::qname_is_in_zone(&qname_lc, zone))?;
+        debug!("Local zone");
+        let ptr_target = self.config.local_ptr_records.get(&qname_lc);
+        let packet = match ptr_target {
+            Some(ptr_target) if normalized_question.qtype == dns::DNS_TYPE_PTR => {
+                dns::build_ptr_packet(normalized_question, ptr_target, self.config.max_ttl)
+            }
+            _ if ptr_target.is_some() || qname_lc == *zone => dns::build_nodata_packet_with_soa(
+                normalized_question,
+                &self.config.synth_soa_mname,
+                &self.config.synth_soa_rname,
+                self.config.synth_soa_minimum,
+                self.config.synth_soa_minimum,
+            ),
+            _ => dns::build_nxdomain_packet_with_soa(
+                normalized_question,
+                &self.config.synth_soa_mname,
+                &self.config.synth_soa_rname,
+                self.config.synth_soa_minimum,
+                self.config.synth_soa_minimum,
+            ),
+        };
+        packet.ok()
+    }
 }
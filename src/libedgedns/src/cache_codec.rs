@@ -0,0 +1,121 @@
+//! Compression of the packets stored in the cache
+//!
+//! This is a byte-oriented LZ77 codec using the LZF format, which is cheap
+//! to decode and doesn't require any dictionary or state. DNS responses
+//! repeat types, classes, TTLs and address prefixes across records, that
+//! name compression doesn't take care of.
+//!
+//! A compressed stream is a sequence of chunks, each starting with a control
+//! byte `c`:
+//!
+//! - `c < 32`: `c + 1` literal bytes follow.
+//! - otherwise: a back reference. The length minus 2 is `c >> 5`, plus the
+//!   next byte if that is 7. The offset minus 1 is `(c & 0x1f) << 8` plus the
+//!   next byte.
+
+use std::cmp;
+use super::DNS_MAX_SIZE;
+
+const HASH_LOG: u32 = 12;
+const MAX_LITERALS: usize = 32;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_MATCH: usize = 7 + 255 + 2;
+const MIN_MATCH: usize = 3;
+
+#[inline]
+fn hash(x: &[u8]) -> usize {
+    let v = (x[0] as u32) << 16 | (x[1] as u32) << 8 | x[2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let input_len = input.len();
+    let mut out = Vec::with_capacity(input_len + input_len / MAX_LITERALS + 1);
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut literals_start = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input_len {
+        let h = hash(&input[i..]);
+        let candidate = table[h] as usize;
+        table[h] = (i + 1) as u32;
+        if candidate > 0 {
+            let reference = candidate - 1;
+            let offset = i - reference;
+            if offset <= MAX_OFFSET && input[reference..reference + MIN_MATCH] ==
+                input[i..i + MIN_MATCH]
+            {
+                let max_len = cmp::min(input_len - i, MAX_MATCH);
+                let mut len = MIN_MATCH;
+                while len < max_len && input[reference + len] == input[i + len] {
+                    len += 1;
+                }
+                push_literals(&mut out, &input[literals_start..i]);
+                let (offset, len) = (offset - 1, len - 2);
+                if len < 7 {
+                    out.push((len << 5 | offset >> 8) as u8);
+                } else {
+                    out.push((7 << 5 | offset >> 8) as u8);
+                    out.push((len - 7) as u8);
+                }
+                out.push(offset as u8);
+                i += len + 2;
+                literals_start = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    push_literals(&mut out, &input[literals_start..]);
+    out
+}
+
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let input_len = input.len();
+    let mut out = Vec::with_capacity(input_len * 2);
+    let mut i = 0;
+    while i < input_len {
+        let c = input[i] as usize;
+        i += 1;
+        if c < MAX_LITERALS {
+            let len = c + 1;
+            if len > input_len - i {
+                return Err("Truncated literals");
+            }
+            out.extend_from_slice(&input[i..i + len]);
+            i += len;
+        } else {
+            let mut len = c >> 5;
+            if len == 7 {
+                if i >= input_len {
+                    return Err("Truncated match length");
+                }
+                len += input[i] as usize;
+                i += 1;
+            }
+            if i >= input_len {
+                return Err("Truncated match offset");
+            }
+            let offset = ((c & 0x1f) << 8 | input[i] as usize) + 1;
+            i += 1;
+            if offset > out.len() {
+                return Err("Match offset out of bounds");
+            }
+            let start = out.len() - offset;
+            for j in start..start + len + 2 {
+                let x = out[j];
+                out.push(x);
+            }
+        }
+        if out.len() > DNS_MAX_SIZE {
+            return Err("Decompressed packet too large");
+        }
+    }
+    Ok(out)
+}
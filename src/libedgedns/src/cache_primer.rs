@@ -0,0 +1,186 @@
+//! Warm the cache from a list of names
+//!
+//! Each line of the list contains a name, optionally followed by a query type,
+//! either as a number or as a mnemonic. The type defaults to `A`. Empty lines
+//! and lines starting with `#` are ignored.
+//!
+//! The list is loaded once at startup, before privileges are dropped. Names
+//! are then resolved using refresh queries, that go through the resolver like
+//! regular queries, but whose responses are only stored in the cache. At most
+//! `cache.prime_qps` queries are sent per second, and names that are already
+//! cached are skipped. Once all the queries have had time to complete, the
+//! number of names present in the cache is logged, and the warmup is
+//! considered complete, which `/ready` can wait for.
+
+use cache::Cache;
+use client_query::ClientQuery;
+use dns::{self, NormalizedQuestion};
+use futures::{Future, Sink};
+use futures::sync::mpsc::Sender;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::Duration;
+use super::EdgeDNSContext;
+use varz::Varz;
+
+#[derive(Clone)]
+pub struct CachePrimer {
+    questions: Arc<Vec<NormalizedQuestion>>,
+    prime_qps: u32,
+    query_deadline_ms: u64,
+    resolver_tx: Sender<ClientQuery>,
+    cache: Cache,
+    varz: Arc<Varz>,
+    running: Arc<AtomicBool>,
+    warmup_complete: Arc<AtomicBool>,
+}
+
+fn parse_qtype(qtype: &str) -> Option<u16> {
+    if let Ok(qtype) = qtype.parse() {
+        return Some(qtype);
+    }
+    match qtype.to_uppercase().as_str() {
+        "A" => Some(dns::DNS_TYPE_A),
+        "NS" => Some(dns::DNS_TYPE_NS),
+        "CNAME" => Some(5),
+        "SOA" => Some(dns::DNS_TYPE_SOA),
+        "PTR" => Some(12),
+        "MX" => Some(15),
+        "TXT" => Some(dns::DNS_TYPE_TXT),
+        "AAAA" => Some(dns::DNS_TYPE_AAAA),
+        "SRV" => Some(33),
+        "DS" => Some(dns::DNS_TYPE_DS),
+        "DNSKEY" => Some(48),
+        "SVCB" => Some(dns::DNS_TYPE_SVCB),
+        "HTTPS" => Some(dns::DNS_TYPE_HTTPS),
+        _ => None,
+    }
+}
+
+fn parse_line(line: &str) -> Result<Option<NormalizedQuestion>, &'static str> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let name = parts.next().ok_or("Missing name")?;
+    let qtype = match parts.next() {
+        None => dns::DNS_TYPE_A,
+        Some(qtype) => parse_qtype(qtype).ok_or("Unsupported query type")?,
+    };
+    if parts.next().is_some() {
+        return Err("Unexpected data after the query type");
+    }
+    let qname = dns::qname_encode(name)?;
+    let packet = dns::build_question_packet(&qname, qtype)?;
+    dns::normalize(&packet, true).map(Some)
+}
+
+impl CachePrimer {
+    /// Returns `None` if no list of names to prime the cache with was configured.
+    pub fn new(
+        edgedns_context: &EdgeDNSContext,
+        resolver_tx: Sender<ClientQuery>,
+    ) -> io::Result<Option<Self>> {
+        let config = &edgedns_context.config;
+        let prime_file = match config.prime_file {
+            None => return Ok(None),
+            Some(ref prime_file) => prime_file,
+        };
+        let questions = Self::load(prime_file)?;
+        info!("{} names loaded from {}", questions.len(), prime_file);
+        Ok(Some(CachePrimer {
+            questions: Arc::new(questions),
+            prime_qps: config.prime_qps,
+            query_deadline_ms: config.query_deadline_ms,
+            resolver_tx: resolver_tx,
+            cache: edgedns_context.cache.clone(),
+            varz: edgedns_context.varz.clone(),
+            running: Arc::new(AtomicBool::new(false)),
+            warmup_complete: edgedns_context.warmup_complete.clone(),
+        }))
+    }
+
+    fn load(prime_file: &str) -> io::Result<Vec<NormalizedQuestion>> {
+        let reader = BufReader::new(File::open(prime_file)?);
+        let mut questions = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            match parse_line(&line?) {
+                Ok(None) => {}
+                Ok(Some(normalized_question)) => questions.push(normalized_question),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}, line {}: {}", prime_file, i + 1, e),
+                    ))
+                }
+            }
+        }
+        Ok(questions)
+    }
+
+    fn is_cached(&mut self, normalized_question: &NormalizedQuestion) -> bool {
+        match self.cache.get(&normalized_question.key()) {
+            None => false,
+            Some(cache_entry) => {
+                !cache_entry.is_expired() &&
+                    dns::rcode(&cache_entry.packet) != dns::DNS_RCODE_SERVFAIL
+            }
+        }
+    }
+
+    fn run(mut self) {
+        let questions = self.questions.clone();
+        let delay = Duration::from_millis(1000 / self.prime_qps as u64);
+        let mut resolver_tx = self.resolver_tx.clone();
+        let mut sent = 0;
+        for normalized_question in questions.iter() {
+            if self.is_cached(normalized_question) {
+                continue;
+            }
+            let client_query =
+                ClientQuery::internal(normalized_question.clone(), self.varz.clone());
+            resolver_tx = match resolver_tx.send(client_query).wait() {
+                Ok(resolver_tx) => resolver_tx,
+                Err(_) => {
+                    warn!("Resolver unavailable - Cache priming aborted");
+                    // Readiness reports the unresponsive resolver instead
+                    self.warmup_complete.store(true, Relaxed);
+                    self.running.store(false, Relaxed);
+                    return;
+                }
+            };
+            sent += 1;
+            thread::sleep(delay);
+        }
+        thread::sleep(Duration::from_millis(self.query_deadline_ms));
+        let primed = questions.iter().filter(|x| self.is_cached(x)).count();
+        info!(
+            "Cache priming done: {} names cached, {} failed, {} queries sent",
+            primed,
+            questions.len() - primed,
+            sent
+        );
+        self.warmup_complete.store(true, Relaxed);
+        self.running.store(false, Relaxed);
+    }
+
+    /// Starts priming the cache in the background. Returns `false` if this
+    /// is already in progress.
+    pub fn start(&self) -> bool {
+        if self.running.swap(true, Relaxed) {
+            return false;
+        }
+        info!("Priming the cache with {} names", self.questions.len());
+        let cache_primer = self.clone();
+        thread::Builder::new()
+            .name("cache_primer".to_string())
+            .spawn(move || cache_primer.run())
+            .expect("Unable to spawn the cache primer");
+        true
+    }
+}
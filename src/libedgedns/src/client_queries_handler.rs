@@ -8,8 +8,8 @@
 //! regular probes have been successfully received.
 
 use cache::Cache;
-use client_query::ClientQuery;
-use coarsetime::{Duration, Instant};
+use client_query::{AnswerSource, ClientQuery};
+use clock::{Clock, Duration, Instant};
 use config::Config;
 use dns::{self, NormalizedQuestion, NormalizedQuestionKey, NormalizedQuestionMinimal};
 use futures::Future;
@@ -19,10 +19,14 @@ use futures::sync::mpsc::Receiver;
 use futures::sync::oneshot;
 use jumphash::JumpHasher;
 use parking_lot::RwLock;
-use pending_query::{PendingQueries, PendingQuery};
+use pending_query::{PendingQueries, PendingQuery, PendingQueryKey};
 use rand::distributions::{IndependentSample, Range};
 use rand;
-use resolver::{LoadBalancingMode, ResolverCore};
+use maintenance::Maintenance;
+use resolver::{EcsPolicy, ExtUdpSockets, LoadBalancingMode, ResolverCore, ShedAction,
+               UpstreamFamily};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::net;
 use std::rc::Rc;
@@ -30,24 +34,100 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::time;
-use super::{UPSTREAM_PROBES_DELAY_MS, UPSTREAM_QUERY_MAX_TIMEOUT_MS};
+use super::{ECS_PREFIX_V4, ECS_PREFIX_V6, STARTUP_WAIT_POLL_MS};
 use tokio_core::reactor::Handle;
 use tokio_timer::{wheel, Timer};
 use upstream_server::UpstreamServer;
-use varz::Varz;
+use varz::{StartInstant, Varz};
+
+const ERR_UPSTREAM_RATELIMITED: &'static str = "Upstream server is rate limited";
+const ERR_ALL_UPSTREAMS_RATELIMITED: &'static str = "All upstream servers are rate limited";
+
+/// Returns `true` if a query couldn't be sent because the upstream servers
+/// it could go to ran out of rate limit tokens, as opposed to being down.
+fn is_upstream_ratelimit_error(e: &str) -> bool {
+    e == ERR_UPSTREAM_RATELIMITED || e == ERR_ALL_UPSTREAMS_RATELIMITED
+}
+
+/// Limits the number of queries being sent upstream at the same time, as well
+/// as the number of queries waiting for their turn.
+struct QuerySlots {
+    active: usize,
+    max_active: usize,
+    waiting: VecDeque<oneshot::Sender<()>>,
+    max_waiting: usize,
+    room_tx: Option<oneshot::Sender<()>>,
+}
+
+impl QuerySlots {
+    fn new(max_active: usize, max_waiting: usize) -> Self {
+        QuerySlots {
+            active: 0,
+            max_active: max_active,
+            waiting: VecDeque::new(),
+            max_waiting: max_waiting,
+            room_tx: None,
+        }
+    }
+
+    /// Returns a future that completes once a slot has been taken.
+    fn acquire(&mut self) -> Box<Future<Item = (), Error = io::Error>> {
+        if self.active < self.max_active {
+            self.active += 1;
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.waiting.push_back(tx);
+        Box::new(rx.map_err(|_| io::Error::last_os_error()))
+    }
+
+    /// Hands a slot over to the next waiting query, or frees it.
+    fn release(&mut self) {
+        loop {
+            match self.waiting.pop_front() {
+                None => {
+                    self.active -= 1;
+                    break;
+                }
+                Some(tx) => if tx.send(()).is_ok() {
+                    break;
+                },
+            }
+        }
+        if self.waiting.len() < self.max_waiting {
+            if let Some(room_tx) = self.room_tx.take() {
+                let _ = room_tx.send(());
+            }
+        }
+    }
+
+    /// Returns a future that completes once more queries can wait for a slot.
+    fn fut_room(&mut self) -> Box<Future<Item = (), Error = io::Error>> {
+        if self.waiting.len() < self.max_waiting {
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.room_tx = Some(tx);
+        Box::new(rx.map_err(|_| io::Error::last_os_error()))
+    }
+}
 
 pub struct ClientQueriesHandler {
     cache: Cache,
     config: Rc<Config>,
     handle: Handle,
-    net_udp_socket: net::UdpSocket,
-    net_ext_udp_sockets_rc: Rc<Vec<net::UdpSocket>>,
+    net_udp_socket: Rc<net::UdpSocket>,
+    net_ext_udp_sockets_rc: Rc<ExtUdpSockets>,
     pending_queries: PendingQueries,
     upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
     upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
+    maintenance: Maintenance,
     waiting_clients_count: Rc<AtomicUsize>,
+    query_slots: Rc<RefCell<QuerySlots>>,
+    scheduled_refreshes: Rc<RefCell<HashSet<NormalizedQuestionKey>>>,
     jumphasher: JumpHasher,
     timer: Timer,
+    clock: Arc<Clock>,
     varz: Arc<Varz>,
 }
 
@@ -57,14 +137,18 @@ impl Clone for ClientQueriesHandler {
             cache: self.cache.clone(),
             config: self.config.clone(),
             handle: self.handle.clone(),
-            net_udp_socket: self.net_udp_socket.try_clone().unwrap(),
+            net_udp_socket: self.net_udp_socket.clone(),
             net_ext_udp_sockets_rc: self.net_ext_udp_sockets_rc.clone(),
             pending_queries: self.pending_queries.clone(),
             upstream_servers_arc: self.upstream_servers_arc.clone(),
             upstream_servers_live_arc: self.upstream_servers_live_arc.clone(),
+            maintenance: self.maintenance.clone(),
             waiting_clients_count: self.waiting_clients_count.clone(),
+            query_slots: self.query_slots.clone(),
+            scheduled_refreshes: self.scheduled_refreshes.clone(),
             jumphasher: self.jumphasher,
             timer: self.timer.clone(),
+            clock: self.clock.clone(),
             varz: self.varz.clone(),
         }
     }
@@ -79,31 +163,53 @@ impl ClientQueriesHandler {
             cache: resolver_core.cache.clone(),
             config: resolver_core.config.clone(),
             handle: resolver_core.handle.clone(),
-            net_udp_socket: resolver_core.net_udp_socket.try_clone().unwrap(),
+            net_udp_socket: Rc::new(
+                resolver_core
+                    .net_udp_socket
+                    .try_clone()
+                    .expect("Unable to clone the UDP listening socket"),
+            ),
             net_ext_udp_sockets_rc: resolver_core.net_ext_udp_sockets_rc.clone(),
             pending_queries: resolver_core.pending_queries.clone(),
             upstream_servers_arc: resolver_core.upstream_servers_arc.clone(),
             upstream_servers_live_arc: resolver_core.upstream_servers_live_arc.clone(),
+            maintenance: Maintenance::new(
+                &resolver_core.config,
+                resolver_core.maintenance_mode.clone(),
+            ),
             waiting_clients_count: resolver_core.waiting_clients_count.clone(),
+            query_slots: Rc::new(RefCell::new(QuerySlots::new(
+                resolver_core.config.max_active_queries,
+                resolver_core.config.max_active_queries,
+            ))),
+            scheduled_refreshes: Rc::new(RefCell::new(HashSet::new())),
             jumphasher: resolver_core.jumphasher,
             timer: timer,
+            clock: resolver_core.clock.clone(),
             varz: resolver_core.varz.clone(),
         }
     }
 
+    /// Processes the queries sent by the listeners.
+    ///
+    /// Queries answered without contacting upstream servers are processed
+    /// right away. At most `max_active_queries` other queries are sent
+    /// upstream at the same time, and as many can wait for their turn. Once
+    /// that many are waiting, no more queries are read from the channel
+    /// until one of them completes. The channel then fills up, and listeners
+    /// wait before sending more queries.
     pub fn fut_process_stream(
         &self,
-        handle: &Handle,
         resolver_rx: Receiver<ClientQuery>,
     ) -> impl Future<Item = (), Error = io::Error> {
-        let handle = handle.clone();
+        let handle = self.handle.clone();
         let mut self_inner = self.clone();
         let fut_client_query = resolver_rx.for_each(move |client_query| {
             let fut = self_inner
                 .fut_process_client_query(client_query)
                 .map_err(|_| {});
             handle.spawn(fut);
-            future::ok(())
+            self_inner.query_slots.borrow_mut().fut_room().map_err(|_| {})
         });
         fut_client_query.map_err(|_| io::Error::last_os_error())
     }
@@ -118,7 +224,7 @@ impl ClientQueriesHandler {
             None => return false,
             Some(key) => key.clone(),
         };
-        if let Some(pending_query) = map.remove(&key) {
+        if let Some(pending_query) = self.pending_queries.remove(&mut map, &key) {
             self.varz.inflight_queries.dec();
             let clients_count = pending_query.client_queries.len();
             let prev_count = self.waiting_clients_count.fetch_sub(clients_count, Relaxed);
@@ -129,11 +235,11 @@ impl ClientQueriesHandler {
 
     fn maybe_add_to_existing_pending_query(
         &mut self,
-        normalized_question_key: &NormalizedQuestionKey,
+        key: &PendingQueryKey,
         client_query: &ClientQuery,
     ) -> bool {
         let mut pending_queries = self.pending_queries.map_arc.write();
-        match pending_queries.get_mut(normalized_question_key) {
+        match pending_queries.get_mut(key) {
             None => false,
             Some(pending_query) => {
                 pending_query.client_queries.push(client_query.clone());
@@ -143,20 +249,54 @@ impl ClientQueriesHandler {
         }
     }
 
+    /// Answers a query that couldn't be resolved with a stale entry, or with
+    /// `SERVFAIL`. `ratelimited` tells whether this is because of upstream
+    /// rate limits rather than unresponsive servers, for accounting.
     fn maybe_respond_with_stale_entry(
         &mut self,
         client_query: &ClientQuery,
+        ratelimited: bool,
     ) -> Box<Future<Item = (), Error = io::Error>> {
+        if client_query.refresh {
+            return Box::new(future::ok(()));
+        }
         let normalized_question = &client_query.normalized_question;
         let cache_entry = self.cache.get2(normalized_question);
         if let Some(mut cache_entry) = cache_entry {
-            self.varz.client_queries_offline.inc();
-            debug!("All upstream servers are down - Responding with stale entry");
-            return client_query.response_send(&mut cache_entry.packet, Some(&self.net_udp_socket));
+            if cache_entry.is_within_stale_window(self.config.stale_ttl) {
+                if ratelimited {
+                    self.varz.client_queries_upstream_ratelimited.inc();
+                    debug!("Upstream servers are rate limited - Responding with stale entry");
+                } else {
+                    self.varz.client_queries_offline.inc();
+                    self.varz.client_queries_offline_stale.inc();
+                    debug!("All upstream servers are down - Responding with stale entry");
+                }
+                if cache_entry.is_expired() {
+                    self.varz.client_queries_served_stale.inc();
+                    let _ = dns::set_ttl(&mut cache_entry.packet, self.config.stale_refresh_ttl);
+                }
+                return client_query.response_send(
+                    &mut cache_entry.packet,
+                    Some(&*self.net_udp_socket),
+                    AnswerSource::Stale,
+                );
+            }
         }
         if let Ok(mut packet) = dns::build_servfail_packet(normalized_question) {
-            debug!("Returning SERVFAIL due to upstream timeouts");
-            return client_query.response_send(&mut packet, Some(&self.net_udp_socket));
+            if ratelimited {
+                debug!("Returning SERVFAIL due to upstream rate limits");
+                self.varz.client_queries_upstream_ratelimited.inc();
+            } else {
+                debug!("Returning SERVFAIL due to upstream timeouts");
+                self.varz.client_queries_offline.inc();
+                self.varz.client_queries_offline_servfail.inc();
+            }
+            return client_query.response_send(
+                &mut packet,
+                Some(&*self.net_udp_socket),
+                AnswerSource::Synth,
+            );
         }
         Box::new(future::ok(()))
     }
@@ -164,22 +304,54 @@ impl ClientQueriesHandler {
     fn maybe_respond_to_all_clients_with_stale_entry(
         &mut self,
         pending_query: &PendingQuery,
+        ratelimited: bool,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         let mut fut = Vec::with_capacity(pending_query.client_queries.len());
         for client_query in &pending_query.client_queries {
-            fut.push(self.maybe_respond_with_stale_entry(client_query));
+            fut.push(self.maybe_respond_with_stale_entry(client_query, ratelimited));
         }
         Box::new(future::join_all(fut).map(|_| {}))
     }
 
+    fn fut_abort_pending_query(
+        &mut self,
+        key: &PendingQueryKey,
+        ratelimited: bool,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let pending_query = match self.pending_queries
+            .remove(&mut self.pending_queries.map_arc.write(), key)
+        {
+            None => return Box::new(future::ok(())),
+            Some(pending_query) => pending_query,
+        };
+        self.varz.inflight_queries.dec();
+        if self.config.slow_query_threshold_ms > 0 {
+            let upstream_addr =
+                self.upstream_servers_arc.read()[pending_query.upstream_server_idx].socket_addr;
+            for client_query in &pending_query.client_queries {
+                client_query.log_if_slow(
+                    self.config.slow_query_threshold_ms,
+                    upstream_addr,
+                    pending_query.retries,
+                    "no response",
+                );
+            }
+        }
+        let fut = self.maybe_respond_to_all_clients_with_stale_entry(&pending_query, ratelimited);
+        let _ = pending_query.done_tx.send(());
+        self.waiting_clients_count
+            .fetch_sub(pending_query.client_queries.len(), Relaxed);
+        fut
+    }
+
     fn maybe_send_probe_to_offline_servers(
         &self,
         query_packet: &[u8],
         upstream_servers: &mut Vec<UpstreamServer>,
         upstream_servers_live: &Vec<usize>,
-        net_ext_udp_socket: &net::UdpSocket,
+        net_ext_udp_sockets: &ExtUdpSockets,
     ) -> Result<Option<usize>, io::Error> {
-        if upstream_servers_live.len() == upstream_servers.len() {
+        if upstream_servers_live.len() == self.config.upstream_servers.len() {
             return Ok(None);
         }
         let offline_servers: Vec<_> = upstream_servers
@@ -200,63 +372,339 @@ impl ClientQueriesHandler {
         let random_offline_server_idx =
             offline_servers[random_offline_server_range.ind_sample(&mut rng)];
         let random_offline_server = &mut upstream_servers[random_offline_server_idx];
-        if let Some(last_probe_ts) = random_offline_server.last_probe_ts {
-            if last_probe_ts.elapsed_since_recent() <
-                Duration::from_millis(UPSTREAM_PROBES_DELAY_MS)
-            {
-                return Ok(None);
-            }
+        let net_ext_udp_socket =
+            match net_ext_udp_sockets.random_for(&random_offline_server.socket_addr) {
+                None => return Ok(None),
+                Some(net_ext_udp_socket) => net_ext_udp_socket,
+            };
+        if !random_offline_server.claim_offline_probe(&self.config) {
+            return Ok(None);
         }
         info!("Sending probe to {}", random_offline_server.remote_addr);
-        random_offline_server.last_probe_ts = Some(Instant::recent());
         net_ext_udp_socket
             .send_to(query_packet, &random_offline_server.socket_addr)
             .map(|_| Some(random_offline_server_idx))
     }
 
+    fn fut_respond_for_maintenance(
+        &self,
+        client_query: &ClientQuery,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        match self.maintenance
+            .build_response_packet(&client_query.normalized_question)
+        {
+            Ok(mut packet) => {
+                debug!("Maintenance mode - Responding with a static response");
+                client_query.response_send(
+                    &mut packet,
+                    Some(&*self.net_udp_socket),
+                    AnswerSource::Synth,
+                )
+            }
+            Err(_) => Box::new(future::ok(())),
+        }
+    }
+
+    /// Listeners only send queries whose response is not cached, or has expired.
+    /// But the response may have been cached in the meantime, in particular if
+    /// a refresh was in flight. Clients can then be answered right away, rather
+    /// than waiting for another response.
+    fn maybe_respond_from_cache(
+        &mut self,
+        client_query: &ClientQuery,
+    ) -> Option<Box<Future<Item = (), Error = io::Error>>> {
+        if client_query.refresh {
+            return None;
+        }
+        let mut cache_entry = match self.cache.get2(&client_query.normalized_question) {
+            None => return None,
+            Some(cache_entry) => cache_entry,
+        };
+        if cache_entry.is_expired() {
+            return None;
+        }
+        debug!("Response cached since the query was received");
+        self.varz.client_queries_cached.inc();
+        let source = cache_entry.source();
+        Some(client_query.response_send(
+            &mut cache_entry.packet,
+            Some(&*self.net_udp_socket),
+            source,
+        ))
+    }
+
+    /// Returns `true` if a new query should be shed. Past the high-water mark,
+    /// the probability of shedding a query grows linearly with the number of
+    /// inflight queries, up to 1 at twice the mark.
+    fn should_shed(&self) -> bool {
+        let high_water_mark = self.config.shed_inflight_queries;
+        if high_water_mark == 0 {
+            return false;
+        }
+        let inflight_queries = self.pending_queries.map_arc.read().len();
+        if inflight_queries <= high_water_mark {
+            return false;
+        }
+        let excess = (inflight_queries - high_water_mark) as f64 / high_water_mark as f64;
+        rand::random::<f64>() < excess
+    }
+
+    fn fut_shed(&self, client_query: &ClientQuery) -> Box<Future<Item = (), Error = io::Error>> {
+        self.varz.queries_shed.inc();
+        if self.config.shed_action == ShedAction::Drop {
+            return Box::new(future::ok(()));
+        }
+        match dns::build_refused_packet(&client_query.normalized_question) {
+            Ok(mut packet) => client_query.response_send(
+                &mut packet,
+                Some(&*self.net_udp_socket),
+                AnswerSource::Synth,
+            ),
+            Err(_) => Box::new(future::ok(())),
+        }
+    }
+
+    fn is_starting_up(&self) -> bool {
+        let StartInstant(start_instant) = self.varz.start_instant;
+        self.clock.elapsed(start_instant) < self.config.startup_wait
+    }
+
+    /// Right after startup, no upstream servers may have been confirmed to be
+    /// live yet. Instead of immediately failing, hold the query until the
+    /// initial health checks complete, or the `startup_wait` delay expires.
+    fn fut_wait_for_live_servers(
+        &mut self,
+        client_query: ClientQuery,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        debug!("No live upstream servers yet - Delaying the query");
+        let mut self_inner = self.clone();
+        let fut = self.timer
+            .sleep(time::Duration::from_millis(STARTUP_WAIT_POLL_MS))
+            .map_err(|_| io::Error::last_os_error())
+            .and_then(move |_| self_inner.fut_process_client_query(client_query));
+        Box::new(fut)
+    }
+
+    /// Queries that are not coalesced are keyed by their transaction ID.
+    /// Draw a new one if a similar query with the same ID is still pending,
+    /// so that it doesn't replace that query.
+    fn ensure_unique_tid(
+        &self,
+        normalized_question: &NormalizedQuestion,
+        query_packet: &mut [u8],
+        normalized_question_minimal: &mut NormalizedQuestionMinimal,
+    ) {
+        let map = self.pending_queries.map_arc.read();
+        let mut key = PendingQueryKey::new(
+            normalized_question.key(),
+            Some(normalized_question_minimal.tid),
+        );
+        while map.contains_key(&key) {
+            let tid = rand::random();
+            dns::set_tid(query_packet, tid);
+            normalized_question_minimal.tid = tid;
+            key.tid = Some(tid);
+        }
+    }
+
+    /// Spreads timeouts by up to `timeout_jitter_percent` in either direction,
+    /// so that queries sent at the same time don't all get retried in lockstep.
+    fn jittered_timeout_ms(&self, timeout_ms: u64) -> u64 {
+        let max_jitter = timeout_ms * self.config.upstream_timeout_jitter_percent / 100;
+        if max_jitter == 0 {
+            return timeout_ms;
+        }
+        let mut rng = rand::thread_rng();
+        let jitter_range = Range::new(0, max_jitter * 2 + 1);
+        timeout_ms - max_jitter + jitter_range.ind_sample(&mut rng)
+    }
+
+    /// Delays the refresh of a stale entry by up to `stale_refresh_jitter_ms`,
+    /// so that entries that expired at the same time don't get refreshed in
+    /// lockstep. A single refresh is scheduled per entry, and none if one is
+    /// already in flight. The refresh is dropped if the entry was refreshed in
+    /// the meantime, and otherwise coalesced with a similar query in flight.
+    fn fut_jittered_refresh(
+        &mut self,
+        mut client_query: ClientQuery,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        client_query.refresh_jitter = false;
+        let key = client_query.normalized_question.key();
+        if self.pending_queries
+            .map_arc
+            .read()
+            .contains_key(&PendingQueryKey::new(key.clone(), None))
+            || !self.scheduled_refreshes.borrow_mut().insert(key.clone())
+        {
+            debug!("Stale entry refresh already scheduled");
+            return Box::new(future::ok(()));
+        }
+        let max_delay_ms = self.config.stale_refresh_jitter_ms;
+        let delay_ms = Range::new(0, max_delay_ms + 1).ind_sample(&mut rand::thread_rng());
+        let mut self_inner = self.clone();
+        let fut = self.timer
+            .sleep(time::Duration::from_millis(delay_ms))
+            .map_err(|_| io::Error::last_os_error())
+            .then(move |res| {
+                self_inner.scheduled_refreshes.borrow_mut().remove(&key);
+                if res.is_err() {
+                    return Box::new(future::ok(())) as Box<Future<Item = _, Error = _>>;
+                }
+                let refreshed = self_inner
+                    .cache
+                    .get2(&client_query.normalized_question)
+                    .map_or(false, |cache_entry| !cache_entry.is_expired());
+                if refreshed {
+                    debug!("Stale entry already refreshed");
+                    return Box::new(future::ok(()));
+                }
+                self_inner.fut_process_client_query(client_query)
+            });
+        Box::new(fut)
+    }
+
     fn fut_process_client_query(
         &mut self,
         client_query: ClientQuery,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         debug!("Incoming client query");
+        if client_query.refresh_jitter && self.config.stale_refresh_jitter_ms > 0 {
+            return self.fut_jittered_refresh(client_query);
+        }
+        if self.maintenance.is_in_maintenance(&client_query.normalized_question) {
+            return self.fut_respond_for_maintenance(&client_query);
+        }
+        if let Some(fut) = self.maybe_respond_from_cache(&client_query) {
+            return fut;
+        }
+        if self.should_shed() {
+            debug!("Too many inflight queries - Shedding a query");
+            return self.fut_shed(&client_query);
+        }
         if self.upstream_servers_live_arc.read().is_empty() {
-            return self.maybe_respond_with_stale_entry(&client_query);
+            if self.is_starting_up() {
+                return self.fut_wait_for_live_servers(client_query);
+            }
+            return self.maybe_respond_with_stale_entry(&client_query, false);
         }
+        if self.maybe_coalesce(&client_query) {
+            return Box::new(future::ok(()));
+        }
+        self.fut_with_query_slot(client_query)
+    }
+
+    /// Adds the query to a similar query in flight, if there is one.
+    fn maybe_coalesce(&mut self, client_query: &ClientQuery) -> bool {
+        let normalized_question = &client_query.normalized_question;
+        if self.config
+            .no_coalescing_qtypes
+            .contains(&normalized_question.qtype)
+        {
+            return false;
+        }
+        self.maybe_add_to_existing_pending_query(
+            &PendingQueryKey::new(normalized_question.key(), None),
+            client_query,
+        )
+    }
+
+    /// Sends a query upstream once a slot is available, and frees the slot
+    /// once the query has been answered, or has failed.
+    fn fut_with_query_slot(
+        &mut self,
+        client_query: ClientQuery,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let mut self_inner = self.clone();
+        let query_slots = self.query_slots.clone();
+        let fut_slot = self.query_slots.borrow_mut().acquire();
+        let fut = fut_slot.and_then(move |_| {
+            self_inner.fut_query_upstream(client_query).then(move |res| {
+                query_slots.borrow_mut().release();
+                res
+            })
+        });
+        Box::new(fut)
+    }
+
+    fn fut_query_upstream(
+        &mut self,
+        client_query: ClientQuery,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
         let normalized_question = &client_query.normalized_question;
-        let key = normalized_question.key();
+        let coalesce = !self.config
+            .no_coalescing_qtypes
+            .contains(&normalized_question.qtype);
         self.cap_pending_queries();
-        if self.maybe_add_to_existing_pending_query(&key, &client_query) {
+        if coalesce &&
+            self.maybe_add_to_existing_pending_query(
+                &PendingQueryKey::new(normalized_question.key(), None),
+                &client_query,
+            ) {
             return Box::new(future::ok(()));
         }
         let mut upstream_servers = self.upstream_servers_arc.write();
-        let (query_packet, normalized_question_minimal, upstream_server_idx, net_ext_udp_socket) =
-            match normalized_question.new_pending_query(
-                &upstream_servers,
+        let (
+            mut query_packet,
+            mut normalized_question_minimal,
+            upstream_server_idx,
+            net_ext_udp_socket,
+        ) = match normalized_question.new_pending_query(
+                &mut upstream_servers,
                 &self.upstream_servers_live_arc.read(),
                 &self.net_ext_udp_sockets_rc,
                 &self.jumphasher,
                 false,
                 self.config.lbmode,
-            ) {
-                Err(_) => return Box::new(future::ok(())),
-                Ok(res) => res,
-            };
+                &self.config.upstream_routes,
+                &self.config,
+                &self.varz,
+        ) {
+            Err(e) => {
+                debug!("No upstream server to send {} to: {}", normalized_question, e);
+                drop(upstream_servers);
+                let ratelimited = is_upstream_ratelimit_error(e);
+                return self.clone().maybe_respond_with_stale_entry(&client_query, ratelimited);
+            }
+            Ok(res) => res,
+        };
+        if !coalesce {
+            self.ensure_unique_tid(
+                normalized_question,
+                &mut query_packet,
+                &mut normalized_question_minimal,
+            );
+        }
         let probe_idx = self.maybe_send_probe_to_offline_servers(
             &query_packet,
             &mut upstream_servers,
             &self.upstream_servers_live_arc.read(),
-            net_ext_udp_socket,
+            &self.net_ext_udp_sockets_rc,
         );
+        let key = if coalesce {
+            PendingQueryKey::new(normalized_question.key(), None)
+        } else {
+            PendingQueryKey::new(
+                normalized_question.key(),
+                Some(normalized_question_minimal.tid),
+            )
+        };
         let upstream_server = &mut upstream_servers[upstream_server_idx];
         let (done_tx, done_rx) = oneshot::channel();
-        let mut pending_query = PendingQuery::new(
+        let mut pending_query = match PendingQuery::new(
             normalized_question_minimal,
             upstream_server,
             upstream_server_idx,
             net_ext_udp_socket,
             &client_query,
             done_tx,
-        );
+        ) {
+            Ok(pending_query) => pending_query,
+            Err(e) => {
+                warn!("Unable to create a new pending query: {}", e);
+                self.varz.upstream_socket_errors.inc();
+                return self.clone().maybe_respond_with_stale_entry(&client_query, false);
+            }
+        };
         debug_assert_eq!(pending_query.client_queries.len(), 1);
         self.waiting_clients_count.fetch_add(1, Relaxed);
         if let Ok(Some(probe_idx)) = probe_idx {
@@ -277,19 +725,20 @@ impl ClientQueriesHandler {
             upstream_server_idx,
             upstream_server.pending_queries_count
         );
-        map.insert(key, pending_query);
+        self.pending_queries
+            .insert(&mut map, key.clone(), pending_query);
         let _ = net_ext_udp_socket.send_to(&query_packet, &upstream_server.socket_addr);
         self.varz.upstream_sent.inc();
         let done_rx = done_rx.map_err(|_| ());
-        let timeout = self.timer.timeout(
-            done_rx,
-            time::Duration::from_millis(upstream_server.timeout_ms_est()),
-        );
-        let retry_query = self.clone();
+        let timeout_ms = self.jittered_timeout_ms(upstream_server.timeout_ms_est(&self.config));
+        let timeout = self.timer
+            .timeout(done_rx, time::Duration::from_millis(timeout_ms));
+        let mut retry_query = self.clone();
         let upstream_servers_arc = self.upstream_servers_arc.clone();
         let upstream_servers_live_arc = self.upstream_servers_live_arc.clone();
         let config = self.config.clone();
         let normalized_question = normalized_question.clone();
+        let retry_key = key.clone();
         let handle = self.handle.clone();
         let net_ext_udp_sockets_rc = self.net_ext_udp_sockets_rc.clone();
         let fut = timeout
@@ -307,18 +756,45 @@ impl ClientQueriesHandler {
                     *upstream_servers_live_arc.write() =
                         UpstreamServer::live_servers(&mut upstream_servers);
                 }
-                retry_query.fut_retry_query(normalized_question)
+                if !config.enable_retry {
+                    debug!("Retries are disabled, giving up");
+                    return retry_query.fut_abort_pending_query(&retry_key, false);
+                }
+                retry_query.fut_retry_query(normalized_question, retry_key)
+            });
+        let elapsed_ms = (client_query.ts.elapsed().as_f64() * 1000.0) as u64;
+        let remaining_ms = self.config
+            .query_deadline_ms
+            .saturating_sub(elapsed_ms)
+            .max(1);
+        let mut deadline_query = self.clone();
+        let fut = self.timer
+            .timeout(fut, time::Duration::from_millis(remaining_ms))
+            .or_else(move |_| {
+                debug!("Query deadline exceeded, giving up");
+                deadline_query.release_upstream_server(&key);
+                deadline_query.fut_abort_pending_query(&key, false)
             });
         Box::new(fut)
     }
 
+    fn release_upstream_server(&mut self, key: &PendingQueryKey) {
+        let map = self.pending_queries.map_arc.read();
+        if let Some(pending_query) = map.get(key) {
+            let mut upstream_servers = self.upstream_servers_arc.write();
+            let upstream_server = &mut upstream_servers[pending_query.upstream_server_idx];
+            upstream_server.pending_queries_count =
+                upstream_server.pending_queries_count.saturating_sub(1);
+        }
+    }
+
     fn fut_retry_query(
         &self,
         normalized_question: NormalizedQuestion,
+        key: PendingQueryKey,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         debug!("timeout");
         let mut map = self.pending_queries.map_arc.write();
-        let key = normalized_question.key();
         let pending_query = match map.get_mut(&key) {
             None => return Box::new(future::ok(())) as Box<Future<Item = (), Error = io::Error>>,
             Some(pending_query) => pending_query,
@@ -336,31 +812,57 @@ impl ClientQueriesHandler {
         );
 
         let nq = normalized_question.new_pending_query(
-            &upstream_servers,
+            &mut upstream_servers,
             &self.upstream_servers_live_arc.read(),
             &self.net_ext_udp_sockets_rc,
             &self.jumphasher,
             true,
             self.config.lbmode,
+            &self.config.upstream_routes,
+            &self.config,
+            &self.varz,
         );
-        let (query_packet, normalized_question_minimal, upstream_server_idx, net_ext_udp_socket) =
-            match nq {
-                Ok(x) => x,
-                Err(_) => {
-                    return Box::new(future::ok(())) as Box<Future<Item = (), Error = io::Error>>
-                }
-            };
+        let (
+            mut query_packet,
+            mut normalized_question_minimal,
+            upstream_server_idx,
+            net_ext_udp_socket,
+        ) = match nq {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("No upstream server to retry {} with: {}", normalized_question, e);
+                drop(upstream_servers);
+                drop(map);
+                let ratelimited = is_upstream_ratelimit_error(e);
+                return self.clone().fut_abort_pending_query(&key, ratelimited);
+            }
+        };
+        if let Some(tid) = key.tid {
+            dns::set_tid(&mut query_packet, tid);
+            normalized_question_minimal.tid = tid;
+        }
         let upstream_server = &mut upstream_servers[upstream_server_idx];
 
         debug!(
             "new attempt with upstream server: {:?}",
             upstream_server.socket_addr
         );
+        let local_port = match net_ext_udp_socket.local_addr() {
+            Ok(local_addr) => local_addr.port(),
+            Err(e) => {
+                warn!("Unable to retry a pending query: {}", e);
+                self.varz.upstream_socket_errors.inc();
+                drop(upstream_servers);
+                drop(map);
+                return self.clone().fut_abort_pending_query(&key, false);
+            }
+        };
         let (done_tx, done_rx) = oneshot::channel();
         pending_query.normalized_question_minimal = normalized_question_minimal;
-        pending_query.local_port = net_ext_udp_socket.local_addr().unwrap().port();
-        pending_query.ts = Instant::recent();
+        pending_query.local_port = local_port;
+        pending_query.ts = upstream_server.clock.now();
         pending_query.upstream_server_idx = upstream_server_idx;
+        pending_query.retries = pending_query.retries.saturating_add(1);
         pending_query.done_tx = done_tx;
         let _ = net_ext_udp_socket.send_to(&query_packet, &upstream_server.socket_addr);
         upstream_server.pending_queries_count =
@@ -373,10 +875,8 @@ impl ClientQueriesHandler {
         let done_rx = done_rx.map_err(|_| ());
         let timeout = self.timer.timeout(
             done_rx,
-            time::Duration::from_millis(UPSTREAM_QUERY_MAX_TIMEOUT_MS),
+            time::Duration::from_millis(self.config.upstream_query_max_timeout_ms),
         );
-        let map_arc = self.pending_queries.map_arc.clone();
-        let waiting_clients_count = self.waiting_clients_count.clone();
         let upstream_servers_arc = self.upstream_servers_arc.clone();
         let upstream_servers_live_arc = self.upstream_servers_live_arc.clone();
         let config = self.config.clone();
@@ -406,16 +906,7 @@ impl ClientQueriesHandler {
                     *upstream_servers_live_arc.write() =
                         UpstreamServer::live_servers(&mut upstream_servers);
                 }
-                let mut map = map_arc.write();
-                if let Some(pending_query) = map.remove(&key) {
-                    varz.inflight_queries.dec();
-                    let fut =
-                        retry_query.maybe_respond_to_all_clients_with_stale_entry(&pending_query);
-                    let _ = pending_query.done_tx.send(());
-                    waiting_clients_count.fetch_sub(pending_query.client_queries.len(), Relaxed);
-                    return fut;
-                }
-                Box::new(future::ok(())) as Box<Future<Item = (), Error = io::Error>>
+                retry_query.fut_abort_pending_query(&key, false)
             });
         debug!("retrying...");
         Box::new(fut) as Box<Future<Item = (), Error = io::Error>>
@@ -424,6 +915,21 @@ impl ClientQueriesHandler {
 
 /// Local additions to the `NormalizedQuestion` struct, for convenience
 impl NormalizedQuestion {
+    /// Returns the server a static route sends this question to, if any.
+    fn routed_upstream(
+        &self,
+        upstream_servers: &Vec<UpstreamServer>,
+        upstream_routes: &HashMap<Vec<u8>, String>,
+    ) -> Option<usize> {
+        if upstream_routes.is_empty() {
+            return None;
+        }
+        let remote_addr = upstream_routes.get(&dns::qname_lc(&self.qname))?;
+        upstream_servers
+            .iter()
+            .position(|upstream_server| &upstream_server.remote_addr == remote_addr)
+    }
+
     fn pick_upstream(
         &self,
         upstream_servers: &Vec<UpstreamServer>,
@@ -438,7 +944,10 @@ impl NormalizedQuestion {
             return Err("All upstream servers are down");
         }
         match lbmode {
-            LoadBalancingMode::Fallback => Ok(upstream_servers_live[0]),
+            LoadBalancingMode::Fallback => {
+                UpstreamServer::preferred_server(upstream_servers, upstream_servers_live)
+                    .ok_or("All upstream servers are down")
+            }
             LoadBalancingMode::Uniform => {
                 let mut i = jumphasher.slot(&self.qname, live_count as u32) as usize;
                 if is_retry {
@@ -447,29 +956,60 @@ impl NormalizedQuestion {
                 Ok(upstream_servers_live[i])
             }
             LoadBalancingMode::P2 => {
-                let mut busy_map = upstream_servers_live
-                    .iter()
-                    .map(|&i| (i, upstream_servers[i].pending_queries_count))
-                    .collect::<Vec<(usize, u64)>>();
-                busy_map.sort_by_key(|x| x.1);
-                let i = if busy_map.len() == 1 {
-                    0
-                } else {
-                    ((self.tid as usize) + (is_retry as usize & 1)) & 1
-                };
-                Ok(busy_map[i].0)
+                UpstreamServer::least_busy_server(
+                    upstream_servers,
+                    upstream_servers_live,
+                    self.tid,
+                    is_retry,
+                ).ok_or("All upstream servers are down")
             }
         }
     }
 
+    /// Builds the query to send to `upstream_server`.
+    pub fn upstream_query_packet(
+        &self,
+        upstream_server: &UpstreamServer,
+        config: &Config,
+    ) -> Result<(Vec<u8>, NormalizedQuestionMinimal), &'static str> {
+        let ecs = match config.ecs_policy {
+            EcsPolicy::Pass => self.ecs.clone(),
+            EcsPolicy::Strip => None,
+            EcsPolicy::Overwrite => self.ecs
+                .as_ref()
+                .and_then(|ecs| dns::ecs_truncate(ecs, ECS_PREFIX_V4, ECS_PREFIX_V6)),
+        };
+        let mut edns_options =
+            dns::edns_options_filter(&self.edns_options, &config.edns_options_allowlist);
+        if config.upstream_cookies {
+            let cookie = upstream_server.cookie();
+            edns_options.push((dns::DNS_EDNS_OPTION_COOKIE >> 8) as u8);
+            edns_options.push(dns::DNS_EDNS_OPTION_COOKIE as u8);
+            edns_options.push((cookie.len() >> 8) as u8);
+            edns_options.push(cookie.len() as u8);
+            edns_options.extend_from_slice(&cookie);
+        }
+        let (mut query_packet, normalized_question_minimal) = dns::build_query_packet(
+            self,
+            false,
+            ecs.as_ref().map(|ecs| &ecs[..]),
+            &edns_options,
+        )?;
+        dns::set_rd(&mut query_packet, upstream_server.recursion_desired);
+        Ok((query_packet, normalized_question_minimal))
+    }
+
     fn new_pending_query<'t>(
         &self,
-        upstream_servers: &Vec<UpstreamServer>,
+        upstream_servers: &mut Vec<UpstreamServer>,
         upstream_servers_live: &Vec<usize>,
-        net_ext_udp_sockets: &'t Vec<net::UdpSocket>,
+        net_ext_udp_sockets: &'t ExtUdpSockets,
         jumphasher: &JumpHasher,
         is_retry: bool,
         lbmode: LoadBalancingMode,
+        upstream_routes: &HashMap<Vec<u8>, String>,
+        config: &Config,
+        varz: &Arc<Varz>,
     ) -> Result<
         (
             Vec<u8>,
@@ -479,27 +1019,73 @@ impl NormalizedQuestion {
         ),
         &'static str,
     > {
-        let (query_packet, normalized_question_minimal) =
-            dns::build_query_packet(self, false).expect("Unable to build a new query packet");
-        let upstream_server_idx = match self.pick_upstream(
-            upstream_servers,
-            upstream_servers_live,
-            jumphasher,
-            is_retry,
-            lbmode,
-        ) {
-            Err(e) => return Err(e),
-            Ok(upstream_server_idx) => upstream_server_idx,
+        let routed_upstream_server_idx = self.routed_upstream(upstream_servers, upstream_routes);
+        let preferred_servers_live;
+        let upstream_servers_live = match config.upstream_prefer_family {
+            UpstreamFamily::Any => upstream_servers_live,
+            prefer_family => {
+                preferred_servers_live = upstream_servers_live
+                    .iter()
+                    .cloned()
+                    .filter(|&i| prefer_family.reaches(&upstream_servers[i].socket_addr))
+                    .collect::<Vec<usize>>();
+                if preferred_servers_live.is_empty() {
+                    upstream_servers_live
+                } else {
+                    &preferred_servers_live
+                }
+            }
         };
-        let mut rng = rand::thread_rng();
-        let random_token_range = Range::new(0usize, net_ext_udp_sockets.len());
-        let random_token = random_token_range.ind_sample(&mut rng);
-        let net_ext_udp_socket = &net_ext_udp_sockets[random_token];
-        Ok((
-            query_packet,
-            normalized_question_minimal,
-            upstream_server_idx,
-            net_ext_udp_socket,
-        ))
+        let mut upstream_server_idx = match routed_upstream_server_idx {
+            Some(upstream_server_idx) => upstream_server_idx,
+            None => match self.pick_upstream(
+                upstream_servers,
+                upstream_servers_live,
+                jumphasher,
+                is_retry,
+                lbmode,
+            ) {
+                Err(e) => return Err(e),
+                Ok(upstream_server_idx) => upstream_server_idx,
+            },
+        };
+        let mut candidates_live = upstream_servers_live.clone();
+        let mut ratelimited = false;
+        loop {
+            let (query_packet, normalized_question_minimal) =
+                self.upstream_query_packet(&upstream_servers[upstream_server_idx], config)?;
+            let net_ext_udp_socket = net_ext_udp_sockets
+                .random_for(&upstream_servers[upstream_server_idx].socket_addr)
+                .ok_or("No socket of the address family of the upstream server")?;
+            // The token is only taken once the query is ready to be sent
+            if upstream_servers[upstream_server_idx].take_ratelimit_token(config) {
+                return Ok((
+                    query_packet,
+                    normalized_question_minimal,
+                    upstream_server_idx,
+                    net_ext_udp_socket,
+                ));
+            }
+            if !ratelimited {
+                ratelimited = true;
+                varz.upstream_ratelimited.inc();
+            }
+            if routed_upstream_server_idx.is_some() {
+                return Err(ERR_UPSTREAM_RATELIMITED);
+            }
+            // Pick the replacement the same way as the original server
+            let picked_idx = upstream_server_idx;
+            candidates_live.retain(|&i| i != picked_idx);
+            upstream_server_idx = match self.pick_upstream(
+                upstream_servers,
+                &candidates_live,
+                jumphasher,
+                is_retry,
+                lbmode,
+            ) {
+                Err(_) => return Err(ERR_ALL_UPSTREAMS_RATELIMITED),
+                Ok(upstream_server_idx) => upstream_server_idx,
+            };
+        }
     }
 }
@@ -3,36 +3,180 @@
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use coarsetime::Instant;
+use config::Config;
 use dns::{self, NormalizedQuestion};
+use duplicate_queries::TrackedQuery;
+use extensions::ResponseRewriter;
 use futures::sync::mpsc::Sender;
 use futures::{future, Future};
 use futures::Sink;
+use query_events::{QueryEvent, QueryEventKind, QueryEvents};
+use rand::{self, Rng};
+use std::fmt;
 use std::io;
-use std::net::{self, SocketAddr};
+use std::net::{self, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
 use super::{DNS_MAX_TCP_SIZE, DNS_MAX_UDP_SIZE, DNS_QUERY_MIN_SIZE};
 use varz::Varz;
 
+/// Shift applied to the addresses of the next response, with `AnswerOrder::Rotate`
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Clone, Debug)]
 pub struct ResolverResponse {
     pub packet: Vec<u8>,
     pub dnssec: bool,
 }
 
+/// Where the response sent to a client comes from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AnswerSource {
+    Cache,
+    Stale,
+    Upstream(SocketAddr),
+    Synth,
+}
+
+impl fmt::Display for AnswerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AnswerSource::Cache => write!(f, "cache"),
+            AnswerSource::Stale => write!(f, "stale"),
+            AnswerSource::Upstream(addr) => write!(f, "upstream:{}", addr),
+            AnswerSource::Synth => write!(f, "synth"),
+        }
+    }
+}
+
+/// Order of the addresses of `A` and `AAAA` records in responses sent to clients
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum AnswerOrder {
+    None,
+    Shuffle,
+    Rotate,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ClientQueryProtocol {
     UDP,
     TCP,
 }
 
+/// Address family that clients can use
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum ClientAddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// Removes the addresses of the family that clients can't use from responses
+pub struct AddressFamilyFilter {
+    filtered_type: u16,
+    soa_mname: Vec<u8>,
+    soa_rname: Vec<u8>,
+    soa_minimum: u32,
+}
+
+impl AddressFamilyFilter {
+    pub fn new(config: &Config) -> Option<Self> {
+        let filtered_type = match config.client_address_family {
+            ClientAddressFamily::Any => return None,
+            ClientAddressFamily::Ipv4 => dns::DNS_TYPE_AAAA,
+            ClientAddressFamily::Ipv6 => dns::DNS_TYPE_A,
+        };
+        Some(AddressFamilyFilter {
+            filtered_type: filtered_type,
+            soa_mname: config.synth_soa_mname.clone(),
+            soa_rname: config.synth_soa_rname.clone(),
+            soa_minimum: config.synth_soa_minimum,
+        })
+    }
+
+    /// Returns the filtered version of `packet`, or `None` if it doesn't have
+    /// to be changed.
+    ///
+    /// Positive responses to queries for the filtered type are replaced with a
+    /// NODATA response, and records of that type are removed from the
+    /// additional section of other responses.
+    pub fn filter(
+        &self,
+        packet: &[u8],
+        normalized_question: &NormalizedQuestion,
+    ) -> Option<Vec<u8>> {
+        if normalized_question.qtype == self.filtered_type {
+            if dns::rcode(packet) != dns::DNS_RCODE_NOERROR || dns::ancount(packet) == 0 {
+                return None;
+            }
+            return dns::build_nodata_packet_with_soa(
+                normalized_question,
+                &self.soa_mname,
+                &self.soa_rname,
+                self.soa_minimum,
+                self.soa_minimum,
+            ).ok();
+        }
+        let mut packet = packet.to_vec();
+        match dns::strip_additional_records(&mut packet, self.filtered_type) {
+            Ok(removed) if removed > 0 => Some(packet),
+            _ => None,
+        }
+    }
+}
+
+/// DNS64 settings (RFC 6147)
+///
+/// A `AAAA` query getting an empty response is sent again to the resolver as
+/// a companion `A` query, whose response is turned into `AAAA` records
+/// embedding the addresses into the NAT64 prefix.
+#[derive(Clone)]
+pub struct Dns64 {
+    prefix: Ipv6Addr,
+    prefix_len: u8,
+    resolver_tx: Sender<ClientQuery>,
+}
+
+impl Dns64 {
+    pub fn new(config: &Config, resolver_tx: Sender<ClientQuery>) -> Option<Self> {
+        config.dns64_prefix.map(|prefix| Dns64 {
+            prefix: prefix,
+            prefix_len: config.dns64_prefix_len,
+            resolver_tx: resolver_tx,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct ClientQuery {
     pub proto: ClientQueryProtocol,
     pub client_addr: Option<SocketAddr>,
     pub tcpclient_tx: Option<Sender<ResolverResponse>>,
     pub normalized_question: NormalizedQuestion,
+    /// Question sent by the client, if a `QueryPreprocessor` changed it
+    pub original_question: Option<NormalizedQuestion>,
+    /// UDP payload size advertised by the client, before it gets capped
+    pub client_payload_size: u16,
     pub ts: Instant,
     pub varz: Arc<Varz>,
+    pub annotate_source: bool,
+    pub response_rewriter: Option<Arc<ResponseRewriter>>,
+    pub query_events: Option<QueryEvents>,
+    pub max_client_ttl: u32,
+    pub answer_order: AnswerOrder,
+    pub compress_responses: bool,
+    pub nsid: Option<Arc<Vec<u8>>>,
+    pub address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    pub dns64: Option<Dns64>,
+    /// Response to the original `AAAA` query, for companion DNS64 queries
+    pub dns64_nodata: Option<Arc<Vec<u8>>>,
+    pub refresh: bool,
+    pub refresh_jitter: bool,
+    /// Keeps the query from being answered twice while it is pending
+    pub tracked_query: Option<Arc<TrackedQuery>>,
 }
 
 impl ClientQuery {
@@ -45,9 +189,24 @@ impl ClientQuery {
             proto: ClientQueryProtocol::UDP,
             client_addr: Some(client_addr),
             tcpclient_tx: None,
+            client_payload_size: normalized_question.payload_size,
             normalized_question: normalized_question,
-            ts: Instant::recent(),
+            original_question: None,
+            ts: Instant::now(),
             varz: varz,
+            annotate_source: false,
+            response_rewriter: None,
+            query_events: None,
+            max_client_ttl: 0,
+            answer_order: AnswerOrder::None,
+            compress_responses: false,
+            nsid: None,
+            address_family_filter: None,
+            dns64: None,
+            dns64_nodata: None,
+            refresh: false,
+            refresh_jitter: false,
+            tracked_query: None,
         }
     }
 
@@ -60,18 +219,315 @@ impl ClientQuery {
             proto: ClientQueryProtocol::TCP,
             client_addr: None,
             tcpclient_tx: Some(tcpclient_tx),
+            client_payload_size: normalized_question.payload_size,
             normalized_question: normalized_question,
-            ts: Instant::recent(),
+            original_question: None,
+            ts: Instant::now(),
             varz: varz.clone(),
+            annotate_source: false,
+            response_rewriter: None,
+            query_events: None,
+            max_client_ttl: 0,
+            answer_order: AnswerOrder::None,
+            compress_responses: false,
+            nsid: None,
+            address_family_filter: None,
+            dns64: None,
+            dns64_nodata: None,
+            refresh: false,
+            refresh_jitter: false,
+            tracked_query: None,
+        }
+    }
+
+    /// Returns a query that doesn't come from any client, and whose only purpose
+    /// is to fill the cache.
+    pub fn internal(normalized_question: NormalizedQuestion, varz: Arc<Varz>) -> Self {
+        ClientQuery {
+            proto: ClientQueryProtocol::UDP,
+            client_addr: None,
+            tcpclient_tx: None,
+            client_payload_size: normalized_question.payload_size,
+            normalized_question: normalized_question,
+            original_question: None,
+            ts: Instant::now(),
+            varz: varz,
+            annotate_source: false,
+            response_rewriter: None,
+            query_events: None,
+            max_client_ttl: 0,
+            answer_order: AnswerOrder::None,
+            compress_responses: false,
+            nsid: None,
+            address_family_filter: None,
+            dns64: None,
+            dns64_nodata: None,
+            refresh: true,
+            refresh_jitter: false,
+            tracked_query: None,
+        }
+    }
+
+    /// Returns a query for the same question, whose only purpose is to refresh
+    /// the cache. Responses to that query are not sent anywhere, and it is
+    /// delayed by the resolver, so that refreshes don't all happen at once.
+    pub fn refresh(&self) -> Self {
+        ClientQuery {
+            proto: self.proto,
+            client_addr: None,
+            tcpclient_tx: None,
+            normalized_question: self.normalized_question.clone(),
+            original_question: None,
+            client_payload_size: self.client_payload_size,
+            ts: Instant::now(),
+            varz: self.varz.clone(),
+            annotate_source: false,
+            response_rewriter: None,
+            query_events: None,
+            max_client_ttl: 0,
+            answer_order: AnswerOrder::None,
+            compress_responses: false,
+            nsid: None,
+            address_family_filter: None,
+            dns64: None,
+            dns64_nodata: None,
+            refresh: true,
+            refresh_jitter: true,
+            tracked_query: None,
+        }
+    }
+
+    /// Logs the query if more than `threshold_ms` elapsed since it was received.
+    pub fn log_if_slow(
+        &self,
+        threshold_ms: u64,
+        upstream_addr: SocketAddr,
+        retries: u32,
+        outcome: &str,
+    ) {
+        let elapsed_ms = (self.ts.elapsed().as_f64() * 1000.0) as u64;
+        if elapsed_ms < threshold_ms {
+            return;
         }
+        self.varz.slow_queries.inc();
+        warn!(
+            "Slow query: {} took {} ms - upstream: {} retries: {} outcome: {} - \
+             client payload: {} upstream payload: {}",
+            self.normalized_question,
+            elapsed_ms,
+            upstream_addr,
+            retries,
+            outcome,
+            self.client_payload_size,
+            dns::DNS_MAX_PACKET_SIZE
+        );
+    }
+
+    /// Notifies the subscribers of query events, if there are any.
+    /// `response` is the packet sent to the client, if there is one.
+    pub fn emit_event(
+        &self,
+        kind: QueryEventKind,
+        response: Option<&[u8]>,
+        source: Option<AnswerSource>,
+    ) {
+        let query_events = match self.query_events {
+            None => return,
+            Some(ref query_events) => query_events,
+        };
+        let mut normalized_question = self.normalized_question.clone();
+        if self.dns64_nodata.is_some() {
+            normalized_question.qtype = dns::DNS_TYPE_AAAA;
+        }
+        let event = QueryEvent {
+            kind: kind,
+            normalized_question: normalized_question,
+            client_addr: self.client_addr,
+            rcode: response.map(dns::rcode),
+            source: source,
+            elapsed: self.ts.elapsed().as_f64(),
+            client_payload_size: self.client_payload_size,
+            upstream_payload_size: match source {
+                Some(AnswerSource::Upstream(_)) => Some(dns::DNS_MAX_PACKET_SIZE as u16),
+                _ => None,
+            },
+            response_size: response.map(|packet| packet.len()),
+            truncated: response.map(dns::tc),
+        };
+        for _ in 0..query_events.emit(event) {
+            self.varz.query_events_dropped.inc();
+        }
+    }
+
+    /// Adds the source of the response, if the client asked for it and the
+    /// annotated response still fits.
+    fn annotated_packet(&self, packet: &[u8], source: AnswerSource) -> Option<Vec<u8>> {
+        if !self.annotate_source {
+            return None;
+        }
+        let annotated_packet = match dns::add_txt_annotation(packet, &source.to_string()) {
+            Err(_) => return None,
+            Ok(annotated_packet) => annotated_packet,
+        };
+        let max_size = match self.proto {
+            ClientQueryProtocol::UDP => self.normalized_question.payload_size as usize,
+            ClientQueryProtocol::TCP => DNS_MAX_TCP_SIZE,
+        };
+        if annotated_packet.len() > max_size {
+            return None;
+        }
+        Some(annotated_packet)
+    }
+
+    /// Returns the companion `A` query to send instead of an empty response to
+    /// a `AAAA` query, if DNS64 is enabled.
+    fn dns64_query(&self, packet: &[u8]) -> Option<ClientQuery> {
+        if self.dns64.is_none() || self.dns64_nodata.is_some() ||
+            self.normalized_question.qtype != dns::DNS_TYPE_AAAA ||
+            dns::rcode(packet) != dns::DNS_RCODE_NOERROR
+        {
+            return None;
+        }
+        if dns::has_answers(packet, dns::DNS_TYPE_AAAA) != Ok(false) {
+            return None;
+        }
+        let mut client_query = self.clone();
+        client_query.normalized_question.qtype = dns::DNS_TYPE_A;
+        client_query.dns64_nodata = Some(Arc::new(packet.to_vec()));
+        Some(client_query)
     }
 
     pub fn response_send(
         &self,
         packet: &mut [u8],
         net_udp_socket: Option<&net::UdpSocket>,
+        source: AnswerSource,
     ) -> Box<Future<Item = (), Error = io::Error>> {
-        let normalized_question = &self.normalized_question;
+        if self.refresh {
+            return Box::new(future::ok(()));
+        }
+        if let Some(client_query) = self.dns64_query(packet) {
+            let mut resolver_tx = self.dns64.as_ref().unwrap().resolver_tx.clone();
+            if resolver_tx.try_send(client_query).is_ok() {
+                self.varz.client_queries_dns64.inc();
+                return Box::new(future::ok(()));
+            }
+        }
+        let mut dns64_packet;
+        let mut dns64_question;
+        let (packet, normalized_question) = match (&self.dns64, &self.dns64_nodata) {
+            (&Some(ref dns64), &Some(ref dns64_nodata)) => {
+                dns64_question = self.normalized_question.clone();
+                dns64_question.qtype = dns::DNS_TYPE_AAAA;
+                dns64_packet =
+                    match dns::build_dns64_packet(packet, &dns64.prefix, dns64.prefix_len) {
+                        Ok(Some(mut dns64_packet)) => {
+                            if let Ok(Some(negative_ttl)) = dns::negative_ttl(dns64_nodata) {
+                                let _ = dns::decrement_ttls(&mut dns64_packet, 0, negative_ttl);
+                            }
+                            dns64_packet
+                        }
+                        _ => dns64_nodata.to_vec(),
+                    };
+                (&mut dns64_packet[..], &dns64_question)
+            }
+            _ => (packet, &self.normalized_question),
+        };
+        let rewritten = match self.response_rewriter {
+            None => None,
+            Some(ref response_rewriter) => {
+                response_rewriter.rewrite(packet, normalized_question, self.client_addr)
+            }
+        };
+        let mut rewritten_packet;
+        let packet = match rewritten {
+            None => packet,
+            Some(packet) => {
+                rewritten_packet = packet;
+                rewritten_packet.as_mut()
+            }
+        };
+        // The packet may be cached afterwards, so its TTLs are capped in a copy
+        let mut capped_packet;
+        let packet = if self.max_client_ttl > 0 {
+            capped_packet = packet.to_vec();
+            let _ = dns::decrement_ttls(&mut capped_packet, 0, self.max_client_ttl);
+            capped_packet.as_mut()
+        } else {
+            packet
+        };
+        let mut filtered_packet;
+        let packet = match self.address_family_filter
+            .as_ref()
+            .and_then(|x| x.filter(packet, normalized_question))
+        {
+            None => packet,
+            Some(packet) => {
+                filtered_packet = packet;
+                filtered_packet.as_mut()
+            }
+        };
+        match self.answer_order {
+            AnswerOrder::None => {}
+            AnswerOrder::Shuffle => {
+                let _ = dns::permute_addresses(packet, |records| {
+                    rand::thread_rng().shuffle(records)
+                });
+            }
+            AnswerOrder::Rotate => {
+                let shift = ROTATION.fetch_add(1, Relaxed);
+                let _ = dns::permute_addresses(packet, |records| {
+                    let len = records.len();
+                    records.rotate_left(shift % len)
+                });
+            }
+        }
+        let mut nsid_packet;
+        let packet = match self.nsid {
+            Some(ref nsid)
+                if dns::has_edns_option(
+                    &normalized_question.edns_options,
+                    dns::DNS_EDNS_OPTION_NSID,
+                ) =>
+            {
+                match dns::add_edns_option(packet, dns::DNS_EDNS_OPTION_NSID, nsid) {
+                    Err(_) => packet,
+                    Ok(packet) => {
+                        nsid_packet = packet;
+                        nsid_packet.as_mut()
+                    }
+                }
+            }
+            _ => packet,
+        };
+        let mut compressed_packet;
+        let packet = if self.compress_responses {
+            match dns::compress_names(packet) {
+                Ok(compressed) if compressed.len() < packet.len() => {
+                    compressed_packet = compressed;
+                    compressed_packet.as_mut()
+                }
+                _ => packet,
+            }
+        } else {
+            packet
+        };
+        // Clients get the question they asked, not the one sent upstream
+        let mut question_packet;
+        let (packet, normalized_question) = match self.original_question {
+            None => (packet, normalized_question),
+            Some(ref original_question) => match dns::replace_question(
+                packet,
+                &original_question.qname,
+                original_question.qtype,
+            ) {
+                Ok(packet) => {
+                    question_packet = packet;
+                    (question_packet.as_mut(), original_question)
+                }
+                Err(_) => (packet, original_question),
+            },
+        };
         let packet_len = packet.len();
         let mut refused_packet;
         let mut packet = if packet_len < DNS_QUERY_MIN_SIZE ||
@@ -83,6 +539,13 @@ impl ClientQuery {
         } else {
             packet
         };
+        // Responses from authoritative servers don't have these flags set
+        dns::set_rd(&mut packet, normalized_question.flags & dns::DNS_FLAG_RD != 0);
+        dns::set_ra(&mut packet, true);
+        // Cached responses come from us, not from the server that was authoritative
+        if source == AnswerSource::Cache || source == AnswerSource::Stale {
+            dns::set_aa(&mut packet, false);
+        }
         let tc_packet;
         let packet = if self.proto == ClientQueryProtocol::UDP &&
             packet.len() > normalized_question.payload_size as usize
@@ -94,8 +557,34 @@ impl ClientQuery {
             dns::overwrite_qname(&mut packet, &normalized_question.qname);
             packet
         };
+        let annotated_packet;
+        let packet = match self.annotated_packet(packet, source) {
+            None => packet,
+            Some(packet) => {
+                annotated_packet = packet;
+                annotated_packet.as_ref()
+            }
+        };
+        self.varz.client_response_sizes.observe(packet.len() as f64);
+        self.varz
+            .client_query_latency
+            .observe(self.ts.elapsed().as_f64());
+        let rcode = dns::rcode(packet);
+        self.varz
+            .client_responses
+            .with_label_values(&[dns::rcode_name(rcode)])
+            .inc();
+        let kind = if rcode == dns::DNS_RCODE_SERVFAIL {
+            QueryEventKind::Failed
+        } else {
+            QueryEventKind::Resolved
+        };
+        self.emit_event(kind, Some(packet), Some(source));
         match self.proto {
             ClientQueryProtocol::UDP => {
+                if let Some(ref tracked_query) = self.tracked_query {
+                    tracked_query.answered();
+                }
                 let _ = net_udp_socket
                     .expect("Response sent using UDP but no associated UDP socket")
                     .send_to(packet, self.client_addr.unwrap());
@@ -0,0 +1,102 @@
+//! Rate limiting of queries received from clients over UDP.
+//!
+//! Each client IP address gets its own token bucket, refilled at `max_qps`
+//! tokens per second. Buckets are kept in a CLOCK-Pro cache, so that the
+//! memory usage remains bounded regardless of the number of clients.
+//!
+//! What happens to queries exceeding the limit depends on the configured
+//! action. With `slip`, every Nth of these queries gets a truncated response,
+//! so that legitimate clients sharing an address with abusive ones can still
+//! retry over TCP, while the response remains too small to be useful for
+//! reflection attacks.
+
+use clockpro_cache::ClockProCache;
+use coarsetime::Instant;
+use config::Config;
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// What to do with queries from clients exceeding their rate limit
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum RateLimitAction {
+    Drop,
+    Refused,
+    Slip,
+}
+
+/// Outcome of the rate limit check for a single query
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum RateLimitVerdict {
+    Allow,
+    Drop,
+    Refuse,
+    Truncate,
+}
+
+struct ClientBucket {
+    tokens: f64,
+    ts: Instant,
+    limited_count: u32,
+}
+
+#[derive(Clone)]
+pub struct ClientRateLimiter {
+    buckets_mx: Arc<Mutex<ClockProCache<IpAddr, ClientBucket>>>,
+    max_qps: f64,
+    action: RateLimitAction,
+    slip: u32,
+}
+
+impl ClientRateLimiter {
+    /// Returns `None` if client queries are not rate limited.
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.client_max_qps == 0 {
+            return None;
+        }
+        let buckets = ClockProCache::new(config.client_ratelimit_max_clients)
+            .expect("Unable to create the client rate limiter");
+        Some(ClientRateLimiter {
+            buckets_mx: Arc::new(Mutex::new(buckets)),
+            max_qps: config.client_max_qps as f64,
+            action: config.client_ratelimit_action,
+            slip: config.client_ratelimit_slip,
+        })
+    }
+
+    pub fn check(&self, client_ip: IpAddr) -> RateLimitVerdict {
+        let mut buckets = self.buckets_mx.lock();
+        if buckets.get_mut(&client_ip).is_none() {
+            let bucket = ClientBucket {
+                tokens: self.max_qps,
+                ts: Instant::recent(),
+                limited_count: 0,
+            };
+            buckets.insert(client_ip, bucket);
+        }
+        let bucket = match buckets.get_mut(&client_ip) {
+            None => return RateLimitVerdict::Allow,
+            Some(bucket) => bucket,
+        };
+        let elapsed = bucket.ts.elapsed_since_recent().as_f64();
+        bucket.ts = Instant::recent();
+        bucket.tokens = (bucket.tokens + elapsed * self.max_qps).min(self.max_qps);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RateLimitVerdict::Allow;
+        }
+        match self.action {
+            RateLimitAction::Drop => RateLimitVerdict::Drop,
+            RateLimitAction::Refused => RateLimitVerdict::Refuse,
+            RateLimitAction::Slip => {
+                bucket.limited_count = bucket.limited_count.wrapping_add(1);
+                if self.slip > 0 && bucket.limited_count % self.slip == 0 {
+                    RateLimitVerdict::Truncate
+                } else {
+                    RateLimitVerdict::Drop
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Source of the current time for the resolver
+//!
+//! Upstream servers and the client queries handler don't read the time
+//! directly, but ask a `Clock`. `SystemClock` is what the server uses, and
+//! returns the coarse time updated by the event loops. `ManualClock` only
+//! moves forward when told to, so that failure windows, probe delays and
+//! rate limits can be tested without actually waiting.
+//!
+//! Timeouts are still scheduled by `tokio_timer`, and are not affected.
+
+pub use coarsetime::{Duration, Instant};
+use parking_lot::Mutex;
+
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns the time elapsed since `ts`, or zero if `ts` is in the future.
+    fn elapsed(&self, ts: Instant) -> Duration {
+        let now = self.now();
+        if now > ts {
+            now.duration_since(ts)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::recent()
+    }
+}
+
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the current time.
+    pub fn new() -> Self {
+        ManualClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock();
+        *now = *now + duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
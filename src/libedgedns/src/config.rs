@@ -3,27 +3,149 @@
 //! This configuration cannot currently be updated without restarting the
 //! server.
 
+use client_query::{AnswerOrder, ClientAddressFamily};
+use client_ratelimiter::RateLimitAction;
 use coarsetime::Duration;
-use resolver::LoadBalancingMode;
+use dns;
+use net_helpers::hostname;
+use resolver::{EcsPolicy, LoadBalancingMode, MaintenanceResponse, ShedAction, UpstreamFamily};
+#[cfg(feature = "webservice")]
+use serde::Serializer;
+#[cfg(feature = "webservice")]
+use serde::ser::{SerializeMap, SerializeSeq};
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
+use super::{DNS_MAX_UDP_SIZE, DNS_QUERY_MIN_SIZE, DNS_UDP_NOEDNS0_MAX_SIZE, UDP_BUFFER_SIZE,
+            UPSTREAM_PROBES_DELAY_MS, UPSTREAM_QUERY_MAX_TIMEOUT_MS, UPSTREAM_TOTAL_TIMEOUT_MS};
 use toml;
 
+/// Zones answered locally by default, from RFC 6303. The `172.16/12` and IPv6
+/// unspecified and loopback zones are added by `default_local_zones()`.
+const DEFAULT_LOCAL_ZONES: &[&str] = &[
+    "10.in-addr.arpa",
+    "168.192.in-addr.arpa",
+    "0.in-addr.arpa",
+    "127.in-addr.arpa",
+    "254.169.in-addr.arpa",
+    "2.0.192.in-addr.arpa",
+    "100.51.198.in-addr.arpa",
+    "113.0.203.in-addr.arpa",
+    "255.255.255.255.in-addr.arpa",
+    "d.f.ip6.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+    "8.b.d.0.1.0.0.2.ip6.arpa",
+];
+
+fn default_local_zones() -> Vec<String> {
+    let mut zones: Vec<String> = DEFAULT_LOCAL_ZONES.iter().map(|x| x.to_string()).collect();
+    zones.extend((16..32).map(|x| format!("{}.172.in-addr.arpa", x)));
+    zones.push(format!("{}ip6.arpa", "0.".repeat(32)));
+    zones.push(format!("1.{}ip6.arpa", "0.".repeat(31)));
+    zones
+}
+
+fn default_local_ptr_records() -> Vec<(String, String)> {
+    vec![
+        ("1.0.0.127.in-addr.arpa".to_owned(), "localhost".to_owned()),
+        (format!("1.{}ip6.arpa", "0.".repeat(31)), "localhost".to_owned()),
+    ]
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize))]
 pub struct Config {
     pub decrement_ttl: bool,
+    pub stale_while_revalidate: bool,
+    pub stale_ttl: u32,
+    pub stale_refresh_ttl: u32,
+    pub stale_refresh_jitter_ms: u64,
+    pub max_client_ttl: u32,
+    pub no_cache_qtypes: Vec<u16>,
+    pub sort_addresses: bool,
+    pub prime_file: Option<String>,
+    pub prime_qps: u32,
     pub upstream_servers: Vec<String>,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_routes"))]
+    pub upstream_routes: HashMap<Vec<u8>, String>,
+    pub upstream_no_rd_servers: Vec<String>,
+    pub upstream_priorities: Vec<u32>,
     pub lbmode: LoadBalancingMode,
+    pub upstream_prefer_family: UpstreamFamily,
+    pub ecs_policy: EcsPolicy,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_duration"))]
     pub upstream_max_failure_duration: Duration,
+    pub upstream_max_response_size: usize,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_duration"))]
+    pub startup_wait: Duration,
+    pub query_deadline_ms: u64,
+    pub no_coalescing_qtypes: Vec<u16>,
+    pub strip_out_of_bailiwick: bool,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_names"))]
+    pub nxdomain_to_nodata_suffixes: Vec<Vec<u8>>,
+    pub nxdomain_to_nodata_qtypes: Vec<u16>,
+    pub edns_options_allowlist: Vec<u16>,
+    pub upstream_probes_delay_ms: u64,
+    pub upstream_query_max_timeout_ms: u64,
+    pub upstream_timeout_jitter_percent: u64,
+    pub upstream_success_rate_window: usize,
+    pub upstream_min_success_rate_percent: u64,
+    pub upstream_max_lame_percent: u64,
+    pub upstream_eject_lame: bool,
+    pub upstream_breaker_enabled: bool,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_duration"))]
+    pub upstream_breaker_cooldown: Duration,
+    pub upstream_breaker_half_open_probes: u32,
+    pub upstream_max_qps: u32,
+    pub enable_retry: bool,
+    pub upstream_cookies: bool,
     pub cache_size: usize,
+    pub max_cache_bytes: usize,
+    pub referrals_cache_size: usize,
     pub udp_ports: u16,
+    pub udp_recv_buffer: usize,
+    pub udp_send_buffer: usize,
+    pub udp_dont_fragment: bool,
+    pub max_client_udp_payload: u16,
+    pub upstream_source_addr: IpAddr,
+    pub upstream_family: UpstreamFamily,
+    pub upstream_interface: Option<String>,
     pub listen_addr: String,
+    pub client_max_qps: u32,
+    pub client_ratelimit_action: RateLimitAction,
+    pub client_ratelimit_slip: u32,
+    pub client_ratelimit_max_clients: usize,
+    pub duplicate_query_window_ms: u64,
+    pub duplicate_query_max_entries: usize,
     pub webservice_enabled: bool,
-    pub webservice_listen_addr: String,
+    pub webservice_listen_addr: Option<String>,
+    pub webservice_listen_path: Option<String>,
+    pub webservice_redact_config: bool,
+    pub webservice_admin_allowed_ips: Vec<IpAddr>,
+    pub webservice_ready_after_prime: bool,
+    pub webservice_ready_min_cache_entries: usize,
+    pub health_log_enabled: bool,
+    pub health_log_interval_secs: u64,
     pub min_ttl: u32,
+    pub min_positive_ttl: u32,
+    pub min_negative_ttl: u32,
     pub max_ttl: u32,
+    pub servfail_ttl: u32,
+    pub cache_zero_ttl: bool,
+    pub cache_shadow: bool,
+    pub cache_compress: bool,
+    pub negative_ttl: u32,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_name"))]
+    pub synth_soa_mname: Vec<u8>,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_name"))]
+    pub synth_soa_rname: Vec<u8>,
+    pub synth_soa_minimum: u32,
     pub user: Option<String>,
     pub group: Option<String>,
     pub chroot_dir: Option<String>,
@@ -37,7 +159,30 @@ pub struct Config {
     pub max_tcp_clients: usize,
     pub max_waiting_clients: usize,
     pub max_active_queries: usize,
+    pub shed_inflight_queries: usize,
+    pub shed_action: ShedAction,
     pub max_clients_waiting_for_query: usize,
+    pub formerr_on_malformed_queries: bool,
+    pub max_answers: Option<u16>,
+    pub answer_order: AnswerOrder,
+    pub compress_responses: bool,
+    pub client_address_family: ClientAddressFamily,
+    pub debug_answer_source: bool,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_nsid"))]
+    pub nsid: Option<Vec<u8>>,
+    pub slow_query_threshold_ms: u64,
+    pub maintenance_enabled: bool,
+    pub maintenance_response: MaintenanceResponse,
+    pub maintenance_sinkhole_ipv4: Ipv4Addr,
+    pub maintenance_sinkhole_ipv6: Ipv6Addr,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_names"))]
+    pub maintenance_suffixes: Vec<Vec<u8>>,
+    pub dns64_prefix: Option<Ipv6Addr>,
+    pub dns64_prefix_len: u8,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_names"))]
+    pub local_zones: Vec<Vec<u8>>,
+    #[cfg_attr(feature = "webservice", serde(serialize_with = "serialize_ptr_records"))]
+    pub local_ptr_records: HashMap<Vec<u8>, Vec<u8>>,
 }
 
 impl Config {
@@ -79,7 +224,7 @@ impl Config {
             }
         };
 
-        let upstream_servers = config_upstream
+        let upstream_servers: Vec<String> = config_upstream
             .and_then(|x| x.get("servers"))
             .expect("upstream.servers is required")
             .as_array()
@@ -92,6 +237,76 @@ impl Config {
             })
             .collect();
 
+        if upstream_servers.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.servers must contain at least one upstream server",
+            ));
+        }
+
+        let upstream_routes = config_upstream
+            .and_then(|x| x.get("routes"))
+            .map_or(HashMap::new(), |x| {
+                x.as_table()
+                    .expect("upstream.routes must be a table")
+                    .iter()
+                    .map(|(name, x)| {
+                        let mut qname = dns::qname_encode(name)
+                            .expect("upstream.routes contains an invalid name");
+                        qname.pop();
+                        let upstream_server = x.as_str()
+                            .expect("upstream.routes must map names to upstream servers")
+                            .to_owned();
+                        (dns::qname_lc(&qname), upstream_server)
+                    })
+                    .collect()
+            });
+
+        let upstream_no_rd_servers: Vec<String> = config_upstream
+            .and_then(|x| x.get("no_rd_servers"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("upstream.no_rd_servers must be a list")
+                    .iter()
+                    .map(|x| {
+                        x.as_str()
+                            .expect("upstream.no_rd_servers must contain strings")
+                            .to_owned()
+                    })
+                    .collect()
+            });
+        for no_rd_server in &upstream_no_rd_servers {
+            if !upstream_servers.contains(no_rd_server) &&
+                upstream_routes.values().all(|x| x != no_rd_server)
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "upstream.no_rd_servers must only contain upstream servers",
+                ));
+            }
+        }
+
+        let upstream_priorities: Vec<u32> = config_upstream
+            .and_then(|x| x.get("priorities"))
+            .map_or((0..upstream_servers.len() as u32).collect(), |x| {
+                x.as_array()
+                    .expect("upstream.priorities must be a list")
+                    .iter()
+                    .map(|x| {
+                        x.as_integer()
+                            .expect("upstream.priorities must contain integers")
+                    })
+                    .filter(|&x| x >= 0 && x <= u32::max_value() as i64)
+                    .map(|x| x as u32)
+                    .collect()
+            });
+        if upstream_priorities.len() != upstream_servers.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.priorities must contain a positive priority for every upstream server",
+            ));
+        }
+
         let lbmode_str = config_upstream.and_then(|x| x.get("strategy")).map_or(
             "uniform",
             |x| x.as_str().expect("upstream.strategy must be a string"),
@@ -108,6 +323,39 @@ impl Config {
             }
         };
 
+        let upstream_prefer_family_str = config_upstream
+            .and_then(|x| x.get("prefer_family"))
+            .map_or("any", |x| {
+                x.as_str().expect("upstream.prefer_family must be a string")
+            });
+        let upstream_prefer_family = match upstream_prefer_family_str {
+            "any" => UpstreamFamily::Any,
+            "ipv4" => UpstreamFamily::Ipv4,
+            "ipv6" => UpstreamFamily::Ipv6,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "upstream.prefer_family must be \"any\", \"ipv4\" or \"ipv6\"",
+                ))
+            }
+        };
+
+        let ecs_policy_str = config_upstream.and_then(|x| x.get("ecs_policy")).map_or(
+            "strip",
+            |x| x.as_str().expect("upstream.ecs_policy must be a string"),
+        );
+        let ecs_policy = match ecs_policy_str {
+            "pass" => EcsPolicy::Pass,
+            "strip" => EcsPolicy::Strip,
+            "overwrite" => EcsPolicy::Overwrite,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the ECS policy. Must be 'pass', 'strip' or 'overwrite'",
+                ))
+            }
+        };
+
         let upstream_max_failure_duration = Duration::from_millis(config_upstream
             .and_then(|x| x.get("max_failure_duration"))
             .map_or(2500, |x| {
@@ -115,22 +363,457 @@ impl Config {
                     .expect("upstream.max_failure_duration must be an integer")
             }) as u64);
 
+        let startup_wait = Duration::from_millis(config_upstream
+            .and_then(|x| x.get("startup_wait_ms"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("upstream.startup_wait_ms must be an integer")
+            }) as u64);
+
+        let upstream_max_response_size = config_upstream
+            .and_then(|x| x.get("max_response_size"))
+            .map_or(DNS_MAX_UDP_SIZE as i64, |x| {
+                x.as_integer()
+                    .expect("upstream.max_response_size must be an integer")
+            }) as usize;
+        if upstream_max_response_size < DNS_QUERY_MIN_SIZE ||
+            upstream_max_response_size > DNS_MAX_UDP_SIZE
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "upstream.max_response_size must be between {} and {}",
+                    DNS_QUERY_MIN_SIZE,
+                    DNS_MAX_UDP_SIZE
+                ),
+            ));
+        }
+
+        let query_deadline_ms = config_upstream
+            .and_then(|x| x.get("query_deadline_ms"))
+            .map_or(UPSTREAM_TOTAL_TIMEOUT_MS as i64, |x| {
+                x.as_integer()
+                    .expect("upstream.query_deadline_ms must be an integer")
+            }) as u64;
+
+        let no_coalescing_qtypes = config_upstream
+            .and_then(|x| x.get("no_coalescing_qtypes"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("upstream.no_coalescing_qtypes must be a list")
+                    .iter()
+                    .map(|x| {
+                        x.as_integer()
+                            .expect("upstream.no_coalescing_qtypes must contain integers")
+                            as u16
+                    })
+                    .collect()
+            });
+
+        let upstream_cookies = config_upstream
+            .and_then(|x| x.get("cookies"))
+            .map_or(false, |x| {
+                x.as_bool().expect("upstream.cookies must be a boolean")
+            });
+
+        let mut edns_options_allowlist: Vec<u16> = config_upstream
+            .and_then(|x| x.get("edns_options"))
+            .map_or(
+                vec![dns::DNS_EDNS_OPTION_NSID, dns::DNS_EDNS_OPTION_EDE],
+                |x| {
+                    x.as_array()
+                        .expect("upstream.edns_options must be a list")
+                        .iter()
+                        .map(|x| {
+                            x.as_integer()
+                                .expect("upstream.edns_options must contain integers")
+                                as u16
+                        })
+                        .collect()
+                },
+            );
+        if upstream_cookies {
+            edns_options_allowlist.retain(|&code| code != dns::DNS_EDNS_OPTION_COOKIE);
+        }
+
+        let strip_out_of_bailiwick = config_upstream
+            .and_then(|x| x.get("strip_out_of_bailiwick"))
+            .map_or(true, |x| {
+                x.as_bool()
+                    .expect("upstream.strip_out_of_bailiwick must be a boolean")
+            });
+
+        let nxdomain_to_nodata_suffixes = config_upstream
+            .and_then(|x| x.get("nxdomain_to_nodata_suffixes"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("upstream.nxdomain_to_nodata_suffixes must be a list")
+                    .iter()
+                    .map(|x| {
+                        let suffix = x.as_str()
+                            .expect("upstream.nxdomain_to_nodata_suffixes must only contain names");
+                        let mut suffix = dns::qname_encode(suffix).expect(
+                            "upstream.nxdomain_to_nodata_suffixes contains an invalid name",
+                        );
+                        suffix.pop();
+                        dns::qname_lc(&suffix)
+                    })
+                    .collect()
+            });
+
+        let nxdomain_to_nodata_qtypes = config_upstream
+            .and_then(|x| x.get("nxdomain_to_nodata_qtypes"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("upstream.nxdomain_to_nodata_qtypes must be a list")
+                    .iter()
+                    .map(|x| {
+                        x.as_integer()
+                            .expect("upstream.nxdomain_to_nodata_qtypes must contain integers")
+                            as u16
+                    })
+                    .collect()
+            });
+
+        let upstream_probes_delay_ms = config_upstream
+            .and_then(|x| x.get("probes_delay_ms"))
+            .map_or(UPSTREAM_PROBES_DELAY_MS as i64, |x| {
+                x.as_integer()
+                    .expect("upstream.probes_delay_ms must be an integer")
+            }) as u64;
+
+        let upstream_query_max_timeout_ms = config_upstream
+            .and_then(|x| x.get("query_max_timeout_ms"))
+            .map_or(UPSTREAM_QUERY_MAX_TIMEOUT_MS as i64, |x| {
+                x.as_integer()
+                    .expect("upstream.query_max_timeout_ms must be an integer")
+            }) as u64;
+
+        let upstream_timeout_jitter_percent = config_upstream
+            .and_then(|x| x.get("timeout_jitter_percent"))
+            .map_or(10, |x| {
+                x.as_integer()
+                    .expect("upstream.timeout_jitter_percent must be an integer")
+            }) as u64;
+        if upstream_timeout_jitter_percent >= 100 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.timeout_jitter_percent must be below 100",
+            ));
+        }
+
+        let upstream_success_rate_window = config_upstream
+            .and_then(|x| x.get("success_rate_window"))
+            .map_or(100, |x| {
+                x.as_integer()
+                    .expect("upstream.success_rate_window must be an integer")
+            }) as usize;
+        if upstream_success_rate_window == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.success_rate_window must be at least 1",
+            ));
+        }
+
+        let upstream_min_success_rate_percent = config_upstream
+            .and_then(|x| x.get("min_success_rate_percent"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("upstream.min_success_rate_percent must be an integer")
+            }) as u64;
+        if upstream_min_success_rate_percent > 100 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.min_success_rate_percent must be at most 100",
+            ));
+        }
+
+        let upstream_max_lame_percent = config_upstream
+            .and_then(|x| x.get("max_lame_percent"))
+            .map_or(50, |x| {
+                x.as_integer()
+                    .expect("upstream.max_lame_percent must be an integer")
+            }) as u64;
+        if upstream_max_lame_percent > 100 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.max_lame_percent must be at most 100",
+            ));
+        }
+
+        let upstream_eject_lame = config_upstream
+            .and_then(|x| x.get("eject_lame"))
+            .map_or(false, |x| {
+                x.as_bool().expect("upstream.eject_lame must be a boolean")
+            });
+
+        let upstream_breaker_enabled = config_upstream
+            .and_then(|x| x.get("breaker_enabled"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("upstream.breaker_enabled must be a boolean")
+            });
+
+        let upstream_breaker_cooldown = Duration::from_millis(config_upstream
+            .and_then(|x| x.get("breaker_cooldown_ms"))
+            .map_or(10_000, |x| {
+                x.as_integer()
+                    .expect("upstream.breaker_cooldown_ms must be an integer")
+            }) as u64);
+
+        let upstream_breaker_half_open_probes = config_upstream
+            .and_then(|x| x.get("breaker_half_open_probes"))
+            .map_or(3, |x| {
+                x.as_integer()
+                    .expect("upstream.breaker_half_open_probes must be an integer")
+            });
+        if upstream_breaker_half_open_probes < 1 || upstream_breaker_half_open_probes > 1000 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.breaker_half_open_probes must be between 1 and 1000",
+            ));
+        }
+        let upstream_breaker_half_open_probes = upstream_breaker_half_open_probes as u32;
+
+        let upstream_max_qps = config_upstream.and_then(|x| x.get("max_qps")).map_or(0, |x| {
+            x.as_integer().expect("upstream.max_qps must be an integer")
+        });
+        if upstream_max_qps < 0 || upstream_max_qps > u32::max_value() as i64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.max_qps must be a positive number of queries per second",
+            ));
+        }
+        let upstream_max_qps = upstream_max_qps as u32;
+
+        let enable_retry = config_upstream
+            .and_then(|x| x.get("enable_retry"))
+            .map_or(true, |x| {
+                x.as_bool().expect("upstream.enable_retry must be a boolean")
+            });
+
         let config_cache = toml_config.get("cache");
 
+        let decrement_ttl = config_cache
+            .and_then(|x| x.get("decrement_ttl"))
+            .map_or(decrement_ttl, |x| {
+                x.as_bool().expect("cache.decrement_ttl must be a boolean")
+            });
+
+        let stale_while_revalidate = config_cache
+            .and_then(|x| x.get("stale_while_revalidate"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("cache.stale_while_revalidate must be a boolean")
+            });
+
+        let stale_ttl = config_cache.and_then(|x| x.get("stale_ttl")).map_or(86_400, |x| {
+            x.as_integer().expect("cache.stale_ttl must be an integer")
+        });
+        if stale_ttl < 0 || stale_ttl > u32::max_value() as i64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.stale_ttl must be a positive number of seconds",
+            ));
+        }
+        let stale_ttl = stale_ttl as u32;
+
+        let stale_refresh_ttl = config_cache
+            .and_then(|x| x.get("stale_refresh_ttl"))
+            .map_or(30, |x| {
+                x.as_integer()
+                    .expect("cache.stale_refresh_ttl must be an integer")
+            });
+        if stale_refresh_ttl < 0 || stale_refresh_ttl > 0x7fff_ffff {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.stale_refresh_ttl must be between 0 and 2147483647",
+            ));
+        }
+        let stale_refresh_ttl = stale_refresh_ttl as u32;
+
+        let stale_refresh_jitter_ms = config_cache
+            .and_then(|x| x.get("stale_refresh_jitter_ms"))
+            .map_or(1000, |x| {
+                x.as_integer()
+                    .expect("cache.stale_refresh_jitter_ms must be an integer")
+            });
+        if stale_refresh_jitter_ms < 0 || stale_refresh_jitter_ms > 60_000 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.stale_refresh_jitter_ms must be between 0 and 60000",
+            ));
+        }
+        let stale_refresh_jitter_ms = stale_refresh_jitter_ms as u64;
+
+        let max_client_ttl = config_cache.and_then(|x| x.get("max_client_ttl")).map_or(0, |x| {
+            x.as_integer().expect("cache.max_client_ttl must be an integer")
+        });
+        if max_client_ttl < 0 || max_client_ttl > 0x7fff_ffff {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.max_client_ttl must be between 0 and 2147483647",
+            ));
+        }
+        let max_client_ttl = max_client_ttl as u32;
+
+        let no_cache_qtypes = config_cache
+            .and_then(|x| x.get("no_cache_qtypes"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("cache.no_cache_qtypes must be a list")
+                    .iter()
+                    .map(|x| {
+                        x.as_integer()
+                            .expect("cache.no_cache_qtypes must contain integers")
+                            as u16
+                    })
+                    .collect()
+            });
+
+        let sort_addresses = config_cache
+            .and_then(|x| x.get("sort_addresses"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("cache.sort_addresses must be a boolean")
+            });
+
+        let prime_file = config_cache.and_then(|x| x.get("prime_file")).map(|x| {
+            x.as_str()
+                .expect("cache.prime_file must be a string")
+                .to_owned()
+        });
+
+        let prime_qps = config_cache.and_then(|x| x.get("prime_qps")).map_or(50, |x| {
+            x.as_integer().expect("cache.prime_qps must be an integer")
+        });
+        if prime_qps <= 0 || prime_qps > 1000 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.prime_qps must be between 1 and 1000",
+            ));
+        }
+        let prime_qps = prime_qps as u32;
+
         let cache_size = config_cache.and_then(|x| x.get("max_items")).map_or(
             250_000,
             |x| x.as_integer().expect("cache.max_items must be an integer"),
         ) as usize;
 
+        let max_cache_bytes = config_cache
+            .and_then(|x| x.get("max_bytes"))
+            .map_or(0, |x| {
+                x.as_integer().expect("cache.max_bytes must be an integer")
+            });
+        if max_cache_bytes < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.max_bytes must be a positive number of bytes, or 0",
+            ));
+        }
+        let max_cache_bytes = max_cache_bytes as usize;
+
+        let referrals_cache_size = config_cache
+            .and_then(|x| x.get("max_referrals"))
+            .map_or(0, |x| {
+                x.as_integer().expect("cache.max_referrals must be an integer")
+            }) as usize;
+        if referrals_cache_size > 0 && referrals_cache_size < 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.max_referrals must be either 0 or at least 3",
+            ));
+        }
+
         let min_ttl = config_cache.and_then(|x| x.get("min_ttl")).map_or(60, |x| {
             x.as_integer().expect("cache.min_ttl must be an integer")
         }) as u32;
 
+        let min_positive_ttl = config_cache
+            .and_then(|x| x.get("min_positive_ttl"))
+            .map_or(min_ttl as i64, |x| {
+                x.as_integer()
+                    .expect("cache.min_positive_ttl must be an integer")
+            });
+        let min_negative_ttl = config_cache
+            .and_then(|x| x.get("min_negative_ttl"))
+            .map_or(min_ttl as i64, |x| {
+                x.as_integer()
+                    .expect("cache.min_negative_ttl must be an integer")
+            });
+        if min_positive_ttl < 0 || min_positive_ttl > 0x7fff_ffff {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.min_positive_ttl must be between 0 and 2147483647",
+            ));
+        }
+        if min_negative_ttl < 0 || min_negative_ttl > 0x7fff_ffff {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.min_negative_ttl must be between 0 and 2147483647",
+            ));
+        }
+        let min_positive_ttl = min_positive_ttl as u32;
+        let min_negative_ttl = min_negative_ttl as u32;
+
         let max_ttl = config_cache.and_then(|x| x.get("max_ttl")).map_or(
             86_400,
             |x| x.as_integer().expect("cache.max_ttl must be an integer"),
         ) as u32;
 
+        let servfail_ttl = config_cache.and_then(|x| x.get("servfail_ttl")).map_or(5, |x| {
+            x.as_integer().expect("cache.servfail_ttl must be an integer")
+        }) as u32;
+
+        let cache_zero_ttl = config_cache
+            .and_then(|x| x.get("cache_zero_ttl"))
+            .map_or(false, |x| {
+                x.as_bool().expect("cache.cache_zero_ttl must be a boolean")
+            });
+
+        let cache_shadow = config_cache
+            .and_then(|x| x.get("shadow"))
+            .map_or(false, |x| x.as_bool().expect("cache.shadow must be a boolean"));
+        if cache_shadow && !cfg!(feature = "shadow-cache") {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cache.shadow requires support for the shadow cache to be compiled in",
+            ));
+        }
+
+        let cache_compress = config_cache
+            .and_then(|x| x.get("compress"))
+            .map_or(false, |x| {
+                x.as_bool().expect("cache.compress must be a boolean")
+            });
+
+        let negative_ttl = config_cache.and_then(|x| x.get("negative_ttl")).map_or(
+            3600,
+            |x| x.as_integer().expect("cache.negative_ttl must be an integer"),
+        ) as u32;
+
+        let synth_soa_mname = config_cache
+            .and_then(|x| x.get("synth_soa_mname"))
+            .map_or("localhost", |x| {
+                x.as_str().expect("cache.synth_soa_mname must be a string")
+            });
+        let synth_soa_mname = dns::qname_encode(synth_soa_mname)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "cache.synth_soa_mname is invalid"))?;
+
+        let synth_soa_rname = config_cache
+            .and_then(|x| x.get("synth_soa_rname"))
+            .map_or("nobody.invalid", |x| {
+                x.as_str().expect("cache.synth_soa_rname must be a string")
+            });
+        let synth_soa_rname = dns::qname_encode(synth_soa_rname)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "cache.synth_soa_rname is invalid"))?;
+
+        let synth_soa_minimum = config_cache
+            .and_then(|x| x.get("synth_soa_minimum"))
+            .map_or(300, |x| {
+                x.as_integer().expect("cache.synth_soa_minimum must be an integer")
+            }) as u32;
+
         let config_network = toml_config.get("network");
 
         let udp_ports = config_network.and_then(|x| x.get("udp_ports")).map_or(
@@ -141,6 +824,120 @@ impl Config {
             },
         ) as u16;
 
+        let udp_recv_buffer = config_network
+            .and_then(|x| x.get("udp_recv_buffer"))
+            .map_or(UDP_BUFFER_SIZE as i64, |x| {
+                x.as_integer()
+                    .expect("network.udp_recv_buffer must be an integer")
+            }) as usize;
+
+        let udp_send_buffer = config_network
+            .and_then(|x| x.get("udp_send_buffer"))
+            .map_or(UDP_BUFFER_SIZE as i64, |x| {
+                x.as_integer()
+                    .expect("network.udp_send_buffer must be an integer")
+            }) as usize;
+
+        let udp_dont_fragment = config_network
+            .and_then(|x| x.get("udp_dont_fragment"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("network.udp_dont_fragment must be a boolean")
+            });
+
+        let max_client_udp_payload = config_network
+            .and_then(|x| x.get("max_client_udp_payload"))
+            .map_or(DNS_MAX_UDP_SIZE as i64, |x| {
+                x.as_integer()
+                    .expect("network.max_client_udp_payload must be an integer")
+            });
+        if max_client_udp_payload < DNS_UDP_NOEDNS0_MAX_SIZE as i64 ||
+            max_client_udp_payload > DNS_MAX_UDP_SIZE as i64
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "network.max_client_udp_payload must be between {} and {}",
+                    DNS_UDP_NOEDNS0_MAX_SIZE,
+                    DNS_MAX_UDP_SIZE
+                ),
+            ));
+        }
+        let max_client_udp_payload = max_client_udp_payload as u16;
+
+        let upstream_source_addr: Option<IpAddr> = config_network
+            .and_then(|x| x.get("upstream_source_addr"))
+            .map(|x| {
+                x.as_str()
+                    .expect("network.upstream_source_addr must be a string")
+                    .parse()
+                    .expect("network.upstream_source_addr must be an IP address")
+            });
+
+        let upstream_addrs: Vec<SocketAddr> = upstream_servers
+            .iter()
+            .chain(upstream_routes.values())
+            .filter_map(|x| x.parse().ok())
+            .collect();
+        let upstream_family_str = config_network
+            .and_then(|x| x.get("upstream_family"))
+            .map(|x| {
+                x.as_str()
+                    .expect("network.upstream_family must be a string")
+            });
+        let upstream_family = match upstream_family_str {
+            None if upstream_addrs.iter().all(|x| x.is_ipv4()) => UpstreamFamily::Ipv4,
+            None if upstream_addrs.iter().all(|x| x.is_ipv6()) => UpstreamFamily::Ipv6,
+            None | Some("any") => UpstreamFamily::Any,
+            Some("ipv4") => UpstreamFamily::Ipv4,
+            Some("ipv6") => UpstreamFamily::Ipv6,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "network.upstream_family must be \"any\", \"ipv4\" or \"ipv6\"",
+                ))
+            }
+        };
+        if let Some(upstream_addr) = upstream_addrs
+            .iter()
+            .find(|x| !upstream_family.reaches(x))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Upstream server {} can't be reached using network.upstream_family",
+                    upstream_addr
+                ),
+            ));
+        }
+        if let Some(upstream_source_addr) = upstream_source_addr {
+            if !upstream_family.reaches(&SocketAddr::new(upstream_source_addr, 0)) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "network.upstream_source_addr is not in network.upstream_family",
+                ));
+            }
+        }
+        if upstream_family != UpstreamFamily::Any &&
+            upstream_prefer_family != UpstreamFamily::Any &&
+            upstream_family != upstream_prefer_family
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream.prefer_family is not in network.upstream_family",
+            ));
+        }
+        let upstream_source_addr =
+            upstream_source_addr.unwrap_or(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+
+        let upstream_interface = config_network
+            .and_then(|x| x.get("upstream_interface"))
+            .map(|x| {
+                x.as_str()
+                    .expect("network.upstream_interface must be a string")
+                    .to_owned()
+            });
+
         let listen_addr = config_network
             .and_then(|x| x.get("listen"))
             .map_or("0.0.0.0:53", |x| {
@@ -148,6 +945,93 @@ impl Config {
             })
             .to_owned();
 
+        let client_max_qps = config_network
+            .and_then(|x| x.get("client_max_qps"))
+            .map_or(0, |x| {
+                x.as_integer().expect("network.client_max_qps must be an integer")
+            });
+        if client_max_qps < 0 || client_max_qps > u32::max_value() as i64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "network.client_max_qps must be a positive number of queries per second",
+            ));
+        }
+        let client_max_qps = client_max_qps as u32;
+
+        let client_ratelimit_action_str = config_network
+            .and_then(|x| x.get("client_ratelimit_action"))
+            .map_or("drop", |x| {
+                x.as_str()
+                    .expect("network.client_ratelimit_action must be a string")
+            });
+        let client_ratelimit_action = match client_ratelimit_action_str {
+            "drop" => RateLimitAction::Drop,
+            "refused" => RateLimitAction::Refused,
+            "slip" => RateLimitAction::Slip,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the client rate limit action",
+                ))
+            }
+        };
+
+        let client_ratelimit_slip = config_network
+            .and_then(|x| x.get("client_ratelimit_slip"))
+            .map_or(2, |x| {
+                x.as_integer()
+                    .expect("network.client_ratelimit_slip must be an integer")
+            });
+        if client_ratelimit_slip < 1 || client_ratelimit_slip > u32::max_value() as i64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "network.client_ratelimit_slip must be at least 1",
+            ));
+        }
+        let client_ratelimit_slip = client_ratelimit_slip as u32;
+
+        let client_ratelimit_max_clients = config_network
+            .and_then(|x| x.get("client_ratelimit_max_clients"))
+            .map_or(100_000, |x| {
+                x.as_integer()
+                    .expect("network.client_ratelimit_max_clients must be an integer")
+            });
+        if client_ratelimit_max_clients < 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "network.client_ratelimit_max_clients must be at least 3",
+            ));
+        }
+        let client_ratelimit_max_clients = client_ratelimit_max_clients as usize;
+
+        let duplicate_query_window_ms = config_network
+            .and_then(|x| x.get("duplicate_query_window_ms"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("network.duplicate_query_window_ms must be an integer")
+            });
+        if duplicate_query_window_ms < 0 || duplicate_query_window_ms > 10_000 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "network.duplicate_query_window_ms must be between 0 and 10000",
+            ));
+        }
+        let duplicate_query_window_ms = duplicate_query_window_ms as u64;
+
+        let duplicate_query_max_entries = config_network
+            .and_then(|x| x.get("duplicate_query_max_entries"))
+            .map_or(100_000, |x| {
+                x.as_integer()
+                    .expect("network.duplicate_query_max_entries must be an integer")
+            });
+        if duplicate_query_max_entries < 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "network.duplicate_query_max_entries must be at least 3",
+            ));
+        }
+        let duplicate_query_max_entries = duplicate_query_max_entries as usize;
+
         let config_webservice = toml_config.get("webservice");
 
         let webservice_enabled = config_webservice.and_then(|x| x.get("enabled")).map_or(
@@ -155,12 +1039,101 @@ impl Config {
             |x| x.as_bool().expect("webservice.enabled must be a boolean"),
         );
 
-        let webservice_listen_addr = config_webservice
-            .and_then(|x| x.get("listen"))
-            .map_or("0.0.0.0:9090", |x| {
-                x.as_str().expect("webservice.listen_addr must be a string")
-            })
-            .to_owned();
+        let webservice_listen_path = config_webservice
+            .and_then(|x| x.get("listen_path"))
+            .map(|x| {
+                x.as_str()
+                    .expect("webservice.listen_path must be a string")
+                    .to_owned()
+            });
+
+        let webservice_listen_addr = match config_webservice.and_then(|x| x.get("listen")) {
+            Some(x) => Some(
+                x.as_str()
+                    .expect("webservice.listen_addr must be a string")
+                    .to_owned(),
+            ),
+            None if webservice_listen_path.is_none() => Some("0.0.0.0:9090".to_owned()),
+            None => None,
+        };
+
+        let webservice_redact_config = config_webservice
+            .and_then(|x| x.get("redact_config"))
+            .map_or(true, |x| {
+                x.as_bool()
+                    .expect("webservice.redact_config must be a boolean")
+            });
+
+        let webservice_admin_allowed_ips: Vec<IpAddr> = config_webservice
+            .and_then(|x| x.get("admin_allowed_ips"))
+            .map_or(
+                vec![
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                ],
+                |x| {
+                    x.as_array()
+                        .expect("webservice.admin_allowed_ips must be a list")
+                        .iter()
+                        .map(|x| {
+                            x.as_str()
+                                .and_then(|x| x.parse().ok())
+                                .expect("webservice.admin_allowed_ips must be a list of IPs")
+                        })
+                        .collect()
+                },
+            );
+
+        let webservice_ready_after_prime = config_webservice
+            .and_then(|x| x.get("ready_after_prime"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("webservice.ready_after_prime must be a boolean")
+            });
+
+        let webservice_ready_min_cache_entries = config_webservice
+            .and_then(|x| x.get("ready_min_cache_entries"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("webservice.ready_min_cache_entries must be an integer")
+            });
+        if webservice_ready_min_cache_entries < 0 ||
+            webservice_ready_min_cache_entries as usize > cache_size
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "webservice.ready_min_cache_entries must be between 0 and cache.max_items",
+            ));
+        }
+        // Without priming, traffic is what fills the cache, and it is withheld
+        // until the instance is ready
+        if webservice_ready_min_cache_entries > 0 && prime_file.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "webservice.ready_min_cache_entries requires cache.prime_file",
+            ));
+        }
+        let webservice_ready_min_cache_entries = webservice_ready_min_cache_entries as usize;
+
+        let config_health_log = toml_config.get("health_log");
+
+        let health_log_enabled = config_health_log.and_then(|x| x.get("enabled")).map_or(
+            false,
+            |x| x.as_bool().expect("health_log.enabled must be a boolean"),
+        );
+
+        let health_log_interval_secs = config_health_log
+            .and_then(|x| x.get("interval_secs"))
+            .map_or(60, |x| {
+                x.as_integer()
+                    .expect("health_log.interval_secs must be an integer")
+            }) as u64;
+        if health_log_enabled && health_log_interval_secs == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "health_log.interval_secs must be greater than 0",
+            ));
+        }
 
         let config_global = toml_config.get("global");
 
@@ -215,7 +1188,44 @@ impl Config {
             .map_or(100_000, |x| {
                 x.as_integer()
                     .expect("global.max_active_queries must be an integer")
-            }) as usize;
+            });
+        if max_active_queries <= 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "global.max_active_queries must be greater than 0",
+            ));
+        }
+        let max_active_queries = max_active_queries as usize;
+
+        let shed_inflight_queries = config_global
+            .and_then(|x| x.get("shed_inflight_queries"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("global.shed_inflight_queries must be an integer")
+            });
+        if shed_inflight_queries < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "global.shed_inflight_queries cannot be negative",
+            ));
+        }
+        let shed_inflight_queries = shed_inflight_queries as usize;
+
+        let shed_action_str = config_global
+            .and_then(|x| x.get("shed_action"))
+            .map_or("refused", |x| {
+                x.as_str().expect("global.shed_action must be a string")
+            });
+        let shed_action = match shed_action_str {
+            "drop" => ShedAction::Drop,
+            "refused" => ShedAction::Refused,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the shed action. Must be 'drop' or 'refused'",
+                ))
+            }
+        };
 
         let max_clients_waiting_for_query = config_global
             .and_then(|x| x.get("max_clients_waiting_for_query"))
@@ -224,6 +1234,272 @@ impl Config {
                     .expect("global.max_clients_waiting_for_query must be an integer")
             }) as usize;
 
+        let formerr_on_malformed_queries = config_global
+            .and_then(|x| x.get("formerr_on_malformed_queries"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("global.formerr_on_malformed_queries must be a boolean")
+            });
+
+        let max_answers = config_global.and_then(|x| x.get("max_answers")).map(|x| {
+            x.as_integer()
+                .expect("global.max_answers must be an integer")
+        });
+        if let Some(max_answers) = max_answers {
+            if max_answers < 1 || max_answers > 0xffff {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "global.max_answers must be between 1 and 65535",
+                ));
+            }
+        }
+        let max_answers = max_answers.map(|max_answers| max_answers as u16);
+
+        let answer_order_str = config_global
+            .and_then(|x| x.get("answer_order"))
+            .map_or("none", |x| {
+                x.as_str().expect("global.answer_order must be a string")
+            });
+        let answer_order = match answer_order_str {
+            "none" => AnswerOrder::None,
+            "shuffle" => AnswerOrder::Shuffle,
+            "rotate" => AnswerOrder::Rotate,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the answer order. Must be 'none', 'shuffle' or 'rotate'",
+                ))
+            }
+        };
+
+        let compress_responses = config_global
+            .and_then(|x| x.get("compress_responses"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("global.compress_responses must be a boolean")
+            });
+
+        let client_address_family_str = config_global
+            .and_then(|x| x.get("client_address_family"))
+            .map_or("any", |x| {
+                x.as_str()
+                    .expect("global.client_address_family must be a string")
+            });
+        let client_address_family = match client_address_family_str {
+            "any" => ClientAddressFamily::Any,
+            "ipv4" => ClientAddressFamily::Ipv4,
+            "ipv6" => ClientAddressFamily::Ipv6,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the client address family. Must be 'any', 'ipv4' or 'ipv6'",
+                ))
+            }
+        };
+
+        let debug_answer_source = config_global
+            .and_then(|x| x.get("debug_answer_source"))
+            .map_or(false, |x| {
+                x.as_bool()
+                    .expect("global.debug_answer_source must be a boolean")
+            });
+
+        let nsid_enabled = config_global
+            .and_then(|x| x.get("nsid_enabled"))
+            .map_or(false, |x| {
+                x.as_bool().expect("global.nsid_enabled must be a boolean")
+            });
+        let nsid = if nsid_enabled {
+            let nsid = match config_global.and_then(|x| x.get("nsid")) {
+                Some(x) => x.as_str().expect("global.nsid must be a string").to_owned(),
+                None => hostname().unwrap_or_default(),
+            };
+            if nsid.is_empty() || nsid.len() > 0xff {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "global.nsid must be between 1 and 255 bytes long",
+                ));
+            }
+            Some(nsid.into_bytes())
+        } else {
+            None
+        };
+
+        let slow_query_threshold_ms = config_global
+            .and_then(|x| x.get("slow_query_threshold_ms"))
+            .map_or(0, |x| {
+                x.as_integer()
+                    .expect("global.slow_query_threshold_ms must be an integer")
+            }) as u64;
+
+        let config_maintenance = toml_config.get("maintenance");
+
+        let maintenance_enabled = config_maintenance
+            .and_then(|x| x.get("enabled"))
+            .map_or(false, |x| {
+                x.as_bool().expect("maintenance.enabled must be a boolean")
+            });
+
+        let maintenance_response_str = config_maintenance
+            .and_then(|x| x.get("response"))
+            .map_or("refused", |x| {
+                x.as_str().expect("maintenance.response must be a string")
+            });
+        let maintenance_response = match maintenance_response_str {
+            "refused" => MaintenanceResponse::Refused,
+            "sinkhole" => MaintenanceResponse::Sinkhole,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid value for the maintenance response. Must be 'refused' or 'sinkhole'",
+                ))
+            }
+        };
+
+        let maintenance_sinkhole_ipv4 = config_maintenance
+            .and_then(|x| x.get("sinkhole_ipv4"))
+            .map_or(Ipv4Addr::new(0, 0, 0, 0), |x| {
+                x.as_str()
+                    .expect("maintenance.sinkhole_ipv4 must be a string")
+                    .parse()
+                    .expect("maintenance.sinkhole_ipv4 must be an IPv4 address")
+            });
+
+        let maintenance_sinkhole_ipv6 = config_maintenance
+            .and_then(|x| x.get("sinkhole_ipv6"))
+            .map_or(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), |x| {
+                x.as_str()
+                    .expect("maintenance.sinkhole_ipv6 must be a string")
+                    .parse()
+                    .expect("maintenance.sinkhole_ipv6 must be an IPv6 address")
+            });
+
+        let maintenance_suffixes = config_maintenance
+            .and_then(|x| x.get("suffixes"))
+            .map_or(vec![], |x| {
+                x.as_array()
+                    .expect("maintenance.suffixes must be a list")
+                    .iter()
+                    .map(|x| {
+                        let suffix = x.as_str()
+                            .expect("maintenance.suffixes must only contain names");
+                        let mut suffix = dns::qname_encode(suffix)
+                            .expect("maintenance.suffixes contains an invalid name");
+                        suffix.pop();
+                        dns::qname_lc(&suffix)
+                    })
+                    .collect()
+            });
+
+        let config_dns64 = toml_config.get("dns64");
+
+        let (dns64_prefix, dns64_prefix_len) = match config_dns64.and_then(|x| x.get("prefix")) {
+            None => (None, 96),
+            Some(x) => {
+                let prefix_str = x.as_str().expect("dns64.prefix must be a string");
+                let mut parts = prefix_str.splitn(2, '/');
+                let prefix: Ipv6Addr = parts
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .expect("dns64.prefix must be an IPv6 prefix");
+                let prefix_len: u8 = parts.next().map_or(96, |x| {
+                    x.parse().expect("dns64.prefix must be an IPv6 prefix")
+                });
+                match prefix_len {
+                    32 | 40 | 48 | 56 | 64 | 96 => {}
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "The DNS64 prefix length must be 32, 40, 48, 56, 64 or 96",
+                        ))
+                    }
+                }
+                if prefix.segments()[..2] == [0x64, 0xff9b] && prefix_len != 96 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "The well-known DNS64 prefix can only be used as 64:ff9b::/96",
+                    ));
+                }
+                if prefix.octets()[prefix_len as usize / 8..]
+                    .iter()
+                    .any(|&x| x != 0)
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "The DNS64 prefix has bits set after its length",
+                    ));
+                }
+                (Some(prefix), prefix_len)
+            }
+        };
+
+        let config_local_zones = toml_config.get("local_zones");
+
+        let local_zones_enabled = config_local_zones
+            .and_then(|x| x.get("enabled"))
+            .map_or(false, |x| {
+                x.as_bool().expect("local_zones.enabled must be a boolean")
+            });
+
+        let local_zones = if !local_zones_enabled {
+            vec![]
+        } else {
+            let zones = config_local_zones
+                .and_then(|x| x.get("zones"))
+                .map_or_else(default_local_zones, |x| {
+                    x.as_array()
+                        .expect("local_zones.zones must be a list")
+                        .iter()
+                        .map(|x| {
+                            x.as_str()
+                                .expect("local_zones.zones must only contain names")
+                                .to_owned()
+                        })
+                        .collect()
+                });
+            zones
+                .iter()
+                .map(|zone| {
+                    let mut zone = dns::qname_encode(zone)
+                        .expect("local_zones.zones contains an invalid name");
+                    zone.pop();
+                    dns::qname_lc(&zone)
+                })
+                .collect()
+        };
+
+        let local_ptr_records = if !local_zones_enabled {
+            HashMap::new()
+        } else {
+            let ptr_records = config_local_zones.and_then(|x| x.get("ptr")).map_or_else(
+                default_local_ptr_records,
+                |x| {
+                    x.as_table()
+                        .expect("local_zones.ptr must be a table")
+                        .iter()
+                        .map(|(name, x)| {
+                            let target = x.as_str()
+                                .expect("local_zones.ptr must map names to names")
+                                .to_owned();
+                            (name.to_owned(), target)
+                        })
+                        .collect()
+                },
+            );
+            ptr_records
+                .iter()
+                .map(|&(ref name, ref target)| {
+                    let mut name = dns::qname_encode(name)
+                        .expect("local_zones.ptr contains an invalid name");
+                    name.pop();
+                    let target = dns::qname_encode(target)
+                        .expect("local_zones.ptr contains an invalid name");
+                    (dns::qname_lc(&name), target)
+                })
+                .collect()
+        };
+
         let config_dnstap = toml_config.get("dnstap");
 
         let dnstap_enabled = config_dnstap.and_then(|x| x.get("enabled")).map_or(
@@ -256,16 +1532,83 @@ impl Config {
 
         Ok(Config {
             decrement_ttl,
+            stale_while_revalidate,
+            stale_ttl,
+            stale_refresh_ttl,
+            stale_refresh_jitter_ms,
+            max_client_ttl,
+            no_cache_qtypes,
+            sort_addresses,
+            prime_file,
+            prime_qps,
             upstream_servers,
+            upstream_routes,
+            upstream_no_rd_servers,
+            upstream_priorities,
             lbmode,
+            upstream_prefer_family,
+            ecs_policy,
             upstream_max_failure_duration,
+            upstream_max_response_size,
+            startup_wait,
+            query_deadline_ms,
+            no_coalescing_qtypes,
+            strip_out_of_bailiwick,
+            nxdomain_to_nodata_suffixes,
+            nxdomain_to_nodata_qtypes,
+            edns_options_allowlist,
+            upstream_probes_delay_ms,
+            upstream_query_max_timeout_ms,
+            upstream_timeout_jitter_percent,
+            upstream_success_rate_window,
+            upstream_min_success_rate_percent,
+            upstream_max_lame_percent,
+            upstream_eject_lame,
+            upstream_breaker_enabled,
+            upstream_breaker_cooldown,
+            upstream_breaker_half_open_probes,
+            upstream_max_qps,
+            enable_retry,
+            upstream_cookies,
             cache_size,
+            max_cache_bytes,
+            referrals_cache_size,
             udp_ports,
+            udp_recv_buffer,
+            udp_send_buffer,
+            udp_dont_fragment,
+            max_client_udp_payload,
+            upstream_source_addr,
+            upstream_family,
+            upstream_interface,
             listen_addr,
+            client_max_qps,
+            client_ratelimit_action,
+            client_ratelimit_slip,
+            client_ratelimit_max_clients,
+            duplicate_query_window_ms,
+            duplicate_query_max_entries,
             webservice_enabled,
             webservice_listen_addr,
+            webservice_listen_path,
+            webservice_redact_config,
+            webservice_admin_allowed_ips,
+            webservice_ready_after_prime,
+            webservice_ready_min_cache_entries,
+            health_log_enabled,
+            health_log_interval_secs,
             min_ttl,
+            min_positive_ttl,
+            min_negative_ttl,
             max_ttl,
+            servfail_ttl,
+            cache_zero_ttl,
+            cache_shadow,
+            cache_compress,
+            negative_ttl,
+            synth_soa_mname,
+            synth_soa_rname,
+            synth_soa_minimum,
             user,
             group,
             chroot_dir,
@@ -279,7 +1622,80 @@ impl Config {
             max_tcp_clients,
             max_waiting_clients,
             max_active_queries,
+            shed_inflight_queries,
+            shed_action,
             max_clients_waiting_for_query,
+            formerr_on_malformed_queries,
+            max_answers,
+            answer_order,
+            compress_responses,
+            client_address_family,
+            debug_answer_source,
+            nsid,
+            slow_query_threshold_ms,
+            maintenance_enabled,
+            maintenance_response,
+            maintenance_sinkhole_ipv4,
+            maintenance_sinkhole_ipv6,
+            maintenance_suffixes,
+            dns64_prefix,
+            dns64_prefix_len,
+            local_zones,
+            local_ptr_records,
         })
     }
 }
+
+#[cfg(feature = "webservice")]
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_f64())
+}
+
+#[cfg(feature = "webservice")]
+fn serialize_name<S: Serializer>(name: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&dns::qname_to_string(name))
+}
+
+#[cfg(feature = "webservice")]
+fn serialize_names<S: Serializer>(names: &Vec<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(names.len()))?;
+    for name in names {
+        seq.serialize_element(&dns::qname_to_string(name))?;
+    }
+    seq.end()
+}
+
+#[cfg(feature = "webservice")]
+fn serialize_routes<S: Serializer>(
+    routes: &HashMap<Vec<u8>, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(routes.len()))?;
+    for (name, upstream_server) in routes {
+        map.serialize_entry(&dns::qname_to_string(name), upstream_server)?;
+    }
+    map.end()
+}
+
+#[cfg(feature = "webservice")]
+fn serialize_ptr_records<S: Serializer>(
+    ptr_records: &HashMap<Vec<u8>, Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(ptr_records.len()))?;
+    for (name, target) in ptr_records {
+        map.serialize_entry(&dns::qname_to_string(name), &dns::qname_to_string(target))?;
+    }
+    map.end()
+}
+
+#[cfg(feature = "webservice")]
+fn serialize_nsid<S: Serializer>(nsid: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *nsid {
+        None => serializer.serialize_none(),
+        Some(ref nsid) => serializer.serialize_some(&String::from_utf8_lossy(nsid)),
+    }
+}
@@ -2,28 +2,58 @@
 //! common responses.
 
 use rand::random;
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use super::{DNS_UDP_NOEDNS0_MAX_SIZE, DNS_QUERY_MIN_SIZE};
 
 pub const DNS_CLASS_CH: u16 = 3;
 pub const DNS_CLASS_IN: u16 = 1;
+pub const DNS_EDNS_OPTION_COOKIE: u16 = 10;
+pub const DNS_EDNS_OPTION_ECS: u16 = 8;
+pub const DNS_EDNS_OPTION_EDE: u16 = 15;
+pub const DNS_EDNS_OPTION_NSID: u16 = 3;
+pub const DNS_FLAG_CD: u16 = 0x0010;
+pub const DNS_FLAG_RD: u16 = 0x0100;
+pub const DNS_FLAG_Z: u16 = 0x0040;
 pub const DNS_HEADER_SIZE: usize = 12;
+pub const DNS_MAX_COMPRESSION_POINTERS: usize = 16;
 pub const DNS_MAX_HOSTNAME_LEN: usize = 255;
+pub const DNS_MAX_LABELS: u16 = 127;
 pub const DNS_MAX_PACKET_SIZE: usize = 65535;
 pub const DNS_OFFSET_EDNS_DO: usize = 6;
 pub const DNS_OFFSET_EDNS_PAYLOAD_SIZE: usize = 2;
+pub const DNS_OFFSET_EDNS_RDLEN: usize = 8;
 pub const DNS_OFFSET_EDNS_TYPE: usize = 0;
+pub const DNS_OFFSET_EDNS_VERSION: usize = 5;
 pub const DNS_OFFSET_QUESTION: usize = DNS_HEADER_SIZE;
+pub const DNS_OPCODE_QUERY: u8 = 0;
 pub const DNS_QTYPE_PLUS_QCLASS_LEN: usize = 4;
+pub const DNS_RCODE_BADCOOKIE: u16 = 23;
+pub const DNS_RCODE_BADVERS: u16 = 16;
+pub const DNS_RCODE_FORMERR: u8 = 1;
+pub const DNS_RCODE_NOERROR: u8 = 0;
 pub const DNS_RCODE_NXDOMAIN: u8 = 3;
 pub const DNS_RCODE_REFUSED: u8 = 5;
 pub const DNS_RCODE_SERVFAIL: u8 = 2;
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_AAAA: u16 = 28;
 pub const DNS_TYPE_ANY: u16 = 255;
+pub const DNS_TYPE_AXFR: u16 = 252;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_TYPE_DS: u16 = 43;
 pub const DNS_TYPE_HINFO: u16 = 13;
+pub const DNS_TYPE_HTTPS: u16 = 65;
+pub const DNS_TYPE_IXFR: u16 = 251;
+pub const DNS_TYPE_MX: u16 = 15;
+pub const DNS_TYPE_NS: u16 = 2;
 pub const DNS_TYPE_OPT: u16 = 41;
+pub const DNS_TYPE_PTR: u16 = 12;
 pub const DNS_TYPE_SOA: u16 = 6;
+pub const DNS_TYPE_SVCB: u16 = 64;
 pub const DNS_TYPE_TXT: u16 = 16;
 
 #[derive(Clone, Debug)]
@@ -36,6 +66,9 @@ pub struct NormalizedQuestion {
     pub qclass: u16,
     pub labels_count: u16,
     pub dnssec: bool,
+    pub ecs: Option<Vec<u8>>,
+    pub edns_version: u8,
+    pub edns_options: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -44,6 +77,19 @@ pub struct NormalizedQuestionKey {
     pub qtype: u16,
     pub qclass: u16,
     pub dnssec: bool,
+    pub checking_disabled: bool,
+}
+
+/// A delegation extracted from a referral response.
+///
+/// `zone` uses the same format as `NormalizedQuestion.qname`, and `records`
+/// contains the uncompressed NS records, followed by the glue records.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Referral {
+    pub zone: Vec<u8>,
+    pub records: Vec<u8>,
+    pub nscount: u16,
+    pub arcount: u16,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq)]
@@ -70,7 +116,6 @@ pub fn flags(packet: &[u8]) -> u16 {
     ((packet[2] as u16) << 8) | packet[3] as u16
 }
 
-#[allow(dead_code)]
 #[inline]
 pub fn rd(packet: &[u8]) -> bool {
     packet[2] & 0x1 != 0
@@ -78,10 +123,10 @@ pub fn rd(packet: &[u8]) -> bool {
 
 #[inline]
 pub fn set_rd(packet: &mut [u8], state: bool) {
+    packet[2] &= !0x1;
     packet[2] |= state as u8;
 }
 
-#[allow(dead_code)]
 #[inline]
 pub fn tc(packet: &[u8]) -> bool {
     packet[2] & 0x2 != 0
@@ -100,15 +145,22 @@ pub fn aa(packet: &[u8]) -> bool {
 
 #[inline]
 pub fn set_aa(packet: &mut [u8], state: bool) {
+    packet[2] &= !0x4;
     packet[2] |= 0x4 * (state as u8);
 }
 
-#[allow(dead_code)]
 #[inline]
 pub fn opcode(packet: &[u8]) -> u8 {
     (packet[2] & 0x78) >> 3
 }
 
+#[inline]
+pub fn set_opcode(packet: &mut [u8], value: u8) {
+    debug_assert!(value <= 0xf);
+    packet[2] &= !0x78;
+    packet[2] |= (value & 0xf) << 3;
+}
+
 #[inline]
 pub fn qr(packet: &[u8]) -> bool {
     packet[2] & 0x80 != 0
@@ -131,12 +183,35 @@ pub fn set_rcode(packet: &mut [u8], value: u8) {
     packet[3] |= value & 0xf;
 }
 
+pub fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "OTHER",
+    }
+}
+
 #[allow(dead_code)]
 #[inline]
 pub fn cd(packet: &[u8]) -> bool {
     packet[3] & 0x10 != 0
 }
 
+#[inline]
+pub fn set_cd(packet: &mut [u8], state: bool) {
+    packet[3] &= !0x10;
+    packet[3] |= 0x10 * (state as u8);
+}
+
 #[allow(dead_code)]
 #[inline]
 pub fn ad(packet: &[u8]) -> bool {
@@ -155,6 +230,12 @@ pub fn ra(packet: &[u8]) -> bool {
     packet[3] & 0x80 != 0
 }
 
+#[inline]
+pub fn set_ra(packet: &mut [u8], state: bool) {
+    packet[3] &= !0x80;
+    packet[3] |= 0x80 * (state as u8);
+}
+
 #[inline]
 pub fn qdcount(packet: &[u8]) -> u16 {
     ((packet[4] as u16) << 8) | packet[5] as u16
@@ -182,7 +263,6 @@ pub fn nscount(packet: &[u8]) -> u16 {
     ((packet[8] as u16) << 8) | packet[9] as u16
 }
 
-#[allow(dead_code)]
 #[inline]
 pub fn set_nscount(packet: &mut [u8], value: u16) {
     packet[8] = (value >> 8) as u8;
@@ -202,11 +282,9 @@ pub fn set_arcount(packet: &mut [u8], value: u16) {
 
 pub fn overwrite_qname(packet: &mut [u8], qname: &[u8]) {
     let packet_len = packet.len();
-    debug_assert!(packet_len >= DNS_OFFSET_QUESTION);
     if packet_len <= DNS_OFFSET_QUESTION {
         return;
     }
-    debug_assert_eq!(qdcount(packet), 1);
     if qdcount(packet) < 1 {
         return;
     }
@@ -215,31 +293,36 @@ pub fn overwrite_qname(packet: &mut [u8], qname: &[u8]) {
         return;
     }
     let mut to = &mut packet[DNS_OFFSET_QUESTION..];
-    if to.len() <= qname_len {
+    if to.len() <= qname_len || to[qname_len] != 0 {
         return;
     }
-    assert_eq!(to[qname_len], 0);
-    let _ = to.write(qname).unwrap();
+    let _ = to.write(qname);
 }
 
-pub struct QuestionRR<'t> {
-    qname: &'t [u8],
+pub struct QuestionRR {
+    qname: Vec<u8>,
     qtype: u16,
     qclass: u16,
     labels_count: u16,
 }
 
+/// Parses the question of a packet.
+///
+/// Cache keys are built from the name of the question, so a compressed name
+/// is expanded, and gives the same key as the same name encoded without
+/// compression pointers.
 pub fn question(packet: &[u8]) -> Result<QuestionRR, &'static str> {
     let packet_len = packet.len();
     if packet_len <= DNS_OFFSET_QUESTION {
         return Err("Short packet");
     }
-    let (offset, labels_count) = match skip_name(packet, DNS_OFFSET_QUESTION) {
-        Ok(offset_and_labels) => offset_and_labels,
-        Err(e) => return Err(e),
-    };
-    assert!(offset > DNS_OFFSET_QUESTION);
-    let qname = &packet[DNS_OFFSET_QUESTION..offset - 1];
+    let (qname, offset) = name_uncompressed(packet, DNS_OFFSET_QUESTION, false)?;
+    let mut labels_count = 0u16;
+    let mut label_offset = 0;
+    while label_offset < qname.len() {
+        label_offset += qname[label_offset] as usize + 1;
+        labels_count += 1;
+    }
     if 4 > packet_len - offset {
         return Err("Short packet");
     }
@@ -254,9 +337,19 @@ pub fn question(packet: &[u8]) -> Result<QuestionRR, &'static str> {
     Ok(question_rr)
 }
 
+const ERR_NAME_TOO_LONG: &'static str = "Name too long";
+const ERR_TOO_MANY_LABELS: &'static str = "Too many labels";
+
+/// Returns `true` if a question was rejected by `normalize()` because its
+/// name exceeds the protocol limits. Such queries are always answered with
+/// `FORMERR`.
+pub fn is_name_limit_error(e: &str) -> bool {
+    e == ERR_NAME_TOO_LONG || e == ERR_TOO_MANY_LABELS
+}
+
 fn skip_name(packet: &[u8], offset: usize) -> Result<(usize, u16), &'static str> {
     let packet_len = packet.len();
-    if offset >= packet_len - 1 {
+    if packet_len == 0 || offset >= packet_len - 1 {
         return Err("Short packet");
     }
     let mut name_len: usize = 0;
@@ -279,12 +372,12 @@ fn skip_name(packet: &[u8], offset: usize) -> Result<(usize, u16), &'static str>
         }
         name_len += label_len + 1;
         if name_len > DNS_MAX_HOSTNAME_LEN {
-            info!(
+            debug!(
                 "Name too long: {} bytes > {}",
                 name_len,
                 DNS_MAX_HOSTNAME_LEN
             );
-            return Err("Name too long");
+            return Err(ERR_NAME_TOO_LONG);
         }
         offset += label_len + 1;
         if label_len == 0 {
@@ -299,6 +392,111 @@ fn skip_name(packet: &[u8], offset: usize) -> Result<(usize, u16), &'static str>
 struct EDNS0 {
     payload_size: u16,
     dnssec: bool,
+    ecs: Option<Vec<u8>>,
+    version: u8,
+    options: Vec<u8>,
+}
+
+/// Checks that the data of an EDNS Client Subnet option is well-formed:
+/// a known family, a consistent source prefix, and no extra address bytes.
+fn ecs_is_valid(ecs: &[u8]) -> bool {
+    if ecs.len() < 4 {
+        return false;
+    }
+    let family = (ecs[0] as u16) << 8 | ecs[1] as u16;
+    let source_prefix = ecs[2] as usize;
+    let max_prefix = match family {
+        1 => 32,
+        2 => 128,
+        _ => return false,
+    };
+    source_prefix <= max_prefix && ecs.len() - 4 == (source_prefix + 7) / 8
+}
+
+/// Returns the data of the Client Subnet option, if present in the OPT record
+/// whose rdata starts at `offset`.
+fn parse_edns0_ecs(packet: &[u8], mut offset: usize, rdlen: usize) -> Option<Vec<u8>> {
+    let packet_len = packet.len();
+    if rdlen > packet_len - offset {
+        return None;
+    }
+    let rdata_end = offset + rdlen;
+    while 4 <= rdata_end - offset {
+        let code = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let len = ((packet[offset + 2] as u16) << 8 | packet[offset + 3] as u16) as usize;
+        offset += 4;
+        if len > rdata_end - offset {
+            return None;
+        }
+        if code == DNS_EDNS_OPTION_ECS {
+            let ecs = &packet[offset..offset + len];
+            if !ecs_is_valid(ecs) {
+                return None;
+            }
+            return Some(ecs.to_vec());
+        }
+        offset += len;
+    }
+    None
+}
+
+/// Reduces the precision of the data of a Client Subnet option to at most
+/// `max_prefix_v4` or `max_prefix_v6` bits, and clears the scope.
+pub fn ecs_truncate(ecs: &[u8], max_prefix_v4: u8, max_prefix_v6: u8) -> Option<Vec<u8>> {
+    if !ecs_is_valid(ecs) {
+        return None;
+    }
+    let max_prefix = if ecs[1] == 1 {
+        max_prefix_v4
+    } else {
+        max_prefix_v6
+    };
+    let source_prefix = cmp::min(ecs[2], max_prefix) as usize;
+    let mut truncated = ecs[..4 + (source_prefix + 7) / 8].to_vec();
+    truncated[2] = source_prefix as u8;
+    truncated[3] = 0;
+    if source_prefix % 8 != 0 {
+        let last = truncated.len() - 1;
+        truncated[last] &= 0xff << (8 - source_prefix % 8);
+    }
+    Some(truncated)
+}
+
+/// Returns the options of `options`, a sequence of EDNS options encoded as in
+/// the data of an OPT record, whose code satisfies `keep`, along with the
+/// number of options that were left out.
+fn edns_options_retain<F>(options: &[u8], keep: F) -> Result<(Vec<u8>, usize), &'static str>
+where
+    F: Fn(u16) -> bool,
+{
+    let options_len = options.len();
+    let mut kept = Vec::with_capacity(options_len);
+    let mut removed = 0;
+    let mut offset = 0;
+    while offset < options_len {
+        if 4 > options_len - offset {
+            return Err("Short EDNS option");
+        }
+        let code = (options[offset] as u16) << 8 | options[offset + 1] as u16;
+        let len = ((options[offset + 2] as u16) << 8 | options[offset + 3] as u16) as usize;
+        if len > options_len - offset - 4 {
+            return Err("EDNS option length would exceed record length");
+        }
+        if keep(code) {
+            kept.extend_from_slice(&options[offset..offset + 4 + len]);
+        } else {
+            removed += 1;
+        }
+        offset += 4 + len;
+    }
+    Ok((kept, removed))
+}
+
+/// Keeps the options of `options` whose code is in `allowlist`.
+pub fn edns_options_filter(options: &[u8], allowlist: &[u16]) -> Vec<u8> {
+    edns_options_retain(options, |code| allowlist.contains(&code))
+        .map(|(kept, _)| kept)
+        .unwrap_or_default()
 }
 
 fn parse_edns0(packet: &[u8]) -> Option<EDNS0> {
@@ -336,35 +534,63 @@ fn parse_edns0(packet: &[u8]) -> Option<EDNS0> {
         return None;
     }
     let dnssec = packet[offset + DNS_OFFSET_EDNS_DO] & 0x80 == 0x80;
+    let version = packet[offset + DNS_OFFSET_EDNS_VERSION];
     if payload_size < DNS_UDP_NOEDNS0_MAX_SIZE as u16 {
         payload_size = DNS_UDP_NOEDNS0_MAX_SIZE as u16;
     }
+    let (ecs, options) = if offset + DNS_OFFSET_EDNS_RDLEN + 2 <= packet_len {
+        let rdlen = ((packet[offset + DNS_OFFSET_EDNS_RDLEN] as u16) << 8 |
+            packet[offset + DNS_OFFSET_EDNS_RDLEN + 1] as u16) as usize;
+        let rdata_offset = offset + DNS_OFFSET_EDNS_RDLEN + 2;
+        let options = if rdlen <= packet_len - rdata_offset {
+            edns_options_retain(
+                &packet[rdata_offset..rdata_offset + rdlen],
+                |code| code != DNS_EDNS_OPTION_ECS,
+            ).map(|(options, _)| options)
+                .unwrap_or_default()
+        } else {
+            vec![]
+        };
+        (parse_edns0_ecs(packet, rdata_offset, rdlen), options)
+    } else {
+        (None, vec![])
+    };
     Some(EDNS0 {
         payload_size: payload_size,
         dnssec: dnssec,
+        ecs: ecs,
+        version: version,
+        options: options,
     })
 }
 
+/// Converts an encoded name to its text representation, for display purposes.
+/// Compression pointers are shown as `&`.
+pub fn qname_to_string(qname: &[u8]) -> String {
+    let qname_len = qname.len();
+    let mut res = Vec::with_capacity(qname_len);
+    let mut offset: usize = 0;
+    while offset < qname_len {
+        let label_len = qname[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            res.push(b'&');
+            offset += 2;
+            continue;
+        }
+        offset += 1;
+        if label_len == 0 || label_len > qname_len - offset {
+            break;
+        }
+        res.extend_from_slice(&qname[offset..offset + label_len]);
+        res.push(b'.');
+        offset += label_len;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
 impl fmt::Display for NormalizedQuestion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let qname = &self.qname;
-        let qname_len = qname.len();
-        let mut res = Vec::with_capacity(qname_len);
-        let mut offset: usize = 0;
-        while offset < qname_len {
-            let label_len = qname[offset] as usize;
-            assert_eq!(label_len, 0);
-            if label_len & 0xc0 == 0xc0 {
-                res.push(b'&');
-                offset += 2;
-                continue;
-            }
-            offset += 1;
-            res.extend_from_slice(&qname[offset..offset + label_len]);
-            res.push(b'.');
-            offset += label_len;
-        }
-        let qname_str = String::from_utf8_lossy(&res);
+        let qname_str = qname_to_string(&self.qname);
         write!(f, "[{}]\t{} {}", qname_str, self.qtype, self.qclass)
     }
 }
@@ -378,6 +604,7 @@ impl NormalizedQuestion {
         };
         NormalizedQuestionKey {
             dnssec: dnssec,
+            checking_disabled: self.flags & DNS_FLAG_CD != 0,
             qname_lc: qname_lc(&self.qname),
             qtype: self.qtype,
             qclass: self.qclass,
@@ -401,12 +628,21 @@ pub fn qname_lc(qname: &[u8]) -> Vec<u8> {
     while offset < qname_len {
         res[offset] = qname[offset];
         let label_len = qname[offset] as usize;
-        assert_ne!(label_len, 0);
+        if label_len == 0 {
+            break;
+        }
         if label_len & 0xc0 == 0xc0 {
+            if offset + 1 < qname_len {
+                res[offset + 1] = qname[offset + 1];
+            }
             offset += 2;
             continue;
         }
         offset += 1;
+        if label_len > qname_len - offset {
+            res[offset..].copy_from_slice(&qname[offset..]);
+            break;
+        }
         for i in 0..label_len {
             res[offset + i] = match qname[offset + i] {
                 c @ 0x41...0x5a => c | 0x20,
@@ -438,6 +674,9 @@ pub fn normalize(packet: &[u8], is_question: bool) -> Result<NormalizedQuestion,
     if is_question == qr(packet) {
         return Err("Invalid flags");
     }
+    if is_question && opcode(packet) != DNS_OPCODE_QUERY {
+        return Err("Unsupported opcode");
+    }
     if qdcount(packet) != 1 {
         return Err("Unsupported number of questions");
     }
@@ -445,22 +684,44 @@ pub fn normalize(packet: &[u8], is_question: bool) -> Result<NormalizedQuestion,
         Ok(question) => question,
         Err(e) => return Err(e),
     };
+    // Checked again here, independently from how the name was parsed, since
+    // the cache and the upstream queries rely on these limits. The encoded
+    // name includes the terminating root label.
+    if question.qname.len() + 1 > DNS_MAX_HOSTNAME_LEN {
+        return Err(ERR_NAME_TOO_LONG);
+    }
+    if question.labels_count > DNS_MAX_LABELS {
+        return Err(ERR_TOO_MANY_LABELS);
+    }
     let mut normalized_question = NormalizedQuestion {
         tid: tid(packet),
         flags: flags(packet),
         payload_size: DNS_UDP_NOEDNS0_MAX_SIZE as u16,
         labels_count: question.labels_count,
         dnssec: false,
-        qname: question.qname.to_owned(),
+        ecs: None,
+        edns_version: 0,
+        edns_options: vec![],
+        qname: question.qname,
         qtype: question.qtype,
         qclass: question.qclass,
     };
     if is_question {
-        if ancount(packet) != 0 || nscount(packet) != 0 {
+        // IXFR queries carry the SOA record of the client in the authority
+        // section. They are accepted, only to be refused later.
+        if ancount(packet) != 0 || (nscount(packet) != 0 && question.qtype != DNS_TYPE_IXFR) {
             return Err("Extra sections found in a question");
         }
-        if let Some(edns0) = parse_edns0(packet) {
+        let edns0 = if nscount(packet) == 0 {
+            parse_edns0(packet)
+        } else {
+            None
+        };
+        if let Some(edns0) = edns0 {
             normalized_question.dnssec = edns0.dnssec;
+            normalized_question.ecs = edns0.ecs;
+            normalized_question.edns_version = edns0.version;
+            normalized_question.edns_options = edns0.options;
             if edns0.payload_size > DNS_UDP_NOEDNS0_MAX_SIZE as u16 {
                 normalized_question.payload_size = edns0.payload_size;
             }
@@ -480,18 +741,17 @@ pub fn min_ttl(
     max_ttl: u32,
     failure_ttl: u32,
 ) -> Result<u32, &'static str> {
-    if qdcount(packet) != 1 {
-        return Err("Unsupported number of questions");
-    }
     let packet_len = packet.len();
     if packet_len <= DNS_OFFSET_QUESTION {
         return Err("Short packet");
     }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
     let mut offset = match skip_name(packet, DNS_OFFSET_QUESTION) {
         Ok(offset) => offset.0,
         Err(e) => return Err(e),
     };
-    assert!(offset > DNS_OFFSET_QUESTION);
     if 4 > packet_len - offset {
         return Err("Short packet");
     }
@@ -503,7 +763,7 @@ pub fn min_ttl(
     let ancount = ancount(packet);
     let nscount = nscount(packet);
     let arcount = arcount(packet);
-    let rrcount = ancount + nscount + arcount;
+    let rrcount = ancount as u32 + nscount as u32 + arcount as u32;
     let mut found_min_ttl = if rrcount > 0 { max_ttl } else { failure_ttl };
     for _ in 0..rrcount {
         offset = match skip_name(packet, offset) {
@@ -541,19 +801,73 @@ pub fn min_ttl(
     Ok(found_min_ttl)
 }
 
-pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
+/// Returns the TTL to use for caching a negative response (RFC 2308).
+///
+/// For `NXDOMAIN` and `NODATA` responses, this is the minimum of the TTL of
+/// the `SOA` record found in the authority section, and of its `MINIMUM`
+/// field. `None` is returned for other responses, or if there is no `SOA`
+/// record.
+pub fn negative_ttl(packet: &[u8]) -> Result<Option<u32>, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
     if qdcount(packet) != 1 {
         return Err("Unsupported number of questions");
     }
+    let rcode = rcode(packet);
+    let ancount = ancount(packet);
+    if rcode != DNS_RCODE_NXDOMAIN && (rcode != DNS_RCODE_NOERROR || ancount != 0) {
+        return Ok(None);
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let rrcount = ancount as u32 + nscount(packet) as u32;
+    for i in 0..rrcount {
+        offset = skip_name(packet, offset)?.0;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let ttl = (packet[offset + 4] as u32) << 24 | (packet[offset + 5] as u32) << 16 |
+            (packet[offset + 6] as u32) << 8 | packet[offset + 7] as u32;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        if i >= ancount as u32 && rr_type == DNS_TYPE_SOA {
+            let rdata_end = offset + rdlen;
+            let mut rdata_offset = skip_name(packet, offset)?.0;
+            rdata_offset = skip_name(packet, rdata_offset)?.0;
+            if rdata_offset > rdata_end || 20 != rdata_end - rdata_offset {
+                return Err("Invalid SOA record");
+            }
+            let minimum = &packet[rdata_offset + 16..rdata_end];
+            let minimum = (minimum[0] as u32) << 24 | (minimum[1] as u32) << 16 |
+                (minimum[2] as u32) << 8 | minimum[3] as u32;
+            return Ok(Some(cmp::min(ttl, minimum)));
+        }
+        offset += rdlen;
+    }
+    Ok(None)
+}
+
+pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
     let packet_len = packet.len();
     if packet_len <= DNS_OFFSET_QUESTION {
         return Err("Short packet");
     }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
     let mut offset = match skip_name(packet, DNS_OFFSET_QUESTION) {
         Ok(offset) => offset.0,
         Err(e) => return Err(e),
     };
-    assert!(offset > DNS_OFFSET_QUESTION);
     if 4 > packet_len - offset {
         return Err("Short packet");
     }
@@ -565,7 +879,7 @@ pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
     let ancount = ancount(packet);
     let nscount = nscount(packet);
     let arcount = arcount(packet);
-    for _ in 0..(ancount + nscount + arcount) {
+    for _ in 0..(ancount as u32 + nscount as u32 + arcount as u32) {
         offset = match skip_name(packet, offset) {
             Ok(offset) => offset.0,
             Err(e) => return Err(e),
@@ -594,70 +908,1115 @@ pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
     Ok(())
 }
 
-pub fn build_tc_packet(normalized_question: &NormalizedQuestion) -> Result<Vec<u8>, &'static str> {
-    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
-    let mut packet = Vec::with_capacity(capacity);
-    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
-    set_tid(&mut packet, normalized_question.tid);
-    set_aa(&mut packet, true);
-    set_qr(&mut packet, true);
-    set_tc(&mut packet, true);
-    set_qdcount(&mut packet, 1);
-    packet.extend_from_slice(&normalized_question.qname);
-    packet.push(0);
-
-    packet.push((normalized_question.qtype >> 8) as u8);
-    packet.push(normalized_question.qtype as u8);
-    packet.push((normalized_question.qclass >> 8) as u8);
-    packet.push(normalized_question.qclass as u8);
-    Ok(packet)
+/// Subtracts `age` from the TTL of every record, without going below 1 nor
+/// above `max_ttl`, so that clients see TTLs decrease as a response ages.
+pub fn decrement_ttls(packet: &mut [u8], age: u32, max_ttl: u32) -> Result<(), &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut offset = match skip_name(packet, DNS_OFFSET_QUESTION) {
+        Ok(offset) => offset.0,
+        Err(e) => return Err(e),
+    };
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let ancount = ancount(packet);
+    let nscount = nscount(packet);
+    let arcount = arcount(packet);
+    for _ in 0..(ancount as u32 + nscount as u32 + arcount as u32) {
+        offset = match skip_name(packet, offset) {
+            Ok(offset) => offset.0,
+            Err(e) => return Err(e),
+        };
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let qtype = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        if qtype != DNS_TYPE_OPT {
+            let ttl = (packet[offset + 4] as u32) << 24 | (packet[offset + 5] as u32) << 16 |
+                (packet[offset + 6] as u32) << 8 | packet[offset + 7] as u32;
+            let ttl = cmp::max(cmp::min(ttl.saturating_sub(age), max_ttl), 1);
+            packet[offset + 4] = (ttl >> 24) as u8;
+            packet[offset + 5] = (ttl >> 16) as u8;
+            packet[offset + 6] = (ttl >> 8) as u8;
+            packet[offset + 7] = ttl as u8;
+        }
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        offset += rdlen;
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    Ok(())
 }
 
-pub fn build_servfail_packet(
-    normalized_question: &NormalizedQuestion,
-) -> Result<Vec<u8>, &'static str> {
-    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
-    let mut packet = Vec::with_capacity(capacity);
-    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
-    set_rcode(&mut packet, DNS_RCODE_SERVFAIL);
-    set_tid(&mut packet, normalized_question.tid);
-    set_aa(&mut packet, true);
-    set_qr(&mut packet, true);
-    set_qdcount(&mut packet, 1);
-    packet.extend_from_slice(&normalized_question.qname);
-    packet.push(0);
-
-    packet.push((normalized_question.qtype >> 8) as u8);
-    packet.push(normalized_question.qtype as u8);
-    packet.push((normalized_question.qclass >> 8) as u8);
-    packet.push(normalized_question.qclass as u8);
-    Ok(packet)
+/// Reorders the `A` and `AAAA` records of the answer section, within each
+/// RRset, using `permute`. Owner names, that are identical within an RRset,
+/// are left in place, so that offsets and compression pointers remain valid.
+/// `permute` gets the rest of each record: type, class, TTL and data, so that
+/// addresses keep their TTL.
+pub fn permute_addresses<F>(packet: &mut [u8], mut permute: F) -> Result<(), &'static str>
+where
+    F: FnMut(&mut [Vec<u8>]),
+{
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let mut rrsets: Vec<(u16, Vec<u8>, Vec<usize>)> = vec![];
+    for _ in 0..ancount(packet) {
+        let (owner, next_offset) = name_lc_uncompressed(packet, offset)?;
+        offset = next_offset;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        if rdlen > packet_len - offset - 10 {
+            return Err("Record length would exceed packet length");
+        }
+        if (rr_type == DNS_TYPE_A && rdlen == 4) || (rr_type == DNS_TYPE_AAAA && rdlen == 16) {
+            match rrsets
+                .iter()
+                .position(|rrset| rrset.0 == rr_type && rrset.1 == owner)
+            {
+                Some(i) => rrsets[i].2.push(offset),
+                None => rrsets.push((rr_type, owner, vec![offset])),
+            }
+        }
+        offset += 10 + rdlen;
+    }
+    for (rr_type, _, offsets) in rrsets {
+        if offsets.len() < 2 {
+            continue;
+        }
+        let record_len = if rr_type == DNS_TYPE_A { 10 + 4 } else { 10 + 16 };
+        let mut records: Vec<Vec<u8>> = offsets
+            .iter()
+            .map(|&offset| packet[offset..offset + record_len].to_vec())
+            .collect();
+        permute(&mut records);
+        for (&offset, record) in offsets.iter().zip(&records) {
+            packet[offset..offset + record_len].copy_from_slice(record);
+        }
+    }
+    Ok(())
 }
 
-pub fn build_refused_packet(
-    normalized_question: &NormalizedQuestion,
-) -> Result<Vec<u8>, &'static str> {
-    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
-    let mut packet = Vec::with_capacity(capacity);
-    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
-    set_rcode(&mut packet, DNS_RCODE_REFUSED);
-    set_tid(&mut packet, normalized_question.tid);
-    set_aa(&mut packet, true);
-    set_qr(&mut packet, true);
-    set_qdcount(&mut packet, 1);
-    packet.extend_from_slice(&normalized_question.qname);
-    packet.push(0);
+/// Sorts the `A` and `AAAA` records of the answer section by address, within
+/// each RRset, so that responses with the same records are identical
+/// regardless of the order they were received in.
+pub fn sort_addresses(packet: &mut [u8]) -> Result<(), &'static str> {
+    permute_addresses(packet, |records| {
+        records.sort_by(|a, b| a[10..].cmp(&b[10..]).then_with(|| a.cmp(b)))
+    })
+}
 
-    packet.push((normalized_question.qtype >> 8) as u8);
-    packet.push(normalized_question.qtype as u8);
-    packet.push((normalized_question.qclass >> 8) as u8);
-    packet.push(normalized_question.qclass as u8);
-    Ok(packet)
+/// Returns the lowercase, uncompressed name starting at `offset`, in the same
+/// format as `NormalizedQuestion.qname` (without the final empty label), as well
+/// as the offset of the data following the name in the packet.
+pub fn name_lc_uncompressed(
+    packet: &[u8],
+    offset: usize,
+) -> Result<(Vec<u8>, usize), &'static str> {
+    name_uncompressed(packet, offset, true)
 }
 
-pub fn build_nxdomain_packet(
-    normalized_question: &NormalizedQuestion,
-) -> Result<Vec<u8>, &'static str> {
+/// Same as `name_lc_uncompressed()`, keeping the original case of the name
+/// unless `lowercase` is set.
+fn name_uncompressed(
+    packet: &[u8],
+    offset: usize,
+    lowercase: bool,
+) -> Result<(Vec<u8>, usize), &'static str> {
+    let packet_len = packet.len();
+    let mut name = Vec::with_capacity(DNS_MAX_HOSTNAME_LEN);
+    let mut offset = offset;
+    let mut next_offset = None;
+    let mut pointers_count = 0;
+    loop {
+        if offset >= packet_len {
+            return Err("Short packet");
+        }
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            if 2 > packet_len - offset {
+                return Err("Incomplete offset");
+            }
+            pointers_count += 1;
+            if pointers_count > DNS_MAX_COMPRESSION_POINTERS {
+                return Err("Too many compression pointers");
+            }
+            if next_offset.is_none() {
+                next_offset = Some(offset + 2);
+            }
+            let target = ((label_len & 0x3f) << 8) | packet[offset + 1] as usize;
+            if target >= offset {
+                return Err("Compression pointer to a forward location");
+            }
+            offset = target;
+            continue;
+        }
+        if label_len > 0x3f {
+            return Err("Label too long");
+        }
+        if label_len >= packet_len - offset {
+            return Err("Malformed packet with an out-of-bounds name");
+        }
+        offset += 1;
+        if label_len == 0 {
+            break;
+        }
+        if name.len() + label_len + 1 > DNS_MAX_HOSTNAME_LEN {
+            return Err(ERR_NAME_TOO_LONG);
+        }
+        name.push(label_len as u8);
+        for &c in &packet[offset..offset + label_len] {
+            name.push(match c {
+                c @ 0x41...0x5a if lowercase => c | 0x20,
+                c => c,
+            });
+        }
+        offset += label_len;
+    }
+    Ok((name, next_offset.unwrap_or(offset)))
+}
+
+/// Checks if `qname` is equal to, or a subdomain of `zone`.
+/// Both names are expected to be lowercase and uncompressed.
+pub fn qname_is_in_zone(qname: &[u8], zone: &[u8]) -> bool {
+    let mut qname = qname;
+    loop {
+        if qname == zone {
+            return true;
+        }
+        match qname_shift(qname) {
+            None => return zone.is_empty(),
+            Some(qname_shifted) => qname = qname_shifted,
+        }
+    }
+}
+
+/// Removes out-of-bailiwick records from the authority and additional sections
+/// of a response, so that an upstream server cannot inject records for
+/// unrelated names.
+///
+/// The names in bailiwick are the question name, and the targets of the CNAME
+/// chain starting from it in the answer section.
+/// Authority records are accepted if one of these names belongs to their zone.
+/// Additional records are accepted if they belong to the zone of one of these
+/// names, or to one of the zones of the accepted authority records.
+/// The root zone is never considered a zone in bailiwick, since every name
+/// would belong to it.
+///
+/// Since names following a rejected record may be compressed using pointers to
+/// that record, everything after the first rejected record is removed, with
+/// the exception of the EDNS pseudo-record.
+///
+/// Returns the number of records that have been removed.
+pub fn strip_out_of_bailiwick(packet: &mut Vec<u8>) -> Result<u32, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let (qname, mut offset) = name_lc_uncompressed(packet, DNS_OFFSET_QUESTION)?;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let mut cnames = vec![];
+    for _ in 0..ancount(packet) {
+        let (owner, name_end) = name_lc_uncompressed(packet, offset)?;
+        offset = name_end;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        if rr_type == DNS_TYPE_CNAME {
+            cnames.push((owner, name_lc_uncompressed(packet, offset)?.0));
+        }
+        offset += rdlen;
+    }
+    let mut names = vec![qname];
+    loop {
+        let pos = match cnames
+            .iter()
+            .position(|&(ref owner, _)| names.contains(owner))
+        {
+            None => break,
+            Some(pos) => pos,
+        };
+        let target = cnames.swap_remove(pos).1;
+        if !names.contains(&target) {
+            names.push(target);
+        }
+    }
+    let nscount = nscount(packet) as u32;
+    let arcount = arcount(packet) as u32;
+    let mut zones = names.clone();
+    let mut cut: Option<(usize, u32)> = None;
+    let mut opt_rr = None;
+    for i in 0..(nscount + arcount) {
+        let rr_offset = offset;
+        let (owner, name_end) = name_lc_uncompressed(packet, offset)?;
+        offset = name_end;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        offset += rdlen;
+        if i >= nscount && rr_type == DNS_TYPE_OPT {
+            if cut.is_some() {
+                opt_rr = Some(packet[rr_offset..offset].to_vec());
+            }
+            continue;
+        }
+        if cut.is_some() {
+            continue;
+        }
+        if i < nscount {
+            if !owner.is_empty() && names.iter().any(|name| qname_is_in_zone(name, &owner)) {
+                zones.push(owner);
+                continue;
+            }
+        } else if zones
+            .iter()
+            .any(|zone| !zone.is_empty() && qname_is_in_zone(&owner, zone))
+        {
+            continue;
+        }
+        cut = Some((rr_offset, i));
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    let (cut_offset, cut_idx) = match cut {
+        None => return Ok(0),
+        Some(cut) => cut,
+    };
+    packet.truncate(cut_offset);
+    let (kept_nscount, mut kept_arcount) = if cut_idx < nscount {
+        (cut_idx, 0)
+    } else {
+        (nscount, cut_idx - nscount)
+    };
+    if let Some(opt_rr) = opt_rr {
+        packet.extend_from_slice(&opt_rr);
+        kept_arcount += 1;
+    }
+    set_nscount(packet, kept_nscount as u16);
+    set_arcount(packet, kept_arcount as u16);
+    Ok(nscount + arcount - kept_nscount - kept_arcount)
+}
+
+/// Only keeps the first `max_answers` records of the answer section.
+///
+/// Records following the removed ones may be compressed using pointers to
+/// them, so the authority and additional sections are removed as well, with
+/// the exception of the EDNS pseudo-record. The TC flag is set when records
+/// are removed, so that clients know that the response is incomplete.
+///
+/// Returns the number of answer records that have been removed.
+pub fn cap_answers(packet: &mut Vec<u8>, max_answers: u16) -> Result<u16, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let ancount = ancount(packet);
+    if ancount <= max_answers {
+        return Ok(0);
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let mut cut_offset = offset;
+    let mut opt_rr = None;
+    let rrcount = ancount as u32 + nscount(packet) as u32 + arcount(packet) as u32;
+    for i in 0..rrcount {
+        if i == max_answers as u32 {
+            cut_offset = offset;
+        }
+        let rr_offset = offset;
+        offset = skip_name(packet, offset)?.0;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        offset += rdlen;
+        if i >= ancount as u32 && rr_type == DNS_TYPE_OPT {
+            opt_rr = Some(packet[rr_offset..offset].to_vec());
+        }
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    packet.truncate(cut_offset);
+    set_ancount(packet, max_answers);
+    set_nscount(packet, 0);
+    set_arcount(packet, 0);
+    set_tc(packet, true);
+    if let Some(opt_rr) = opt_rr {
+        packet.extend_from_slice(&opt_rr);
+        set_arcount(packet, 1);
+    }
+    Ok(ancount - max_answers)
+}
+
+/// Turns a `NXDOMAIN` response without any answers into a `NODATA` response.
+/// The authority section, and the SOA record it should contain, are kept,
+/// so that the negative caching TTL doesn't change.
+///
+/// Returns `false` if the response was left untouched.
+pub fn nxdomain_to_nodata(packet: &mut [u8]) -> bool {
+    if packet.len() <= DNS_OFFSET_QUESTION || rcode(packet) != DNS_RCODE_NXDOMAIN ||
+        ancount(packet) != 0
+    {
+        return false;
+    }
+    set_rcode(packet, DNS_RCODE_NOERROR);
+    true
+}
+
+/// Removes the records of type `rr_type` from the additional section.
+///
+/// The following records may be compressed using pointers to the removed
+/// ones, so the packet is rebuilt the same way as with `compress_names()`.
+/// It is left untouched if there are no records to remove.
+///
+/// Returns the number of records that have been removed.
+pub fn strip_additional_records(packet: &mut Vec<u8>, rr_type: u16) -> Result<u16, &'static str> {
+    let stripped = rebuild_compressed(packet, None, Some(rr_type))?;
+    let removed = arcount(packet) - arcount(&stripped);
+    if removed > 0 {
+        *packet = stripped;
+    }
+    Ok(removed)
+}
+
+/// Appends `name`, as returned by `name_uncompressed()`, using a pointer to
+/// the longest suffix already present in the packet. The suffixes written
+/// are added to `suffixes`, a map of lowercase names to their offset.
+fn push_compressed_name(packet: &mut Vec<u8>, name: &[u8], suffixes: &mut HashMap<Vec<u8>, u16>) {
+    let name_len = name.len();
+    let mut offset = 0;
+    while offset < name_len {
+        let suffix = qname_lc(&name[offset..]);
+        if let Some(&target) = suffixes.get(&suffix) {
+            packet.push(0xc0 | (target >> 8) as u8);
+            packet.push(target as u8);
+            return;
+        }
+        let position = packet.len();
+        if position < 0x4000 {
+            suffixes.insert(suffix, position as u16);
+        }
+        let label_len = name[offset] as usize;
+        packet.extend_from_slice(&name[offset..offset + 1 + label_len]);
+        offset += 1 + label_len;
+    }
+    packet.push(0);
+}
+
+/// Rebuilds a response using name compression everywhere it is allowed:
+/// owner names, and names in the data of the record types defined in RFC
+/// 1035 (`NS`, `CNAME`, `PTR`, `MX` and `SOA`). The data of other records is
+/// copied as-is. The original case of names is preserved.
+///
+/// Responses built by upstream servers, as well as synthesized ones, may
+/// include names that are only partially compressed, or not at all.
+pub fn compress_names(packet: &[u8]) -> Result<Vec<u8>, &'static str> {
+    rebuild_compressed(packet, None, None)
+}
+
+/// Returns a copy of a response whose question is replaced with `qname`, in
+/// the format of `NormalizedQuestion.qname`, and `qtype`. `qname` can have a
+/// different length than the original name: the packet is rebuilt the same
+/// way as with `compress_names()`, so that compression pointers stay valid.
+pub fn replace_question(packet: &[u8], qname: &[u8], qtype: u16) -> Result<Vec<u8>, &'static str> {
+    rebuild_compressed(packet, Some((qname, qtype)), None)
+}
+
+/// Rebuilds a response, optionally replacing its question, and leaving out
+/// the additional records of type `stripped_additional_type`.
+fn rebuild_compressed(
+    packet: &[u8],
+    question: Option<(&[u8], u16)>,
+    stripped_additional_type: Option<u16>,
+) -> Result<Vec<u8>, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut suffixes = HashMap::new();
+    let mut compressed = packet[..DNS_OFFSET_QUESTION].to_vec();
+    let (qname, mut offset) = name_uncompressed(packet, DNS_OFFSET_QUESTION, false)?;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    match question {
+        None => {
+            push_compressed_name(&mut compressed, &qname, &mut suffixes);
+            compressed.extend_from_slice(&packet[offset..offset + 4]);
+        }
+        Some((qname, qtype)) => {
+            push_compressed_name(&mut compressed, qname, &mut suffixes);
+            compressed.push((qtype >> 8) as u8);
+            compressed.push(qtype as u8);
+            compressed.extend_from_slice(&packet[offset + 2..offset + 4]);
+        }
+    }
+    offset += 4;
+    let first_additional = ancount(packet) as u32 + nscount(packet) as u32;
+    let arcount = arcount(packet);
+    let mut stripped_count = 0;
+    for i in 0..(first_additional + arcount as u32) {
+        let (owner, name_end) = name_uncompressed(packet, offset, false)?;
+        offset = name_end;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        let rdata_offset = offset + 10;
+        if rdlen > packet_len - rdata_offset {
+            return Err("Record length would exceed packet length");
+        }
+        let rdata_end = rdata_offset + rdlen;
+        if i >= first_additional && stripped_additional_type == Some(rr_type) {
+            stripped_count += 1;
+            offset = rdata_end;
+            continue;
+        }
+        push_compressed_name(&mut compressed, &owner, &mut suffixes);
+        compressed.extend_from_slice(&packet[offset..offset + 8]);
+        let rdlen_offset = compressed.len();
+        compressed.extend_from_slice(&[0, 0]);
+        match rr_type {
+            DNS_TYPE_NS | DNS_TYPE_CNAME | DNS_TYPE_PTR => {
+                let (target, target_end) = name_uncompressed(packet, rdata_offset, false)?;
+                if target_end != rdata_end {
+                    return Err("Invalid record data");
+                }
+                push_compressed_name(&mut compressed, &target, &mut suffixes);
+            }
+            DNS_TYPE_MX => {
+                if 2 > rdlen {
+                    return Err("Invalid record data");
+                }
+                let (exchange, exchange_end) = name_uncompressed(packet, rdata_offset + 2, false)?;
+                if exchange_end != rdata_end {
+                    return Err("Invalid record data");
+                }
+                compressed.extend_from_slice(&packet[rdata_offset..rdata_offset + 2]);
+                push_compressed_name(&mut compressed, &exchange, &mut suffixes);
+            }
+            DNS_TYPE_SOA => {
+                let (mname, mname_end) = name_uncompressed(packet, rdata_offset, false)?;
+                let (rname, rname_end) = name_uncompressed(packet, mname_end, false)?;
+                if rname_end > rdata_end || 20 != rdata_end - rname_end {
+                    return Err("Invalid record data");
+                }
+                push_compressed_name(&mut compressed, &mname, &mut suffixes);
+                push_compressed_name(&mut compressed, &rname, &mut suffixes);
+                compressed.extend_from_slice(&packet[rname_end..rdata_end]);
+            }
+            _ => compressed.extend_from_slice(&packet[rdata_offset..rdata_end]),
+        }
+        let new_rdlen = compressed.len() - rdlen_offset - 2;
+        compressed[rdlen_offset] = (new_rdlen >> 8) as u8;
+        compressed[rdlen_offset + 1] = new_rdlen as u8;
+        offset = rdata_end;
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    set_arcount(&mut compressed, arcount - stripped_count);
+    Ok(compressed)
+}
+
+/// Returns `true` if the answer section contains records of type `rr_type`.
+pub fn has_answers(packet: &[u8], rr_type: u16) -> Result<bool, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    for _ in 0..ancount(packet) {
+        offset = skip_name(packet, offset)?.0;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let record_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        if record_type == rr_type {
+            return Ok(true);
+        }
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        offset += rdlen;
+    }
+    Ok(false)
+}
+
+/// Embeds an IPv4 address into a NAT64 prefix, as described in RFC 6052.
+/// Bits 64 to 71 of the address are reserved, and always set to zero.
+pub fn nat64_address(prefix: &Ipv6Addr, prefix_len: u8, ipv4: &[u8]) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    let mut i = prefix_len as usize / 8;
+    for x in &mut octets[i..] {
+        *x = 0;
+    }
+    for &x in ipv4 {
+        if i == 8 {
+            i += 1;
+        }
+        octets[i] = x;
+        i += 1;
+    }
+    Ipv6Addr::from(octets)
+}
+
+/// Builds a DNS64 response to a `AAAA` query (RFC 6147) from the response to
+/// the `A` query for the same name.
+///
+/// The addresses of the `A` records are embedded into the NAT64 prefix, and
+/// `CNAME` records are kept. Names are decompressed, since records change
+/// size. The other sections are removed, with the exception of the EDNS
+/// pseudo-record.
+///
+/// Returns `None` if the response doesn't have any `A` records.
+pub fn build_dns64_packet(
+    packet: &[u8],
+    prefix: &Ipv6Addr,
+    prefix_len: u8,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let mut dns64_packet = packet[..offset].to_vec();
+    dns64_packet[offset - 4] = (DNS_TYPE_AAAA >> 8) as u8;
+    dns64_packet[offset - 3] = DNS_TYPE_AAAA as u8;
+    let ancount = ancount(packet);
+    let rrcount = ancount as u32 + nscount(packet) as u32 + arcount(packet) as u32;
+    let mut dns64_ancount = 0;
+    let mut addresses_count = 0;
+    let mut opt_rr = None;
+    for i in 0..rrcount {
+        let rr_offset = offset;
+        let (name, name_end) = name_lc_uncompressed(packet, offset)?;
+        offset = name_end;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        let rdata_offset = offset + 10;
+        if rdlen > packet_len - rdata_offset {
+            return Err("Record length would exceed packet length");
+        }
+        if i >= ancount as u32 {
+            if rr_type == DNS_TYPE_OPT {
+                opt_rr = Some(packet[rr_offset..rdata_offset + rdlen].to_vec());
+            }
+        } else if rr_type == DNS_TYPE_A && rdlen == 4 {
+            let address =
+                nat64_address(prefix, prefix_len, &packet[rdata_offset..rdata_offset + 4]);
+            push_name(&mut dns64_packet, &name);
+            dns64_packet.push((DNS_TYPE_AAAA >> 8) as u8);
+            dns64_packet.push(DNS_TYPE_AAAA as u8);
+            dns64_packet.extend_from_slice(&packet[offset + 2..offset + 8]);
+            dns64_packet.push(0);
+            dns64_packet.push(16);
+            dns64_packet.extend_from_slice(&address.octets());
+            dns64_ancount += 1;
+            addresses_count += 1;
+        } else if rr_type == DNS_TYPE_CNAME {
+            let (target, _) = name_lc_uncompressed(packet, rdata_offset)?;
+            let target_len = target.len() + 1;
+            push_name(&mut dns64_packet, &name);
+            dns64_packet.extend_from_slice(&packet[offset..offset + 8]);
+            dns64_packet.push((target_len >> 8) as u8);
+            dns64_packet.push(target_len as u8);
+            push_name(&mut dns64_packet, &target);
+            dns64_ancount += 1;
+        }
+        offset = rdata_offset + rdlen;
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    if addresses_count == 0 {
+        return Ok(None);
+    }
+    set_ancount(&mut dns64_packet, dns64_ancount);
+    set_nscount(&mut dns64_packet, 0);
+    set_arcount(&mut dns64_packet, 0);
+    if let Some(opt_rr) = opt_rr {
+        dns64_packet.extend_from_slice(&opt_rr);
+        set_arcount(&mut dns64_packet, 1);
+    }
+    Ok(Some(dns64_packet))
+}
+
+/// Appends an uncompressed name, as returned by `name_lc_uncompressed()`.
+fn push_name(records: &mut Vec<u8>, name: &[u8]) {
+    records.extend_from_slice(name);
+    records.push(0);
+}
+
+/// Extracts the delegation from a referral response: a non-authoritative
+/// `NOERROR` response with an empty answer section, and only NS records for
+/// a parent of the question name in the authority section.
+///
+/// Names are decompressed, so that the delegation can be used to answer
+/// queries for other names of the same zone. Only `A` and `AAAA` records are
+/// kept from the additional section.
+pub fn referral(packet: &[u8]) -> Result<Option<Referral>, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    if !qr(packet) || aa(packet) || rcode(packet) != DNS_RCODE_NOERROR || ancount(packet) != 0 ||
+        nscount(packet) == 0
+    {
+        return Ok(None);
+    }
+    let (qname, mut offset) = name_lc_uncompressed(packet, DNS_OFFSET_QUESTION)?;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let nscount = nscount(packet);
+    let mut zone: Option<Vec<u8>> = None;
+    let mut records = Vec::with_capacity(packet_len);
+    let mut arcount = 0;
+    for i in 0..(nscount as u32 + self::arcount(packet) as u32) {
+        let (owner, name_end) = name_lc_uncompressed(packet, offset)?;
+        offset = name_end;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        let rr_header = &packet[offset..offset + 8];
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        let rdata_offset = offset;
+        offset += rdlen;
+        if i < nscount as u32 {
+            if rr_type != DNS_TYPE_NS || owner.is_empty() || !qname_is_in_zone(&qname, &owner) {
+                return Ok(None);
+            }
+            if zone.as_ref().map_or(false, |zone| *zone != owner) {
+                return Ok(None);
+            }
+            let (target, _) = name_lc_uncompressed(packet, rdata_offset)?;
+            push_name(&mut records, &owner);
+            records.extend_from_slice(rr_header);
+            records.push(((target.len() + 1) >> 8) as u8);
+            records.push((target.len() + 1) as u8);
+            push_name(&mut records, &target);
+            zone = Some(owner);
+        } else if rr_type == DNS_TYPE_A || rr_type == DNS_TYPE_AAAA {
+            push_name(&mut records, &owner);
+            records.extend_from_slice(&packet[rdata_offset - 10..offset]);
+            arcount += 1;
+        }
+    }
+    if offset != packet_len {
+        return Err("Garbage after packet");
+    }
+    Ok(zone.map(|zone| {
+        Referral {
+            zone,
+            records,
+            nscount,
+            arcount,
+        }
+    }))
+}
+
+/// Builds a referral response to a question, from a delegation previously
+/// extracted from another response.
+pub fn build_referral_packet(
+    normalized_question: &NormalizedQuestion,
+    referral: &Referral,
+) -> Result<Vec<u8>, &'static str> {
+    if !qname_is_in_zone(&qname_lc(&normalized_question.qname), &referral.zone) {
+        return Err("Name is not part of the delegated zone");
+    }
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1 + 4 +
+        referral.records.len();
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_rcode(&mut packet, DNS_RCODE_NOERROR);
+    set_tid(&mut packet, normalized_question.tid);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    set_nscount(&mut packet, referral.nscount);
+    set_arcount(&mut packet, referral.arcount);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+
+    packet.extend_from_slice(&referral.records);
+    Ok(packet)
+}
+
+/// Returns the offset of the data of the OPT record of a response, and its
+/// length, if there is one.
+fn find_opt_rdata(packet: &[u8]) -> Result<Option<(usize, usize)>, &'static str> {
+    let packet_len = packet.len();
+    if packet_len <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?.0;
+    if 4 > packet_len - offset {
+        return Err("Short packet");
+    }
+    offset += 4;
+    let ancount_nscount = ancount(packet) as u32 + nscount(packet) as u32;
+    let rrcount = ancount_nscount + arcount(packet) as u32;
+    let mut opt_rdata = None;
+    for i in 0..rrcount {
+        offset = skip_name(packet, offset)?.0;
+        if 10 > packet_len - offset {
+            return Err("Short packet");
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return Err("Record length would exceed packet length");
+        }
+        if i >= ancount_nscount && rr_type == DNS_TYPE_OPT {
+            opt_rdata = Some((offset, rdlen));
+        }
+        offset += rdlen;
+    }
+    Ok(opt_rdata)
+}
+
+/// Returns the rcode of a response, including the upper bits stored in its
+/// OPT record.
+pub fn extended_rcode(packet: &[u8]) -> Result<u16, &'static str> {
+    let opt_rdata = find_opt_rdata(packet)?;
+    let rcode = rcode(packet) as u16;
+    match opt_rdata {
+        None => Ok(rcode),
+        Some((rdata_offset, _)) => Ok((packet[rdata_offset - 6] as u16) << 4 | rcode),
+    }
+}
+
+/// Returns the data of the first option of the OPT record of a response whose
+/// code is `code`.
+pub fn edns_option(packet: &[u8], code: u16) -> Result<Option<Vec<u8>>, &'static str> {
+    let (rdata_offset, rdlen) = match find_opt_rdata(packet)? {
+        None => return Ok(None),
+        Some(opt_rdata) => opt_rdata,
+    };
+    let (options, _) = edns_options_retain(
+        &packet[rdata_offset..rdata_offset + rdlen],
+        |option_code| option_code == code,
+    )?;
+    if options.is_empty() {
+        return Ok(None);
+    }
+    let len = ((options[2] as u16) << 8 | options[3] as u16) as usize;
+    Ok(Some(options[4..4 + len].to_vec()))
+}
+
+/// Returns `true` if `options`, the data of an OPT record, include an option
+/// whose code is `code`.
+pub fn has_edns_option(options: &[u8], code: u16) -> bool {
+    edns_options_retain(options, |option_code| option_code == code)
+        .map(|(kept, _)| !kept.is_empty())
+        .unwrap_or(false)
+}
+
+/// Returns a copy of a response, with an additional option in its OPT record.
+pub fn add_edns_option(packet: &[u8], code: u16, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let (rdata_offset, rdlen) = match find_opt_rdata(packet)? {
+        None => return Err("No OPT record"),
+        Some(opt_rdata) => opt_rdata,
+    };
+    let new_rdlen = rdlen + 4 + data.len();
+    if new_rdlen > 0xffff {
+        return Err("EDNS options too long");
+    }
+    let options_end = rdata_offset + rdlen;
+    let mut extended = Vec::with_capacity(packet.len() + 4 + data.len());
+    extended.extend_from_slice(&packet[..options_end]);
+    extended.push((code >> 8) as u8);
+    extended.push(code as u8);
+    extended.push((data.len() >> 8) as u8);
+    extended.push(data.len() as u8);
+    extended.extend_from_slice(data);
+    extended.extend_from_slice(&packet[options_end..]);
+    extended[rdata_offset - 2] = (new_rdlen >> 8) as u8;
+    extended[rdata_offset - 1] = new_rdlen as u8;
+    Ok(extended)
+}
+
+/// Removes the options of the OPT record of a response whose code is not in
+/// `allowlist`. Client Subnet options are always kept. NSID options are always
+/// removed: they identify the upstream server, and the local NSID is added
+/// when responding instead.
+///
+/// Returns the number of options that were removed.
+pub fn strip_edns_options(packet: &mut Vec<u8>, allowlist: &[u16]) -> Result<usize, &'static str> {
+    let (rdata_offset, rdlen) = match find_opt_rdata(packet)? {
+        None => return Ok(0),
+        Some(opt_rdata) => opt_rdata,
+    };
+    let (options, removed) = edns_options_retain(
+        &packet[rdata_offset..rdata_offset + rdlen],
+        |code| {
+            code == DNS_EDNS_OPTION_ECS ||
+                (code != DNS_EDNS_OPTION_NSID && allowlist.contains(&code))
+        },
+    )?;
+    if removed == 0 {
+        return Ok(0);
+    }
+    let tail = packet.split_off(rdata_offset + rdlen);
+    packet.truncate(rdata_offset);
+    packet.extend_from_slice(&options);
+    packet.extend_from_slice(&tail);
+    packet[rdata_offset - 2] = (options.len() >> 8) as u8;
+    packet[rdata_offset - 1] = options.len() as u8;
+    Ok(removed)
+}
+
+/// Returns a copy of a response, with an additional `CH TXT` record
+/// containing `txt`.
+pub fn add_txt_annotation(packet: &[u8], txt: &str) -> Result<Vec<u8>, &'static str> {
+    if packet.len() <= DNS_OFFSET_QUESTION {
+        return Err("Short packet");
+    }
+    if qdcount(packet) != 1 {
+        return Err("Unsupported number of questions");
+    }
+    let txt = txt.as_bytes();
+    if txt.len() > 0xff {
+        return Err("Text too long");
+    }
+    let arcount = arcount(packet);
+    if arcount == 0xffff {
+        return Err("Too many records");
+    }
+    let rdata_len = 1 + txt.len();
+    let mut annotated = Vec::with_capacity(packet.len() + 12 + rdata_len);
+    annotated.extend_from_slice(packet);
+    set_arcount(&mut annotated, arcount + 1);
+
+    annotated.push(0xc0 + (DNS_HEADER_SIZE >> 8) as u8);
+    annotated.push(DNS_HEADER_SIZE as u8);
+
+    annotated.push((DNS_TYPE_TXT >> 8) as u8);
+    annotated.push(DNS_TYPE_TXT as u8);
+    annotated.push((DNS_CLASS_CH >> 8) as u8);
+    annotated.push(DNS_CLASS_CH as u8);
+
+    annotated.extend_from_slice(&[0u8; 4]); // TTL
+
+    annotated.push((rdata_len >> 8) as u8);
+    annotated.push(rdata_len as u8);
+
+    annotated.push(txt.len() as u8);
+    annotated.extend_from_slice(txt);
+
+    Ok(annotated)
+}
+
+pub fn build_tc_packet(normalized_question: &NormalizedQuestion) -> Result<Vec<u8>, &'static str> {
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_tid(&mut packet, normalized_question.tid);
+    set_aa(&mut packet, true);
+    set_qr(&mut packet, true);
+    set_tc(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+    Ok(packet)
+}
+
+/// Builds a header-only `FORMERR` response to a query that couldn't be parsed.
+///
+/// Packets that are too short to contain a header, or that are responses
+/// themselves, never get a response, so that the server cannot be used to
+/// reflect crafted packets.
+pub fn build_formerr_packet(query_packet: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if query_packet.len() < DNS_HEADER_SIZE {
+        return Err("Short packet");
+    }
+    if qr(query_packet) {
+        return Err("Not responding to a response");
+    }
+    let mut packet = vec![0u8; DNS_HEADER_SIZE];
+    set_tid(&mut packet, tid(query_packet));
+    set_opcode(&mut packet, opcode(query_packet));
+    set_rcode(&mut packet, DNS_RCODE_FORMERR);
+    set_qr(&mut packet, true);
+    Ok(packet)
+}
+
+pub fn build_servfail_packet(
+    normalized_question: &NormalizedQuestion,
+) -> Result<Vec<u8>, &'static str> {
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_rcode(&mut packet, DNS_RCODE_SERVFAIL);
+    set_tid(&mut packet, normalized_question.tid);
+    set_aa(&mut packet, true);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+    Ok(packet)
+}
+
+pub fn build_refused_packet(
+    normalized_question: &NormalizedQuestion,
+) -> Result<Vec<u8>, &'static str> {
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_rcode(&mut packet, DNS_RCODE_REFUSED);
+    set_tid(&mut packet, normalized_question.tid);
+    set_aa(&mut packet, true);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+    Ok(packet)
+}
+
+/// Builds a `BADVERS` response, advertising the only EDNS version we support.
+pub fn build_badvers_packet(
+    normalized_question: &NormalizedQuestion,
+) -> Result<Vec<u8>, &'static str> {
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1 + 15;
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_rcode(&mut packet, (DNS_RCODE_BADVERS & 0xf) as u8);
+    set_tid(&mut packet, normalized_question.tid);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    set_arcount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+
+    packet.push(0); // EDNS name
+    packet.push((DNS_TYPE_OPT >> 8) as u8);
+    packet.push(DNS_TYPE_OPT as u8);
+    packet.push((DNS_MAX_PACKET_SIZE >> 8) as u8);
+    packet.push(DNS_MAX_PACKET_SIZE as u8);
+    packet.push((DNS_RCODE_BADVERS >> 4) as u8); // EDNS extended rcode
+    packet.extend_from_slice(&[0u8; 5]); // EDNS version + flags + rdlen
+    Ok(packet)
+}
+
+pub fn build_nxdomain_packet(
+    normalized_question: &NormalizedQuestion,
+) -> Result<Vec<u8>, &'static str> {
     let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1;
     let mut packet = Vec::with_capacity(capacity);
     packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
@@ -676,6 +2035,61 @@ pub fn build_nxdomain_packet(
     Ok(packet)
 }
 
+/// Builds a NXDOMAIN response with a SOA record in the authority section,
+/// so that clients can derive a negative caching TTL from it (RFC 2308).
+///
+/// `mname` and `rname` are encoded names. The record is owned by the root
+/// zone, its `minimum` field is set to `minimum`, and its TTL to `ttl`.
+pub fn build_nxdomain_packet_with_soa(
+    normalized_question: &NormalizedQuestion,
+    mname: &[u8],
+    rname: &[u8],
+    minimum: u32,
+    ttl: u32,
+) -> Result<Vec<u8>, &'static str> {
+    let mut packet = build_nxdomain_packet(normalized_question)?;
+    let rdata_len = mname.len() + rname.len() + 5 * 4;
+    if rdata_len > 0xffff {
+        return Err("SOA record too large");
+    }
+    set_nscount(&mut packet, 1);
+    packet.push(0);
+    packet.push((DNS_TYPE_SOA >> 8) as u8);
+    packet.push(DNS_TYPE_SOA as u8);
+    packet.push((DNS_CLASS_IN >> 8) as u8);
+    packet.push(DNS_CLASS_IN as u8);
+    packet.push((ttl >> 24) as u8);
+    packet.push((ttl >> 16) as u8);
+    packet.push((ttl >> 8) as u8);
+    packet.push(ttl as u8);
+    packet.push((rdata_len >> 8) as u8);
+    packet.push(rdata_len as u8);
+    packet.extend_from_slice(mname);
+    packet.extend_from_slice(rname);
+    for value in &[1, 3600, 600, 86400, minimum] {
+        packet.push((value >> 24) as u8);
+        packet.push((value >> 16) as u8);
+        packet.push((value >> 8) as u8);
+        packet.push(*value as u8);
+    }
+    Ok(packet)
+}
+
+/// Builds a NODATA response with a SOA record in the authority section, the
+/// same way as `build_nxdomain_packet_with_soa()`.
+pub fn build_nodata_packet_with_soa(
+    normalized_question: &NormalizedQuestion,
+    mname: &[u8],
+    rname: &[u8],
+    minimum: u32,
+    ttl: u32,
+) -> Result<Vec<u8>, &'static str> {
+    let mut packet =
+        build_nxdomain_packet_with_soa(normalized_question, mname, rname, minimum, ttl)?;
+    set_rcode(&mut packet, DNS_RCODE_NOERROR);
+    Ok(packet)
+}
+
 pub fn build_any_packet(
     normalized_question: &NormalizedQuestion,
     ttl: u32,
@@ -724,6 +2138,103 @@ pub fn build_any_packet(
     Ok(packet)
 }
 
+/// Builds a response pointing `A` and `AAAA` queries to fixed addresses.
+/// Queries for other types get an empty response.
+pub fn build_sinkhole_packet(
+    normalized_question: &NormalizedQuestion,
+    ipv4: &Ipv4Addr,
+    ipv6: &Ipv6Addr,
+    ttl: u32,
+) -> Result<Vec<u8>, &'static str> {
+    let rdata = match normalized_question.qtype {
+        DNS_TYPE_A => Some(ipv4.octets().to_vec()),
+        DNS_TYPE_AAAA => Some(ipv6.octets().to_vec()),
+        _ => None,
+    };
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1 + 4 + 12 + 16;
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_tid(&mut packet, normalized_question.tid);
+    set_aa(&mut packet, true);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+
+    let rdata = match rdata {
+        None => return Ok(packet),
+        Some(rdata) => rdata,
+    };
+    set_ancount(&mut packet, 1);
+
+    packet.push(0xc0 + (DNS_HEADER_SIZE >> 8) as u8);
+    packet.push(DNS_HEADER_SIZE as u8);
+
+    packet.push((normalized_question.qtype >> 8) as u8);
+    packet.push(normalized_question.qtype as u8);
+    packet.push((normalized_question.qclass >> 8) as u8);
+    packet.push(normalized_question.qclass as u8);
+
+    packet.push((ttl >> 24) as u8);
+    packet.push((ttl >> 16) as u8);
+    packet.push((ttl >> 8) as u8);
+    packet.push(ttl as u8);
+
+    packet.push((rdata.len() >> 8) as u8);
+    packet.push(rdata.len() as u8);
+    packet.extend_from_slice(&rdata);
+
+    Ok(packet)
+}
+
+/// Builds a response with a single `PTR` record for the question name.
+/// `target` is an uncompressed name, including the root label.
+pub fn build_ptr_packet(
+    normalized_question: &NormalizedQuestion,
+    target: &[u8],
+    ttl: u32,
+) -> Result<Vec<u8>, &'static str> {
+    let capacity = DNS_HEADER_SIZE + normalized_question.qname.len() + 1 + 4 + 12 + target.len();
+    let mut packet = Vec::with_capacity(capacity);
+    packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
+    set_tid(&mut packet, normalized_question.tid);
+    set_aa(&mut packet, true);
+    set_qr(&mut packet, true);
+    set_qdcount(&mut packet, 1);
+    set_ancount(&mut packet, 1);
+    packet.extend_from_slice(&normalized_question.qname);
+    packet.push(0);
+
+    packet.push((DNS_TYPE_PTR >> 8) as u8);
+    packet.push(DNS_TYPE_PTR as u8);
+    packet.push((DNS_CLASS_IN >> 8) as u8);
+    packet.push(DNS_CLASS_IN as u8);
+
+    packet.push(0xc0 + (DNS_HEADER_SIZE >> 8) as u8);
+    packet.push(DNS_HEADER_SIZE as u8);
+
+    packet.push((DNS_TYPE_PTR >> 8) as u8);
+    packet.push(DNS_TYPE_PTR as u8);
+    packet.push((DNS_CLASS_IN >> 8) as u8);
+    packet.push(DNS_CLASS_IN as u8);
+
+    packet.push((ttl >> 24) as u8);
+    packet.push((ttl >> 16) as u8);
+    packet.push((ttl >> 8) as u8);
+    packet.push(ttl as u8);
+
+    packet.push((target.len() >> 8) as u8);
+    packet.push(target.len() as u8);
+    packet.extend_from_slice(target);
+
+    Ok(packet)
+}
+
 pub fn build_version_packet(
     normalized_question: &NormalizedQuestion,
     ttl: u32,
@@ -771,6 +2282,11 @@ pub fn build_version_packet(
 }
 
 pub fn build_probe_packet(qname: &[u8]) -> Result<Vec<u8>, &'static str> {
+    build_question_packet(qname, DNS_TYPE_SOA)
+}
+
+/// Builds a query with a single question in the `IN` class, and no EDNS section.
+pub fn build_question_packet(qname: &[u8], qtype: u16) -> Result<Vec<u8>, &'static str> {
     let capacity = DNS_HEADER_SIZE + qname.len() + 1;
     let mut packet = Vec::with_capacity(capacity);
     packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
@@ -778,7 +2294,6 @@ pub fn build_probe_packet(qname: &[u8]) -> Result<Vec<u8>, &'static str> {
     set_rd(&mut packet, true);
     set_qdcount(&mut packet, 1);
     packet.extend_from_slice(qname);
-    let qtype = DNS_TYPE_SOA;
     let qclass = DNS_CLASS_IN;
     packet.push((qtype >> 8) as u8);
     packet.push(qtype as u8);
@@ -787,9 +2302,15 @@ pub fn build_probe_packet(qname: &[u8]) -> Result<Vec<u8>, &'static str> {
     Ok(packet)
 }
 
+/// Builds a query to be sent to an upstream server. `ecs` is the data of the
+/// Client Subnet option to include, if any.
+///
+/// The transaction ID is unpredictable: `random()` uses the thread-local CSPRNG.
 pub fn build_query_packet(
     normalized_question: &NormalizedQuestion,
     force_dnssec: bool,
+    ecs: Option<&[u8]>,
+    options: &[u8],
 ) -> Result<(Vec<u8>, NormalizedQuestionMinimal), &'static str> {
     let mut qname = qname_lc(&normalized_question.qname);
     let qname_len = qname.len();
@@ -799,12 +2320,18 @@ pub fn build_query_packet(
             qname[qname_len - 1] &= !0x20;
         }
     }
-    let capacity = DNS_HEADER_SIZE + qname_len + 1 + 15;
+    let ecs_option_len = ecs.map_or(0, |ecs| 4 + ecs.len());
+    let rdlen = ecs_option_len + options.len();
+    if rdlen > 0xffff {
+        return Err("EDNS options too long");
+    }
+    let capacity = DNS_HEADER_SIZE + qname_len + 1 + 15 + rdlen;
     let mut packet = Vec::with_capacity(capacity);
     let tid: u16 = random();
     packet.extend_from_slice(&[0u8; DNS_HEADER_SIZE]);
     set_tid(&mut packet, tid);
     set_rd(&mut packet, true);
+    set_cd(&mut packet, normalized_question.flags & DNS_FLAG_CD != 0);
     set_qdcount(&mut packet, 1);
     set_arcount(&mut packet, 1);
     packet.extend_from_slice(&qname);
@@ -821,12 +2348,22 @@ pub fn build_query_packet(
     packet.push((DNS_MAX_PACKET_SIZE >> 8) as u8);
     packet.push(DNS_MAX_PACKET_SIZE as u8);
 
-    let edns_rcode_rdlen = if force_dnssec || normalized_question.dnssec {
+    let mut edns_rcode_rdlen = if force_dnssec || normalized_question.dnssec {
         [0u8, 0u8, 0x80u8, 0u8, 0u8, 0u8]
     } else {
         [0u8; 6]
     };
+    edns_rcode_rdlen[4] = (rdlen >> 8) as u8;
+    edns_rcode_rdlen[5] = rdlen as u8;
     packet.extend_from_slice(&edns_rcode_rdlen); // EDNS rcode + rdlen
+    if let Some(ecs) = ecs {
+        packet.push((DNS_EDNS_OPTION_ECS >> 8) as u8);
+        packet.push(DNS_EDNS_OPTION_ECS as u8);
+        packet.push((ecs.len() >> 8) as u8);
+        packet.push(ecs.len() as u8);
+        packet.extend_from_slice(ecs);
+    }
+    packet.extend_from_slice(options);
 
     let normalized_question_minimal = NormalizedQuestionMinimal {
         qname: qname,
@@ -0,0 +1,115 @@
+//! Suppression of duplicate queries received from clients over UDP.
+//!
+//! Some stub resolvers send the exact same query several times within a few
+//! milliseconds. Each copy would get its own response. When enabled, a query
+//! with the same client address, port, transaction ID and question as a query
+//! received less than `duplicate_query_window_ms` milliseconds earlier is
+//! dropped, so that the client only gets a single response.
+//!
+//! Only queries that are still pending are considered: once a response has
+//! been sent, the same query is a retransmission from a client that may not
+//! have received it, and is answered again.
+//!
+//! Recent queries are kept in a CLOCK-Pro cache, so that the memory usage
+//! remains bounded regardless of the number of clients.
+
+use clockpro_cache::ClockProCache;
+use coarsetime::Instant;
+use config::Config;
+use dns::{NormalizedQuestion, NormalizedQuestionKey};
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+struct DuplicateQueryKey {
+    client_addr: SocketAddr,
+    tid: u16,
+    question_key: NormalizedQuestionKey,
+}
+
+struct RecentQuery {
+    ts: Instant,
+    pending: bool,
+}
+
+/// Keeps a query pending until a response has been sent, or the client query
+/// it was attached to, and all its copies, have been dropped.
+pub struct TrackedQuery {
+    recent_mx: Arc<Mutex<ClockProCache<DuplicateQueryKey, RecentQuery>>>,
+    key: DuplicateQueryKey,
+}
+
+impl TrackedQuery {
+    pub fn answered(&self) {
+        if let Some(recent_query) = self.recent_mx.lock().get_mut(&self.key) {
+            recent_query.pending = false;
+        }
+    }
+}
+
+impl Drop for TrackedQuery {
+    fn drop(&mut self) {
+        self.answered();
+    }
+}
+
+#[derive(Clone)]
+pub struct DuplicateQueries {
+    recent_mx: Arc<Mutex<ClockProCache<DuplicateQueryKey, RecentQuery>>>,
+    window_ms: u64,
+}
+
+impl DuplicateQueries {
+    /// Returns `None` if duplicate queries are not suppressed.
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.duplicate_query_window_ms == 0 {
+            return None;
+        }
+        let recent = ClockProCache::new(config.duplicate_query_max_entries)
+            .expect("Unable to create the duplicate queries cache");
+        Some(DuplicateQueries {
+            recent_mx: Arc::new(Mutex::new(recent)),
+            window_ms: config.duplicate_query_window_ms,
+        })
+    }
+
+    /// Returns `None` if the same query from the same client is still pending,
+    /// and was received within the window. Otherwise, the query is tracked as
+    /// pending until the returned value is dropped.
+    pub fn track(
+        &self,
+        client_addr: SocketAddr,
+        normalized_question: &NormalizedQuestion,
+    ) -> Option<Arc<TrackedQuery>> {
+        let key = DuplicateQueryKey {
+            client_addr: client_addr,
+            tid: normalized_question.tid,
+            question_key: normalized_question.key(),
+        };
+        let mut recent = self.recent_mx.lock();
+        let found = match recent.get_mut(&key) {
+            None => false,
+            Some(recent_query) => {
+                let elapsed_ms = (recent_query.ts.elapsed().as_f64() * 1000.0) as u64;
+                if recent_query.pending && elapsed_ms < self.window_ms {
+                    return None;
+                }
+                recent_query.ts = Instant::now();
+                recent_query.pending = true;
+                true
+            }
+        };
+        if !found {
+            let recent_query = RecentQuery {
+                ts: Instant::now(),
+                pending: true,
+            };
+            recent.insert(key.clone(), recent_query);
+        }
+        Some(Arc::new(TrackedQuery {
+            recent_mx: self.recent_mx.clone(),
+            key: key,
+        }))
+    }
+}
@@ -12,20 +12,39 @@
 //! DNSSEC information is a response to a query with the `DO` bit, but the zone is
 //! not signed, or a response to a question sent without the `DO` bit. We encode
 //! the `DO` bit in the case of the query name in order to lift this ambiguity.
+//!
+//! When DNS cookies are enabled, responses must echo the client cookie sent to
+//! the server. Once a server has sent a server cookie, its responses without
+//! a cookie are dropped as well. A BADCOOKIE response carrying a new server
+//! cookie makes the query be sent again right away, with that cookie.
+//!
+//! On Linux, ICMP errors are also read from the sockets. A port unreachable
+//! error immediately fails the pending queries sent to that server, instead
+//! of waiting for them to time out.
 
 use cache::Cache;
-use client_query::ClientQuery;
+use client_query::{AnswerSource, ClientQuery};
 use config::Config;
-use dns::{min_ttl, normalize, rcode, set_ttl, tid, NormalizedQuestionKey, DNS_RCODE_SERVFAIL};
+use dns::{self, cap_answers, edns_option, extended_rcode, min_ttl, negative_ttl, normalize,
+          nxdomain_to_nodata, rcode, referral, set_tid, set_ttl, sort_addresses,
+          strip_edns_options, strip_out_of_bailiwick, tc, tid, NormalizedQuestion,
+          NormalizedQuestionKey, DNS_EDNS_OPTION_COOKIE, DNS_RCODE_BADCOOKIE, DNS_RCODE_NOERROR,
+          DNS_RCODE_NXDOMAIN, DNS_RCODE_REFUSED, DNS_RCODE_SERVFAIL};
 use futures::Future;
 use futures::Stream;
 use futures::future;
+use futures::sync::oneshot;
+use libc;
 use log_dnstap;
+use net_helpers::socket_udp_recv_error;
 use parking_lot::RwLock;
-use pending_query::{PendingQueries, PendingQuery};
-use resolver::ResolverCore;
+use pending_query::{PendingQueries, PendingQuery, PendingQueryKey};
+use resolver::{ExtUdpSockets, ResolverCore};
+use std::cmp;
 use std::io;
+use std::mem;
 use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
@@ -36,32 +55,53 @@ use udp_stream::*;
 use upstream_server::UpstreamServer;
 use varz::Varz;
 
+/// A response is lame if it doesn't answer the question: SERVFAIL, REFUSED,
+/// or a referral from a server that was asked to recurse.
+fn is_lame_response(packet: &[u8], recursion_desired: bool) -> bool {
+    match rcode(packet) {
+        DNS_RCODE_SERVFAIL | DNS_RCODE_REFUSED => true,
+        DNS_RCODE_NOERROR if recursion_desired => match referral(packet) {
+            Ok(Some(_)) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 pub struct ExtResponse {
     config: Rc<Config>,
+    handle: Handle,
+    net_ext_udp_sockets_rc: Rc<ExtUdpSockets>,
     dnstap_sender: Option<log_dnstap::Sender>,
     pending_queries: PendingQueries,
     waiting_clients_count: Rc<AtomicUsize>,
     upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
+    upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
     cache: Cache,
     varz: Arc<Varz>,
     decrement_ttl: bool,
     local_port: u16,
     net_udp_socket: net::UdpSocket,
+    net_ext_udp_socket: net::UdpSocket,
 }
 
 impl ExtResponse {
-    pub fn new(resolver_core: &ResolverCore, local_port: u16) -> Self {
+    pub fn new(resolver_core: &ResolverCore, net_ext_udp_socket: &net::UdpSocket) -> Self {
         ExtResponse {
             config: resolver_core.config.clone(),
+            handle: resolver_core.handle.clone(),
+            net_ext_udp_sockets_rc: resolver_core.net_ext_udp_sockets_rc.clone(),
             dnstap_sender: resolver_core.dnstap_sender.clone(),
             pending_queries: resolver_core.pending_queries.clone(),
             waiting_clients_count: resolver_core.waiting_clients_count.clone(),
             upstream_servers_arc: resolver_core.upstream_servers_arc.clone(),
+            upstream_servers_live_arc: resolver_core.upstream_servers_live_arc.clone(),
             cache: resolver_core.cache.clone(),
             varz: resolver_core.varz.clone(),
             decrement_ttl: resolver_core.decrement_ttl,
-            local_port: local_port,
+            local_port: net_ext_udp_socket.local_addr().unwrap().port(),
             net_udp_socket: resolver_core.net_udp_socket.try_clone().unwrap(),
+            net_ext_udp_socket: net_ext_udp_socket.try_clone().unwrap(),
         }
     }
 
@@ -70,25 +110,30 @@ impl ExtResponse {
         handle: &Handle,
         net_ext_udp_socket: &net::UdpSocket,
     ) -> impl Future<Item = (), Error = io::Error> + 'a {
+        let socket_fd = net_ext_udp_socket.as_raw_fd();
         let fut_ext_socket = UdpStream::from_net_udp_socket(
             net_ext_udp_socket
                 .try_clone()
                 .expect("Cannot clone a UDP socket"),
             handle,
         ).expect("Cannot create a UDP stream")
-            .for_each(move |(packet, client_addr)| {
-                self.fut_process_ext_socket(packet, client_addr)
+            .then(|res| Ok::<_, io::Error>(res))
+            .for_each(move |res| match res {
+                Ok((packet, client_addr)) => self.fut_process_ext_socket(packet, client_addr),
+                Err(e) => self.fut_process_ext_socket_error(e, socket_fd),
             })
             .map_err(|_| io::Error::last_os_error());
         fut_ext_socket
     }
 
+    /// Returns `true` if the response contains a new server cookie.
     fn verify_ext_response(
         &self,
         pending_query: &PendingQuery,
         packet: &[u8],
         client_addr: SocketAddr,
-    ) -> Result<(), String> {
+        cookie: Option<&[u8]>,
+    ) -> Result<bool, String> {
         debug_assert!(packet.len() >= DNS_QUERY_MIN_SIZE);
         if self.local_port != pending_query.local_port {
             return Err(format!(
@@ -105,17 +150,50 @@ impl ExtResponse {
             ));
         }
         let mut upstream_servers = self.upstream_servers_arc.write();
+        let mut server_cookie_changed = false;
+        if self.config.upstream_cookies {
+            if let Some(upstream_server) = upstream_servers
+                .iter_mut()
+                .find(|upstream_server| upstream_server.socket_addr == client_addr)
+            {
+                match cookie {
+                    Some(cookie) => {
+                        server_cookie_changed = upstream_server
+                            .learn_server_cookie(cookie)
+                            .map_err(|e| {
+                                format!("Invalid cookie received from {:?}: {}", client_addr, e)
+                            })?;
+                    }
+                    None if upstream_server.server_cookie.is_some() => {
+                        return Err(format!(
+                            "Response without a cookie received from {:?}",
+                            client_addr
+                        ));
+                    }
+                    None => {}
+                }
+            }
+        }
         if client_addr != upstream_servers[pending_query.upstream_server_idx].socket_addr {
             if let Some(probed_upstream_server_idx) = pending_query.probed_upstream_server_idx {
-                let mut probed_upstream_server = &mut upstream_servers[probed_upstream_server_idx];
-                if client_addr == probed_upstream_server.socket_addr {
-                    probed_upstream_server.record_success_after_failure();
-                } else {
-                    return Err(format!(
-                        "Sent a probe query to {:?} but got a response from {:?}",
-                        probed_upstream_server.socket_addr,
-                        client_addr
-                    ));
+                let back_online = {
+                    let probed_upstream_server =
+                        &mut upstream_servers[probed_upstream_server_idx];
+                    if client_addr != probed_upstream_server.socket_addr {
+                        return Err(format!(
+                            "Sent a probe query to {:?} but got a response from {:?}",
+                            probed_upstream_server.socket_addr,
+                            client_addr
+                        ));
+                    }
+                    let was_offline = probed_upstream_server.offline;
+                    probed_upstream_server.record_success_after_failure(&self.config);
+                    probed_upstream_server.record_success(&self.config);
+                    was_offline && !probed_upstream_server.offline
+                };
+                if back_online {
+                    *self.upstream_servers_live_arc.write() =
+                        UpstreamServer::live_servers(&mut upstream_servers);
                 }
             } else {
                 return Err(format!(
@@ -125,11 +203,77 @@ impl ExtResponse {
                 ));
             }
         } else {
-            let upstream_server = &mut upstream_servers[pending_query.upstream_server_idx];
-            upstream_server.pending_queries_count =
-                upstream_server.pending_queries_count.saturating_sub(1);
-            upstream_server.record_rtt(pending_query.ts.elapsed_since_recent(), &self.varz);
+            let mut ejected = false;
+            {
+                let upstream_server = &mut upstream_servers[pending_query.upstream_server_idx];
+                upstream_server.pending_queries_count =
+                    upstream_server.pending_queries_count.saturating_sub(1);
+                let rtt = upstream_server.clock.elapsed(pending_query.ts);
+                upstream_server.record_rtt(rtt, &self.varz);
+                let lame_response = is_lame_response(packet, upstream_server.recursion_desired);
+                if upstream_server.record_lameness(&self.config, lame_response) {
+                    self.varz.upstream_lame.inc();
+                }
+                if lame_response && upstream_server.lame && self.config.upstream_eject_lame {
+                    upstream_server.record_failure(
+                        &self.config,
+                        &self.handle,
+                        &self.net_ext_udp_sockets_rc,
+                    );
+                    ejected = upstream_server.offline;
+                } else {
+                    upstream_server.record_success(&self.config);
+                }
+            }
+            if ejected {
+                *self.upstream_servers_live_arc.write() =
+                    UpstreamServer::live_servers(&mut upstream_servers);
+            }
         }
+        Ok(server_cookie_changed)
+    }
+
+    /// Returns `true` if `NXDOMAIN` responses to that question should be
+    /// turned into `NODATA` responses.
+    fn is_nxdomain_to_nodata(&self, normalized_question: &NormalizedQuestion) -> bool {
+        let suffixes = &self.config.nxdomain_to_nodata_suffixes;
+        let qtypes = &self.config.nxdomain_to_nodata_qtypes;
+        if suffixes.is_empty() ||
+            (!qtypes.is_empty() && !qtypes.contains(&normalized_question.qtype))
+        {
+            return false;
+        }
+        let qname_lc = dns::qname_lc(&normalized_question.qname);
+        suffixes
+            .iter()
+            .any(|suffix| dns::qname_is_in_zone(&qname_lc, suffix))
+    }
+
+    /// Sends a query again to the server that rejected its cookie, with the
+    /// same transaction ID, so that the response still matches the pending
+    /// query.
+    fn send_again_with_new_cookie(
+        &self,
+        pending_query: &PendingQuery,
+        client_addr: SocketAddr,
+    ) -> Result<(), &'static str> {
+        let client_query = match pending_query.client_queries.first() {
+            None => return Err("No clients waiting for this query"),
+            Some(client_query) => client_query,
+        };
+        let mut upstream_servers = self.upstream_servers_arc.write();
+        let upstream_server = &mut upstream_servers[pending_query.upstream_server_idx];
+        if upstream_server.socket_addr != client_addr {
+            return Err("Cookie rejected by a server that is not the one queried");
+        }
+        let (mut query_packet, _) = client_query
+            .normalized_question
+            .upstream_query_packet(upstream_server, &self.config)?;
+        set_tid(&mut query_packet, pending_query.normalized_question_minimal.tid);
+        let _ = self.net_ext_udp_socket
+            .send_to(&query_packet, &upstream_server.socket_addr);
+        upstream_server.pending_queries_count =
+            upstream_server.pending_queries_count.saturating_add(1);
         Ok(())
     }
 
@@ -141,24 +285,38 @@ impl ExtResponse {
     }
 
     fn clamped_ttl(&self, mut packet: &mut [u8]) -> Result<u32, &'static str> {
-        match min_ttl(
-            packet,
-            self.config.min_ttl,
-            self.config.max_ttl,
-            FAILURE_TTL,
-        ) {
+        let soa_negative_ttl = negative_ttl(packet);
+        let floor_ttl = match soa_negative_ttl {
+            Ok(Some(_)) => self.config.min_negative_ttl,
+            _ if rcode(packet) == DNS_RCODE_NXDOMAIN => self.config.min_negative_ttl,
+            _ => self.config.min_positive_ttl,
+        };
+        match min_ttl(packet, floor_ttl, self.config.max_ttl, FAILURE_TTL) {
             Err(_) => {
                 self.varz.upstream_errors.inc();
                 Err("Unexpected RRs in a response")
             }
             Ok(ttl) => if rcode(packet) == DNS_RCODE_SERVFAIL {
-                let _ = set_ttl(&mut packet, FAILURE_TTL);
-                Ok(FAILURE_TTL)
-            } else if ttl < self.config.min_ttl {
+                let _ = set_ttl(&mut packet, self.config.servfail_ttl);
+                Ok(self.config.servfail_ttl)
+            } else if !self.config.cache_zero_ttl &&
+                min_ttl(packet, 0, self.config.max_ttl, FAILURE_TTL) == Ok(0)
+            {
+                Ok(0)
+            } else if let Ok(Some(ttl)) = soa_negative_ttl {
+                let ttl = cmp::min(
+                    cmp::max(ttl, self.config.min_negative_ttl),
+                    self.config.negative_ttl,
+                );
                 if self.decrement_ttl {
-                    let _ = set_ttl(&mut packet, self.config.min_ttl);
+                    let _ = set_ttl(&mut packet, ttl);
                 }
-                Ok(self.config.min_ttl)
+                Ok(ttl)
+            } else if ttl < floor_ttl {
+                if self.decrement_ttl {
+                    let _ = set_ttl(&mut packet, floor_ttl);
+                }
+                Ok(floor_ttl)
             } else {
                 Ok(ttl)
             },
@@ -171,19 +329,34 @@ impl ExtResponse {
         normalized_question_key: NormalizedQuestionKey,
         ttl: u32,
     ) {
+        if self.config
+            .no_cache_qtypes
+            .contains(&normalized_question_key.qtype)
+        {
+            return;
+        }
         if rcode(&packet) == DNS_RCODE_SERVFAIL {
             match self.cache.get(&normalized_question_key) {
                 None => {
                     self.cache
-                        .insert(normalized_question_key, packet, FAILURE_TTL);
-                }                
+                        .insert(normalized_question_key, packet, self.config.servfail_ttl);
+                }
+                Some(ref cache_entry) if cache_entry.is_servfail() => {
+                    self.cache
+                        .insert(normalized_question_key, packet, self.config.servfail_ttl);
+                }
                 Some(cache_entry) => {
                     self.varz.client_queries_offline.inc();
                     self.cache
                         .insert(normalized_question_key, cache_entry.packet, FAILURE_TTL);
                 }
             }
+        } else if ttl == 0 {
+            debug!("Not caching a response with a zero TTL");
         } else {
+            if let Ok(Some(delegation)) = referral(&packet) {
+                self.cache.insert_referral(delegation, ttl);
+            }
             self.cache.insert(normalized_question_key, packet, ttl);
         }
         self.update_cache_stats();
@@ -193,9 +366,14 @@ impl ExtResponse {
         &self,
         mut packet: &mut [u8],
         client_query: &ClientQuery,
+        upstream_addr: SocketAddr,
     ) -> Result<(), io::Error> {
         client_query
-            .response_send(&mut packet, Some(&self.net_udp_socket))
+            .response_send(
+                &mut packet,
+                Some(&self.net_udp_socket),
+                AnswerSource::Upstream(upstream_addr),
+            )
             .wait()
     }
 
@@ -203,36 +381,148 @@ impl ExtResponse {
         &self,
         packet: &mut [u8],
         client_queries: &Vec<ClientQuery>,
+        upstream_addr: SocketAddr,
+        retries: u32,
     ) -> Result<(), &'static str> {
         self.varz.upstream_received.inc();
+        if self.config.slow_query_threshold_ms > 0 {
+            let outcome = format!(
+                "rcode {} size {} tc {}",
+                rcode(packet),
+                packet.len(),
+                tc(packet)
+            );
+            for client_query in client_queries {
+                client_query.log_if_slow(
+                    self.config.slow_query_threshold_ms,
+                    upstream_addr,
+                    retries,
+                    &outcome,
+                );
+            }
+        }
         for client_query in client_queries {
-            let _ = self.dispatch_client_query(packet, client_query);
+            let _ = self.dispatch_client_query(packet, client_query, upstream_addr);
         }
         Ok(())
     }
 
+    /// Returns the key of the pending query a response is for.
+    ///
+    /// Queries for types excluded from coalescing are keyed by the transaction
+    /// ID they were sent with, in addition to the question.
+    fn pending_query_key(
+        &self,
+        normalized_question_key: &NormalizedQuestionKey,
+        packet: &[u8],
+    ) -> PendingQueryKey {
+        let key = PendingQueryKey::new(normalized_question_key.clone(), None);
+        if self.pending_queries.map_arc.read().contains_key(&key) {
+            return key;
+        }
+        PendingQueryKey::new(normalized_question_key.clone(), Some(tid(packet)))
+    }
+
+    /// Checks that a response matches a query sent to that server from this
+    /// socket, without dispatching it.
+    fn is_pending_response(
+        &self,
+        key: &PendingQueryKey,
+        packet: &[u8],
+        upstream_server_idx: usize,
+    ) -> bool {
+        self.pending_queries
+            .map_arc
+            .read()
+            .get(key)
+            .map_or(false, |pending_query| {
+                pending_query.local_port == self.local_port &&
+                    pending_query.normalized_question_minimal.tid == tid(packet) &&
+                    pending_query.upstream_server_idx == upstream_server_idx
+            })
+    }
+
     fn verify_and_maybe_dispatch_pending_query(
         &mut self,
         mut packet: &mut [u8],
-        normalized_question_key: &NormalizedQuestionKey,
+        key: &PendingQueryKey,
         client_addr: SocketAddr,
+        cookie: Option<&[u8]>,
     ) -> Result<(), &'static str> {
         let map = self.pending_queries.map_arc.read();
-        let pending_query = match map.get(normalized_question_key) {
+        let pending_query = match map.get(key) {
             None => return Err("No clients waiting for this query"),                
             Some(pending_query) => pending_query,
         };
-        if let Err(e) = self.verify_ext_response(pending_query, packet, client_addr) {
-            warn!("{}", e);
-            return Err(
-                "Received response is not valid for the query originally sent",
-            );
+        let server_cookie_changed =
+            match self.verify_ext_response(pending_query, packet, client_addr, cookie) {
+                Err(e) => {
+                    warn!("{}", e);
+                    return Err(
+                        "Received response is not valid for the query originally sent",
+                    );
+                }
+                Ok(server_cookie_changed) => server_cookie_changed,
+            };
+        if self.config.upstream_cookies && extended_rcode(packet) == Ok(DNS_RCODE_BADCOOKIE) {
+            self.varz.upstream_bad_cookies.inc();
+            if server_cookie_changed {
+                self.send_again_with_new_cookie(pending_query, client_addr)?;
+            }
+            return Err("Cookie rejected by the server");
         }
         let client_queries = &pending_query.client_queries;
         if let Some(ref dnstap_sender) = self.dnstap_sender {
             dnstap_sender.send_forwarder_response(packet, client_addr, self.local_port);
         }
-        self.dispatch_client_queries(&mut packet, client_queries)
+        self.dispatch_client_queries(
+            &mut packet,
+            client_queries,
+            client_addr,
+            pending_query.retries,
+        )
+    }
+
+    /// Reads the ICMP errors queued for the socket, if that's what `e` is about.
+    /// Any other error terminates the stream.
+    fn fut_process_ext_socket_error(
+        &mut self,
+        e: io::Error,
+        socket_fd: RawFd,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let mut queued_errors = 0;
+        while let Some((upstream_addr, icmp_errno)) = socket_udp_recv_error(socket_fd) {
+            queued_errors += 1;
+            if icmp_errno == Some(libc::ECONNREFUSED) {
+                self.fail_pending_queries_sent_to(upstream_addr);
+            }
+        }
+        if queued_errors == 0 {
+            return Box::new(future::err(e));
+        }
+        Box::new(future::ok(()))
+    }
+
+    /// Makes the queries sent to a server that responded with port unreachable
+    /// fail right away, so that they can be immediately retried.
+    fn fail_pending_queries_sent_to(&mut self, upstream_addr: SocketAddr) {
+        let upstream_server_idx = match self.upstream_idx_from_client_addr(upstream_addr) {
+            None => return,
+            Some(upstream_server_idx) => upstream_server_idx,
+        };
+        info!("Port unreachable received from {}", upstream_addr);
+        self.varz.upstream_port_unreachable.inc();
+        let mut map = self.pending_queries.map_arc.write();
+        for pending_query in map.values_mut() {
+            if pending_query.local_port != self.local_port ||
+                pending_query.upstream_server_idx != upstream_server_idx
+            {
+                continue;
+            }
+            // Dropping the sender makes the timeout future fail immediately
+            let (done_tx, _) = oneshot::channel();
+            drop(mem::replace(&mut pending_query.done_tx, done_tx));
+        }
     }
 
     fn fut_process_ext_socket(
@@ -246,10 +536,14 @@ impl ExtResponse {
             self.varz.upstream_errors.inc();
             return Box::new(future::ok(()));
         }
-        if self.upstream_idx_from_client_addr(client_addr).is_none() {
-            debug!("Got a response from an unexpected upstream server");
-            return Box::new(future::ok(()));
-        }
+        let upstream_server_idx = match self.upstream_idx_from_client_addr(client_addr) {
+            None => {
+                debug!("Got a response from an unexpected upstream server");
+                self.varz.spoofed_source_dropped.inc();
+                return Box::new(future::ok(()));
+            }
+            Some(upstream_server_idx) => upstream_server_idx,
+        };
         let normalized_question = match normalize(&packet, false) {
             Err(e) => {
                 info!("Unexpected question in a response: {}", e);
@@ -257,7 +551,89 @@ impl ExtResponse {
             }
             Ok(normalized_question) => normalized_question,
         };
+        if packet.len() > self.config.upstream_max_response_size {
+            info!(
+                "Oversized response received from {}: {} bytes",
+                client_addr,
+                packet.len()
+            );
+            self.varz.upstream_oversized_responses.inc();
+            // Only responses to a query we sent are held against the server, not
+            // datagrams anyone could send with its address
+            let key = self.pending_query_key(&normalized_question.key(), &packet);
+            if self.is_pending_response(&key, &packet, upstream_server_idx) {
+                let mut upstream_servers = self.upstream_servers_arc.write();
+                upstream_servers[upstream_server_idx].record_failure(
+                    &self.config,
+                    &self.handle,
+                    &self.net_ext_udp_sockets_rc,
+                );
+                *self.upstream_servers_live_arc.write() =
+                    UpstreamServer::live_servers(&mut upstream_servers);
+            }
+            return Box::new(future::ok(()));
+        }
+        let cookie = if self.config.upstream_cookies {
+            match edns_option(&packet, DNS_EDNS_OPTION_COOKIE) {
+                Err(e) => {
+                    info!("Unable to parse the EDNS options of a response: {}", e);
+                    self.varz.upstream_errors.inc();
+                    return Box::new(future::ok(()));
+                }
+                Ok(cookie) => cookie,
+            }
+        } else {
+            None
+        };
         let mut packet = (*packet).clone();
+        if self.config.strip_out_of_bailiwick {
+            match strip_out_of_bailiwick(&mut packet) {
+                Err(e) => {
+                    info!("Unable to check the bailiwick of a response: {}", e);
+                    self.varz.upstream_errors.inc();
+                    return Box::new(future::ok(()));
+                }
+                Ok(0) => {}
+                Ok(removed) => {
+                    debug!("{} out-of-bailiwick records removed", removed);
+                    self.varz.upstream_out_of_bailiwick.inc();
+                }
+            }
+        }
+        if let Some(max_answers) = self.config.max_answers {
+            match cap_answers(&mut packet, max_answers) {
+                Err(e) => {
+                    info!("Unable to cap the number of answers of a response: {}", e);
+                    self.varz.upstream_errors.inc();
+                    return Box::new(future::ok(()));
+                }
+                Ok(0) => {}
+                Ok(removed) => {
+                    debug!("{} answers removed", removed);
+                    self.varz.upstream_answers_capped.inc();
+                }
+            }
+        }
+        if self.is_nxdomain_to_nodata(&normalized_question) && nxdomain_to_nodata(&mut packet) {
+            debug!("NXDOMAIN response rewritten to NODATA");
+            self.varz.upstream_nxdomain_rewritten.inc();
+        }
+        if self.config.sort_addresses {
+            if let Err(e) = sort_addresses(&mut packet) {
+                info!("Unable to sort the addresses of a response: {}", e);
+                self.varz.upstream_errors.inc();
+                return Box::new(future::ok(()));
+            }
+        }
+        match strip_edns_options(&mut packet, &self.config.edns_options_allowlist) {
+            Err(e) => {
+                info!("Unable to filter the EDNS options of a response: {}", e);
+                self.varz.upstream_errors.inc();
+                return Box::new(future::ok(()));
+            }
+            Ok(0) => {}
+            Ok(removed) => debug!("{} EDNS options removed", removed),
+        }
         let ttl = match self.clamped_ttl(&mut packet) {
             Err(e) => {
                 info!("Unable to compute a TTL for caching a response: {}", e);
@@ -266,10 +642,12 @@ impl ExtResponse {
             Ok(ttl) => ttl,
         };
         let normalized_question_key = normalized_question.key();
+        let key = self.pending_query_key(&normalized_question_key, &packet);
         if let Err(e) = self.verify_and_maybe_dispatch_pending_query(
             &mut packet,
-            &normalized_question_key,
+            &key,
             client_addr,
+            cookie.as_ref().map(|cookie| &cookie[..]),
         ) {
             debug!("Couldn't dispatch response: {}", e);
             return Box::new(future::ok(()));
@@ -278,9 +656,7 @@ impl ExtResponse {
             .upstream_response_sizes
             .observe(packet.len() as f64);
         if let Some(pending_query) = self.pending_queries
-            .map_arc
-            .write()
-            .remove(&normalized_question_key)
+            .remove(&mut self.pending_queries.map_arc.write(), &key)
         {
             self.varz.inflight_queries.dec();
             let _ = pending_query.done_tx.send(());
@@ -303,5 +679,6 @@ impl ExtResponse {
         self.varz.cache_test_len.set(cache_stats.test_len as f64);
         self.varz.cache_inserted.set(cache_stats.inserted as f64);
         self.varz.cache_evicted.set(cache_stats.evicted as f64);
+        self.varz.cache_bytes.set(cache_stats.bytes as f64);
     }
 }
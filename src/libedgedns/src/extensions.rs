@@ -0,0 +1,72 @@
+//! Extension points for applications embedding EdgeDNS
+//!
+//! Extensions are registered with `EdgeDNS::with_extensions()`, and are
+//! shared by all the threads handling client queries.
+//!
+//! A `QueryPreprocessor` is invoked for every client query, right after it
+//! has been parsed, and before the cache is looked up. It can change the
+//! question, or answer the query directly.
+//!
+//! A `ResponseRewriter` is invoked right before a response is sent to a
+//! client, no matter where that response comes from (cache, upstream servers,
+//! or built locally). Responses from upstream servers are stored in the cache
+//! before being rewritten, so the cache always holds the original responses,
+//! and the rewriter is invoked again for every client.
+//!
+//! `QueryEvents` receives a structured event for every client query and for
+//! every response, that subscribers can consume from other threads.
+//!
+//! A `Clock` replaces the system time for the upstream servers and the client
+//! queries handler, for example to drive failure windows and rate limits from
+//! a `ManualClock` in tests.
+
+use clock::Clock;
+use dns::NormalizedQuestion;
+use query_events::QueryEvents;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub trait QueryPreprocessor: Send + Sync {
+    /// Inspects and possibly modifies a client query.
+    ///
+    /// Changes made to `normalized_question` (for example to its `qname` or
+    /// `qtype`) apply to the cache lookup and to the query sent upstream.
+    /// The response sent to the client still has the original question, with
+    /// its original case.
+    ///
+    /// Returning a packet answers the query with it, without looking up the
+    /// cache or contacting upstream servers.
+    fn preprocess(
+        &self,
+        _normalized_question: &mut NormalizedQuestion,
+        _client_addr: Option<SocketAddr>,
+    ) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+pub trait ResponseRewriter: Send + Sync {
+    /// Returns a modified version of `packet`, a response to
+    /// `normalized_question`, or `None` to send it as-is.
+    ///
+    /// `client_addr` is only known for queries received over UDP.
+    /// The transaction ID and the case of the question are restored after
+    /// the rewrite, and responses exceeding the client buffer size are still
+    /// truncated.
+    fn rewrite(
+        &self,
+        _packet: &[u8],
+        _normalized_question: &NormalizedQuestion,
+        _client_addr: Option<SocketAddr>,
+    ) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Extensions {
+    pub query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    pub response_rewriter: Option<Arc<ResponseRewriter>>,
+    pub query_events: Option<QueryEvents>,
+    pub clock: Option<Arc<Clock>>,
+}
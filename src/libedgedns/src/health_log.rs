@@ -0,0 +1,105 @@
+//! Periodic summary of the resolver health
+//!
+//! For deployments without a metrics scraper, a single log line summarizing
+//! the activity since the previous one can be emitted at regular intervals.
+//! Rates are computed from the difference between two snapshots of the `Varz`
+//! counters.
+
+use coarsetime::Instant;
+use config::Config;
+use futures::Future;
+use futures::Stream;
+use parking_lot::RwLock;
+use resolver::ResolverCore;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time;
+use tokio_timer::{wheel, Timer};
+use upstream_server::UpstreamServer;
+use varz::Varz;
+
+struct Snapshot {
+    ts: Instant,
+    client_queries: f64,
+    client_queries_cached: f64,
+}
+
+impl Snapshot {
+    fn new(varz: &Varz) -> Snapshot {
+        Snapshot {
+            ts: Instant::recent(),
+            client_queries: varz.client_queries_udp.get() + varz.client_queries_tcp.get(),
+            client_queries_cached: varz.client_queries_cached.get(),
+        }
+    }
+}
+
+pub struct HealthLog {
+    config: Rc<Config>,
+    varz: Arc<Varz>,
+    upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
+    upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
+    timer: Timer,
+    last_snapshot: Snapshot,
+}
+
+impl HealthLog {
+    pub fn new(resolver_core: &ResolverCore) -> Self {
+        HealthLog {
+            config: resolver_core.config.clone(),
+            varz: resolver_core.varz.clone(),
+            upstream_servers_arc: resolver_core.upstream_servers_arc.clone(),
+            upstream_servers_live_arc: resolver_core.upstream_servers_live_arc.clone(),
+            timer: wheel().build(),
+            last_snapshot: Snapshot::new(&resolver_core.varz),
+        }
+    }
+
+    pub fn fut_process_stream<'a>(mut self) -> impl Future<Item = (), Error = io::Error> + 'a {
+        let interval = self.timer.interval(time::Duration::from_secs(
+            self.config.health_log_interval_secs,
+        ));
+        interval
+            .map_err(|_| io::Error::last_os_error())
+            .for_each(move |_| {
+                self.log_summary();
+                Ok(())
+            })
+    }
+
+    fn log_summary(&mut self) {
+        let snapshot = Snapshot::new(&self.varz);
+        let elapsed = snapshot.ts.duration_since(self.last_snapshot.ts).as_f64();
+        let client_queries = snapshot.client_queries - self.last_snapshot.client_queries;
+        let client_queries_cached =
+            snapshot.client_queries_cached - self.last_snapshot.client_queries_cached;
+        self.last_snapshot = snapshot;
+        let qps = if elapsed > 0.0 {
+            client_queries / elapsed
+        } else {
+            0.0
+        };
+        let cache_hit_ratio = if client_queries > 0.0 {
+            client_queries_cached / client_queries
+        } else {
+            0.0
+        };
+        let upstream_servers_count = self.upstream_servers_arc
+            .read()
+            .iter()
+            .filter(|upstream_server| upstream_server.pooled)
+            .count();
+        let upstream_servers_live_count = self.upstream_servers_live_arc.read().len();
+        info!(
+            "Health: qps={:.1} cache_hit_ratio={:.3} inflight={} upstreams_live={} \
+             upstreams_down={} upstream_avg_rtt_ms={:.1}",
+            qps,
+            cache_hit_ratio,
+            self.varz.inflight_queries.get(),
+            upstream_servers_live_count,
+            upstream_servers_count.saturating_sub(upstream_servers_live_count),
+            self.varz.upstream_avg_rtt.get() * 1000.0
+        );
+    }
+}
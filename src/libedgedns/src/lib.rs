@@ -19,6 +19,7 @@ extern crate futures;
 extern crate jumphash;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate net2;
@@ -37,45 +38,77 @@ extern crate toml;
 
 #[cfg(feature = "webservice")]
 extern crate hyper;
+#[cfg(feature = "webservice")]
+extern crate serde;
+#[cfg(feature = "webservice")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "webservice")]
+extern crate serde_json;
+#[cfg(feature = "webservice")]
+extern crate tokio_uds;
 
 #[macro_use]
 extern crate prometheus;
 
 mod cache;
+pub mod cache_codec;
+mod cache_primer;
 mod client_query;
 mod client_queries_handler;
+mod client_ratelimiter;
+pub mod clock;
 mod config;
 pub mod dns;
+mod duplicate_queries;
 mod ext_response;
+mod extensions;
+mod health_log;
 mod log_dnstap;
+mod maintenance;
 mod net_helpers;
 mod pending_query;
+mod query_events;
 mod resolver;
+#[cfg(feature = "shadow-cache")]
+mod shadow_cache;
 use std::io;
 mod tcp_acceptor;
 mod tcp_arbitrator;
 mod udp_acceptor;
 mod udp_stream;
 mod upstream_probe;
-mod upstream_server;
+pub mod upstream_server;
 mod varz;
 
 #[cfg(feature = "webservice")]
 mod webservice;
 
 use cache::Cache;
+use cache_primer::CachePrimer;
+use client_ratelimiter::ClientRateLimiter;
+use duplicate_queries::DuplicateQueries;
+use clock::{Clock, SystemClock};
 pub use config::Config;
+pub use client_query::{AnswerOrder, AnswerSource, ClientAddressFamily};
+pub use extensions::{Extensions, QueryPreprocessor, ResponseRewriter};
 use log_dnstap::LogDNSTap;
 use net_helpers::*;
+use parking_lot::RwLock;
+pub use pending_query::PendingQueryAges;
 use privdrop::PrivDrop;
+pub use query_events::{QueryEvent, QueryEventKind, QueryEvents};
 use resolver::*;
+pub use resolver::UpstreamFamily;
 use std::net;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::sync::mpsc;
 use std::thread;
 use tcp_acceptor::*;
 use tcp_arbitrator::TcpArbitrator;
 use udp_acceptor::*;
+use upstream_server::UpstreamServer;
 use varz::*;
 
 #[cfg(feature = "webservice")]
@@ -88,11 +121,14 @@ const DNS_MAX_UDP_SIZE: usize = 4096;
 const DNS_QUERY_MAX_SIZE: usize = 283;
 const DNS_QUERY_MIN_SIZE: usize = 17;
 const DNS_UDP_NOEDNS0_MAX_SIZE: usize = 512;
+const ECS_PREFIX_V4: u8 = 24;
+const ECS_PREFIX_V6: u8 = 56;
 const HEALTH_CHECK_MS: u64 = 10 * 1000;
 const MAX_EVENTS_PER_BATCH: usize = 1024;
 const MAX_TCP_CLIENTS: usize = 1_000;
 const MAX_TCP_HASH_DISTANCE: usize = 10;
 const MAX_TCP_IDLE_MS: u64 = 10 * 1000;
+const STARTUP_WAIT_POLL_MS: u64 = 100;
 const FAILURE_TTL: u32 = 30;
 const TCP_BACKLOG: usize = 1024;
 const UDP_BUFFER_SIZE: usize = 16 * 1024 * 1024;
@@ -113,7 +149,16 @@ pub struct EdgeDNSContext {
     pub cache: Cache,
     pub varz: Arc<Varz>,
     pub tcp_arbitrator: TcpArbitrator,
+    pub client_ratelimiter: Option<ClientRateLimiter>,
+    pub duplicate_queries: Option<DuplicateQueries>,
+    pub upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
+    pub upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
+    pub maintenance_mode: Arc<AtomicBool>,
+    pub resolver_heartbeat: Arc<RwLock<coarsetime::Instant>>,
+    pub warmup_complete: Arc<AtomicBool>,
+    pub extensions: Extensions,
     pub dnstap_sender: Option<log_dnstap::Sender>,
+    pub clock: Arc<Clock>,
 }
 
 pub struct EdgeDNS;
@@ -122,14 +167,16 @@ impl EdgeDNS {
     #[cfg(feature = "webservice")]
     fn webservice_start(
         edgedns_context: &EdgeDNSContext,
+        cache_primer: Option<CachePrimer>,
         service_ready_tx: mpsc::SyncSender<u8>,
     ) -> io::Result<thread::JoinHandle<()>> {
-        WebService::spawn(edgedns_context, service_ready_tx)
+        WebService::spawn(edgedns_context, cache_primer, service_ready_tx)
     }
 
     #[cfg(not(feature = "webservice"))]
     fn webservice_start(
         _edgedns_context: &EdgeDNSContext,
+        _cache_primer: Option<CachePrimer>,
         _service_ready_tx: mpsc::SyncSender<u8>,
     ) -> io::Result<thread::JoinHandle<()>> {
         Err(io::Error::new(
@@ -153,13 +200,23 @@ impl EdgeDNS {
     }
 
     pub fn new(config: Config) -> EdgeDNS {
+        Self::with_extensions(config, Extensions::default())
+    }
+
+    pub fn with_extensions(config: Config, extensions: Extensions) -> EdgeDNS {
         let ct = coarsetime::Updater::new(CLOCK_RESOLUTION)
             .start()
             .expect("Unable to spawn the internal timer");
         let varz = Arc::new(Varz::new());
         let cache = Cache::new(config.clone());
-        let udp_socket =
-            socket_udp_bound(&config.listen_addr).expect("Unable to create a UDP client socket");
+        #[cfg(feature = "shadow-cache")]
+        let cache = cache.with_shadow(varz.clone());
+        let udp_socket = socket_udp_bound(
+            &config.listen_addr,
+            config.udp_recv_buffer,
+            config.udp_send_buffer,
+            config.udp_dont_fragment,
+        ).expect("Unable to create a UDP client socket");
         let tcp_listener =
             socket_tcp_bound(&config.listen_addr).expect("Unable to create a TCP client socket");
         let (log_dnstap, dnstap_sender) = if config.dnstap_enabled {
@@ -170,6 +227,38 @@ impl EdgeDNS {
             (None, None)
         };
         let tcp_arbitrator = TcpArbitrator::with_capacity(config.max_tcp_clients);
+        let clock: Arc<Clock> = extensions
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(SystemClock));
+        let mut upstream_servers: Vec<UpstreamServer> = config
+            .upstream_servers
+            .iter()
+            .zip(&config.upstream_priorities)
+            .map(|(s, &priority)| {
+                let mut upstream_server = UpstreamServer::new(s, clock.clone())
+                    .expect("Invalid upstream server address");
+                upstream_server.priority = priority;
+                upstream_server
+            })
+            .collect();
+        for s in config.upstream_routes.values() {
+            if upstream_servers.iter().all(|x| &x.remote_addr != s) {
+                let mut upstream_server = UpstreamServer::new(s, clock.clone())
+                    .expect("Invalid upstream server address");
+                upstream_server.pooled = false;
+                upstream_servers.push(upstream_server);
+            }
+        }
+        for upstream_server in &mut upstream_servers {
+            if config
+                .upstream_no_rd_servers
+                .contains(&upstream_server.remote_addr)
+            {
+                upstream_server.recursion_desired = false;
+            }
+        }
+        let upstream_servers_live: Vec<usize> = (0..config.upstream_servers.len()).collect();
         let edgedns_context = EdgeDNSContext {
             config: config.clone(),
             listen_addr: config.listen_addr.to_owned(),
@@ -178,10 +267,23 @@ impl EdgeDNS {
             cache: cache,
             varz: varz,
             tcp_arbitrator: tcp_arbitrator,
+            client_ratelimiter: ClientRateLimiter::new(&config),
+            duplicate_queries: DuplicateQueries::new(&config),
+            upstream_servers_arc: Arc::new(RwLock::new(upstream_servers)),
+            upstream_servers_live_arc: Arc::new(RwLock::new(upstream_servers_live)),
+            maintenance_mode: Arc::new(AtomicBool::new(config.maintenance_enabled)),
+            resolver_heartbeat: Arc::new(RwLock::new(coarsetime::Instant::now())),
+            warmup_complete: Arc::new(AtomicBool::new(
+                !config.webservice_ready_after_prime || config.prime_file.is_none(),
+            )),
+            extensions: extensions,
             dnstap_sender: dnstap_sender,
+            clock: clock,
         };
         let resolver_tx =
             ResolverCore::spawn(&edgedns_context).expect("Unable to spawn the resolver");
+        let cache_primer = CachePrimer::new(&edgedns_context, resolver_tx.clone())
+            .expect("Unable to load the list of names to prime the cache with");
         let (service_ready_tx, service_ready_rx) = mpsc::sync_channel::<u8>(1);
         let mut tasks: Vec<thread::JoinHandle<()>> = Vec::new();
         for _ in 0..config.udp_acceptor_threads {
@@ -203,13 +305,20 @@ impl EdgeDNS {
             service_ready_rx.recv().unwrap();
         }
         if config.webservice_enabled {
-            let webservice = Self::webservice_start(&edgedns_context, service_ready_tx.clone());
+            let webservice = Self::webservice_start(
+                &edgedns_context,
+                cache_primer.clone(),
+                service_ready_tx.clone(),
+            );
             tasks.push(webservice.unwrap());
             service_ready_rx.recv().unwrap();
         }
         Self::privileges_drop(&config);
         log_dnstap.map(|mut x| x.start());
         info!("EdgeDNS is ready to process requests");
+        if let Some(ref cache_primer) = cache_primer {
+            cache_primer.start();
+        }
         for task in tasks {
             let _ = task.join();
         }
@@ -0,0 +1,68 @@
+//! Maintenance mode.
+//!
+//! While the maintenance mode is on, queries for names within
+//! `maintenance.suffixes` (or all queries, if no suffixes are configured) get
+//! a static response instead of the actual records, whether they are cached
+//! or not.
+//!
+//! The mode can be switched at runtime from the webservice, so the flag is
+//! shared by all the listeners and resolvers, and checked for every query.
+
+use config::Config;
+use dns::{self, NormalizedQuestion};
+use resolver::MaintenanceResponse;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use super::FAILURE_TTL;
+
+#[derive(Clone)]
+pub struct Maintenance {
+    maintenance_mode: Arc<AtomicBool>,
+    response: MaintenanceResponse,
+    sinkhole_ipv4: Ipv4Addr,
+    sinkhole_ipv6: Ipv6Addr,
+    suffixes: Arc<Vec<Vec<u8>>>,
+}
+
+impl Maintenance {
+    pub fn new(config: &Config, maintenance_mode: Arc<AtomicBool>) -> Self {
+        Maintenance {
+            maintenance_mode: maintenance_mode,
+            response: config.maintenance_response,
+            sinkhole_ipv4: config.maintenance_sinkhole_ipv4,
+            sinkhole_ipv6: config.maintenance_sinkhole_ipv6,
+            suffixes: Arc::new(config.maintenance_suffixes.clone()),
+        }
+    }
+
+    pub fn is_in_maintenance(&self, normalized_question: &NormalizedQuestion) -> bool {
+        if !self.maintenance_mode.load(Relaxed) {
+            return false;
+        }
+        if self.suffixes.is_empty() {
+            return true;
+        }
+        let qname_lc = dns::qname_lc(&normalized_question.qname);
+        self.suffixes
+            .iter()
+            .any(|suffix| dns::qname_is_in_zone(&qname_lc, suffix))
+    }
+
+    /// Builds the static response to a query received during the maintenance.
+    pub fn build_response_packet(
+        &self,
+        normalized_question: &NormalizedQuestion,
+    ) -> Result<Vec<u8>, &'static str> {
+        match self.response {
+            MaintenanceResponse::Refused => dns::build_refused_packet(normalized_question),
+            MaintenanceResponse::Sinkhole => dns::build_sinkhole_packet(
+                normalized_question,
+                &self.sinkhole_ipv4,
+                &self.sinkhole_ipv6,
+                FAILURE_TTL,
+            ),
+        }
+    }
+}
@@ -1,16 +1,18 @@
 //! Unix-specific helpers to create sockets with specific options
 
 use bpf;
+use libc;
 use nix::fcntl::FcntlArg::F_SETFL;
 use nix::fcntl::{fcntl, O_NONBLOCK};
-use nix::sys::socket::{bind, listen, setsockopt, socket, sockopt, AddressFamily, InetAddr,
-                       SockAddr, SockFlag, SockLevel, SockType};
+use nix::sys::socket::{bind, getsockopt, listen, setsockopt, socket, sockopt, AddressFamily,
+                       InetAddr, SockAddr, SockFlag, SockLevel, SockType};
 use socket_priority;
-use std::net::{self, SocketAddr, UdpSocket};
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
 use std::io;
+use std::mem;
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::str::FromStr;
-use super::{TCP_BACKLOG, UDP_BUFFER_SIZE};
+use super::TCP_BACKLOG;
 
 #[inline]
 pub fn socket_tcp_v4() -> io::Result<RawFd> {
@@ -52,15 +54,58 @@ pub fn socket_tcp_bound(addr: &str) -> io::Result<net::TcpListener> {
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &UDP_BUFFER_SIZE);
+pub fn socket_udp_set_buffer_size(
+    socket_fd: RawFd,
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+) {
+    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &send_buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &recv_buffer_size);
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-pub fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBuf, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &UDP_BUFFER_SIZE);
+pub fn socket_udp_set_buffer_size(
+    socket_fd: RawFd,
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+) {
+    let _ = setsockopt(socket_fd, sockopt::SndBuf, &send_buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &recv_buffer_size);
+}
+
+/// Returns the receive and send buffer sizes actually granted by the kernel,
+/// which may have been clamped.
+pub fn socket_udp_buffer_sizes(socket_fd: RawFd) -> io::Result<(usize, usize)> {
+    let recv_buffer_size = getsockopt(socket_fd, sockopt::RcvBuf)?;
+    let send_buffer_size = getsockopt(socket_fd, sockopt::SndBuf)?;
+    Ok((recv_buffer_size, send_buffer_size))
+}
+
+/// Restricts a socket to a network interface, so that packets are sent and
+/// received through that interface regardless of the routing table.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn socket_bind_to_device(socket_fd: RawFd, interface: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket_fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn socket_bind_to_device(_socket_fd: RawFd, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Binding to an interface is only supported on Linux",
+    ))
 }
 
 #[inline]
@@ -85,7 +130,12 @@ pub fn socket_udp_v6() -> io::Result<RawFd> {
     Ok(socket_fd)
 }
 
-pub fn socket_udp_bound(addr: &str) -> io::Result<UdpSocket> {
+pub fn socket_udp_bound(
+    addr: &str,
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+    dont_fragment: bool,
+) -> io::Result<UdpSocket> {
     let actual: SocketAddr = FromStr::from_str(addr).expect("Invalid address");
     let nix_addr = SockAddr::Inet(InetAddr::from_std(&actual));
     let socket_fd = match actual {
@@ -96,7 +146,19 @@ pub fn socket_udp_bound(addr: &str) -> io::Result<UdpSocket> {
     let _ = setsockopt(socket_fd, sockopt::ReusePort, &true);
     let _ = set_bpf_udp_dns(socket_fd);
     let _ = socket_priority::set_priority(socket_fd, socket_priority::Priority::Interactive);
-    socket_udp_set_buffer_size(socket_fd);
+    socket_udp_set_buffer_size(socket_fd, recv_buffer_size, send_buffer_size);
+    if let Ok((recv_buffer_size, send_buffer_size)) = socket_udp_buffer_sizes(socket_fd) {
+        info!(
+            "UDP listener buffers: {} bytes to receive, {} bytes to send",
+            recv_buffer_size,
+            send_buffer_size
+        );
+    }
+    if dont_fragment {
+        if let Err(e) = socket_udp_set_dont_fragment(socket_fd, actual.is_ipv6()) {
+            warn!("Unable to set the Don't Fragment flag on UDP responses: {}", e);
+        }
+    }
     bind(socket_fd, &nix_addr).expect("Unable to bind a UDP socket");
     let socket = unsafe { UdpSocket::from_raw_fd(socket_fd) };
     Ok(socket)
@@ -113,3 +175,168 @@ pub fn set_nonblock(sock: RawFd) -> io::Result<()> {
     fcntl(sock, F_SETFL(O_NONBLOCK))?;
     Ok(())
 }
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_RECVERR: libc::c_int = 11;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPV6_RECVERR: libc::c_int = 25;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_EE_ORIGIN_ICMP: u8 = 2;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+/// Asks the kernel to queue ICMP errors received in response to packets sent
+/// by a UDP socket, so that they can be read with `socket_udp_recv_error()`.
+/// `ipv6` must be set for IPv6 sockets, whose errors are reported separately.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn socket_udp_set_recverr(socket_fd: RawFd, ipv6: bool) {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, IPV6_RECVERR)
+    } else {
+        (libc::IPPROTO_IP, IP_RECVERR)
+    };
+    let on: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            socket_fd,
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&on) as libc::socklen_t,
+        );
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn socket_udp_set_recverr(_socket_fd: RawFd, _ipv6: bool) {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_MTU_DISCOVER: libc::c_int = 10;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_PMTUDISC_DO: libc::c_int = 2;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPV6_DONTFRAG: libc::c_int = 62;
+
+/// Sets the Don't Fragment flag on packets sent by a UDP socket. Packets
+/// larger than the path MTU are then not sent, instead of being fragmented.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn socket_udp_set_dont_fragment(socket_fd: RawFd, ipv6: bool) -> io::Result<()> {
+    let (level, name, value) = if ipv6 {
+        (libc::IPPROTO_IPV6, IPV6_DONTFRAG, 1)
+    } else {
+        (libc::IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_DO)
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket_fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn socket_udp_set_dont_fragment(_socket_fd: RawFd, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Setting the Don't Fragment flag is only supported on Linux",
+    ))
+}
+
+/// Removes an error from the error queue of a UDP socket.
+///
+/// Returns `None` if the queue is empty. Otherwise, returns the destination
+/// of the packet that caused the error, and, if it was caused by an ICMP
+/// message, the corresponding error code (e.g. `ECONNREFUSED` for port
+/// unreachable).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn socket_udp_recv_error(socket_fd: RawFd) -> Option<(SocketAddr, Option<i32>)> {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut buf = [0u8; 512];
+    let mut control = [0u8; 512];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let res = unsafe {
+        libc::recvmsg(
+            socket_fd,
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+    let addr = match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let name = unsafe { &*(&name as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)),
+                u16::from_be(name.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let name = unsafe { &*(&name as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(name.sin6_addr.s6_addr),
+                u16::from_be(name.sin6_port),
+                u32::from_be(name.sin6_flowinfo),
+                name.sin6_scope_id,
+            ))
+        }
+        _ => return None,
+    };
+    let mut icmp_errno = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if (level == libc::IPPROTO_IP && ty == IP_RECVERR) ||
+            (level == libc::IPPROTO_IPV6 && ty == IPV6_RECVERR)
+        {
+            // struct sock_extended_err: ee_errno (u32), then ee_origin (u8)
+            let data = unsafe { libc::CMSG_DATA(cmsg) };
+            let ee_errno = unsafe { (data as *const u32).read_unaligned() };
+            let ee_origin = unsafe { *data.offset(4) };
+            if ee_origin == SO_EE_ORIGIN_ICMP || ee_origin == SO_EE_ORIGIN_ICMP6 {
+                icmp_errno = Some(ee_errno as i32);
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Some((addr, icmp_errno))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn socket_udp_recv_error(_socket_fd: RawFd) -> Option<(SocketAddr, Option<i32>)> {
+    None
+}
+
+/// Returns the host name of the machine, if it can be retrieved.
+pub fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    let ret = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
@@ -3,12 +3,13 @@
 //! the same response.
 
 use client_query::ClientQuery;
-use coarsetime::Instant;
+use clock::{Clock, Instant};
 use dns::{NormalizedQuestionKey, NormalizedQuestionMinimal};
 use futures::sync::mpsc::{channel, Receiver, Sender};
 use futures::sync::oneshot;
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::net;
 use std::sync::Arc;
 use upstream_server::UpstreamServer;
@@ -18,9 +19,13 @@ pub struct PendingQuery {
     pub normalized_question_minimal: NormalizedQuestionMinimal,
     pub local_port: u16,
     pub client_queries: Vec<ClientQuery>,
+    /// When the query was last sent, for computing the RTT
     pub ts: Instant,
+    /// When the query was first sent, not updated by retries
+    pub created_ts: Instant,
     pub upstream_server_idx: usize,
     pub probed_upstream_server_idx: Option<usize>,
+    pub retries: u32,
     pub done_tx: oneshot::Sender<()>,
     pub varz: Arc<Varz>,
 }
@@ -33,29 +38,138 @@ impl PendingQuery {
         net_ext_udp_socket: &net::UdpSocket,
         client_query: &ClientQuery,
         done_tx: oneshot::Sender<()>,
-    ) -> Self {
+    ) -> io::Result<Self> {
         let varz = client_query.varz.clone();
-        PendingQuery {
+        let local_port = net_ext_udp_socket.local_addr()?.port();
+        Ok(PendingQuery {
             normalized_question_minimal: normalized_question_minimal,
-            local_port: net_ext_udp_socket.local_addr().unwrap().port(),
+            local_port: local_port,
             client_queries: vec![client_query.clone()],
-            ts: Instant::recent(),
+            ts: upstream_server.clock.now(),
+            created_ts: upstream_server.clock.now(),
             upstream_server_idx: upstream_server_idx,
             probed_upstream_server_idx: None,
+            retries: 0,
             done_tx: done_tx,
             varz: varz,
+        })
+    }
+}
+
+/// Key of a `PendingQuery`.
+///
+/// Similar questions share the same key, so that they can be coalesced.
+/// Questions whose type is excluded from coalescing additionally include
+/// the transaction ID sent upstream, which makes their key unique.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct PendingQueryKey {
+    pub normalized_question_key: NormalizedQuestionKey,
+    pub tid: Option<u16>,
+}
+
+impl PendingQueryKey {
+    pub fn new(normalized_question_key: NormalizedQuestionKey, tid: Option<u16>) -> Self {
+        PendingQueryKey {
+            normalized_question_key: normalized_question_key,
+            tid: tid,
         }
     }
 }
 
+/// Creation times of the pending queries, ordered so that the oldest one
+/// can be found without scanning the whole map.
+///
+/// Several queries may have been created at the same coarse instant, so each
+/// instant is stored along with the number of queries created at that time.
+#[derive(Default)]
+pub struct PendingQueryAges {
+    created: BTreeMap<Instant, usize>,
+}
+
+impl PendingQueryAges {
+    pub fn new() -> Self {
+        PendingQueryAges::default()
+    }
+
+    pub fn insert(&mut self, created_ts: Instant) {
+        *self.created.entry(created_ts).or_insert(0) += 1;
+    }
+
+    pub fn remove(&mut self, created_ts: Instant) {
+        let count = match self.created.get_mut(&created_ts) {
+            None => return,
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+        };
+        if count == 0 {
+            self.created.remove(&created_ts);
+        }
+    }
+
+    /// Returns the creation time of the oldest pending query.
+    pub fn oldest(&self) -> Option<Instant> {
+        self.created.keys().next().cloned()
+    }
+
+    /// Returns the number of seconds elapsed since the oldest pending query
+    /// was first sent, or `0.0` if there are no pending queries.
+    pub fn oldest_age(&self, clock: &Clock) -> f64 {
+        self.oldest()
+            .map_or(0.0, |created_ts| clock.elapsed(created_ts).as_f64())
+    }
+}
+
 #[derive(Clone)]
 pub struct PendingQueries {
-    pub map_arc: Arc<RwLock<HashMap<NormalizedQuestionKey, PendingQuery>>>,
+    pub map_arc: Arc<RwLock<HashMap<PendingQueryKey, PendingQuery>>>,
+    ages_arc: Arc<Mutex<PendingQueryAges>>,
 }
 
 impl PendingQueries {
     pub fn new() -> Self {
         let map_arc = Arc::new(RwLock::new(HashMap::new()));
-        PendingQueries { map_arc: map_arc }
+        let ages_arc = Arc::new(Mutex::new(PendingQueryAges::new()));
+        PendingQueries {
+            map_arc: map_arc,
+            ages_arc: ages_arc,
+        }
+    }
+
+    /// Adds a pending query to the locked `map`.
+    ///
+    /// Queries must be added and removed through these functions, so that
+    /// their creation times remain indexed.
+    pub fn insert(
+        &self,
+        map: &mut HashMap<PendingQueryKey, PendingQuery>,
+        key: PendingQueryKey,
+        pending_query: PendingQuery,
+    ) {
+        let created_ts = pending_query.created_ts;
+        if let Some(prev_pending_query) = map.insert(key, pending_query) {
+            self.ages_arc.lock().remove(prev_pending_query.created_ts);
+        }
+        self.ages_arc.lock().insert(created_ts);
+    }
+
+    /// Removes a pending query from the locked `map`.
+    pub fn remove(
+        &self,
+        map: &mut HashMap<PendingQueryKey, PendingQuery>,
+        key: &PendingQueryKey,
+    ) -> Option<PendingQuery> {
+        let pending_query = map.remove(key);
+        if let Some(ref pending_query) = pending_query {
+            self.ages_arc.lock().remove(pending_query.created_ts);
+        }
+        pending_query
+    }
+
+    /// Returns the number of seconds elapsed since the oldest pending query
+    /// was first sent, or `0.0` if there are no pending queries.
+    pub fn oldest_age(&self, clock: &Clock) -> f64 {
+        self.ages_arc.lock().oldest_age(clock)
     }
 }
@@ -0,0 +1,95 @@
+//! Stream of structured events about client queries, for applications
+//! embedding EdgeDNS
+//!
+//! A `QueryEvents` instance is registered with `EdgeDNS::with_extensions()`,
+//! and any number of subscribers can be attached to it, before or after the
+//! server is started. Each subscriber gets its own bounded channel.
+//!
+//! A `Received` event is emitted for every client query accepted by the UDP
+//! and TCP listeners, and a `Resolved` or `Failed` event for every response
+//! sent to a client. Queries that are dropped (for example when shedding
+//! load) don't get a response event. Malformed and rate limited queries are
+//! rejected before any event is emitted.
+//!
+//! Events are never waited for: if the channel of a subscriber is full, the
+//! event is dropped for that subscriber, and counted in the
+//! `query_events_dropped` metric. Subscribers whose receiver has been dropped
+//! are removed.
+
+use client_query::AnswerSource;
+use dns::NormalizedQuestion;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QueryEventKind {
+    /// A query was received from a client
+    Received,
+    /// A response was sent to the client
+    Resolved,
+    /// A SERVFAIL response was sent to the client
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryEvent {
+    pub kind: QueryEventKind,
+    pub normalized_question: NormalizedQuestion,
+    /// Only known for queries received over UDP
+    pub client_addr: Option<SocketAddr>,
+    /// The response code, for `Resolved` and `Failed` events
+    pub rcode: Option<u8>,
+    /// Where the response comes from, for `Resolved` and `Failed` events
+    pub source: Option<AnswerSource>,
+    /// Time elapsed since the query was received, in seconds
+    pub elapsed: f64,
+    /// UDP payload size advertised by the client, or 512 without EDNS
+    pub client_payload_size: u16,
+    /// UDP payload size advertised to the upstream server, for responses
+    /// coming from an upstream server
+    pub upstream_payload_size: Option<u16>,
+    /// Size of the response sent to the client, for `Resolved` and `Failed`
+    /// events
+    pub response_size: Option<usize>,
+    /// Whether the response sent to the client was truncated, for `Resolved`
+    /// and `Failed` events
+    pub truncated: Option<bool>,
+}
+
+#[derive(Clone, Default)]
+pub struct QueryEvents {
+    subscribers: Arc<Mutex<Vec<SyncSender<QueryEvent>>>>,
+}
+
+impl QueryEvents {
+    pub fn new() -> Self {
+        QueryEvents::default()
+    }
+
+    /// Returns a receiver for all the events emitted from now on. At most
+    /// `capacity` events are queued; further events are dropped until the
+    /// receiver catches up.
+    pub fn subscribe(&self, capacity: usize) -> Receiver<QueryEvent> {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Sends `event` to all the subscribers, and returns the number of
+    /// subscribers it had to be dropped for.
+    pub fn emit(&self, event: QueryEvent) -> usize {
+        let mut subscribers = self.subscribers.lock();
+        let mut dropped = 0;
+        subscribers.retain(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        dropped
+    }
+}
@@ -3,54 +3,113 @@
 //!
 //! The `ResolverCore` class is also responsible for binding the UDP sockets dedicated
 //! to communicating with upstream resolvers.
+//!
+//! The event loop of the resolver periodically records a heartbeat, so that
+//! the webservice can tell if it is still responsive. The age of the oldest
+//! pending query is sampled at the same time.
 
 use cache::Cache;
 use client_queries_handler::ClientQueriesHandler;
 use client_query::ClientQuery;
+use clock::Clock;
 use coarsetime::{Duration, Instant};
 use config::Config;
 use dns::{NormalizedQuestionKey, NormalizedQuestionMinimal};
 use ext_response::ExtResponse;
-use futures::Future;
+use futures::{Future, Stream};
 use futures::sync::mpsc::{channel, Receiver, Sender};
 use futures::sync::oneshot;
+use health_log::HealthLog;
 use jumphash::JumpHasher;
 use log_dnstap;
 use net_helpers::*;
 use nix::sys::socket::{bind, setsockopt, sockopt, InetAddr, SockAddr};
 use parking_lot::RwLock;
 use pending_query::{PendingQueries, PendingQuery};
+use rand;
+use rand::distributions::{IndependentSample, Range};
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::net;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::rc::Rc;
+use std::slice;
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::thread;
-use super::EdgeDNSContext;
+use super::{EdgeDNSContext, HEALTH_CHECK_MS};
+use std::time;
 use tokio_core::reactor::{Core, Handle};
+use tokio_timer::wheel;
 use upstream_server::UpstreamServer;
 use varz::Varz;
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
 pub enum LoadBalancingMode {
     Uniform,
     Fallback,
     P2,
 }
 
+/// What to do with the Client Subnet option of client queries
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum EcsPolicy {
+    Pass,
+    Strip,
+    Overwrite,
+}
+
+/// Address family of the sockets used to send queries to upstream servers
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum UpstreamFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl UpstreamFamily {
+    /// Checks if `addr` can be reached using that family.
+    pub fn reaches(&self, addr: &SocketAddr) -> bool {
+        match *self {
+            UpstreamFamily::Any => true,
+            UpstreamFamily::Ipv4 => addr.is_ipv4(),
+            UpstreamFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Response sent to clients while the maintenance mode is on
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum MaintenanceResponse {
+    Refused,
+    Sinkhole,
+}
+
+/// What to do with queries shed due to overload
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "webservice", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum ShedAction {
+    Drop,
+    Refused,
+}
+
 pub struct ResolverCore {
     pub config: Rc<Config>,
     pub handle: Handle,
     pub dnstap_sender: Option<log_dnstap::Sender>,
     pub net_udp_socket: net::UdpSocket,
-    pub net_ext_udp_sockets_rc: Rc<Vec<net::UdpSocket>>,
+    pub net_ext_udp_sockets_rc: Rc<ExtUdpSockets>,
     pub pending_queries: PendingQueries,
     pub upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
     pub upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
+    pub maintenance_mode: Arc<AtomicBool>,
     pub waiting_clients_count: Rc<AtomicUsize>,
     pub cache: Cache,
     pub varz: Arc<Varz>,
@@ -58,6 +117,36 @@ pub struct ResolverCore {
     pub lbmode: LoadBalancingMode,
     pub upstream_max_failure_duration: Duration,
     pub jumphasher: JumpHasher,
+    pub clock: Arc<Clock>,
+}
+
+/// Sockets used to send queries to upstream servers, by address family
+pub struct ExtUdpSockets {
+    ipv4: Vec<net::UdpSocket>,
+    ipv6: Vec<net::UdpSocket>,
+}
+
+impl ExtUdpSockets {
+    /// Returns all the sockets, starting with IPv4 sockets.
+    pub fn iter<'t>(
+        &'t self,
+    ) -> iter::Chain<slice::Iter<'t, net::UdpSocket>, slice::Iter<'t, net::UdpSocket>> {
+        self.ipv4.iter().chain(self.ipv6.iter())
+    }
+
+    /// Returns a random socket of the same family as `addr`, if there is one.
+    pub fn random_for(&self, addr: &SocketAddr) -> Option<&net::UdpSocket> {
+        let net_ext_udp_sockets = match *addr {
+            SocketAddr::V4(_) => &self.ipv4,
+            SocketAddr::V6(_) => &self.ipv6,
+        };
+        if net_ext_udp_sockets.is_empty() {
+            return None;
+        }
+        let random_token_range = Range::new(0usize, net_ext_udp_sockets.len());
+        let random_token = random_token_range.ind_sample(&mut rand::thread_rng());
+        Some(&net_ext_udp_sockets[random_token])
+    }
 }
 
 impl ResolverCore {
@@ -70,33 +159,63 @@ impl ResolverCore {
         let (resolver_tx, resolver_rx): (Sender<ClientQuery>, Receiver<ClientQuery>) =
             channel(edgedns_context.config.max_active_queries);
         let pending_queries = PendingQueries::new();
-        let mut net_ext_udp_sockets: Vec<net::UdpSocket> = Vec::new();
         let ports = if config.udp_ports > 65535 - 1024 {
             65535 - 1024
         } else {
             config.udp_ports
         };
-        for port in 1024..1024 + ports {
-            if (port + 1) % 1024 == 0 {
-                info!("Binding ports... {}/{}", port, ports)
+        let upstream_interface = config.upstream_interface.as_ref().map(|x| x.as_str());
+        let mut net_ext_udp_sockets = ExtUdpSockets {
+            ipv4: Vec::new(),
+            ipv6: Vec::new(),
+        };
+        for &family in &[UpstreamFamily::Ipv4, UpstreamFamily::Ipv6] {
+            if config.upstream_family != UpstreamFamily::Any && config.upstream_family != family {
+                continue;
+            }
+            let source_addr = match (family, config.upstream_source_addr) {
+                (UpstreamFamily::Ipv4, source_addr @ IpAddr::V4(_)) |
+                (UpstreamFamily::Ipv6, source_addr @ IpAddr::V6(_)) => source_addr,
+                (UpstreamFamily::Ipv6, _) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+                _ => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            };
+            net_socket_udp_check_source(source_addr, upstream_interface)?;
+            let family_sockets = match family {
+                UpstreamFamily::Ipv6 => &mut net_ext_udp_sockets.ipv6,
+                _ => &mut net_ext_udp_sockets.ipv4,
+            };
+            for port in 1024..1024 + ports {
+                if (port + 1) % 1024 == 0 {
+                    info!("Binding ports... {}/{}", port, ports)
+                }
+                if let Ok(net_ext_udp_socket) = net_socket_udp_bound(
+                    SocketAddr::new(source_addr, port),
+                    upstream_interface,
+                    config.udp_recv_buffer,
+                    config.udp_send_buffer,
+                ) {
+                    family_sockets.push(net_ext_udp_socket);
+                }
             }
-            if let Ok(net_ext_udp_socket) = net_socket_udp_bound(port) {
-                net_ext_udp_sockets.push(net_ext_udp_socket);
+            if family_sockets.is_empty() {
+                panic!("Couldn't bind any ports");
             }
         }
-        if net_ext_udp_sockets.is_empty() {
-            panic!("Couldn't bind any ports");
+        if let Some(net_ext_udp_socket) = net_ext_udp_sockets.iter().next() {
+            if let Ok((recv_buffer_size, send_buffer_size)) =
+                socket_udp_buffer_sizes(net_ext_udp_socket.as_raw_fd())
+            {
+                info!(
+                    "Upstream UDP sockets buffers: {} bytes to receive, {} bytes to send",
+                    recv_buffer_size,
+                    send_buffer_size
+                );
+            }
         }
-        let upstream_servers: Vec<UpstreamServer> = config
-            .upstream_servers
-            .iter()
-            .map(|s| {
-                UpstreamServer::new(s).expect("Invalid upstream server address")
-            })
-            .collect();
-        let upstream_servers_live: Vec<usize> = (0..config.upstream_servers.len()).collect();
-        let upstream_servers_live_arc = Arc::new(RwLock::new(upstream_servers_live));
-        let upstream_servers_arc = Arc::new(RwLock::new(upstream_servers));
+        let upstream_servers_arc = edgedns_context.upstream_servers_arc.clone();
+        let upstream_servers_live_arc = edgedns_context.upstream_servers_live_arc.clone();
+        let maintenance_mode = edgedns_context.maintenance_mode.clone();
+        let resolver_heartbeat = edgedns_context.resolver_heartbeat.clone();
         if config.decrement_ttl {
             info!("Resolver mode: TTL will be automatically decremented");
         }
@@ -107,6 +226,7 @@ impl ResolverCore {
         let decrement_ttl = config.decrement_ttl;
         let lbmode = config.lbmode;
         let upstream_max_failure_duration = config.upstream_max_failure_duration;
+        let clock = edgedns_context.clock.clone();
         thread::Builder::new()
             .name("resolver".to_string())
             .spawn(move || {
@@ -121,6 +241,7 @@ impl ResolverCore {
                     pending_queries: pending_queries,
                     upstream_servers_arc: upstream_servers_arc,
                     upstream_servers_live_arc: upstream_servers_live_arc,
+                    maintenance_mode: maintenance_mode,
                     waiting_clients_count: Rc::new(AtomicUsize::new(0)),
                     cache: cache,
                     varz: varz,
@@ -128,23 +249,40 @@ impl ResolverCore {
                     lbmode: lbmode,
                     upstream_max_failure_duration: upstream_max_failure_duration,
                     jumphasher: JumpHasher::default(),
+                    clock: clock,
                 };
                 info!("Registering UDP ports...");
-                for net_ext_udp_socket in &*resolver_core.net_ext_udp_sockets_rc {
-                    let ext_response_listener = ExtResponse::new(
-                        &resolver_core,
-                        net_ext_udp_socket.local_addr().unwrap().port(),
-                    );
+                for net_ext_udp_socket in resolver_core.net_ext_udp_sockets_rc.iter() {
+                    let ext_response_listener =
+                        ExtResponse::new(&resolver_core, net_ext_udp_socket);
                     let stream =
                         ext_response_listener.fut_process_stream(&handle, net_ext_udp_socket);
                     handle.spawn(stream.map_err(|_| {}).map(|_| {}));
                 }
                 let client_queries_handler = ClientQueriesHandler::new(&resolver_core);
-                let stream = client_queries_handler.fut_process_stream(&handle, resolver_rx);
+                let stream = client_queries_handler.fut_process_stream(resolver_rx);
                 event_loop
                     .handle()
                     .spawn(stream.map_err(|_| {}).map(|_| {}));
                 info!("UDP ports registered");
+                if resolver_core.config.health_log_enabled {
+                    let health_log = HealthLog::new(&resolver_core);
+                    handle.spawn(health_log.fut_process_stream().map_err(|_| {}));
+                }
+                let pending_queries = resolver_core.pending_queries.clone();
+                let varz = resolver_core.varz.clone();
+                let clock = resolver_core.clock.clone();
+                let heartbeat = wheel()
+                    .build()
+                    .interval(time::Duration::from_millis(HEALTH_CHECK_MS / 10))
+                    .map_err(|_| {})
+                    .for_each(move |_| {
+                        *resolver_heartbeat.write() = Instant::recent();
+                        varz.pending_queries_oldest_age
+                            .set(pending_queries.oldest_age(&*clock));
+                        Ok(())
+                    });
+                handle.spawn(heartbeat);
                 loop {
                     event_loop.turn(None)
                 }
@@ -154,8 +292,44 @@ impl ResolverCore {
     }
 }
 
-fn net_socket_udp_bound(port: u16) -> io::Result<net::UdpSocket> {
-    let actual = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
+/// Makes sure that sockets can be bound to the configured source address and
+/// interface before binding the ext ports, so that a typo or an address not
+/// assigned to this host is reported instead of leaving no usable sockets.
+fn net_socket_udp_check_source(source_addr: IpAddr, interface: Option<&str>) -> io::Result<()> {
+    let net_socket = net::UdpSocket::bind(SocketAddr::new(source_addr, 0)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Unable to use [{}] as a source address for upstream queries: {}",
+                source_addr,
+                e
+            ),
+        )
+    })?;
+    if let Some(interface) = interface {
+        socket_bind_to_device(net_socket.as_raw_fd(), interface).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Unable to bind upstream sockets to the [{}] interface: {}",
+                    interface,
+                    e
+                ),
+            )
+        })?;
+    }
+    if !source_addr.is_unspecified() {
+        info!("Upstream queries will be sent from [{}]", source_addr);
+    }
+    Ok(())
+}
+
+fn net_socket_udp_bound(
+    actual: SocketAddr,
+    interface: Option<&str>,
+    recv_buffer_size: usize,
+    send_buffer_size: usize,
+) -> io::Result<net::UdpSocket> {
     let nix_addr = SockAddr::Inet(InetAddr::from_std(&actual));
     let socket_fd = match actual {
         SocketAddr::V4(_) => socket_udp_v4()?,
@@ -164,7 +338,11 @@ fn net_socket_udp_bound(port: u16) -> io::Result<net::UdpSocket> {
     set_nonblock(socket_fd)?;
     setsockopt(socket_fd, sockopt::ReuseAddr, &true)?;
     setsockopt(socket_fd, sockopt::ReusePort, &true)?;
-    socket_udp_set_buffer_size(socket_fd);
+    socket_udp_set_buffer_size(socket_fd, recv_buffer_size, send_buffer_size);
+    socket_udp_set_recverr(socket_fd, actual.is_ipv6());
+    if let Some(interface) = interface {
+        socket_bind_to_device(socket_fd, interface)?;
+    }
     bind(socket_fd, &nix_addr)?;
     let net_socket: net::UdpSocket = unsafe { net::UdpSocket::from_raw_fd(socket_fd) };
     Ok(net_socket)
@@ -0,0 +1,94 @@
+//! Shadow cache, to validate a new cache implementation with real traffic
+//!
+//! When enabled, every response stored in the main cache is also stored in
+//! the shadow cache, and every lookup is also done in the shadow cache. The
+//! results are compared, and differences are logged and counted, but only
+//! the entries of the main cache are ever served.
+//!
+//! The shadow cache is currently a sharded hash map, with a fixed capacity
+//! per shard, and arbitrary eviction when a shard is full. Since the eviction
+//! policy differs from the CLOCK-Pro algorithm of the main cache, entries
+//! missing from one of the caches are expected, and counted separately from
+//! entries that are present in both caches, but with different content.
+//!
+//! This is only compiled in with the `shadow-cache` feature.
+
+use cache::CacheEntry;
+use dns::NormalizedQuestionKey;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use varz::Varz;
+
+const SHADOW_CACHE_SHARDS: usize = 16;
+
+#[derive(Clone)]
+pub struct ShadowCache {
+    shards: Arc<Vec<Mutex<HashMap<NormalizedQuestionKey, CacheEntry>>>>,
+    shard_capacity: usize,
+    varz: Arc<Varz>,
+}
+
+impl ShadowCache {
+    pub fn new(capacity: usize, varz: Arc<Varz>) -> Self {
+        let shards = (0..SHADOW_CACHE_SHARDS)
+            .map(|_| Mutex::new(HashMap::new()))
+            .collect();
+        ShadowCache {
+            shards: Arc::new(shards),
+            shard_capacity: (capacity / SHADOW_CACHE_SHARDS).max(1),
+            varz: varz,
+        }
+    }
+
+    fn shard(&self, normalized_question_key: &NormalizedQuestionKey) -> usize {
+        let mut hasher = DefaultHasher::new();
+        normalized_question_key.hash(&mut hasher);
+        hasher.finish() as usize % SHADOW_CACHE_SHARDS
+    }
+
+    pub fn insert(&self, normalized_question_key: NormalizedQuestionKey, cache_entry: CacheEntry) {
+        let mut shard = self.shards[self.shard(&normalized_question_key)].lock();
+        if shard.len() >= self.shard_capacity && !shard.contains_key(&normalized_question_key) {
+            let evicted_key = shard.keys().next().cloned();
+            if let Some(evicted_key) = evicted_key {
+                shard.remove(&evicted_key);
+            }
+        }
+        shard.insert(normalized_question_key, cache_entry);
+    }
+
+    /// Looks up `normalized_question_key` in the shadow cache, and compares
+    /// the result with `cache_entry`, the entry found in the main cache.
+    pub fn compare(
+        &self,
+        normalized_question_key: &NormalizedQuestionKey,
+        cache_entry: Option<&CacheEntry>,
+    ) {
+        let shard = self.shards[self.shard(normalized_question_key)].lock();
+        match (cache_entry, shard.get(normalized_question_key)) {
+            (None, None) => {}
+            (Some(cache_entry), Some(shadow_cache_entry)) => {
+                if cache_entry.packet != shadow_cache_entry.packet ||
+                    cache_entry.expiration != shadow_cache_entry.expiration
+                {
+                    debug!(
+                        "Shadow cache mismatch for {:?}",
+                        normalized_question_key
+                    );
+                    self.varz.cache_shadow_mismatches.inc();
+                }
+            }
+            (cache_entry, _) => {
+                debug!(
+                    "Entry for {:?} only present in the {} cache",
+                    normalized_question_key,
+                    if cache_entry.is_some() { "main" } else { "shadow" }
+                );
+                self.varz.cache_shadow_missing.inc();
+            }
+        }
+    }
+}
@@ -7,10 +7,13 @@ use bytes::BufMut;
 use cache::Cache;
 use client_query::*;
 use dns::{self, NormalizedQuestion};
+use extensions::{QueryPreprocessor, ResponseRewriter};
 use futures::future::{self, Future};
 use futures::Sink;
 use futures::stream::Stream;
 use futures::sync::mpsc::{channel, Sender};
+use maintenance::Maintenance;
+use query_events::{QueryEventKind, QueryEvents};
 use std::cell::RefCell;
 use std::io::{self, Read, Write};
 use std::net::{self, SocketAddr};
@@ -36,6 +39,21 @@ struct TcpAcceptor {
     cache: Cache,
     varz: Arc<Varz>,
     tcp_arbitrator: TcpArbitrator,
+    formerr_on_malformed_queries: bool,
+    debug_answer_source: bool,
+    stale_while_revalidate: bool,
+    stale_ttl: u32,
+    stale_refresh_ttl: u32,
+    max_client_ttl: u32,
+    answer_order: AnswerOrder,
+    compress_responses: bool,
+    nsid: Option<Arc<Vec<u8>>>,
+    address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    dns64: Option<Dns64>,
+    maintenance: Maintenance,
+    query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    response_rewriter: Option<Arc<ResponseRewriter>>,
+    query_events: Option<QueryEvents>,
 }
 
 pub struct TcpAcceptorCore {
@@ -47,6 +65,21 @@ pub struct TcpAcceptorCore {
     varz: Arc<Varz>,
     service_ready_tx: Option<mpsc::SyncSender<u8>>,
     tcp_arbitrator: TcpArbitrator,
+    formerr_on_malformed_queries: bool,
+    debug_answer_source: bool,
+    stale_while_revalidate: bool,
+    stale_ttl: u32,
+    stale_refresh_ttl: u32,
+    max_client_ttl: u32,
+    answer_order: AnswerOrder,
+    compress_responses: bool,
+    nsid: Option<Arc<Vec<u8>>>,
+    address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    dns64: Option<Dns64>,
+    maintenance: Maintenance,
+    query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    response_rewriter: Option<Arc<ResponseRewriter>>,
+    query_events: Option<QueryEvents>,
 }
 
 struct TcpClientQuery {
@@ -56,6 +89,20 @@ struct TcpClientQuery {
     resolver_tx: Sender<ClientQuery>,
     cache: Cache,
     varz: Arc<Varz>,
+    debug_answer_source: bool,
+    stale_while_revalidate: bool,
+    stale_ttl: u32,
+    stale_refresh_ttl: u32,
+    max_client_ttl: u32,
+    answer_order: AnswerOrder,
+    compress_responses: bool,
+    nsid: Option<Arc<Vec<u8>>>,
+    address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    dns64: Option<Dns64>,
+    maintenance: Maintenance,
+    query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    response_rewriter: Option<Arc<ResponseRewriter>>,
+    query_events: Option<QueryEvents>,
 }
 
 impl TcpClientQuery {
@@ -67,16 +114,78 @@ impl TcpClientQuery {
             resolver_tx: tcp_acceptor.resolver_tx.clone(),
             cache: tcp_acceptor.cache.clone(),
             varz: tcp_acceptor.varz.clone(),
+            debug_answer_source: tcp_acceptor.debug_answer_source,
+            stale_while_revalidate: tcp_acceptor.stale_while_revalidate,
+            stale_ttl: tcp_acceptor.stale_ttl,
+            stale_refresh_ttl: tcp_acceptor.stale_refresh_ttl,
+            max_client_ttl: tcp_acceptor.max_client_ttl,
+            answer_order: tcp_acceptor.answer_order,
+            compress_responses: tcp_acceptor.compress_responses,
+            nsid: tcp_acceptor.nsid.clone(),
+            address_family_filter: tcp_acceptor.address_family_filter.clone(),
+            dns64: tcp_acceptor.dns64.clone(),
+            maintenance: tcp_acceptor.maintenance.clone(),
+            query_preprocessor: tcp_acceptor.query_preprocessor.clone(),
+            response_rewriter: tcp_acceptor.response_rewriter.clone(),
+            query_events: tcp_acceptor.query_events.clone(),
         }
     }
 
+    fn fut_send_formerr(self, query_packet: &[u8]) -> Box<Future<Item = (), Error = io::Error>> {
+        let formerr_packet = match dns::build_formerr_packet(query_packet) {
+            Ok(formerr_packet) => formerr_packet,
+            Err(_) => return Box::new(future::ok(())),
+        };
+        let packet_len = formerr_packet.len();
+        let mut tcp_packet = vec![0; 2 + packet_len];
+        BigEndian::write_u16(&mut tcp_packet, packet_len as u16);
+        tcp_packet[2..].copy_from_slice(&formerr_packet);
+        Box::new(write_all(self.wh, tcp_packet).map(|_| {}))
+    }
+
     fn fut_process_query(
         mut self,
-        normalized_question: NormalizedQuestion,
+        mut normalized_question: NormalizedQuestion,
     ) -> Box<Future<Item = (), Error = io::Error>> {
+        let mut original_question = None;
+        let preprocessed_packet = match self.query_preprocessor {
+            None => None,
+            Some(ref query_preprocessor) => {
+                let question = normalized_question.clone();
+                let preprocessed_packet =
+                    query_preprocessor.preprocess(&mut normalized_question, None);
+                if normalized_question.qname != question.qname ||
+                    normalized_question.qtype != question.qtype
+                {
+                    original_question = Some(question);
+                }
+                preprocessed_packet
+            }
+        };
         let (tcpclient_tx, tcpclient_rx) = channel(1);
-        let cache_entry = self.cache.get2(&normalized_question);
-        let client_query = ClientQuery::tcp(tcpclient_tx, normalized_question, self.varz.clone());
+        // Cached responses must not be served during the maintenance either
+        let in_maintenance = preprocessed_packet.is_none() &&
+            self.maintenance.is_in_maintenance(&normalized_question);
+        let cache_entry = if preprocessed_packet.is_some() || in_maintenance {
+            None
+        } else {
+            self.cache.get2(&normalized_question)
+        };
+        let annotate_source =
+            self.debug_answer_source && normalized_question.flags & dns::DNS_FLAG_Z != 0;
+        let mut client_query =
+            ClientQuery::tcp(tcpclient_tx, normalized_question, self.varz.clone());
+        client_query.original_question = original_question;
+        client_query.annotate_source = annotate_source;
+        client_query.response_rewriter = self.response_rewriter.clone();
+        client_query.max_client_ttl = self.max_client_ttl;
+        client_query.answer_order = self.answer_order;
+        client_query.compress_responses = self.compress_responses;
+        client_query.nsid = self.nsid.clone();
+        client_query.address_family_filter = self.address_family_filter.clone();
+        client_query.dns64 = self.dns64.clone();
+        client_query.query_events = self.query_events.clone();
+        client_query.emit_event(QueryEventKind::Received, None, None);
         let wh_cell = RefCell::new(self.wh);
         let fut = tcpclient_rx
             .into_future()
@@ -95,14 +204,45 @@ impl TcpClientQuery {
                     .map(|_| {})
                     .map_err(|_| {})
             });
+        if let Some(mut packet) = preprocessed_packet {
+            self.handle.spawn(fut.map_err(|_| {}));
+            return client_query.response_send(&mut packet, None, AnswerSource::Synth);
+        }
+        if in_maintenance {
+            if let Ok(mut packet) = self.maintenance
+                .build_response_packet(&client_query.normalized_question)
+            {
+                debug!("Maintenance mode - Responding with a static response");
+                self.handle.spawn(fut.map_err(|_| {}));
+                return client_query.response_send(&mut packet, None, AnswerSource::Synth);
+            }
+        }
         if let Some(mut cache_entry) = cache_entry {
             if !cache_entry.is_expired() {
                 self.varz.client_queries_cached.inc();
                 self.handle.spawn(fut.map_err(|_| {}));
-                return client_query.response_send(&mut cache_entry.packet, None);
+                let source = cache_entry.source();
+                return client_query.response_send(&mut cache_entry.packet, None, source);
             }
             debug!("expired");
             self.varz.client_queries_expired.inc();
+            if self.stale_while_revalidate && cache_entry.is_within_stale_window(self.stale_ttl) {
+                debug!("Serving a stale entry while refreshing it");
+                self.varz.client_queries_served_stale.inc();
+                let _ = dns::set_ttl(&mut cache_entry.packet, self.stale_refresh_ttl);
+                self.handle.spawn(fut.map_err(|_| {}));
+                let fut_refresh = self.resolver_tx
+                    .clone()
+                    .send(client_query.refresh())
+                    .map(|_| {})
+                    .map_err(|_| {});
+                self.handle.spawn(fut_refresh);
+                return client_query.response_send(
+                    &mut cache_entry.packet,
+                    None,
+                    AnswerSource::Stale,
+                );
+            }
         }
         let fut_send = self.resolver_tx.send(client_query).map_err(|_| {});
         let futs = fut.join(fut_send);
@@ -123,6 +263,21 @@ impl TcpAcceptor {
             cache: tcp_acceptor_core.cache.clone(),
             varz: tcp_acceptor_core.varz.clone(),
             tcp_arbitrator: tcp_acceptor_core.tcp_arbitrator.clone(),
+            formerr_on_malformed_queries: tcp_acceptor_core.formerr_on_malformed_queries,
+            debug_answer_source: tcp_acceptor_core.debug_answer_source,
+            stale_while_revalidate: tcp_acceptor_core.stale_while_revalidate,
+            stale_ttl: tcp_acceptor_core.stale_ttl,
+            stale_refresh_ttl: tcp_acceptor_core.stale_refresh_ttl,
+            max_client_ttl: tcp_acceptor_core.max_client_ttl,
+            answer_order: tcp_acceptor_core.answer_order,
+            compress_responses: tcp_acceptor_core.compress_responses,
+            nsid: tcp_acceptor_core.nsid.clone(),
+            address_family_filter: tcp_acceptor_core.address_family_filter.clone(),
+            dns64: tcp_acceptor_core.dns64.clone(),
+            maintenance: tcp_acceptor_core.maintenance.clone(),
+            query_preprocessor: tcp_acceptor_core.query_preprocessor.clone(),
+            response_rewriter: tcp_acceptor_core.response_rewriter.clone(),
+            query_events: tcp_acceptor_core.query_events.clone(),
         }
     }
 
@@ -141,6 +296,8 @@ impl TcpAcceptor {
         );
         let varz = self.varz.clone();
         varz.client_queries_tcp.inc();
+        varz.tcp_client_connections_opened.inc();
+        varz.tcp_client_connections.inc();
         let (rh, wh) = client.split();
         let fut_expected_len = read_exact(rh, vec![0u8; 2]).and_then(move |(rh, len_buf)| {
             let expected_len = BigEndian::read_u16(&len_buf) as usize;
@@ -158,13 +315,21 @@ impl TcpAcceptor {
         let fut_packet_read =
             fut_expected_len.and_then(|(rh, expected_len)| read_exact(rh, vec![0u8; expected_len]));
         let varz = self.varz.clone();
+        let formerr_on_malformed_queries = self.formerr_on_malformed_queries;
         let tcp_client_query = TcpClientQuery::new(self, wh);
         let fut_packet = fut_packet_read.and_then(move |(rh, packet)| {
+            varz.client_query_sizes.observe(packet.len() as f64);
             let normalized_question = match dns::normalize(&packet, true) {
                 Ok(normalized_question) => normalized_question,
                 Err(e) => {
                     debug!("Error while parsing the question: {}", e);
                     varz.client_queries_errors.inc();
+                    varz.malformed_queries.inc();
+                    if formerr_on_malformed_queries || dns::qdcount(&packet) != 1 ||
+                        dns::is_name_limit_error(e)
+                    {
+                        return tcp_client_query.fut_send_formerr(&packet);
+                    }
                     return Box::new(future::err(io::Error::new(
                         io::ErrorKind::InvalidInput,
                         "Suspicious query",
@@ -182,8 +347,14 @@ impl TcpAcceptor {
             future::ok(())
         });
         let fut_session_rx = session_rx.map(|_| {});
+        let varz = self.varz.clone();
         let fut = fut_session_rx
             .select(fut_with_timeout)
+            .then(move |res| {
+                varz.tcp_client_connections.dec();
+                varz.tcp_client_connections_closed.inc();
+                res
+            })
             .map(|_| {})
             .map_err(|_| io::Error::last_os_error());
         Box::new(fut) as Box<Future<Item = _, Error = _>>
@@ -235,6 +406,25 @@ impl TcpAcceptorCore {
         let cache = edgedns_context.cache.clone();
         let varz = edgedns_context.varz.clone();
         let tcp_arbitrator = edgedns_context.tcp_arbitrator.clone();
+        let formerr_on_malformed_queries = edgedns_context.config.formerr_on_malformed_queries;
+        let debug_answer_source = edgedns_context.config.debug_answer_source;
+        let stale_while_revalidate = edgedns_context.config.stale_while_revalidate;
+        let stale_ttl = edgedns_context.config.stale_ttl;
+        let stale_refresh_ttl = edgedns_context.config.stale_refresh_ttl;
+        let max_client_ttl = edgedns_context.config.max_client_ttl;
+        let answer_order = edgedns_context.config.answer_order;
+        let compress_responses = edgedns_context.config.compress_responses;
+        let nsid = edgedns_context.config.nsid.clone().map(Arc::new);
+        let address_family_filter =
+            AddressFamilyFilter::new(&edgedns_context.config).map(Arc::new);
+        let dns64 = Dns64::new(&edgedns_context.config, resolver_tx.clone());
+        let maintenance = Maintenance::new(
+            &edgedns_context.config,
+            edgedns_context.maintenance_mode.clone(),
+        );
+        let query_preprocessor = edgedns_context.extensions.query_preprocessor.clone();
+        let response_rewriter = edgedns_context.extensions.response_rewriter.clone();
+        let query_events = edgedns_context.extensions.query_events.clone();
         let timer = wheel()
             .tick_duration(time::Duration::from_millis(MAX_TCP_IDLE_MS / 2))
             .max_timeout(time::Duration::from_millis(MAX_TCP_IDLE_MS))
@@ -252,6 +442,21 @@ impl TcpAcceptorCore {
                     service_ready_tx: Some(service_ready_tx),
                     varz: varz,
                     tcp_arbitrator: tcp_arbitrator,
+                    formerr_on_malformed_queries: formerr_on_malformed_queries,
+                    debug_answer_source: debug_answer_source,
+                    stale_while_revalidate: stale_while_revalidate,
+                    stale_ttl: stale_ttl,
+                    stale_refresh_ttl: stale_refresh_ttl,
+                    max_client_ttl: max_client_ttl,
+                    answer_order: answer_order,
+                    compress_responses: compress_responses,
+                    nsid: nsid,
+                    address_family_filter: address_family_filter,
+                    dns64: dns64,
+                    maintenance: maintenance,
+                    query_preprocessor: query_preprocessor,
+                    response_rewriter: response_rewriter,
+                    query_events: query_events,
                 };
                 let tcp_acceptor = TcpAcceptor::new(&tcp_acceptor_core);
                 tcp_acceptor_core
@@ -12,12 +12,17 @@
 
 use cache::Cache;
 use client_query::*;
+use client_ratelimiter::{ClientRateLimiter, RateLimitVerdict};
+use duplicate_queries::DuplicateQueries;
 use dns;
+use extensions::{QueryPreprocessor, ResponseRewriter};
 use futures::Sink;
 use futures::future::{self, Future};
 use futures::oneshot;
 use futures::stream::Stream;
 use futures::sync::mpsc::Sender;
+use maintenance::Maintenance;
+use query_events::{QueryEventKind, QueryEvents};
 use std::io;
 use std::net::{self, SocketAddr};
 use std::rc::Rc;
@@ -35,6 +40,24 @@ struct UdpAcceptor {
     resolver_tx: Sender<ClientQuery>,
     cache: Cache,
     varz: Arc<Varz>,
+    formerr_on_malformed_queries: bool,
+    debug_answer_source: bool,
+    stale_while_revalidate: bool,
+    stale_ttl: u32,
+    stale_refresh_ttl: u32,
+    max_client_ttl: u32,
+    answer_order: AnswerOrder,
+    compress_responses: bool,
+    max_client_udp_payload: u16,
+    nsid: Option<Arc<Vec<u8>>>,
+    address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    dns64: Option<Dns64>,
+    client_ratelimiter: Option<ClientRateLimiter>,
+    duplicate_queries: Option<DuplicateQueries>,
+    maintenance: Maintenance,
+    query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    response_rewriter: Option<Arc<ResponseRewriter>>,
+    query_events: Option<QueryEvents>,
 }
 
 pub struct UdpAcceptorCore {
@@ -42,6 +65,24 @@ pub struct UdpAcceptorCore {
     resolver_tx: Sender<ClientQuery>,
     cache: Cache,
     varz: Arc<Varz>,
+    formerr_on_malformed_queries: bool,
+    debug_answer_source: bool,
+    stale_while_revalidate: bool,
+    stale_ttl: u32,
+    stale_refresh_ttl: u32,
+    max_client_ttl: u32,
+    answer_order: AnswerOrder,
+    compress_responses: bool,
+    max_client_udp_payload: u16,
+    nsid: Option<Arc<Vec<u8>>>,
+    address_family_filter: Option<Arc<AddressFamilyFilter>>,
+    dns64: Option<Dns64>,
+    client_ratelimiter: Option<ClientRateLimiter>,
+    duplicate_queries: Option<DuplicateQueries>,
+    maintenance: Maintenance,
+    query_preprocessor: Option<Arc<QueryPreprocessor>>,
+    response_rewriter: Option<Arc<ResponseRewriter>>,
+    query_events: Option<QueryEvents>,
     service_ready_tx: Option<mpsc::SyncSender<u8>>,
 }
 
@@ -55,6 +96,24 @@ impl UdpAcceptor {
             resolver_tx: udp_acceptor_core.resolver_tx.clone(),
             cache: udp_acceptor_core.cache.clone(),
             varz: udp_acceptor_core.varz.clone(),
+            formerr_on_malformed_queries: udp_acceptor_core.formerr_on_malformed_queries,
+            debug_answer_source: udp_acceptor_core.debug_answer_source,
+            stale_while_revalidate: udp_acceptor_core.stale_while_revalidate,
+            stale_ttl: udp_acceptor_core.stale_ttl,
+            stale_refresh_ttl: udp_acceptor_core.stale_refresh_ttl,
+            max_client_ttl: udp_acceptor_core.max_client_ttl,
+            answer_order: udp_acceptor_core.answer_order,
+            compress_responses: udp_acceptor_core.compress_responses,
+            max_client_udp_payload: udp_acceptor_core.max_client_udp_payload,
+            nsid: udp_acceptor_core.nsid.clone(),
+            address_family_filter: udp_acceptor_core.address_family_filter.clone(),
+            dns64: udp_acceptor_core.dns64.clone(),
+            client_ratelimiter: udp_acceptor_core.client_ratelimiter.clone(),
+            duplicate_queries: udp_acceptor_core.duplicate_queries.clone(),
+            maintenance: udp_acceptor_core.maintenance.clone(),
+            query_preprocessor: udp_acceptor_core.query_preprocessor.clone(),
+            response_rewriter: udp_acceptor_core.response_rewriter.clone(),
+            query_events: udp_acceptor_core.query_events.clone(),
         }
     }
 
@@ -70,24 +129,146 @@ impl UdpAcceptor {
             self.varz.client_queries_errors.inc();
             return Box::new(future::ok(())) as Box<Future<Item = _, Error = _>>;
         }
-        let normalized_question = match dns::normalize(&packet, true) {
+        self.varz.client_query_sizes.observe(count as f64);
+        let mut normalized_question = match dns::normalize(&packet, true) {
             Ok(normalized_question) => normalized_question,
             Err(e) => {
                 debug!("Error while parsing the question: {}", e);
                 self.varz.client_queries_errors.inc();
+                self.varz.malformed_queries.inc();
+                // Only single-question queries are supported, and other ones
+                // are always answered, so that clients don't retry them. So
+                // are names exceeding the protocol limits.
+                if self.formerr_on_malformed_queries || dns::qdcount(&packet) != 1 ||
+                    dns::is_name_limit_error(e)
+                {
+                    if let Ok(formerr_packet) = dns::build_formerr_packet(&packet) {
+                        let _ = self.net_udp_socket.send_to(&formerr_packet, client_addr);
+                    }
+                }
                 return Box::new(future::ok(())) as Box<Future<Item = _, Error = _>>;
             }
         };
-        let cache_entry = self.cache.get2(&normalized_question);
-        let client_query = ClientQuery::udp(client_addr, normalized_question, self.varz.clone());
+        let client_payload_size = normalized_question.payload_size;
+        // Large responses are truncated instead of being fragmented
+        if normalized_question.payload_size > self.max_client_udp_payload {
+            normalized_question.payload_size = self.max_client_udp_payload;
+        }
+        if let Some(ref client_ratelimiter) = self.client_ratelimiter {
+            let verdict = client_ratelimiter.check(client_addr.ip());
+            if verdict != RateLimitVerdict::Allow {
+                debug!("Client {} is rate limited", client_addr.ip());
+                self.varz.client_queries_ratelimited.inc();
+                let response = match verdict {
+                    RateLimitVerdict::Refuse => {
+                        dns::build_refused_packet(&normalized_question).ok()
+                    }
+                    RateLimitVerdict::Truncate => dns::build_tc_packet(&normalized_question).ok(),
+                    _ => None,
+                };
+                if let Some(response) = response {
+                    let _ = self.net_udp_socket.send_to(&response, client_addr);
+                }
+                return Box::new(future::ok(())) as Box<Future<Item = _, Error = _>>;
+            }
+        }
+        let mut tracked_query = None;
+        if let Some(ref duplicate_queries) = self.duplicate_queries {
+            tracked_query = duplicate_queries.track(client_addr, &normalized_question);
+            if tracked_query.is_none() {
+                debug!("Duplicate query from {}", client_addr);
+                self.varz.client_queries_duplicate.inc();
+                return Box::new(future::ok(())) as Box<Future<Item = _, Error = _>>;
+            }
+        }
+        let mut original_question = None;
+        let preprocessed_packet = match self.query_preprocessor {
+            None => None,
+            Some(ref query_preprocessor) => {
+                let question = normalized_question.clone();
+                let preprocessed_packet =
+                    query_preprocessor.preprocess(&mut normalized_question, Some(client_addr));
+                if normalized_question.qname != question.qname ||
+                    normalized_question.qtype != question.qtype
+                {
+                    original_question = Some(question);
+                }
+                preprocessed_packet
+            }
+        };
+        // Cached responses must not be served during the maintenance either
+        let in_maintenance = preprocessed_packet.is_none() &&
+            self.maintenance.is_in_maintenance(&normalized_question);
+        let cache_entry = if preprocessed_packet.is_some() || in_maintenance {
+            None
+        } else {
+            self.cache.get2(&normalized_question)
+        };
+        let annotate_source =
+            self.debug_answer_source && normalized_question.flags & dns::DNS_FLAG_Z != 0;
+        let mut client_query =
+            ClientQuery::udp(client_addr, normalized_question, self.varz.clone());
+        client_query.client_payload_size = client_payload_size;
+        client_query.original_question = original_question;
+        client_query.tracked_query = tracked_query;
+        client_query.annotate_source = annotate_source;
+        client_query.response_rewriter = self.response_rewriter.clone();
+        client_query.max_client_ttl = self.max_client_ttl;
+        client_query.answer_order = self.answer_order;
+        client_query.compress_responses = self.compress_responses;
+        client_query.nsid = self.nsid.clone();
+        client_query.address_family_filter = self.address_family_filter.clone();
+        client_query.dns64 = self.dns64.clone();
+        client_query.query_events = self.query_events.clone();
+        client_query.emit_event(QueryEventKind::Received, None, None);
+        if let Some(mut packet) = preprocessed_packet {
+            return client_query.response_send(
+                &mut packet,
+                Some(&self.net_udp_socket),
+                AnswerSource::Synth,
+            );
+        }
+        if in_maintenance {
+            if let Ok(mut packet) = self.maintenance
+                .build_response_packet(&client_query.normalized_question)
+            {
+                debug!("Maintenance mode - Responding with a static response");
+                return client_query.response_send(
+                    &mut packet,
+                    Some(&self.net_udp_socket),
+                    AnswerSource::Synth,
+                );
+            }
+        }
         if let Some(mut cache_entry) = cache_entry {
             if !cache_entry.is_expired() {
                 self.varz.client_queries_cached.inc();
-                return client_query
-                    .response_send(&mut cache_entry.packet, Some(&self.net_udp_socket));
+                let source = cache_entry.source();
+                return client_query.response_send(
+                    &mut cache_entry.packet,
+                    Some(&self.net_udp_socket),
+                    source,
+                );
             }
             debug!("expired");
             self.varz.client_queries_expired.inc();
+            if self.stale_while_revalidate && cache_entry.is_within_stale_window(self.stale_ttl) {
+                debug!("Serving a stale entry while refreshing it");
+                self.varz.client_queries_served_stale.inc();
+                let _ = dns::set_ttl(&mut cache_entry.packet, self.stale_refresh_ttl);
+                let fut_refresh = self.resolver_tx
+                    .clone()
+                    .send(client_query.refresh())
+                    .map_err(|_| io::Error::last_os_error())
+                    .map(move |_| {});
+                let fut_stale = client_query.response_send(
+                    &mut cache_entry.packet,
+                    Some(&self.net_udp_socket),
+                    AnswerSource::Stale,
+                );
+                return Box::new(fut_stale.join(fut_refresh).map(|_| {}))
+                    as Box<Future<Item = _, Error = _>>;
+            }
         }
         debug!("Sending query to the resolver");
         let fut_resolver_query = self.resolver_tx
@@ -137,6 +318,28 @@ impl UdpAcceptorCore {
         let net_udp_socket = edgedns_context.udp_socket.try_clone()?;
         let cache = edgedns_context.cache.clone();
         let varz = edgedns_context.varz.clone();
+        let formerr_on_malformed_queries = edgedns_context.config.formerr_on_malformed_queries;
+        let debug_answer_source = edgedns_context.config.debug_answer_source;
+        let stale_while_revalidate = edgedns_context.config.stale_while_revalidate;
+        let stale_ttl = edgedns_context.config.stale_ttl;
+        let stale_refresh_ttl = edgedns_context.config.stale_refresh_ttl;
+        let max_client_ttl = edgedns_context.config.max_client_ttl;
+        let answer_order = edgedns_context.config.answer_order;
+        let compress_responses = edgedns_context.config.compress_responses;
+        let max_client_udp_payload = edgedns_context.config.max_client_udp_payload;
+        let nsid = edgedns_context.config.nsid.clone().map(Arc::new);
+        let address_family_filter =
+            AddressFamilyFilter::new(&edgedns_context.config).map(Arc::new);
+        let dns64 = Dns64::new(&edgedns_context.config, resolver_tx.clone());
+        let client_ratelimiter = edgedns_context.client_ratelimiter.clone();
+        let duplicate_queries = edgedns_context.duplicate_queries.clone();
+        let maintenance = Maintenance::new(
+            &edgedns_context.config,
+            edgedns_context.maintenance_mode.clone(),
+        );
+        let query_preprocessor = edgedns_context.extensions.query_preprocessor.clone();
+        let response_rewriter = edgedns_context.extensions.response_rewriter.clone();
+        let query_events = edgedns_context.extensions.query_events.clone();
 
         let udp_acceptor_th = thread::Builder::new()
             .name("udp_acceptor".to_string())
@@ -148,6 +351,24 @@ impl UdpAcceptorCore {
                     resolver_tx: resolver_tx,
                     service_ready_tx: Some(service_ready_tx),
                     varz: varz,
+                    formerr_on_malformed_queries: formerr_on_malformed_queries,
+                    debug_answer_source: debug_answer_source,
+                    stale_while_revalidate: stale_while_revalidate,
+                    stale_ttl: stale_ttl,
+                    stale_refresh_ttl: stale_refresh_ttl,
+                    max_client_ttl: max_client_ttl,
+                    answer_order: answer_order,
+                    compress_responses: compress_responses,
+                    max_client_udp_payload: max_client_udp_payload,
+                    nsid: nsid,
+                    address_family_filter: address_family_filter,
+                    dns64: dns64,
+                    client_ratelimiter: client_ratelimiter,
+                    duplicate_queries: duplicate_queries,
+                    maintenance: maintenance,
+                    query_preprocessor: query_preprocessor,
+                    response_rewriter: response_rewriter,
+                    query_events: query_events,
                 };
                 let udp_acceptor = UdpAcceptor::new(&udp_acceptor_core);
                 udp_acceptor_core
@@ -7,12 +7,12 @@ use base64;
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
 use coarsetime::Clock;
 use dns;
-use rand::distributions::{IndependentSample, Range};
 use rand::{self, Rng};
+use resolver::ExtUdpSockets;
 use siphasher::sip::SipHasher13;
 use std::hash::{Hash, Hasher};
 use std::io::Cursor;
-use std::net::{self, SocketAddr};
+use std::net::SocketAddr;
 use std::rc::Rc;
 use tokio_core::reactor::Handle;
 use upstream_server::UpstreamServer;
@@ -36,7 +36,7 @@ pub struct UpstreamProbe {
 impl UpstreamProbe {
     pub fn new(
         handle: &Handle,
-        net_ext_udp_sockets: &Rc<Vec<net::UdpSocket>>,
+        net_ext_udp_sockets: &Rc<ExtUdpSockets>,
         upstream_server: &UpstreamServer,
     ) -> Self {
         let probe = UpstreamProbe { hasher: *HASHER };
@@ -44,12 +44,11 @@ impl UpstreamProbe {
             .compute_probe_qname(PROBE_SUFFIX, &upstream_server.socket_addr)
             .unwrap();
         let packet = dns::build_probe_packet(&probe_qname).unwrap();
-        let mut rng = rand::thread_rng();
-        let random_token_range = Range::new(0usize, net_ext_udp_sockets.len());
-        let random_token = random_token_range.ind_sample(&mut rng);
-        let net_ext_udp_socket = &net_ext_udp_sockets[random_token];
-        let _ = net_ext_udp_socket.send_to(&packet, &upstream_server.socket_addr);
-        info!("Sent probe to {}", upstream_server.socket_addr.ip());
+        let net_ext_udp_socket = net_ext_udp_sockets.random_for(&upstream_server.socket_addr);
+        if let Some(net_ext_udp_socket) = net_ext_udp_socket {
+            let _ = net_ext_udp_socket.send_to(&packet, &upstream_server.socket_addr);
+            info!("Sent probe to {}", upstream_server.socket_addr.ip());
+        }
         probe
     }
 
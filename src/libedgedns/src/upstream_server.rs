@@ -4,14 +4,42 @@
 //!
 //! The number of in-flight queries for individual servers is also present,
 //! so that we can use this information for balancing the load.
+//!
+//! The outcomes of the most recent queries are kept in a ring buffer, in order
+//! to compute a success rate, that can get a server marked as offline even if
+//! it keeps answering some queries.
+//!
+//! An optional token bucket limits the rate of queries sent to each server.
+//!
+//! When DNS cookies are enabled, each server gets its own random client cookie,
+//! and remembers the last server cookie it sent.
+//!
+//! Servers that are only used for static routes are not `pooled`: they are
+//! never picked by the load balancer, and never marked as offline.
+//!
+//! Queries sent to authoritative servers don't have the `RD` flag set.
+//!
+//! With the fallback strategy, the live server with the lowest priority gets
+//! all the queries.
+//!
+//! Timestamps are read from a `Clock` shared by all the servers.
+//!
+//! With the circuit breaker enabled, a server marked as offline is not probed
+//! at all for a cooldown period. The breaker then becomes half-open, and a
+//! limited number of probes are sent. The server is put back online once all
+//! of them got a response, and the breaker opens again otherwise.
 
-use coarsetime::{Duration, Instant};
+use clock::{Clock, Duration, Instant};
 use config::Config;
-use std::net::{self, SocketAddr};
+use rand::distributions::{IndependentSample, Range};
+use rand::{self, random};
+use resolver::ExtUdpSockets;
+use std::collections::VecDeque;
+use std::f64;
+use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
-use super::{UPSTREAM_QUERY_MAX_DEVIATION_COEFFICIENT, UPSTREAM_QUERY_MAX_TIMEOUT_MS,
-            UPSTREAM_QUERY_MIN_TIMEOUT_MS};
+use super::{UPSTREAM_QUERY_MAX_DEVIATION_COEFFICIENT, UPSTREAM_QUERY_MIN_TIMEOUT_MS};
 use tokio_core::reactor::Handle;
 use upstream_probe::UpstreamProbe;
 use varz::Varz;
@@ -19,20 +47,60 @@ use varz::Varz;
 const RTT_DECAY: f64 = 0.125;
 const RTT_DEV_DECAY: f64 = 0.25;
 
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn name(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        }
+    }
+}
+
 pub struct UpstreamServer {
     pub remote_addr: String,
     pub socket_addr: SocketAddr,
     pub pending_queries_count: u64,
     pub failures: u32,
+    pub total_failures: u64,
     pub last_successful_response_instant: Instant,
     pub offline: bool,
+    /// Set when the server was brought back online without having responded,
+    /// because all the servers were offline.
+    pub resurrected: bool,
     pub last_probe_ts: Option<Instant>,
     pub rtt_est: Option<f64>,
     pub rtt_dev_est: f64,
+    pub pooled: bool,
+    pub outcomes: VecDeque<bool>,
+    pub last_response_ts: Option<Instant>,
+    pub client_cookie: [u8; 8],
+    pub server_cookie: Option<Vec<u8>>,
+    pub ratelimit_tokens: f64,
+    pub ratelimit_ts: Instant,
+    pub total_ratelimited: u64,
+    pub recursion_desired: bool,
+    pub breaker_state: BreakerState,
+    pub breaker_ts: Instant,
+    pub breaker_probes_sent: u32,
+    pub breaker_probes_succeeded: u32,
+    pub drained: bool,
+    pub lame_outcomes: VecDeque<bool>,
+    pub lame: bool,
+    pub total_lame_responses: u64,
+    pub priority: u32,
+    pub clock: Arc<Clock>,
 }
 
 impl UpstreamServer {
-    pub fn new(remote_addr: &str) -> Result<UpstreamServer, &'static str> {
+    pub fn new(remote_addr: &str, clock: Arc<Clock>) -> Result<UpstreamServer, &'static str> {
         let socket_addr = match remote_addr.parse() {
             Err(_) => return Err("Unable to parse an upstream resolver address"),
             Ok(socket_addr) => socket_addr,
@@ -42,62 +110,304 @@ impl UpstreamServer {
             socket_addr: socket_addr,
             pending_queries_count: 0,
             failures: 0,
-            last_successful_response_instant: Instant::now(),
+            total_failures: 0,
+            last_successful_response_instant: clock.now(),
             offline: false,
+            resurrected: false,
             last_probe_ts: None,
             rtt_est: None,
             rtt_dev_est: 0.0,
+            pooled: true,
+            outcomes: VecDeque::new(),
+            last_response_ts: None,
+            client_cookie: random(),
+            server_cookie: None,
+            ratelimit_tokens: f64::MAX,
+            ratelimit_ts: clock.now(),
+            total_ratelimited: 0,
+            recursion_desired: true,
+            breaker_state: BreakerState::Closed,
+            breaker_ts: clock.now(),
+            breaker_probes_sent: 0,
+            breaker_probes_succeeded: 0,
+            drained: false,
+            lame_outcomes: VecDeque::new(),
+            lame: false,
+            total_lame_responses: 0,
+            priority: 0,
+            clock: clock,
         };
         Ok(upstream_server)
     }
 
     fn reset_state(&mut self) {
         self.offline = false;
+        self.resurrected = false;
         self.failures = 0;
         self.pending_queries_count = 0;
-        self.last_successful_response_instant = Instant::recent();
+        self.last_successful_response_instant = self.clock.now();
+        self.outcomes.clear();
+    }
+
+    fn record_outcome(&mut self, config: &Config, success: bool) {
+        if self.outcomes.len() >= config.upstream_success_rate_window {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(success);
+    }
+
+    /// Returns the fraction of the recent queries that got a response.
+    pub fn success_rate(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        let successes = self.outcomes.iter().filter(|&&success| success).count();
+        Some(successes as f64 / self.outcomes.len() as f64)
+    }
+
+    fn success_rate_too_low(&self, config: &Config) -> bool {
+        if self.outcomes.len() < config.upstream_success_rate_window {
+            return false;
+        }
+        match self.success_rate() {
+            None => false,
+            Some(success_rate) => {
+                success_rate * 100.0 < config.upstream_min_success_rate_percent as f64
+            }
+        }
+    }
+
+    pub fn record_success(&mut self, config: &Config) {
+        self.resurrected = false;
+        self.record_outcome(config, true);
+        self.last_response_ts = Some(self.clock.now());
+    }
+
+    /// Records whether a response from this server was lame, over the last
+    /// `success_rate_window` responses.
+    ///
+    /// Returns `true` if the server just became lame.
+    pub fn record_lameness(&mut self, config: &Config, lame_response: bool) -> bool {
+        if config.upstream_max_lame_percent == 0 {
+            return false;
+        }
+        if lame_response {
+            self.total_lame_responses = self.total_lame_responses.saturating_add(1);
+        }
+        if self.lame_outcomes.len() >= config.upstream_success_rate_window {
+            self.lame_outcomes.pop_front();
+        }
+        self.lame_outcomes.push_back(lame_response);
+        let was_lame = self.lame;
+        self.lame = match self.lame_rate() {
+            Some(lame_rate) if self.lame_outcomes.len() >= config.upstream_success_rate_window => {
+                lame_rate * 100.0 >= config.upstream_max_lame_percent as f64
+            }
+            _ => false,
+        };
+        if self.lame && !was_lame {
+            warn!(
+                "Resolver {} is lame: {:.0}% of its recent responses are not usable",
+                self.remote_addr,
+                self.lame_rate().unwrap_or(0.0) * 100.0
+            );
+        } else if was_lame && !self.lame {
+            info!("Resolver {} is not lame any more", self.remote_addr);
+        }
+        self.lame && !was_lame
+    }
+
+    /// Returns the fraction of the recent responses that were lame.
+    pub fn lame_rate(&self) -> Option<f64> {
+        if self.lame_outcomes.is_empty() {
+            return None;
+        }
+        let lame_count = self.lame_outcomes.iter().filter(|&&lame| lame).count();
+        Some(lame_count as f64 / self.lame_outcomes.len() as f64)
+    }
+
+    /// Returns the data of the cookie option to send to this server.
+    pub fn cookie(&self) -> Vec<u8> {
+        let mut cookie = self.client_cookie.to_vec();
+        if let Some(ref server_cookie) = self.server_cookie {
+            cookie.extend_from_slice(server_cookie);
+        }
+        cookie
+    }
+
+    /// Checks that the cookie option of a response from this server echoes
+    /// our client cookie, and remembers the server cookie it contains.
+    ///
+    /// Returns `true` if the server cookie changed.
+    pub fn learn_server_cookie(&mut self, cookie: &[u8]) -> Result<bool, &'static str> {
+        let cookie_len = cookie.len();
+        if cookie_len != 8 && (cookie_len < 16 || cookie_len > 40) {
+            return Err("Invalid cookie length");
+        }
+        if cookie[..8] != self.client_cookie {
+            return Err("Client cookie mismatch");
+        }
+        if cookie_len == 8 || self.server_cookie.as_ref().map(|x| &x[..]) == Some(&cookie[8..]) {
+            return Ok(false);
+        }
+        self.server_cookie = Some(cookie[8..].to_vec());
+        Ok(true)
+    }
+
+    /// Takes a token from the bucket of this server, if it is rate limited.
+    ///
+    /// Returns `false` if the server can't accept another query right now.
+    pub fn take_ratelimit_token(&mut self, config: &Config) -> bool {
+        let max_qps = config.upstream_max_qps as f64;
+        if max_qps <= 0.0 {
+            return true;
+        }
+        let elapsed = self.clock.elapsed(self.ratelimit_ts).as_f64();
+        self.ratelimit_ts = self.clock.now();
+        self.ratelimit_tokens = (self.ratelimit_tokens + elapsed * max_qps).min(max_qps);
+        if self.ratelimit_tokens < 1.0 {
+            self.total_ratelimited = self.total_ratelimited.saturating_add(1);
+            return false;
+        }
+        self.ratelimit_tokens -= 1.0;
+        true
     }
 
     pub fn prepare_send(&mut self, config: &Config) {
         if self.offline ||
-            self.last_successful_response_instant.elapsed_since_recent() <
+            self.clock.elapsed(self.last_successful_response_instant) <
                 config.upstream_max_failure_duration
         {
             return;
         }
-        self.last_successful_response_instant = Instant::now();
+        self.last_successful_response_instant = self.clock.now();
     }
 
     pub fn record_failure(
         &mut self,
         config: &Config,
         handle: &Handle,
-        ext_net_udp_sockets_rc: &Rc<Vec<net::UdpSocket>>,
+        ext_net_udp_sockets_rc: &Rc<ExtUdpSockets>,
     ) {
         if self.offline {
             return;
         }
         self.failures = self.failures.saturating_add(1);
-        if self.last_successful_response_instant.elapsed_since_recent() <
+        self.total_failures = self.total_failures.saturating_add(1);
+        self.record_outcome(config, false);
+        if !self.pooled {
+            return;
+        }
+        if self.success_rate_too_low(config) {
+            warn!(
+                "Success rate of resolver {} is too low, putting offline",
+                self.remote_addr
+            );
+        } else if self.clock.elapsed(self.last_successful_response_instant) <
             config.upstream_max_failure_duration
         {
             return;
+        } else {
+            warn!(
+                "Too many failures from resolver {}, putting offline",
+                self.remote_addr
+            );
         }
         self.offline = true;
-        warn!(
-            "Too many failures from resolver {}, putting offline",
-            self.remote_addr
-        );
+        if config.upstream_breaker_enabled {
+            self.set_breaker_state(BreakerState::Open);
+        }
     }
 
-    pub fn record_success_after_failure(&mut self) {
+    fn set_breaker_state(&mut self, breaker_state: BreakerState) {
+        self.breaker_state = breaker_state;
+        self.breaker_ts = self.clock.now();
+        self.breaker_probes_sent = 0;
+        self.breaker_probes_succeeded = 0;
+    }
+
+    /// Puts the server offline, and opens its circuit breaker.
+    pub fn open_breaker(&mut self) {
+        self.offline = true;
+        self.set_breaker_state(BreakerState::Open);
+    }
+
+    /// Checks that no probes have been sent to this server for `probes_delay`,
+    /// and if this is the case, records that a probe is about to be sent.
+    ///
+    /// Both steps happen at once, with the write lock on the list of servers
+    /// held by the caller, so that concurrent queries can't send more than one
+    /// probe per window.
+    pub fn claim_probe(&mut self, probes_delay: Duration) -> bool {
+        if let Some(last_probe_ts) = self.last_probe_ts {
+            if self.clock.elapsed(last_probe_ts) < probes_delay {
+                return false;
+            }
+        }
+        self.last_probe_ts = Some(self.clock.now());
+        true
+    }
+
+    /// Checks that a probe can be sent to this offline server, and if this is
+    /// the case, records that a probe is about to be sent.
+    ///
+    /// Without the circuit breaker, probes are only spaced by `probes_delay`.
+    /// Otherwise, no probes are sent while the breaker is open, and at most
+    /// `breaker_half_open_probes` once it is half-open.
+    pub fn claim_offline_probe(&mut self, config: &Config) -> bool {
+        let probes_delay = Duration::from_millis(config.upstream_probes_delay_ms);
+        if !config.upstream_breaker_enabled {
+            return self.claim_probe(probes_delay);
+        }
+        let elapsed = self.clock.elapsed(self.breaker_ts);
+        match self.breaker_state {
+            BreakerState::Closed => return self.claim_probe(probes_delay),
+            BreakerState::Open => {
+                if elapsed < config.upstream_breaker_cooldown {
+                    return false;
+                }
+                info!("Circuit breaker of {} is half-open", self.remote_addr);
+                self.set_breaker_state(BreakerState::HalfOpen);
+            }
+            BreakerState::HalfOpen => {
+                if self.breaker_probes_sent >= config.upstream_breaker_half_open_probes {
+                    if elapsed >= config.upstream_breaker_cooldown {
+                        warn!(
+                            "Probes to {} failed, opening the circuit breaker again",
+                            self.remote_addr
+                        );
+                        self.set_breaker_state(BreakerState::Open);
+                    }
+                    return false;
+                }
+            }
+        }
+        self.breaker_probes_sent += 1;
+        self.last_probe_ts = Some(self.clock.now());
+        true
+    }
+
+    pub fn record_success_after_failure(&mut self, config: &Config) {
         if !self.offline {
             self.failures = self.failures.saturating_sub(1);
             if self.failures == 0 {
-                self.last_successful_response_instant = Instant::recent();
+                self.last_successful_response_instant = self.clock.now();
             }
             return;
         }
+        if config.upstream_breaker_enabled {
+            match self.breaker_state {
+                BreakerState::Open => return,
+                BreakerState::HalfOpen => {
+                    self.breaker_probes_succeeded += 1;
+                    if self.breaker_probes_succeeded < config.upstream_breaker_half_open_probes {
+                        return;
+                    }
+                }
+                BreakerState::Closed => {}
+            }
+            self.set_breaker_state(BreakerState::Closed);
+        }
         self.reset_state();
         warn!("Marking {} as live again", self.socket_addr);
     }
@@ -122,17 +432,18 @@ impl UpstreamServer {
         ));
     }
 
-    pub fn timeout_ms_est(&self) -> u64 {
+    pub fn timeout_ms_est(&self, config: &Config) -> u64 {
+        let max_timeout = config.upstream_query_max_timeout_ms;
         let timeout = match self.rtt_est {
-            None => UPSTREAM_QUERY_MAX_TIMEOUT_MS,
+            None => max_timeout,
             Some(rtt_est) => {
                 let timeout = ((rtt_est +
                     self.rtt_dev_est * UPSTREAM_QUERY_MAX_DEVIATION_COEFFICIENT) *
                     1000.0) as u64;
                 if timeout < UPSTREAM_QUERY_MIN_TIMEOUT_MS {
                     UPSTREAM_QUERY_MIN_TIMEOUT_MS
-                } else if timeout > UPSTREAM_QUERY_MAX_TIMEOUT_MS {
-                    UPSTREAM_QUERY_MAX_TIMEOUT_MS
+                } else if timeout > max_timeout {
+                    max_timeout
                 } else {
                     timeout
                 }
@@ -151,18 +462,109 @@ impl UpstreamServer {
     pub fn live_servers(upstream_servers: &mut Vec<UpstreamServer>) -> Vec<usize> {
         let mut new_live: Vec<usize> = Vec::with_capacity(upstream_servers.len());
         for (idx, upstream_server) in upstream_servers.iter().enumerate() {
-            if !upstream_server.offline {
+            if upstream_server.pooled && !upstream_server.offline && !upstream_server.drained {
                 new_live.push(idx);
             }
         }
         if new_live.is_empty() {
             warn!("No more live servers, trying to resurrect them all");
             for (idx, upstream_server) in upstream_servers.iter_mut().enumerate() {
-                upstream_server.offline = false;
-                new_live.push(idx);
+                if upstream_server.pooled && !upstream_server.drained {
+                    upstream_server.offline = false;
+                    upstream_server.resurrected = true;
+                    upstream_server.set_breaker_state(BreakerState::Closed);
+                    new_live.push(idx);
+                }
             }
         }
         info!("Live upstream servers: {:?}", new_live);
         new_live
     }
+
+    /// Checks that at least one server in the pool is online, not counting
+    /// servers that were only resurrected by `live_servers()`.
+    pub fn has_healthy_servers(upstream_servers: &Vec<UpstreamServer>) -> bool {
+        upstream_servers.iter().any(|upstream_server| {
+            upstream_server.pooled && !upstream_server.drained && !upstream_server.offline &&
+                !upstream_server.resurrected
+        })
+    }
+
+    /// Returns the live server with the lowest priority, the first one in case
+    /// of a tie.
+    pub fn preferred_server(
+        upstream_servers: &Vec<UpstreamServer>,
+        upstream_servers_live: &Vec<usize>,
+    ) -> Option<usize> {
+        upstream_servers_live
+            .iter()
+            .cloned()
+            .min_by_key(|&idx| (upstream_servers[idx].priority, idx))
+    }
+
+    /// Returns one of the two live servers with the fewest pending queries.
+    ///
+    /// If several servers are tied for the fewest pending queries, one of them
+    /// is picked at random, so that ties don't depend on the distribution of
+    /// transaction IDs. Otherwise, `tid` picks one of the two, and a retry
+    /// picks the other one.
+    pub fn least_busy_server(
+        upstream_servers: &Vec<UpstreamServer>,
+        upstream_servers_live: &Vec<usize>,
+        tid: u16,
+        is_retry: bool,
+    ) -> Option<usize> {
+        let mut busy_map = upstream_servers_live
+            .iter()
+            .map(|&i| (i, upstream_servers[i].pending_queries_count))
+            .collect::<Vec<(usize, u64)>>();
+        busy_map.sort_by_key(|x| x.1);
+        let i = match busy_map.len() {
+            0 => return None,
+            1 => 0,
+            _ if !is_retry && busy_map[0].1 == busy_map[1].1 => {
+                let min_count = busy_map[0].1;
+                let tied_count = busy_map.iter().take_while(|x| x.1 == min_count).count();
+                Range::new(0, tied_count).ind_sample(&mut rand::thread_rng())
+            }
+            _ => ((tid as usize) + (is_retry as usize & 1)) & 1,
+        };
+        Some(busy_map[i].0)
+    }
+
+    /// Drains the server whose address is `remote_addr`, or puts it back in
+    /// rotation, and returns the new list of live servers.
+    ///
+    /// The last pooled server that is not drained can't be drained.
+    pub fn set_drained(
+        upstream_servers: &mut Vec<UpstreamServer>,
+        remote_addr: &str,
+        drained: bool,
+    ) -> Result<Vec<usize>, &'static str> {
+        let idx = match upstream_servers
+            .iter()
+            .position(|upstream_server| upstream_server.remote_addr == remote_addr)
+        {
+            None => return Err("Unknown upstream server"),
+            Some(idx) => idx,
+        };
+        let others_in_rotation = upstream_servers
+            .iter()
+            .enumerate()
+            .any(|(i, upstream_server)| {
+                i != idx && upstream_server.pooled && !upstream_server.drained
+            });
+        if drained && upstream_servers[idx].pooled && !others_in_rotation {
+            return Err("No other upstream servers would be left");
+        }
+        if upstream_servers[idx].drained != drained {
+            warn!(
+                "Upstream server {} {}",
+                remote_addr,
+                if drained { "drained" } else { "back in rotation" }
+            );
+            upstream_servers[idx].drained = drained;
+        }
+        Ok(UpstreamServer::live_servers(upstream_servers))
+    }
 }
@@ -4,7 +4,7 @@
 //! operations: set() and inc().
 
 use coarsetime::Instant;
-use prometheus::{Counter, Gauge, Histogram};
+use prometheus::{Counter, CounterVec, Gauge, Histogram};
 
 pub struct StartInstant(pub Instant);
 
@@ -16,20 +16,52 @@ pub struct Varz {
     pub cache_test_len: Gauge,
     pub cache_inserted: Gauge,
     pub cache_evicted: Gauge,
+    pub cache_bytes: Gauge,
+    pub cache_shadow_missing: Counter,
+    pub cache_shadow_mismatches: Counter,
     pub client_queries: Gauge,
     pub client_queries_udp: Counter,
     pub client_queries_tcp: Counter,
     pub client_queries_cached: Counter,
     pub client_queries_expired: Counter,
     pub client_queries_offline: Counter,
+    pub client_queries_offline_stale: Counter,
+    pub client_queries_offline_servfail: Counter,
+    pub client_queries_upstream_ratelimited: Counter,
+    pub client_queries_served_stale: Counter,
+    pub client_queries_dns64: Counter,
     pub client_queries_errors: Counter,
+    pub client_queries_ratelimited: Counter,
+    pub client_queries_duplicate: Counter,
+    pub queries_shed: Counter,
+    pub malformed_queries: Counter,
+    pub tcp_client_connections: Gauge,
+    pub tcp_client_connections_opened: Counter,
+    pub tcp_client_connections_closed: Counter,
     pub inflight_queries: Gauge,
+    pub pending_queries_oldest_age: Gauge,
     pub upstream_errors: Counter,
+    pub upstream_socket_errors: Counter,
+    pub upstream_out_of_bailiwick: Counter,
+    pub upstream_answers_capped: Counter,
+    pub upstream_nxdomain_rewritten: Counter,
+    pub upstream_oversized_responses: Counter,
+    pub upstream_bad_cookies: Counter,
+    pub upstream_ratelimited: Counter,
+    pub upstream_lame: Counter,
+    pub upstream_port_unreachable: Counter,
+    pub spoofed_source_dropped: Counter,
+    pub slow_queries: Counter,
+    pub query_events_dropped: Counter,
     pub upstream_sent: Counter,
     pub upstream_received: Counter,
     pub upstream_timeout: Counter,
     pub upstream_avg_rtt: Gauge,
     pub upstream_response_sizes: Histogram,
+    pub client_query_sizes: Histogram,
+    pub client_response_sizes: Histogram,
+    pub client_query_latency: Histogram,
+    pub client_responses: CounterVec,
 }
 
 impl Varz {
@@ -69,6 +101,24 @@ impl Varz {
                 "Number of entries evicted from the cache",
                 labels!{"handler" => "all",}
             )).unwrap(),
+            cache_bytes: register_gauge!(opts!(
+                "edgedns_cache_bytes",
+                "Size of the cached packets, if the cache \
+                 size is limited in bytes",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            cache_shadow_missing: register_counter!(opts!(
+                "edgedns_cache_shadow_missing",
+                "Number of lookups found in only one of \
+                 the main and shadow caches",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            cache_shadow_mismatches: register_counter!(opts!(
+                "edgedns_cache_shadow_mismatches",
+                "Number of lookups for which the main and \
+                 shadow caches returned different responses",
+                labels!{"handler" => "all",}
+            )).unwrap(),
             client_queries: register_gauge!(opts!(
                 "edgedns_client_queries",
                 "Number of client queries received",
@@ -104,21 +154,170 @@ impl Varz {
                  unresponsive",
                 labels!{"handler" => "all",}
             )).unwrap(),
+            client_queries_offline_stale: register_counter!(opts!(
+                "edgedns_client_queries_offline_stale",
+                "Number of client queries answered \
+                 from the cache while upstream \
+                 resolvers are unresponsive",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            client_queries_offline_servfail: register_counter!(opts!(
+                "edgedns_client_queries_offline_servfail",
+                "Number of client queries answered \
+                 with SERVFAIL while upstream \
+                 resolvers are unresponsive",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            client_queries_upstream_ratelimited: register_counter!(opts!(
+                "edgedns_client_queries_upstream_ratelimited",
+                "Number of client queries answered \
+                 without being sent upstream because \
+                 of upstream rate limits",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            client_queries_served_stale: register_counter!(opts!(
+                "edgedns_client_queries_served_stale",
+                "Number of client queries answered \
+                 with an expired cache entry",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            client_queries_dns64: register_counter!(opts!(
+                "edgedns_client_queries_dns64",
+                "Number of AAAA client queries \
+                 resolved again for DNS64 synthesis",
+                labels!{"handler" => "all",}
+            )).unwrap(),
             client_queries_errors: register_counter!(opts!(
                 "edgedns_client_queries_errors",
                 "Number of bogus client queries",
                 labels!{"handler" => "all",}
             )).unwrap(),
+            client_queries_ratelimited: register_counter!(opts!(
+                "edgedns_client_queries_ratelimited",
+                "Number of client queries exceeding \
+                 the rate limit of their client",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            client_queries_duplicate: register_counter!(opts!(
+                "edgedns_client_queries_duplicate",
+                "Number of duplicate client queries \
+                 dropped",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            queries_shed: register_counter!(opts!(
+                "edgedns_queries_shed",
+                "Number of client queries shed due to \
+                 too many inflight queries",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            malformed_queries: register_counter!(opts!(
+                "edgedns_malformed_queries",
+                "Number of client queries rejected due to \
+                 a malformed header or question",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            tcp_client_connections: register_gauge!(opts!(
+                "edgedns_tcp_client_connections",
+                "Number of currently open TCP connections from clients",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            tcp_client_connections_opened: register_counter!(opts!(
+                "edgedns_tcp_client_connections_opened",
+                "Number of TCP connections accepted from clients",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            tcp_client_connections_closed: register_counter!(opts!(
+                "edgedns_tcp_client_connections_closed",
+                "Number of TCP connections from clients \
+                 that have been closed",
+                labels!{"handler" => "all",}
+            )).unwrap(),
             inflight_queries: register_gauge!(opts!(
                 "edgedns_inflight_queries",
                 "Number of queries currently waiting for a response",
                 labels!{"handler" => "all",}
             )).unwrap(),
+            pending_queries_oldest_age: register_gauge!(opts!(
+                "edgedns_pending_queries_oldest_age",
+                "Number of seconds the oldest query still \
+                 waiting for a response has been pending",
+                labels!{"handler" => "all",}
+            )).unwrap(),
             upstream_errors: register_counter!(opts!(
                 "edgedns_upstream_errors",
                 "Number of bogus upstream servers responses",
                 labels!{"handler" => "all",}
             )).unwrap(),
+            upstream_socket_errors: register_counter!(opts!(
+                "edgedns_upstream_socket_errors",
+                "Number of queries that couldn't be sent \
+                 due to an error on a local socket",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_out_of_bailiwick: register_counter!(opts!(
+                "edgedns_upstream_out_of_bailiwick",
+                "Number of upstream servers responses \
+                 with out-of-bailiwick records",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_answers_capped: register_counter!(opts!(
+                "edgedns_upstream_answers_capped",
+                "Number of upstream servers responses \
+                 truncated to the maximum number of answers",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_nxdomain_rewritten: register_counter!(opts!(
+                "edgedns_upstream_nxdomain_rewritten",
+                "Number of upstream servers NXDOMAIN responses \
+                 rewritten to NODATA",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_oversized_responses: register_counter!(opts!(
+                "edgedns_upstream_oversized_responses",
+                "Number of upstream servers responses \
+                 dropped for exceeding the maximum size",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_bad_cookies: register_counter!(opts!(
+                "edgedns_upstream_bad_cookies",
+                "Number of BADCOOKIE responses received from upstream servers",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_ratelimited: register_counter!(opts!(
+                "edgedns_upstream_ratelimited",
+                "Number of queries that could not be sent to an upstream \
+                 server due to its rate limit",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_lame: register_counter!(opts!(
+                "edgedns_upstream_lame",
+                "Number of times an upstream server was found to be lame",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            upstream_port_unreachable: register_counter!(opts!(
+                "edgedns_upstream_port_unreachable",
+                "Number of ICMP port unreachable errors \
+                 received from upstream servers",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            spoofed_source_dropped: register_counter!(opts!(
+                "edgedns_spoofed_source_dropped",
+                "Number of responses dropped because they \
+                 were not sent by an upstream server",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            slow_queries: register_counter!(opts!(
+                "edgedns_slow_queries",
+                "Number of queries slower than the \
+                 slow query threshold",
+                labels!{"handler" => "all",}
+            )).unwrap(),
+            query_events_dropped: register_counter!(opts!(
+                "edgedns_query_events_dropped",
+                "Number of query events dropped because \
+                 a subscriber was lagging behind",
+                labels!{"handler" => "all",}
+            )).unwrap(),
             upstream_sent: register_counter!(opts!(
                 "edgedns_upstream_sent",
                 "Number of upstream servers queries sent",
@@ -145,6 +344,31 @@ impl Varz {
                 "Response size in bytes",
                 vec![64.0, 128.0, 192.0, 256.0, 512.0, 1024.0, 2048.0]
             )).unwrap(),
+            client_query_sizes: register_histogram!(histogram_opts!(
+                "edgedns_client_query_sizes",
+                "Client query size in bytes",
+                vec![512.0, 1232.0, 4096.0]
+            )).unwrap(),
+            client_response_sizes: register_histogram!(histogram_opts!(
+                "edgedns_client_response_sizes",
+                "Size in bytes of responses sent to clients",
+                vec![512.0, 1232.0, 4096.0]
+            )).unwrap(),
+            client_query_latency: register_histogram!(histogram_opts!(
+                "edgedns_client_query_latency",
+                "Time in seconds between the reception of a client \
+                 query and the response being sent",
+                vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+            )).unwrap(),
+            client_responses: register_counter_vec!(
+                opts!(
+                    "edgedns_client_responses",
+                    "Number of responses sent to clients, \
+                     by response code",
+                    labels!{"handler" => "all",}
+                ),
+                &["rcode"]
+            ).unwrap(),
         }
     }
 }
@@ -1,23 +1,93 @@
-//! Expose metrics via the Prometheus API
+//! Expose metrics via the Prometheus API, and the state of upstream servers
+//! as JSON. Also allows toggling the maintenance mode, and priming the cache
+//! again with the configured list of names.
+//!
+//! The webservice can listen to a TCP address, a Unix socket, or both.
+//!
+//! `/healthz` and `/ready` are cheap endpoints meant for health checks of load
+//! balancers and orchestrators. `/ready` fails with a 503 status code if all
+//! the upstream servers are offline, including servers that were only brought
+//! back because all of them were, or if the resolver stopped recording
+//! heartbeats.
+//! It can also fail until the cache is warm: with `webservice.ready_after_prime`,
+//! until the names of `cache.prime_file` have been resolved once, and with
+//! `webservice.ready_min_cache_entries`, until the cache has held that many
+//! entries. Once the cache is warm, it is never considered cold again.
+//!
+//! `/config` returns the effective configuration, including default values,
+//! as JSON. Unless `webservice.redact_config` is turned off, values that can
+//! identify the host are replaced with `"redacted"`.
+//!
+//! `POST /drain/<address>` stops sending new queries to an upstream server,
+//! and `POST /undrain/<address>` puts it back in rotation.
+//!
+//! `POST` requests are only accepted over the Unix socket, and from the
+//! addresses listed in `webservice.admin_allowed_ips` (loopback by default).
 
+use cache::Cache;
+use cache_primer::CachePrimer;
+use coarsetime::{Duration, Instant};
+use config::Config;
 use futures::future::{self, FutureResult};
+use futures::{Future, Stream};
 use hyper;
 use hyper::header::{ContentLength, ContentType};
 use hyper::mime::Mime;
 use hyper::server::{Http, Request, Response, Server, Service};
-use hyper::{StatusCode, Uri};
+use hyper::{Method, StatusCode, Uri};
+use parking_lot::RwLock;
 use prometheus::{self, Encoder, TextEncoder};
+use serde_json;
+use std::fs;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::mpsc;
 use std::thread;
+use tokio_core::reactor::{Core, Handle};
+use tokio_uds::UnixListener;
+use upstream_server::UpstreamServer;
 use varz::{StartInstant, Varz};
 
-use super::EdgeDNSContext;
+use super::{EdgeDNSContext, HEALTH_CHECK_MS};
+
+/// Configuration values hidden from `/config` when `webservice.redact_config` is set
+const REDACTED_CONFIG_KEYS: &[&str] = &["nsid", "dnstap_identity", "dnstap_version"];
 
 #[derive(Clone)]
 pub struct WebService {
+    config: Config,
     varz: Arc<Varz>,
+    upstream_servers_arc: Arc<RwLock<Vec<UpstreamServer>>>,
+    upstream_servers_live_arc: Arc<RwLock<Vec<usize>>>,
+    maintenance_mode: Arc<AtomicBool>,
+    resolver_heartbeat: Arc<RwLock<Instant>>,
+    warmup_complete: Arc<AtomicBool>,
+    cache_warm: Arc<AtomicBool>,
+    cache: Cache,
+    cache_primer: Option<CachePrimer>,
+}
+
+#[derive(Serialize)]
+struct UpstreamServerStatus {
+    address: String,
+    live: bool,
+    pooled: bool,
+    pending_queries_count: u64,
+    last_probe_age: Option<f64>,
+    rtt_est: Option<f64>,
+    total_failures: u64,
+    success_rate: Option<f64>,
+    last_response_age: Option<f64>,
+    total_ratelimited: u64,
+    breaker: &'static str,
+    drained: bool,
+    lame: bool,
+    lame_rate: Option<f64>,
+    total_lame_responses: u64,
+    priority: u32,
 }
 
 impl Service for WebService {
@@ -27,9 +97,60 @@ impl Service for WebService {
     type Future = FutureResult<Response, hyper::Error>;
 
     fn call(&self, req: Request) -> Self::Future {
-        if req.uri().path() != "/metrics" {
-            return future::ok(Response::new().with_status(StatusCode::NotFound));
+        if req.method() == &Method::Post && !self.is_admin(&req) {
+            return self.plaintext(StatusCode::Forbidden, "forbidden\n");
+        }
+        match req.uri().path() {
+            "/metrics" => self.metrics(),
+            "/upstreams" => self.upstreams(),
+            "/config" => self.config(),
+            "/healthz" => self.plaintext(StatusCode::Ok, "ok\n"),
+            "/ready" => self.ready(),
+            "/maintenance" => self.maintenance(None),
+            "/maintenance/on" if req.method() == &Method::Post => self.maintenance(Some(true)),
+            "/maintenance/off" if req.method() == &Method::Post => self.maintenance(Some(false)),
+            "/prime" if req.method() == &Method::Post => self.prime(),
+            path if req.method() == &Method::Post && path.starts_with("/drain/") => {
+                self.drain(&path["/drain/".len()..], true)
+            }
+            path if req.method() == &Method::Post && path.starts_with("/undrain/") => {
+                self.drain(&path["/undrain/".len()..], false)
+            }
+            _ => future::ok(Response::new().with_status(StatusCode::NotFound)),
         }
+    }
+}
+
+impl WebService {
+    fn new(edgedns_context: &EdgeDNSContext, cache_primer: Option<CachePrimer>) -> WebService {
+        WebService {
+            config: edgedns_context.config.clone(),
+            varz: edgedns_context.varz.clone(),
+            upstream_servers_arc: edgedns_context.upstream_servers_arc.clone(),
+            upstream_servers_live_arc: edgedns_context.upstream_servers_live_arc.clone(),
+            maintenance_mode: edgedns_context.maintenance_mode.clone(),
+            resolver_heartbeat: edgedns_context.resolver_heartbeat.clone(),
+            warmup_complete: edgedns_context.warmup_complete.clone(),
+            cache_warm: Arc::new(AtomicBool::new(
+                edgedns_context.config.webservice_ready_min_cache_entries == 0,
+            )),
+            cache: edgedns_context.cache.clone(),
+            cache_primer: cache_primer,
+        }
+    }
+
+    /// Requests over the Unix socket have no remote address, and access to the
+    /// socket is already restricted by its permissions.
+    fn is_admin(&self, req: &Request) -> bool {
+        match req.remote_addr() {
+            None => true,
+            Some(remote_addr) => self.config
+                .webservice_admin_allowed_ips
+                .contains(&remote_addr.ip()),
+        }
+    }
+
+    fn metrics(&self) -> FutureResult<Response, hyper::Error> {
         let StartInstant(start_instant) = self.varz.start_instant;
         let uptime = start_instant.elapsed().as_secs();
         self.varz.uptime.set(uptime as f64);
@@ -47,35 +168,212 @@ impl Service for WebService {
                 .with_body(buffer),
         )
     }
-}
 
-impl WebService {
-    fn new(edgedns_context: &EdgeDNSContext) -> WebService {
-        WebService {
-            varz: edgedns_context.varz.clone(),
+    fn upstreams(&self) -> FutureResult<Response, hyper::Error> {
+        let statuses: Vec<UpstreamServerStatus> = {
+            let upstream_servers = self.upstream_servers_arc.read();
+            let upstream_servers_live = self.upstream_servers_live_arc.read();
+            upstream_servers
+                .iter()
+                .enumerate()
+                .map(|(idx, upstream_server)| UpstreamServerStatus {
+                    address: upstream_server.remote_addr.clone(),
+                    live: upstream_servers_live.contains(&idx),
+                    pooled: upstream_server.pooled,
+                    pending_queries_count: upstream_server.pending_queries_count,
+                    last_probe_age: upstream_server
+                        .last_probe_ts
+                        .map(|ts| ts.elapsed_since_recent().as_f64()),
+                    rtt_est: upstream_server.rtt_est,
+                    total_failures: upstream_server.total_failures,
+                    success_rate: upstream_server.success_rate(),
+                    last_response_age: upstream_server
+                        .last_response_ts
+                        .map(|ts| ts.elapsed_since_recent().as_f64()),
+                    total_ratelimited: upstream_server.total_ratelimited,
+                    breaker: upstream_server.breaker_state.name(),
+                    drained: upstream_server.drained,
+                    lame: upstream_server.lame,
+                    lame_rate: upstream_server.lame_rate(),
+                    total_lame_responses: upstream_server.total_lame_responses,
+                    priority: upstream_server.priority,
+                })
+                .collect()
+        };
+        let buffer = serde_json::to_vec(&statuses).expect("Unable to serialize upstream servers");
+        future::ok(
+            Response::new()
+                .with_header(ContentLength(buffer.len() as u64))
+                .with_header(ContentType::json())
+                .with_body(buffer),
+        )
+    }
+
+    fn config(&self) -> FutureResult<Response, hyper::Error> {
+        let mut config =
+            serde_json::to_value(&self.config).expect("Unable to serialize the configuration");
+        if self.config.webservice_redact_config {
+            if let Some(config) = config.as_object_mut() {
+                for key in REDACTED_CONFIG_KEYS {
+                    if let Some(value) = config.get_mut(*key) {
+                        if !value.is_null() {
+                            *value = serde_json::Value::from("redacted");
+                        }
+                    }
+                }
+            }
         }
+        let buffer = serde_json::to_vec(&config).expect("Unable to serialize the configuration");
+        future::ok(
+            Response::new()
+                .with_header(ContentLength(buffer.len() as u64))
+                .with_header(ContentType::json())
+                .with_body(buffer),
+        )
+    }
+
+    fn plaintext(
+        &self,
+        status: StatusCode,
+        body: &'static str,
+    ) -> FutureResult<Response, hyper::Error> {
+        future::ok(
+            Response::new()
+                .with_status(status)
+                .with_header(ContentLength(body.len() as u64))
+                .with_header(ContentType::plaintext())
+                .with_body(body),
+        )
+    }
+
+    fn ready(&self) -> FutureResult<Response, hyper::Error> {
+        if !UpstreamServer::has_healthy_servers(&self.upstream_servers_arc.read()) {
+            return self.plaintext(StatusCode::ServiceUnavailable, "no live upstream servers\n");
+        }
+        let heartbeat_age = self.resolver_heartbeat.read().elapsed_since_recent();
+        if heartbeat_age > Duration::from_millis(HEALTH_CHECK_MS) {
+            return self.plaintext(StatusCode::ServiceUnavailable, "resolver unresponsive\n");
+        }
+        if !self.warmup_complete.load(Relaxed) {
+            return self.plaintext(StatusCode::ServiceUnavailable, "cache priming in progress\n");
+        }
+        if !self.cache_warm.load(Relaxed) {
+            let cache_stats = self.cache.stats();
+            let cache_entries = cache_stats.frequent_len + cache_stats.recent_len;
+            if cache_entries < self.config.webservice_ready_min_cache_entries {
+                return self.plaintext(StatusCode::ServiceUnavailable, "cache warming up\n");
+            }
+            self.cache_warm.store(true, Relaxed);
+        }
+        self.plaintext(StatusCode::Ok, "ready\n")
+    }
+
+    fn maintenance(&self, enabled: Option<bool>) -> FutureResult<Response, hyper::Error> {
+        if let Some(enabled) = enabled {
+            if self.maintenance_mode.swap(enabled, Relaxed) != enabled {
+                warn!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+            }
+        }
+        let body = if self.maintenance_mode.load(Relaxed) {
+            "on\n"
+        } else {
+            "off\n"
+        };
+        future::ok(
+            Response::new()
+                .with_header(ContentLength(body.len() as u64))
+                .with_header(ContentType::plaintext())
+                .with_body(body),
+        )
+    }
+
+    fn prime(&self) -> FutureResult<Response, hyper::Error> {
+        match self.cache_primer {
+            None => self.plaintext(StatusCode::NotFound, "no list of names to prime\n"),
+            Some(ref cache_primer) => if cache_primer.start() {
+                self.plaintext(StatusCode::Accepted, "started\n")
+            } else {
+                self.plaintext(StatusCode::Conflict, "already in progress\n")
+            },
+        }
+    }
+
+    fn drain(&self, remote_addr: &str, drained: bool) -> FutureResult<Response, hyper::Error> {
+        let mut upstream_servers = self.upstream_servers_arc.write();
+        if !upstream_servers
+            .iter()
+            .any(|upstream_server| upstream_server.remote_addr == remote_addr)
+        {
+            return self.plaintext(StatusCode::NotFound, "unknown upstream server\n");
+        }
+        match UpstreamServer::set_drained(&mut upstream_servers, remote_addr, drained) {
+            Ok(upstream_servers_live) => {
+                *self.upstream_servers_live_arc.write() = upstream_servers_live;
+                self.plaintext(StatusCode::Ok, if drained { "drained\n" } else { "undrained\n" })
+            }
+            Err(_) => self.plaintext(StatusCode::Conflict, "last upstream server in rotation\n"),
+        }
+    }
+
+    /// Serves the webservice on a Unix socket, using the event loop of `handle`.
+    fn listen_unix(self, handle: &Handle, listen_path: &str) {
+        let _ = fs::remove_file(listen_path);
+        let listener =
+            UnixListener::bind(listen_path).expect("Unable to create the webservice Unix socket");
+        let mut http = Http::new();
+        http.keep_alive(false);
+        let handle_inner = handle.clone();
+        let fut = listener
+            .incoming()
+            .for_each(move |stream| {
+                let fut_connection = http.serve_connection(stream, self.clone())
+                    .map(|_| {})
+                    .map_err(|_| {});
+                handle_inner.spawn(fut_connection);
+                Ok(())
+            })
+            .map_err(|_| {});
+        handle.spawn(fut);
+        info!("Webservice started on {}", listen_path);
     }
 
     pub fn spawn(
         edgedns_context: &EdgeDNSContext,
+        cache_primer: Option<CachePrimer>,
         service_ready_tx: mpsc::SyncSender<u8>,
     ) -> io::Result<thread::JoinHandle<()>> {
-        let listen_addr = edgedns_context
-            .config
-            .webservice_listen_addr
-            .parse()
-            .expect("Unsupport listen address for the prometheus service");
-        let web_service = WebService::new(edgedns_context);
+        let listen_addr: Option<SocketAddr> =
+            edgedns_context.config.webservice_listen_addr.as_ref().map(|x| {
+                x.parse()
+                    .expect("Unsupport listen address for the prometheus service")
+            });
+        let listen_path = edgedns_context.config.webservice_listen_path.clone();
+        let web_service = WebService::new(edgedns_context, cache_primer);
         let webservice_th = thread::Builder::new()
             .name("webservice".to_string())
-            .spawn(move || {
-                let server = Http::new()
-                    .keep_alive(false)
-                    .bind(&listen_addr, move || Ok(web_service.clone()))
-                    .expect("Unable to spawn the webservice");
-                service_ready_tx.send(2).unwrap();
-                info!("Webservice started on {}", listen_addr);
-                server.run().expect("Unable to start the webservice");
+            .spawn(move || match listen_addr {
+                Some(listen_addr) => {
+                    let web_service_tcp = web_service.clone();
+                    let server = Http::new()
+                        .keep_alive(false)
+                        .bind(&listen_addr, move || Ok(web_service_tcp.clone()))
+                        .expect("Unable to spawn the webservice");
+                    if let Some(ref listen_path) = listen_path {
+                        web_service.listen_unix(&server.handle(), listen_path);
+                    }
+                    service_ready_tx.send(2).unwrap();
+                    info!("Webservice started on {}", listen_addr);
+                    server.run().expect("Unable to start the webservice");
+                }
+                None => {
+                    let mut event_loop = Core::new().unwrap();
+                    let listen_path = listen_path.expect("No address to listen to");
+                    web_service.listen_unix(&event_loop.handle(), &listen_path);
+                    service_ready_tx.send(2).unwrap();
+                    loop {
+                        event_loop.turn(None)
+                    }
+                }
             })
             .unwrap();
         Ok(webservice_th)
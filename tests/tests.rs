@@ -6,7 +6,13 @@ extern crate tempfile;
 #[cfg(test)]
 mod test {
     extern crate env_logger;
-    use libedgedns::{Config, EdgeDNS};
+    use libedgedns::{AnswerOrder, ClientAddressFamily, Config, EdgeDNS, PendingQueryAges,
+                     QueryEvent, QueryEventKind, QueryEvents};
+    use libedgedns::cache_codec;
+    use libedgedns::clock::{self, Clock, ManualClock};
+    use libedgedns::dns;
+    use libedgedns::upstream_server::{BreakerState, UpstreamServer};
+    use libedgedns::UpstreamFamily;
 
     use nix::sys::signal::{kill, SIGKILL};
     use nix::sys::ioctl::libc::pid_t;
@@ -16,13 +22,17 @@ mod test {
 
     use regex::Regex;
 
+    use std::collections::{HashMap, HashSet};
     use std::env;
-    use std::io::Write;
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv6Addr, TcpListener, TcpStream, UdpSocket};
     use std::process::{exit, Command, ExitStatus};
     use std::os::unix::io::RawFd;
     use std::os::unix::process::CommandExt;
     use std::string::String;
-    use std::time::Duration;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use tempfile::NamedTempFile;
 
@@ -238,6 +248,18 @@ mod test {
         }
     }
 
+    /// Polls `done` until it returns `true`, for at most `timeout`.
+    fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut done: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !done() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
     static EXAMPLE_DOT_COM_ZONE : &'static str = r#"
 $ORIGIN example.com.     ; designates the start of this zone file in the namespace
 $TTL 1h                  ; default expiration time of all resource records without their own TTL value
@@ -287,17 +309,4175 @@ listen = "127.0.0.1:0"
     }
 
     #[test]
-    fn empty_config() {
+    fn upstream_routes() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let canary_zone = EXAMPLE_DOT_COM_ZONE.replace("192.0.2.3", "192.0.2.99");
+        let canary = spawn_coredns("example.com", &canary_zone);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+routes = {{ "MAIL.example.com" = "127.0.0.1:{}" }}
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port,
+            canary.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let routed = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail.example.com.\s+\d+\s+IN\s+A\s+192.0.2.99",
+        ).unwrap();
+        let default = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail2.example.com.\s+\d+\s+IN\s+A\s+192.0.2.4",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(routed.is_match(&output));
+        let output = dig("mail2.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(default.is_match(&output));
+    }
+
+    #[test]
+    fn upstream_max_response_size() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+max_response_size = 40
+query_deadline_ms = 500
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", server.udp_ports[0]);
+        assert!(!output.stdout.contains("ANSWER SECTION"));
+
         let cfg = r#"
 [upstream]
 servers = ["127.0.0.1:53"]
+max_response_size = 65535
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        // Only oversized responses to actual queries count as failures
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+max_response_size = 100
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
 [network]
 listen = "127.0.0.1:0"
-udp_ports = 1
+"#,
+            upstream.local_addr().unwrap(),
+            webservice_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let query = query_packet("example.com", 1);
+        let mut stream = TcpStream::connect(("127.0.0.1", server.tcp_ports[0])).unwrap();
+        stream
+            .write_all(&[(query.len() >> 8) as u8, query.len() as u8])
+            .unwrap();
+        stream.write_all(&query).unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answers: Vec<_> = (0..10)
+            .map(|i| rr("example.com", 1, 3600, &[192, 0, 2, i]))
+            .collect();
+        let mut response = response_packet("example.com", 1, &answers, &[], &[]);
+        assert!(response.len() > 100);
+        dns::set_tid(&mut response, dns::tid(&upstream_query).wrapping_add(1));
+        upstream.send_to(&response, ext_addr).unwrap();
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let total_failures = Regex::new(r#""total_failures":(\d+)"#).unwrap();
+        let mut failures = 0;
+        assert!(wait_until(Duration::from_secs(2), || {
+            if let Some(upstreams) = http_get(webservice_port, "/upstreams") {
+                if let Some(cap) = total_failures.captures(&upstreams) {
+                    failures = cap[1].parse::<u64>().unwrap();
+                }
+            }
+            failures > 0
+        }));
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn max_client_ttl() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[cache]
+max_client_ttl = 60
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let capped = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail.example.com.\s+60\s+IN\s+A\s+192.0.2.3",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        // The first response comes from the upstream server, the second one from the cache
+        for _ in 0..2 {
+            let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+            assert!(capped.is_match(&output));
+        }
+
+        // The cache entry keeps the original TTL: it is only refreshed once that
+        // TTL has elapsed, long after the TTL seen by clients
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[cache]
+max_client_ttl = 1
+min_ttl = 1
+stale_while_revalidate = true
+stale_refresh_jitter_ms = 0
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = query_packet("example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("example.com", 1, 3, &[192, 0, 2, 1]);
+        let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::min_ttl(&client_response[..len], 0, 3600, 0), Ok(1));
+        let cached = Instant::now();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(5), || {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            assert_eq!(dns::min_ttl(&client_response[..len], 0, 3600, 0), Ok(1));
+            upstream.recv_from(&mut upstream_query).is_ok()
+        }));
+        let cache_ttl = cached.elapsed();
+        assert!(cache_ttl >= Duration::from_millis(2500));
+        assert!(cache_ttl < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn no_cache_qtypes() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[cache]
+no_cache_qtypes = [1]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let answer = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail.example.com.\s+\d+\s+IN\s+A\s+192.0.2.3",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(answer.is_match(&output));
+        // Without the upstream server, the response can't come from the cache
+        drop(coredns);
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(!answer.is_match(&output));
+    }
+
+    #[test]
+    fn prime_cache() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let mut prime_file = NamedTempFile::new().unwrap();
+        prime_file
+            .write_all(b"# Names to prime the cache with\nmail.example.com\nexample.com MX\n")
+            .expect("write_all failed");
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[cache]
+prime_file = "{}"
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+ready_after_prime = true
+ready_min_cache_entries = 2
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port,
+            prime_file.path().to_str().unwrap(),
+            webservice_port
+        );
+        let server = spawn_edgedns(&cfg);
+        assert!(wait_until(Duration::from_secs(5), || {
+            http_get(webservice_port, "/ready")
+                .map_or(false, |response| response.starts_with("HTTP/1.1 200"))
+        }));
+        // Responses can only come from the cache once the upstream server is gone
+        drop(coredns);
+        let answer = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail.example.com.\s+\d+\s+IN\s+A\s+192.0.2.3",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(answer.is_match(&output));
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+prime_qps = 0
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn zero_ttl() {
+        let zone = format!("{}zero 0 IN A 192.0.2.9\n", EXAMPLE_DOT_COM_ZONE);
+        let coredns = spawn_coredns("example.com", &zone);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let answer = Regex::new(
+            r"\n;; ANSWER SECTION:\nzero.example.com.\s+0\s+IN\s+A\s+192.0.2.9",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        let output = dig("zero.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(answer.is_match(&output));
+        // The response was not cached, so it requires the upstream server
+        drop(coredns);
+        let output = dig("zero.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(!answer.is_match(&output));
+    }
+
+    #[test]
+    fn synthesized_nxdomain_soa() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[cache]
+synth_soa_mname = "ns.synth.example"
+synth_soa_rname = "hostmaster.synth.example"
+synth_soa_minimum = 60
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let port = server.udp_ports[0];
+        let output = dig("nonexistent.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(output.contains("status: NXDOMAIN"));
+        // Names below a cached NXDOMAIN are answered without the upstream server
+        drop(coredns);
+        let output = dig("www.nonexistent.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(output.contains("status: NXDOMAIN"));
+        let authority = Regex::new(concat!(
+            r"\n;; AUTHORITY SECTION:\n\.\s+60\s+IN\s+SOA\s+",
+            r"ns\.synth\.example\.\s+hostmaster\.synth\.example\.\s+1 3600 600 86400 60\n",
+        )).unwrap();
+        assert!(authority.is_match(&output));
+    }
+
+    #[test]
+    fn shed_inflight_queries() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
 [global]
-threads_udp = 1
-threads_tcp = 1
+shed_inflight_queries = 1000
+shed_action = "drop"
 "#;
-        spawn_edgedns(&cfg);
+        assert!(Config::from_string(cfg).is_ok());
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+shed_inflight_queries = 1000
+shed_action = "slip"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn shed_queries() {
+        for &shed_action in &["refused", "drop"] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["{}"]
+[global]
+shed_inflight_queries = 1
+shed_action = "{}"
+[network]
+listen = "127.0.0.1:0"
+"#,
+                upstream.local_addr().unwrap(),
+                shed_action
+            );
+            let server = spawn_edgedns(&cfg);
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut upstream_query = [0u8; 512];
+
+            // The upstream server never responds, so that the first two queries
+            // remain inflight, and twice the high-water mark sheds every new query
+            let names = ["a.example.com", "b.example.com", "c.example.com"];
+            for (i, name) in names.iter().enumerate() {
+                let mut query = query_packet(name, 1);
+                dns::set_tid(&mut query, i as u16 + 1);
+                socket
+                    .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                    .unwrap();
+                if i < 2 {
+                    upstream.recv_from(&mut upstream_query).unwrap();
+                }
+            }
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let mut client_response = [0u8; 512];
+            let mut shed_rcodes = vec![];
+            while let Ok(len) = socket.recv(&mut client_response) {
+                if dns::tid(&client_response[..len]) == 3 {
+                    shed_rcodes.push(dns::rcode(&client_response[..len]));
+                }
+            }
+            if shed_action == "refused" {
+                assert_eq!(shed_rcodes, vec![dns::DNS_RCODE_REFUSED]);
+            } else {
+                assert!(shed_rcodes.is_empty());
+            }
+
+            // Shed queries are never sent upstream
+            upstream
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let shed_qname = dns::normalize(&query_packet(names[2], 1), true)
+                .unwrap()
+                .qname;
+            while let Ok((len, _)) = upstream.recv_from(&mut upstream_query) {
+                let normalized_question = dns::normalize(&upstream_query[..len], true).unwrap();
+                assert_ne!(normalized_question.qname, shed_qname);
+            }
+        }
+    }
+
+    #[test]
+    fn max_active_queries() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+max_active_queries = 0
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+[global]
+max_active_queries = 2
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let mut client_response = [0u8; 512];
+        let cached_query = query_packet("cached.example.com", 1);
+        socket
+            .send_to(&cached_query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let (len, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("cached.example.com", 1, 3600, &[192, 0, 2, 10]);
+        let mut response = response_packet("cached.example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query[..len]));
+        upstream.send_to(&response, ext_addr).unwrap();
+        socket.recv(&mut client_response).unwrap();
+
+        for name in &["a.example.com", "b.example.com", "c.example.com"] {
+            socket
+                .send_to(&query_packet(name, 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+        }
+
+        // Only two queries are sent upstream at the same time
+        let mut sent = vec![];
+        while let Ok((len, ext_addr)) = upstream.recv_from(&mut upstream_query) {
+            let first_label = (upstream_query[13] | 0x20) as char;
+            sent.push((first_label, dns::tid(&upstream_query[..len]), ext_addr));
+        }
+        let labels: HashSet<_> = sent.iter().map(|&(label, _, _)| label).collect();
+        assert_eq!(labels.len(), 2);
+
+        // Cached names are still answered while the limit is reached
+        socket
+            .send_to(&cached_query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 10]));
+
+        // The third one is sent once a slot is available
+        let (label, tid, ext_addr) = sent[0];
+        let name = format!("{}.example.com", label);
+        let answer = rr(&name, 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet(&name, 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, tid);
+        upstream.send_to(&response, ext_addr).unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 1]));
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let third_sent = (0..10).any(|_| {
+            upstream.recv_from(&mut upstream_query).unwrap();
+            !labels.contains(&((upstream_query[13] | 0x20) as char))
+        });
+        assert!(third_sent);
+    }
+
+    #[test]
+    fn webservice_listen_path() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[webservice]
+enabled = true
+listen_path = "/tmp/edgedns.sock"
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.webservice_listen_addr, None);
+        assert_eq!(config.webservice_listen_path, Some("/tmp/edgedns.sock".to_owned()));
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.webservice_listen_addr, Some("0.0.0.0:9090".to_owned()));
+    }
+
+    #[test]
+    fn webservice_admin_allowed_ips() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(
+            config.webservice_admin_allowed_ips,
+            vec!["127.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]
+        );
+
+        for &(allowed_ips, expected_status) in &[("[]", "403"), ("[\"127.0.0.1\"]", "200")] {
+            let webservice_port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+admin_allowed_ips = {}
+[network]
+listen = "127.0.0.1:0"
+"#,
+                webservice_port,
+                allowed_ips
+            );
+            let _server = spawn_edgedns(&cfg);
+            let mut response = None;
+            assert!(wait_until(Duration::from_secs(5), || {
+                response = http_request(webservice_port, "POST", "/maintenance/on");
+                response.is_some()
+            }));
+            let status = format!("HTTP/1.1 {}", expected_status);
+            assert!(response.unwrap().starts_with(&status));
+            let response = http_get(webservice_port, "/maintenance").unwrap();
+            let enabled = expected_status == "200";
+            assert_eq!(response.ends_with("on\n"), enabled);
+        }
+    }
+
+    #[test]
+    fn maintenance_cached() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap(),
+            webservice_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = query_packet("www.example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("www.example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        socket.recv(&mut client_response).unwrap();
+
+        assert!(wait_until(Duration::from_secs(5), || {
+            http_request(webservice_port, "POST", "/maintenance/on").is_some()
+        }));
+
+        // The cached entry is not served during the maintenance, over UDP or TCP
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_REFUSED);
+        assert_eq!(dns::ancount(&client_response[..len]), 0);
+
+        let mut stream = TcpStream::connect(("127.0.0.1", server.tcp_ports[0])).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(&[(query.len() >> 8) as u8, query.len() as u8])
+            .unwrap();
+        stream.write_all(&query).unwrap();
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut tcp_response = vec![0u8; ((len[0] as usize) << 8) | len[1] as usize];
+        stream.read_exact(&mut tcp_response).unwrap();
+        assert_eq!(dns::rcode(&tcp_response), dns::DNS_RCODE_REFUSED);
+        assert_eq!(dns::ancount(&tcp_response), 0);
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+
+        // Once the maintenance is over, the cached entry is served again
+        assert!(http_request(webservice_port, "POST", "/maintenance/off").is_some());
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+        assert_eq!(dns::rcode(&client_response[..len]), 0);
+        assert_eq!(dns::ancount(&client_response[..len]), 1);
+    }
+
+    #[test]
+    fn webservice_redact_config() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(config.webservice_redact_config);
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[webservice]
+redact_config = false
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(!config.webservice_redact_config);
+    }
+
+    #[test]
+    fn qname_to_string() {
+        let qname = dns::qname_encode("www.Example.com").unwrap();
+        assert_eq!(dns::qname_to_string(&qname), "www.Example.com.");
+        assert_eq!(dns::qname_to_string(&[0]), "");
+    }
+
+    #[test]
+    fn upstream_max_qps() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+max_qps = -1
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn upstream_ratelimited_queries() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+max_qps = 1
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap(),
+            webservice_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client_response = [0u8; 512];
+
+        // The first query takes the only token of the bucket
+        client
+            .send_to(&query_packet("a.example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answers = [rr("a.example.com", 1, 3600, &[192, 0, 2, 1])];
+        let mut response = response_packet("a.example.com", 1, &answers, &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let (len, _) = client.recv_from(&mut client_response).unwrap();
+        assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_NOERROR);
+
+        // The second one is dropped before reaching the upstream server
+        client
+            .send_to(&query_packet("b.example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let (len, _) = client.recv_from(&mut client_response).unwrap();
+        assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_SERVFAIL);
+
+        let metrics = http_get(webservice_port, "/metrics").unwrap();
+        let counter = |name: &str| {
+            let re = Regex::new(&format!(r#"\n{}\{{handler="all"\}} (\d+)"#, name)).unwrap();
+            re.captures(&metrics)
+                .map_or(0, |cap| cap[1].parse::<u64>().unwrap())
+        };
+        assert_eq!(counter("edgedns_client_queries_upstream_ratelimited"), 1);
+        assert_eq!(counter("edgedns_upstream_ratelimited"), 1);
+        assert_eq!(counter("edgedns_client_queries_offline"), 0);
+        assert_eq!(counter("edgedns_client_queries_offline_servfail"), 0);
+    }
+
+    #[test]
+    fn cache_codec() {
+        let answers: Vec<Vec<u8>> = (1..17)
+            .map(|i| rr("example.com", 1, 3600, &[192, 0, 2, i]))
+            .collect();
+        let packet = response_packet("example.com", 1, &answers, &[], &[opt_rr()]);
+        let compressed = cache_codec::compress(&packet);
+        assert!(compressed.len() < packet.len());
+        assert_eq!(cache_codec::decompress(&compressed).unwrap(), packet);
+
+        let zeros = vec![0u8; 65535];
+        assert_eq!(cache_codec::decompress(&cache_codec::compress(&zeros)).unwrap(), zeros);
+        let bytes: Vec<u8> = (0..10000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        assert_eq!(cache_codec::decompress(&cache_codec::compress(&bytes)).unwrap(), bytes);
+        assert!(cache_codec::decompress(&cache_codec::compress(&[])).unwrap().is_empty());
+
+        assert!(cache_codec::decompress(&[5, 1, 2]).is_err());
+        assert!(cache_codec::decompress(&[0x20, 0]).is_err());
+    }
+
+    #[test]
+    fn cache_compress() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+[cache]
+compress = true
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = query_packet("example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answers: Vec<Vec<u8>> = (1..9)
+            .map(|i| rr("example.com", 1, 3600, &[192, 0, 2, i]))
+            .collect();
+        let mut response = response_packet("example.com", 1, &answers, &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+
+        // The second response comes from the cache, and must be identical
+        let mut responses = vec![];
+        for i in 0..2 {
+            if i > 0 {
+                socket
+                    .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                    .unwrap();
+            }
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            responses.push(client_response[..len].to_vec());
+        }
+        assert_eq!(dns::ancount(&responses[0]), 8);
+        assert_eq!(responses[0], responses[1]);
+    }
+
+    #[test]
+    fn cache_shadow() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+shadow = true
+"#;
+        assert_eq!(
+            Config::from_string(cfg).is_ok(),
+            cfg!(feature = "shadow-cache")
+        );
+    }
+
+    #[cfg(feature = "shadow-cache")]
+    #[test]
+    fn cache_shadow_lookups() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // Only one response fits in the main cache, while the shadow cache
+        // keeps both
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[cache]
+shadow = true
+max_bytes = 100
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap(),
+            webservice_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut responses = vec![];
+        for &(name, cached) in &[
+            ("a.example.com", false),
+            ("a.example.com", true),
+            ("b.example.com", false),
+            ("a.example.com", false),
+        ] {
+            upstream
+                .set_read_timeout(Some(Duration::from_millis(if cached { 500 } else { 5000 })))
+                .unwrap();
+            socket
+                .send_to(&query_packet(name, 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            match upstream.recv_from(&mut upstream_query) {
+                Err(_) => assert!(cached),
+                Ok((_, ext_addr)) => {
+                    assert!(!cached);
+                    let answers = [rr(name, 1, 3600, &[192, 0, 2, 1])];
+                    let mut response = response_packet(name, 1, &answers, &[], &[]);
+                    dns::set_tid(&mut response, dns::tid(&upstream_query));
+                    upstream.send_to(&response, ext_addr).unwrap();
+                }
+            }
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            responses.push(client_response[..len].to_vec());
+        }
+        assert_eq!(responses[0], responses[1]);
+        assert_eq!(responses[0], responses[3]);
+
+        // Both caches agree on the entries they share, and the evicted entry
+        // is only found in the shadow cache
+        let metrics = http_get(webservice_port, "/metrics").unwrap();
+        let counter = |name: &str| {
+            let re = Regex::new(&format!(r#"\n{}\{{handler="all"\}} (\d+)"#, name)).unwrap();
+            re.captures(&metrics)
+                .map_or(0, |cap| cap[1].parse::<u64>().unwrap())
+        };
+        assert_eq!(counter("edgedns_cache_shadow_mismatches"), 0);
+        assert!(counter("edgedns_cache_shadow_missing") > 0);
+    }
+
+    #[test]
+    fn upstream_server_clock() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+max_qps = 2
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_server = UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap();
+
+        let probes_delay = clock::Duration::from_secs(10);
+        assert!(upstream_server.claim_probe(probes_delay));
+        assert!(!upstream_server.claim_probe(probes_delay));
+        clock.advance(clock::Duration::from_secs(5));
+        assert!(!upstream_server.claim_probe(probes_delay));
+        clock.advance(clock::Duration::from_secs(5));
+        assert!(upstream_server.claim_probe(probes_delay));
+
+        assert!(upstream_server.take_ratelimit_token(&config));
+        assert!(upstream_server.take_ratelimit_token(&config));
+        assert!(!upstream_server.take_ratelimit_token(&config));
+        clock.advance(clock::Duration::from_secs(1));
+        assert!(upstream_server.take_ratelimit_token(&config));
+
+        let last_success = upstream_server.last_successful_response_instant;
+        upstream_server.prepare_send(&config);
+        assert!(upstream_server.last_successful_response_instant == last_success);
+        clock.advance(config.upstream_max_failure_duration);
+        upstream_server.prepare_send(&config);
+        assert!(upstream_server.last_successful_response_instant > last_success);
+    }
+
+    #[test]
+    fn pending_query_ages() {
+        let clock = ManualClock::new();
+        let mut ages = PendingQueryAges::new();
+        assert_eq!(ages.oldest_age(&clock), 0.0);
+
+        let first_ts = clock.now();
+        ages.insert(first_ts);
+        ages.insert(first_ts);
+        clock.advance(clock::Duration::from_secs(3));
+        let second_ts = clock.now();
+        ages.insert(second_ts);
+        clock.advance(clock::Duration::from_secs(2));
+        assert_eq!(ages.oldest(), Some(first_ts));
+        assert_eq!(ages.oldest_age(&clock), 5.0);
+
+        ages.remove(first_ts);
+        assert_eq!(ages.oldest(), Some(first_ts));
+        ages.remove(first_ts);
+        assert_eq!(ages.oldest(), Some(second_ts));
+        assert_eq!(ages.oldest_age(&clock), 2.0);
+
+        ages.remove(second_ts);
+        ages.remove(second_ts);
+        assert_eq!(ages.oldest(), None);
+        assert_eq!(ages.oldest_age(&clock), 0.0);
+    }
+
+    #[test]
+    fn upstream_probe_dedup() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+probes_delay_ms = 10000
+"#;
+        let config = Arc::new(Config::from_string(cfg).unwrap());
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_server = UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap();
+        upstream_server.offline = true;
+        let upstream_servers = Arc::new(Mutex::new(vec![upstream_server]));
+        let threads_count = 16;
+        for _ in 0..3 {
+            let barrier = Arc::new(Barrier::new(threads_count));
+            let threads: Vec<_> = (0..threads_count)
+                .map(|_| {
+                    let (config, upstream_servers, barrier) =
+                        (config.clone(), upstream_servers.clone(), barrier.clone());
+                    thread::spawn(move || {
+                        barrier.wait();
+                        upstream_servers.lock().unwrap()[0].claim_offline_probe(&config)
+                    })
+                })
+                .collect();
+            let claimed = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|&claimed| claimed)
+                .count();
+            assert_eq!(claimed, 1);
+            clock.advance(clock::Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn upstream_breaker() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+breaker_enabled = true
+breaker_cooldown_ms = 10000
+breaker_half_open_probes = 2
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_server = UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap();
+        let cooldown = clock::Duration::from_secs(10);
+
+        upstream_server.open_breaker();
+        assert!(upstream_server.offline);
+        assert!(!upstream_server.claim_offline_probe(&config));
+        clock.advance(cooldown);
+        assert!(upstream_server.claim_offline_probe(&config));
+        assert_eq!(upstream_server.breaker_state, BreakerState::HalfOpen);
+        assert!(upstream_server.claim_offline_probe(&config));
+        assert!(!upstream_server.claim_offline_probe(&config));
+        upstream_server.record_success_after_failure(&config);
+        assert!(upstream_server.offline);
+        upstream_server.record_success_after_failure(&config);
+        assert!(!upstream_server.offline);
+        assert_eq!(upstream_server.breaker_state, BreakerState::Closed);
+
+        // Probes that don't all get a response open the breaker again
+        upstream_server.open_breaker();
+        clock.advance(cooldown);
+        assert!(upstream_server.claim_offline_probe(&config));
+        assert!(upstream_server.claim_offline_probe(&config));
+        upstream_server.record_success_after_failure(&config);
+        clock.advance(cooldown);
+        assert!(!upstream_server.claim_offline_probe(&config));
+        assert_eq!(upstream_server.breaker_state, BreakerState::Open);
+        assert!(!upstream_server.claim_offline_probe(&config));
+        clock.advance(cooldown);
+        assert!(upstream_server.claim_offline_probe(&config));
+        assert!(upstream_server.offline);
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+breaker_half_open_probes = 0
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn upstream_least_busy() {
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_servers: Vec<_> = (0..4)
+            .map(|i| UpstreamServer::new(&format!("127.0.0.1:{}", 53 + i), clock.clone()).unwrap())
+            .collect();
+        upstream_servers[3].pending_queries_count = 1;
+        let upstream_servers_live = vec![0, 1, 2, 3];
+
+        // Ties are broken at random, even if all queries share the same ID
+        let rounds = 6000;
+        let mut picks = [0usize; 4];
+        for _ in 0..rounds {
+            let idx = UpstreamServer::least_busy_server(
+                &upstream_servers,
+                &upstream_servers_live,
+                0,
+                false,
+            ).unwrap();
+            picks[idx] += 1;
+        }
+        assert_eq!(picks[3], 0);
+        for &count in &picks[..3] {
+            assert!(count > rounds * 28 / 100 && count < rounds * 39 / 100);
+        }
+
+        upstream_servers[0].pending_queries_count = 2;
+        upstream_servers[1].pending_queries_count = 2;
+        upstream_servers[2].pending_queries_count = 0;
+        for tid in 0..100 {
+            let idx = UpstreamServer::least_busy_server(
+                &upstream_servers,
+                &upstream_servers_live,
+                tid,
+                false,
+            ).unwrap();
+            assert!(idx == 2 || idx == 3);
+        }
+        assert_eq!(UpstreamServer::least_busy_server(&upstream_servers, &vec![], 0, false), None);
+    }
+
+    #[test]
+    fn upstream_resurrection() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "127.0.0.1:54"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_servers = vec![
+            UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap(),
+            UpstreamServer::new("127.0.0.1:54", clock.clone()).unwrap(),
+        ];
+        assert!(UpstreamServer::has_healthy_servers(&upstream_servers));
+        upstream_servers[0].open_breaker();
+        assert_eq!(UpstreamServer::live_servers(&mut upstream_servers), vec![1]);
+        assert!(UpstreamServer::has_healthy_servers(&upstream_servers));
+
+        // Servers resurrected when all of them are offline are not healthy
+        upstream_servers[1].open_breaker();
+        assert_eq!(UpstreamServer::live_servers(&mut upstream_servers), vec![0, 1]);
+        assert!(!upstream_servers[0].offline);
+        assert!(!UpstreamServer::has_healthy_servers(&upstream_servers));
+
+        // Until one of them responds
+        upstream_servers[1].record_success(&config);
+        assert!(UpstreamServer::has_healthy_servers(&upstream_servers));
+    }
+
+    #[test]
+    fn upstream_lameness() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+success_rate_window = 4
+max_lame_percent = 50
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(!config.upstream_eject_lame);
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_server = UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap();
+
+        // Lameness is only reported once the window is full
+        assert!(!upstream_server.record_lameness(&config, true));
+        assert!(!upstream_server.record_lameness(&config, true));
+        assert!(!upstream_server.record_lameness(&config, false));
+        assert!(upstream_server.record_lameness(&config, false));
+        assert!(upstream_server.lame);
+        assert_eq!(upstream_server.lame_rate(), Some(0.5));
+        assert!(!upstream_server.record_lameness(&config, false));
+        assert!(!upstream_server.lame);
+        assert_eq!(upstream_server.total_lame_responses, 2);
+        assert!(!upstream_server.offline);
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+max_lame_percent = 0
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        let mut upstream_server = UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap();
+        for _ in 0..config.upstream_success_rate_window {
+            assert!(!upstream_server.record_lameness(&config, true));
+        }
+        assert!(!upstream_server.lame);
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+max_lame_percent = 101
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn upstream_drain() {
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_servers = vec![
+            UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap(),
+            UpstreamServer::new("127.0.0.2:53", clock.clone()).unwrap(),
+        ];
+        assert_eq!(UpstreamServer::live_servers(&mut upstream_servers), vec![0, 1]);
+
+        let live = UpstreamServer::set_drained(&mut upstream_servers, "127.0.0.1:53", true);
+        assert_eq!(live, Ok(vec![1]));
+        assert!(!upstream_servers[0].offline);
+        assert_eq!(upstream_servers[0].failures, 0);
+        assert!(UpstreamServer::set_drained(&mut upstream_servers, "127.0.0.2:53", true).is_err());
+        assert!(UpstreamServer::set_drained(&mut upstream_servers, "127.0.0.3:53", true).is_err());
+
+        // Drained servers are not resurrected when all the others are offline
+        upstream_servers[1].offline = true;
+        assert_eq!(UpstreamServer::live_servers(&mut upstream_servers), vec![1]);
+        assert!(upstream_servers[0].drained);
+
+        let live = UpstreamServer::set_drained(&mut upstream_servers, "127.0.0.1:53", false);
+        assert_eq!(live, Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn upstream_priorities() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "127.0.0.2:53", "127.0.0.3:53"]
+strategy = "fallback"
+priorities = [10, 30, 20]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.upstream_priorities, vec![10, 30, 20]);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "127.0.0.2:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.upstream_priorities, vec![0, 1]);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "127.0.0.2:53"]
+priorities = [0]
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let clock = Arc::new(ManualClock::new());
+        let mut upstream_servers = vec![
+            UpstreamServer::new("127.0.0.1:53", clock.clone()).unwrap(),
+            UpstreamServer::new("127.0.0.2:53", clock.clone()).unwrap(),
+            UpstreamServer::new("127.0.0.3:53", clock.clone()).unwrap(),
+        ];
+        upstream_servers[0].priority = 30;
+        upstream_servers[1].priority = 10;
+        upstream_servers[2].priority = 20;
+        let live = UpstreamServer::live_servers(&mut upstream_servers);
+        assert_eq!(UpstreamServer::preferred_server(&upstream_servers, &live), Some(1));
+
+        // The secondary server is picked when the primary one is down
+        upstream_servers[1].offline = true;
+        let live = UpstreamServer::live_servers(&mut upstream_servers);
+        assert_eq!(UpstreamServer::preferred_server(&upstream_servers, &live), Some(2));
+
+        upstream_servers[2].offline = true;
+        let live = UpstreamServer::live_servers(&mut upstream_servers);
+        assert_eq!(UpstreamServer::preferred_server(&upstream_servers, &live), Some(0));
+        assert_eq!(UpstreamServer::preferred_server(&upstream_servers, &vec![]), None);
+    }
+
+    #[test]
+    fn upstream_family() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "127.0.0.2:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.upstream_family, UpstreamFamily::Ipv4);
+        assert_eq!(config.upstream_prefer_family, UpstreamFamily::Any);
+        let cfg = r#"
+[upstream]
+servers = ["[::1]:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.upstream_family, UpstreamFamily::Ipv6);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "[::1]:53"]
+prefer_family = "ipv6"
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.upstream_family, UpstreamFamily::Any);
+        assert_eq!(config.upstream_prefer_family, UpstreamFamily::Ipv6);
+
+        // Upstream servers must be reachable using the sockets of the chosen family
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53", "[::1]:53"]
+[network]
+upstream_family = "ipv4"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+        let cfg = r#"
+[upstream]
+servers = ["[::1]:53"]
+[network]
+upstream_family = "ipv6"
+upstream_source_addr = "192.0.2.10"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+prefer_family = "ipv6"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn ready_after_warmup() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(!config.webservice_ready_after_prime);
+        assert_eq!(config.webservice_ready_min_cache_entries, 0);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+max_items = 1000
+prime_file = "/tmp/names.txt"
+[webservice]
+ready_after_prime = true
+ready_min_cache_entries = 100
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(config.webservice_ready_after_prime);
+        assert_eq!(config.webservice_ready_min_cache_entries, 100);
+
+        // The threshold could never be reached with a smaller cache
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+max_items = 1000
+prime_file = "/tmp/names.txt"
+[webservice]
+ready_min_cache_entries = 1001
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        // Nor without a list of names to prime the cache with
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[webservice]
+ready_min_cache_entries = 1
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut prime_file = NamedTempFile::new().unwrap();
+        prime_file
+            .write_all(b"example.com\n")
+            .expect("write_all failed");
+        let webservice_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+query_deadline_ms = 1000
+[cache]
+prime_file = "{}"
+[webservice]
+enabled = true
+listen = "127.0.0.1:{}"
+ready_after_prime = true
+ready_min_cache_entries = 1
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap(),
+            prime_file.path().to_str().unwrap(),
+            webservice_port
+        );
+        let _server = spawn_edgedns(&cfg);
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+
+        // Priming waits for the query deadline before completing
+        assert!(wait_until(Duration::from_secs(1), || {
+            http_get(webservice_port, "/ready").map_or(false, |response| {
+                response.starts_with("HTTP/1.1 503") && response.ends_with("priming in progress\n")
+            })
+        }));
+        assert!(wait_until(Duration::from_secs(5), || {
+            http_get(webservice_port, "/ready")
+                .map_or(false, |response| response.starts_with("HTTP/1.1 200"))
+        }));
+    }
+
+    /// Sends a `GET` request to the webservice, and returns the raw response.
+    fn http_get(port: u16, path: &str) -> Option<String> {
+        http_request(port, "GET", path)
+    }
+
+    fn http_request(port: u16, method: &str, path: &str) -> Option<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            method, path
+        );
+        stream.write_all(request.as_bytes()).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        Some(response)
+    }
+
+    #[test]
+    fn referrals_cached() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[cache]
+max_referrals = 16
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+            .send_to(&query_packet("www.sub.example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let ns = rr("sub.example.com", 2, 3600, &dns::qname_encode("ns.sub.example.com").unwrap());
+        let glue = rr("ns.sub.example.com", 1, 3600, &[192, 0, 2, 53]);
+        let mut response = response_packet("www.sub.example.com", 1, &[], &[ns], &[glue]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        socket.recv(&mut client_response).unwrap();
+
+        // Other names of the zone are answered with the cached delegation, whose
+        // TTLs are decremented over time
+        let query = query_packet("mail.sub.example.com", 1);
+        let (mut len, mut ttl) = (0, 0);
+        assert!(wait_until(Duration::from_secs(5), || {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            len = socket.recv(&mut client_response).unwrap();
+            let client_response = &client_response[..len];
+            assert_eq!(dns::rcode(client_response), dns::DNS_RCODE_NOERROR);
+            assert_eq!(dns::ancount(client_response), 0);
+            assert_eq!(dns::nscount(client_response), 1);
+            assert_eq!(dns::arcount(client_response), 1);
+            ttl = dns::min_ttl(client_response, 0, 86400, 0).unwrap();
+            ttl < 3600
+        }));
+        assert!(ttl >= 3590);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let mut expected = dns::build_referral_packet(
+            &normalized_question,
+            &dns::referral(&response).unwrap().unwrap(),
+        ).unwrap();
+        assert!(dns::decrement_ttls(&mut expected, 3600 - ttl, 86400).is_ok());
+        assert_eq!(&client_response[..len], &expected[..]);
+
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+    }
+
+    #[test]
+    fn stale_while_revalidate() {
+        let zone = EXAMPLE_DOT_COM_ZONE.replace("$TTL 1h", "$TTL 1");
+        let coredns = spawn_coredns("example.com", &zone);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[cache]
+min_ttl = 1
+stale_while_revalidate = true
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let stale = Regex::new(
+            r"\n;; ANSWER SECTION:\nmail.example.com.\s+30\s+IN\s+A\s+192.0.2.3",
+        ).unwrap();
+        let port = server.udp_ports[0];
+        let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+        assert!(!stale.is_match(&output));
+        assert!(wait_until(Duration::from_secs(5), || {
+            let output = dig("mail.example.com", Qprotocol::UDP, "127.0.0.1", port).stdout;
+            stale.is_match(&output)
+        }));
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+stale_ttl = -1
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn stale_refresh_jitter() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[cache]
+min_ttl = 1
+stale_while_revalidate = true
+stale_refresh_jitter_ms = 500
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let config = Config::from_string(&cfg).unwrap();
+        assert_eq!(config.stale_refresh_jitter_ms, 500);
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = query_packet("example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("example.com", 1, 1, &[192, 0, 2, 1]);
+        let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        socket.recv(&mut client_response).unwrap();
+
+        // Clients get a stale response right away, and a single refresh is sent
+        assert!(wait_until(Duration::from_secs(5), || {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            dns::min_ttl(&client_response[..len], 0, 3600, 0) == Ok(30)
+        }));
+        for _ in 0..5 {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_NOERROR);
+        }
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+stale_refresh_jitter_ms = 60001
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn min_positive_negative_ttl() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+min_ttl = 30
+min_negative_ttl = 5
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.min_positive_ttl, 30);
+        assert_eq!(config.min_negative_ttl, 5);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[cache]
+min_positive_ttl = -1
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[cache]
+min_positive_ttl = 60
+min_negative_ttl = 1
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let answer = rr("www.example.com", 1, 1, &[192, 0, 2, 1]);
+        let positive = response_packet("www.example.com", 1, &[answer], &[], &[]);
+        let mut negative =
+            response_packet("nx.example.com", 1, &[], &[soa_rr("example.com", 1, 1)], &[]);
+        dns::set_rcode(&mut negative, dns::DNS_RCODE_NXDOMAIN);
+        let responses = [("www.example.com", positive), ("nx.example.com", negative)];
+        for &(name, ref response) in &responses {
+            let mut response = response.clone();
+            socket
+                .send_to(&query_packet(name, 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            upstream.send_to(&response, ext_addr).unwrap();
+            let mut client_response = [0u8; 512];
+            socket.recv(&mut client_response).unwrap();
+        }
+
+        // The negative response expires, while the positive one is still cached
+        let mut upstream_query = [0u8; 512];
+        let mut upstream_query_len = 0;
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(5), || {
+            socket
+                .send_to(&query_packet("nx.example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            match upstream.recv_from(&mut upstream_query) {
+                Ok((len, _)) => {
+                    upstream_query_len = len;
+                    true
+                }
+                Err(_) => {
+                    let mut client_response = [0u8; 512];
+                    socket.recv(&mut client_response).unwrap();
+                    false
+                }
+            }
+        }));
+        let qname = dns::qname_encode("nx.example.com").unwrap();
+        assert!(
+            upstream_query[..upstream_query_len]
+                .windows(qname.len())
+                .any(|x| x == &qname[..])
+        );
+        socket
+            .send_to(&query_packet("www.example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 1]));
+    }
+
+    #[test]
+    fn client_address_family() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert_eq!(config.client_address_family, ClientAddressFamily::Any);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+client_address_family = "ipv5"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let mx = rr("example.com", 15, 3600, &[0, 10, 2, b'm', b'x', 0xc0, 12]);
+        let glue_v4 = rr("mx.example.com", dns::DNS_TYPE_A, 3600, &[192, 0, 2, 1]);
+        let glue_v6 = rr("mx.example.com", dns::DNS_TYPE_AAAA, 3600, &[0x20; 16]);
+        for &(family, filtered_type, kept_type) in &[
+            ("ipv4", dns::DNS_TYPE_AAAA, dns::DNS_TYPE_A),
+            ("ipv6", dns::DNS_TYPE_A, dns::DNS_TYPE_AAAA),
+        ] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["{}"]
+[global]
+client_address_family = "{}"
+[network]
+listen = "127.0.0.1:0"
+"#,
+                upstream.local_addr().unwrap(),
+                family
+            );
+            let server = spawn_edgedns(&cfg);
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let filtered = if filtered_type == dns::DNS_TYPE_A {
+                glue_v4.clone()
+            } else {
+                glue_v6.clone()
+            };
+            let kept = if kept_type == dns::DNS_TYPE_A {
+                glue_v4.clone()
+            } else {
+                glue_v6.clone()
+            };
+            let exchanges = vec![
+                // Positive responses for the filtered type become NODATA
+                (
+                    query_packet("mx.example.com", filtered_type),
+                    response_packet("mx.example.com", filtered_type, &[filtered], &[], &[]),
+                    (0, 1),
+                ),
+                (
+                    query_packet("mx.example.com", kept_type),
+                    response_packet("mx.example.com", kept_type, &[kept], &[], &[]),
+                    (1, 0),
+                ),
+                // Addresses of the filtered type are removed from the additional section
+                (
+                    query_packet("example.com", 15),
+                    response_packet(
+                        "example.com",
+                        15,
+                        &[mx.clone()],
+                        &[],
+                        &[glue_v4.clone(), glue_v6.clone()],
+                    ),
+                    (1, 0),
+                ),
+            ];
+            for (query, mut response, (ancount, nscount)) in exchanges {
+                socket
+                    .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                    .unwrap();
+                let mut upstream_query = [0u8; 512];
+                let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+                dns::set_tid(&mut response, dns::tid(&upstream_query));
+                upstream.send_to(&response, ext_addr).unwrap();
+                let mut client_response = [0u8; 512];
+                let len = socket.recv(&mut client_response).unwrap();
+                let client_response = &client_response[..len];
+                assert_eq!(dns::rcode(client_response), dns::DNS_RCODE_NOERROR);
+                assert_eq!(dns::ancount(client_response), ancount);
+                assert_eq!(dns::nscount(client_response), nscount);
+                assert_eq!(dns::arcount(client_response), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn dns64_synthesis() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[dns64]
+prefix = "64:ff9b::/48"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[dns64]
+prefix = "2001:db8:64::/48"
+"#;
+        assert_eq!(Config::from_string(cfg).unwrap().dns64_prefix_len, 48);
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[dns64]
+prefix = "64:ff9b::1/96"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[dns64]
+prefix = "64:ff9b::/96"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let respond = |expected_qtype: u16, response: &[u8]| {
+            let mut upstream_query = [0u8; 512];
+            let (len, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            let normalized_question = dns::normalize(&upstream_query[..len], true).unwrap();
+            assert_eq!(normalized_question.qtype, expected_qtype);
+            let mut response = response.to_vec();
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            upstream.send_to(&response, ext_addr).unwrap();
+        };
+
+        // A name without AAAA records gets synthesized records
+        socket
+            .send_to(
+                &query_packet("v4.example.com", dns::DNS_TYPE_AAAA),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let soa = soa_rr("example.com", 3600, 300);
+        respond(
+            dns::DNS_TYPE_AAAA,
+            &response_packet("v4.example.com", dns::DNS_TYPE_AAAA, &[], &[soa], &[]),
+        );
+        let a = rr("v4.example.com", dns::DNS_TYPE_A, 3600, &[192, 0, 2, 33]);
+        respond(
+            dns::DNS_TYPE_A,
+            &response_packet("v4.example.com", dns::DNS_TYPE_A, &[a], &[], &[]),
+        );
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        let client_response = &client_response[..len];
+        assert_eq!(dns::tid(client_response), 0x1234);
+        assert_eq!(dns::rcode(client_response), dns::DNS_RCODE_NOERROR);
+        assert_eq!(dns::ancount(client_response), 1);
+        let normalized_question = dns::normalize(client_response, false).unwrap();
+        assert_eq!(normalized_question.qtype, dns::DNS_TYPE_AAAA);
+        let address = "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap();
+        assert!(client_response.ends_with(&address.octets()));
+        // The TTL is capped by the SOA minimum of the empty AAAA response
+        assert_eq!(&client_response[len - 22..len - 18], &[0, 0, 0x01, 0x2c]);
+
+        // Real AAAA records are sent unchanged, without querying the A records
+        socket
+            .send_to(
+                &query_packet("v6.example.com", dns::DNS_TYPE_AAAA),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let aaaa = rr("v6.example.com", dns::DNS_TYPE_AAAA, 300, &[0x20; 16]);
+        respond(
+            dns::DNS_TYPE_AAAA,
+            &response_packet("v6.example.com", dns::DNS_TYPE_AAAA, &[aaaa], &[], &[]),
+        );
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::ancount(&client_response[..len]), 1);
+        assert!(client_response[..len].ends_with(&[0x20; 16]));
+        let mut upstream_query = [0u8; 512];
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+    }
+
+    #[test]
+    fn duplicate_queries() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+duplicate_query_window_ms = 5000
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let query = query_packet("www.example.com", 1);
+        for _ in 0..3 {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+        }
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("www.example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 1]));
+        assert!(socket.recv(&mut client_response).is_err());
+
+        // Once answered, the same query is a retransmission, and is answered again
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 1]));
+
+        // The same question with a different transaction ID is not a duplicate
+        let mut query = query;
+        dns::set_tid(&mut query, 0x4321);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::tid(&client_response[..len]), 0x4321);
+    }
+
+    #[test]
+    fn cache_max_bytes() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+[cache]
+max_bytes = 100
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client_response = [0u8; 512];
+        let mut upstream_query = [0u8; 512];
+
+        // Each response is 60 bytes long, so only one of them fits in the cache
+        for &(name, address) in &[("a.example.com", 1), ("b.example.com", 2)] {
+            socket
+                .send_to(&query_packet(name, 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            let answer = rr(name, 1, 3600, &[192, 0, 2, address]);
+            let mut response = response_packet(name, 1, &[answer], &[], &[]);
+            assert_eq!(response.len(), 60);
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            upstream.send_to(&response, ext_addr).unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            assert!(client_response[..len].ends_with(&[192, 0, 2, address]));
+        }
+
+        // The most recent response is still cached
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+            .send_to(
+                &query_packet("b.example.com", 1),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 2]));
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+
+        // The other one was evicted, and is requested again
+        socket
+            .send_to(
+                &query_packet("a.example.com", 1),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let (len, _) = upstream.recv_from(&mut upstream_query).unwrap();
+        let upstream_question = dns::normalize(&upstream_query[..len], true).unwrap();
+        let question = dns::normalize(&query_packet("a.example.com", 1), true).unwrap();
+        assert_eq!(
+            dns::qname_lc(&upstream_question.qname),
+            dns::qname_lc(&question.qname)
+        );
+    }
+
+    #[test]
+    fn cached_response_flags() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+            .send_to(
+                &query_packet("www.example.com", 1),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("www.example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        dns::set_aa(&mut response, true);
+        dns::set_ra(&mut response, false);
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(dns::ra(&client_response[..len]));
+        assert!(!dns::rd(&client_response[..len]));
+
+        // The cached copy is served with our own flags, and the RD bit of the new query
+        let mut query = query_packet("www.example.com", 1);
+        dns::set_rd(&mut query, true);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        let cached = &client_response[..len];
+        assert!(cached.ends_with(&[192, 0, 2, 1]));
+        assert!(!dns::aa(cached));
+        assert!(dns::ra(cached));
+        assert!(dns::rd(cached));
+    }
+
+    #[test]
+    fn nxdomain_to_nodata() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+nxdomain_to_nodata_suffixes = ["example.com"]
+nxdomain_to_nodata_qtypes = [28]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let soa = soa_rr("example.com", 3600, 300);
+        let mut client_response = [0u8; 512];
+        for &(name, qtype, rewritten) in &[
+            ("www.example.com", dns::DNS_TYPE_AAAA, true),
+            ("www.example.com", dns::DNS_TYPE_A, false),
+            ("www.example.net", dns::DNS_TYPE_AAAA, false),
+        ] {
+            socket
+                .send_to(&query_packet(name, qtype), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            let mut response = response_packet(name, qtype, &[], &[soa.clone()], &[]);
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            dns::set_rcode(&mut response, dns::DNS_RCODE_NXDOMAIN);
+            upstream.send_to(&response, ext_addr).unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            let expected_rcode = if rewritten {
+                dns::DNS_RCODE_NOERROR
+            } else {
+                dns::DNS_RCODE_NXDOMAIN
+            };
+            assert_eq!(dns::rcode(&client_response[..len]), expected_rcode);
+            assert_eq!(dns::ancount(&client_response[..len]), 0);
+            assert_eq!(dns::nscount(&client_response[..len]), 1);
+        }
+
+        // The rewritten response is the one that was cached
+        socket
+            .send_to(
+                &query_packet("www.example.com", dns::DNS_TYPE_AAAA),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_NOERROR);
+        assert_eq!(dns::nscount(&client_response[..len]), 1);
+    }
+
+    #[test]
+    fn out_of_bailiwick_not_cached() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+"#;
+        let config = Config::from_string(cfg).unwrap();
+        assert!(config.strip_out_of_bailiwick);
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let ns = rr("example.com", 2, 3600, &dns::qname_encode("ns.example.com").unwrap());
+        let glue = rr("ns.example.com", 1, 3600, &[192, 0, 2, 2]);
+        let poison = rr("www.victim.com", 1, 3600, &[203, 0, 113, 66]);
+        let query = query_packet("www.example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let mut response = response_packet("www.example.com", 1, &[answer], &[ns], &[glue, poison]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        socket.recv(&mut client_response).unwrap();
+
+        // The cached response only keeps the records in bailiwick
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+        let client_response = &client_response[..len];
+        assert_eq!(dns::ancount(client_response), 1);
+        assert_eq!(dns::nscount(client_response), 1);
+        assert_eq!(dns::arcount(client_response), 1);
+        assert!(!client_response.windows(4).any(|x| x == [203, 0, 113, 66]));
+
+        // Responses whose bailiwick can't be checked are dropped, and the query
+        // is retried
+        let answer = rr("mail.example.com", 1, 3600, &[192, 0, 2, 3]);
+        let clean_response = response_packet("mail.example.com", 1, &[answer], &[], &[]);
+        let mut crafted_response = with_forward_pointer_record(&clean_response, &[203, 0, 113, 66]);
+        let query = query_packet("mail.example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        dns::set_tid(&mut crafted_response, dns::tid(&upstream_query));
+        upstream.send_to(&crafted_response, ext_addr).unwrap();
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let mut response = clean_response.clone();
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(&client_response[..len], &clean_response[..]);
+
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+        assert_eq!(&client_response[..len], &clean_response[..]);
+    }
+
+    #[test]
+    fn sort_addresses_cached() {
+        let records = [
+            rr("www.example.com", 1, 3600, &[192, 0, 2, 3]),
+            rr("www.example.com", 1, 1800, &[192, 0, 2, 1]),
+            rr("www.example.com", 1, 900, &[192, 0, 2, 2]),
+        ];
+        let query = query_packet("www.example.com", 1);
+        let mut cached_responses = vec![];
+        for order in &[[0, 1, 2], [2, 0, 1]] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["{}"]
+[cache]
+sort_addresses = true
+[network]
+listen = "127.0.0.1:0"
+"#,
+                upstream.local_addr().unwrap()
+            );
+            let server = spawn_edgedns(&cfg);
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            let answers: Vec<_> = order.iter().map(|&i| records[i].clone()).collect();
+            let mut response = response_packet("www.example.com", 1, &answers, &[], &[]);
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            upstream.send_to(&response, ext_addr).unwrap();
+            let mut client_response = [0u8; 512];
+            socket.recv(&mut client_response).unwrap();
+
+            // The second response comes from the cache
+            upstream
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let len = socket.recv(&mut client_response).unwrap();
+            assert!(upstream.recv_from(&mut upstream_query).is_err());
+            cached_responses.push(client_response[..len].to_vec());
+        }
+        let expected = response_packet(
+            "www.example.com",
+            1,
+            &[records[1].clone(), records[2].clone(), records[0].clone()],
+            &[],
+            &[],
+        );
+        assert_eq!(
+            &cached_responses[0][dns::DNS_HEADER_SIZE..],
+            &expected[dns::DNS_HEADER_SIZE..]
+        );
+        assert_eq!(cached_responses[0], cached_responses[1]);
+    }
+
+    #[test]
+    fn upstream_tids_unique() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+no_coalescing_qtypes = [16]
+query_max_timeout_ms = 30000
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let queries_count = 500;
+        let receiver = thread::spawn(move || {
+            let mut sent = vec![];
+            let mut upstream_query = [0u8; 512];
+            while sent.len() < queries_count {
+                let (len, ext_addr) = match upstream.recv_from(&mut upstream_query) {
+                    Err(_) => break,
+                    Ok(res) => res,
+                };
+                let upstream_query = &upstream_query[..len];
+                if dns::normalize(upstream_query, true).unwrap().qtype == dns::DNS_TYPE_TXT {
+                    sent.push((ext_addr.port(), dns::tid(upstream_query)));
+                }
+            }
+            sent
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut query = query_packet("www.example.com", dns::DNS_TYPE_TXT);
+        for tid in 0..queries_count {
+            dns::set_tid(&mut query, tid as u16);
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            if tid % 50 == 49 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let sent = receiver.join().unwrap();
+        assert_eq!(sent.len(), queries_count);
+
+        // None of these similar queries, all pending at the same time, share
+        // an ID, whether they were sent from the same port or not
+        let mut tids_by_port = HashMap::new();
+        for &(port, tid) in &sent {
+            assert!(
+                tids_by_port
+                    .entry(port)
+                    .or_insert_with(HashSet::new)
+                    .insert(tid)
+            );
+        }
+        let tids: HashSet<_> = sent.iter().map(|&(_, tid)| tid).collect();
+        assert_eq!(tids.len(), queries_count);
+    }
+
+    #[test]
+    fn upstream_cookies() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+cookies = true
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let recv_upstream = || {
+            let mut upstream_query = [0u8; 512];
+            let (len, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            let upstream_query = upstream_query[..len].to_vec();
+            let cookie = dns::edns_option(&upstream_query, dns::DNS_EDNS_OPTION_COOKIE)
+                .unwrap()
+                .unwrap();
+            (upstream_query, cookie, ext_addr)
+        };
+        let respond = |name: &str, upstream_query: &[u8], cookie: Option<&[u8]>, rcode: u16| {
+            let answers = if rcode == 0 {
+                vec![rr(name, 1, 3600, &[192, 0, 2, 1])]
+            } else {
+                vec![]
+            };
+            let additional = match cookie {
+                None => vec![],
+                Some(cookie) => {
+                    let mut option = vec![0, 10, 0, cookie.len() as u8];
+                    option.extend_from_slice(cookie);
+                    let mut opt_rr = opt_rr_with_options(&[&option]);
+                    opt_rr[5] = (rcode >> 4) as u8;
+                    vec![opt_rr]
+                }
+            };
+            let mut response = response_packet(name, 1, &answers, &[], &additional);
+            dns::set_tid(&mut response, dns::tid(upstream_query));
+            dns::set_rcode(&mut response, (rcode & 0xf) as u8);
+            response
+        };
+        let mut client_response = [0u8; 512];
+
+        // The server cookie is learned from the first response
+        socket
+            .send_to(
+                &query_packet("a.example.com", 1),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let (upstream_query, client_cookie, ext_addr) = recv_upstream();
+        assert_eq!(client_cookie.len(), 8);
+        let server_cookie = [&client_cookie[..], &b"server01"[..]].concat();
+        let response = respond("a.example.com", &upstream_query, Some(&server_cookie[..]), 0);
+        upstream.send_to(&response, ext_addr).unwrap();
+        socket.recv(&mut client_response).unwrap();
+
+        // And sent along with the client cookie in the next queries
+        socket
+            .send_to(
+                &query_packet("b.example.com", 1),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        let (upstream_query, cookie, ext_addr) = recv_upstream();
+        assert_eq!(cookie, server_cookie);
+
+        // Responses without a cookie, or with another client cookie, are dropped
+        let response = respond("b.example.com", &upstream_query, None, 0);
+        upstream.send_to(&response, ext_addr).unwrap();
+        let response = respond("b.example.com", &upstream_query, Some(&b"spoofed!server01"[..]), 0);
+        upstream.send_to(&response, ext_addr).unwrap();
+        assert!(socket.recv(&mut client_response).is_err());
+
+        // BADCOOKIE makes the query be sent again with the new server cookie
+        let new_server_cookie = [&client_cookie[..], &b"server02"[..]].concat();
+        let response = respond(
+            "b.example.com",
+            &upstream_query,
+            Some(&new_server_cookie[..]),
+            dns::DNS_RCODE_BADCOOKIE,
+        );
+        upstream.send_to(&response, ext_addr).unwrap();
+        let (retried_query, cookie, ext_addr) = recv_upstream();
+        assert_eq!(dns::tid(&retried_query), dns::tid(&upstream_query));
+        assert_eq!(cookie, new_server_cookie);
+        let response = respond("b.example.com", &retried_query, Some(&new_server_cookie[..]), 0);
+        upstream.send_to(&response, ext_addr).unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::rcode(&client_response[..len]), dns::DNS_RCODE_NOERROR);
+        assert!(client_response[..len].ends_with(&[192, 0, 2, 1]));
+    }
+
+    #[test]
+    fn local_zones() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+[local_zones]
+enabled = true
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = [0u8; 512];
+        let ipv6_loopback = format!("1.{}ip6.arpa", "0.".repeat(31));
+        let queries = [
+            ("1.1.168.192.in-addr.arpa", dns::DNS_TYPE_PTR, dns::DNS_RCODE_NXDOMAIN, 0),
+            ("2.1.0.10.in-addr.arpa", dns::DNS_TYPE_PTR, dns::DNS_RCODE_NXDOMAIN, 0),
+            ("1.0.20.172.in-addr.arpa", dns::DNS_TYPE_PTR, dns::DNS_RCODE_NXDOMAIN, 0),
+            ("10.in-addr.arpa", dns::DNS_TYPE_SOA, dns::DNS_RCODE_NOERROR, 0),
+            ("1.0.0.127.in-addr.arpa", dns::DNS_TYPE_PTR, dns::DNS_RCODE_NOERROR, 1),
+            (ipv6_loopback.as_str(), dns::DNS_TYPE_PTR, dns::DNS_RCODE_NOERROR, 1),
+        ];
+        for &(name, qtype, rcode, ancount) in &queries {
+            socket
+                .send_to(&query_packet(name, qtype), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let len = socket.recv(&mut response).unwrap();
+            let response = &response[..len];
+            assert_eq!(dns::rcode(response), rcode);
+            assert_eq!(dns::ancount(response), ancount);
+            if ancount > 0 {
+                assert!(response.ends_with(&dns::qname_encode("localhost").unwrap()));
+            }
+        }
+        let mut upstream_query = [0u8; 512];
+        assert!(upstream.recv_from(&mut upstream_query).is_err());
+
+        // Public addresses are still resolved upstream
+        socket
+            .send_to(
+                &query_packet("8.8.8.8.in-addr.arpa", dns::DNS_TYPE_PTR),
+                ("127.0.0.1", server.udp_ports[0]),
+            )
+            .unwrap();
+        assert!(upstream.recv_from(&mut upstream_query).is_ok());
+    }
+
+    #[test]
+    fn zone_transfers() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+listen = "127.0.0.1:0"
+"#;
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let axfr = query_packet("example.com", dns::DNS_TYPE_AXFR);
+        let mut ixfr = query_packet("example.com", dns::DNS_TYPE_IXFR);
+        dns::set_nscount(&mut ixfr, 1);
+        ixfr.extend_from_slice(&[0xc0, 12, 0, 6, 0, 1, 0, 0, 0, 0, 0, 22]);
+        ixfr.extend_from_slice(&[0; 22]);
+        for query in &[axfr, ixfr] {
+            socket
+                .send_to(query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut response = [0u8; 512];
+            let len = socket.recv(&mut response).unwrap();
+            assert!(len >= dns::DNS_HEADER_SIZE);
+            assert_eq!(dns::rcode(&response[..len]), dns::DNS_RCODE_REFUSED);
+            assert_eq!(dns::tid(&response[..len]), 0x1234);
+        }
+    }
+
+    #[test]
+    fn nsid() {
+        let coredns = spawn_coredns("example.com", EXAMPLE_DOT_COM_ZONE);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[global]
+nsid_enabled = true
+nsid = "node1"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut query = query_packet("mail.example.com", 1);
+        dns::set_arcount(&mut query, 1);
+        query.extend_from_slice(&opt_rr_with_options(&[&[0, 3, 0, 0]]));
+        for _ in 0..2 {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut response = [0u8; 512];
+            let len = socket.recv(&mut response).unwrap();
+            let nsid = dns::edns_option(&response[..len], dns::DNS_EDNS_OPTION_NSID).unwrap();
+            assert_eq!(nsid, Some(b"node1".to_vec()));
+        }
+
+        let query = query_packet("mail.example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut response = [0u8; 512];
+        let len = socket.recv(&mut response).unwrap();
+        let nsid = dns::edns_option(&response[..len], dns::DNS_EDNS_OPTION_NSID).unwrap();
+        assert_eq!(nsid, None);
+    }
+
+    #[test]
+    fn upstream_nsid_stripped() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+edns_options = [3, 15]
+[global]
+nsid_enabled = true
+nsid = "node1"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut query = query_packet("www.example.com", 1);
+        dns::set_arcount(&mut query, 1);
+        query.extend_from_slice(&opt_rr_with_options(&[&[0, 3, 0, 0]]));
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let upstream_nsid: &[u8] = &[0, 3, 0, 3, b'u', b'p', b'1'];
+        let mut response = response_packet(
+            "www.example.com",
+            1,
+            &[answer],
+            &[],
+            &[opt_rr_with_options(&[upstream_nsid])],
+        );
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        let nsid = dns::edns_option(&client_response[..len], dns::DNS_EDNS_OPTION_NSID).unwrap();
+        assert_eq!(nsid, Some(b"node1".to_vec()));
+        assert!(!client_response[..len].windows(3).any(|x| x == b"up1"));
+
+        // The cached response doesn't include the NSID of the upstream server
+        let query = query_packet("www.example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::ancount(&client_response[..len]), 1);
+        let nsid = dns::edns_option(&client_response[..len], dns::DNS_EDNS_OPTION_NSID).unwrap();
+        assert_eq!(nsid, None);
+    }
+
+    #[test]
+    fn max_client_udp_payload() {
+        for &max_client_udp_payload in &[511, 4097] {
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+max_client_udp_payload = {}
+"#,
+                max_client_udp_payload
+            );
+            assert!(Config::from_string(&cfg).is_err());
+        }
+
+        let txt: Vec<String> = (0..6).map(|_| format!("\"{}\"", "x".repeat(200))).collect();
+        let zone = format!("{}big IN TXT {}\n", EXAMPLE_DOT_COM_ZONE, txt.join(" "));
+        let coredns = spawn_coredns("example.com", &zone);
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["127.0.0.1:{}"]
+[network]
+listen = "127.0.0.1:0"
+max_client_udp_payload = 1232
+"#,
+            coredns.udp_port
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // Both queries advertise a 4096 bytes payload, but only the response
+        // to the first one exceeds 1232 bytes
+        for &(name, qtype, truncated) in &[
+            ("big.example.com", dns::DNS_TYPE_TXT, true),
+            ("mail.example.com", dns::DNS_TYPE_A, false),
+        ] {
+            let mut query = query_packet(name, qtype);
+            dns::set_arcount(&mut query, 1);
+            query.extend_from_slice(&opt_rr());
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut response = [0u8; 4096];
+            let len = socket.recv(&mut response).unwrap();
+            assert_eq!(dns::tc(&response[..len]), truncated);
+            assert!(len <= 1232);
+        }
+    }
+
+    #[test]
+    fn no_rd_servers() {
+        for &recursion_desired in &[true, false] {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            let no_rd_servers = if recursion_desired {
+                "[]".to_owned()
+            } else {
+                format!(r#"["{}"]"#, upstream_addr)
+            };
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["{}"]
+no_rd_servers = {}
+[network]
+listen = "127.0.0.1:0"
+"#,
+                upstream_addr, no_rd_servers
+            );
+            let server = spawn_edgedns(&cfg);
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut query = query_packet("example.com", 1);
+            dns::set_rd(&mut query, true);
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            let len = upstream.recv(&mut upstream_query).unwrap();
+            assert!(len >= dns::DNS_HEADER_SIZE);
+            assert_eq!(dns::rd(&upstream_query[..len]), recursion_desired);
+        }
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+no_rd_servers = ["127.0.0.1:5353"]
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn spoofed_source_dropped() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+            .send_to(&query_packet("example.com", 1), ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let tid = dns::tid(&upstream_query);
+
+        // A response with the right transaction ID, but from another address
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 66]);
+        let mut spoofed_response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut spoofed_response, tid);
+        spoofer.send_to(&spoofed_response, ext_addr).unwrap();
+
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 1]);
+        let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+        dns::set_tid(&mut response, tid);
+        upstream.send_to(&response, ext_addr).unwrap();
+
+        let mut client_response = [0u8; 512];
+        let len = socket.recv(&mut client_response).unwrap();
+        assert_eq!(dns::ancount(&client_response[..len]), 1);
+        let client_response = &client_response[..len];
+        assert!(client_response.windows(4).any(|x| x == [192, 0, 2, 1]));
+        assert!(!client_response.windows(4).any(|x| x == [192, 0, 2, 66]));
+    }
+
+    #[test]
+    fn https_record_fidelity() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Priority 1, target ".", alpn="h2", ipv4hint=192.0.2.1, ipv6hint=2001:db8::1
+        let rdata: &[u8] = &[
+            0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2', 0, 4, 0, 4, 192, 0, 2, 1, 0, 6, 0, 16, 0x20, 0x01,
+            0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+        ];
+        let query = query_packet("example.com", dns::DNS_TYPE_HTTPS);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answer = rr("example.com", dns::DNS_TYPE_HTTPS, 3600, rdata);
+        let mut response = response_packet("example.com", dns::DNS_TYPE_HTTPS, &[answer], &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+
+        // The second response comes from the cache
+        for i in 0..2 {
+            if i > 0 {
+                socket
+                    .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                    .unwrap();
+            }
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            let client_response = &client_response[..len];
+            assert_eq!(dns::ancount(client_response), 1);
+            assert!(client_response.windows(rdata.len()).any(|x| x == rdata));
+        }
+    }
+
+    #[test]
+    fn no_upstream_server_available() {
+        let cfg = r#"
+[upstream]
+servers = []
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+max_qps = 1
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // The only upstream server is rate limited after the first query
+        for name in &["first.example.com", "second.example.com"] {
+            socket
+                .send_to(&query_packet(name, 1), ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+        }
+        let mut response = [0u8; 512];
+        let len = socket.recv(&mut response).unwrap();
+        let response = &response[..len];
+        assert_eq!(dns::rcode(response), dns::DNS_RCODE_SERVFAIL);
+        let qname = dns::qname_encode("second.example.com").unwrap();
+        assert!(response.windows(qname.len()).any(|x| x == &qname[..]));
+    }
+
+    #[test]
+    fn oversized_qname_formerr() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Answered even though formerr_on_malformed_queries is not set
+        let mut query = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for _ in 0..4 {
+            query.push(63);
+            query.extend(vec![b'a'; 63]);
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut response = [0u8; 512];
+        let len = socket.recv(&mut response).unwrap();
+        let response = &response[..len];
+        assert_eq!(dns::tid(response), 0x1234);
+        assert_eq!(dns::rcode(response), dns::DNS_RCODE_FORMERR);
+    }
+
+    #[test]
+    fn query_events() {
+        let query_events = QueryEvents::new();
+        let event = QueryEvent {
+            kind: QueryEventKind::Received,
+            normalized_question: dns::normalize(&query_packet("example.com", 1), true).unwrap(),
+            client_addr: None,
+            rcode: None,
+            source: None,
+            elapsed: 0.0,
+            client_payload_size: 1232,
+            upstream_payload_size: None,
+            response_size: None,
+            truncated: None,
+        };
+        assert_eq!(query_events.emit(event.clone()), 0);
+
+        let rx = query_events.subscribe(1);
+        assert_eq!(query_events.emit(event.clone()), 0);
+        assert_eq!(query_events.emit(event.clone()), 1);
+        let received = rx.try_recv().unwrap();
+        assert_eq!(received.kind, QueryEventKind::Received);
+        assert_eq!(received.client_payload_size, 1232);
+        assert_eq!(received.truncated, None);
+        assert!(rx.try_recv().is_err());
+
+        let rx2 = query_events.subscribe(1);
+        drop(rx);
+        assert_eq!(query_events.emit(event.clone()), 0);
+        assert_eq!(query_events.emit(event), 1);
+        assert!(rx2.try_recv().is_ok());
+    }
+
+    #[test]
+    fn checking_disabled() {
+        let mut query = query_packet("example.com", 1);
+        let key = dns::normalize(&query, true).unwrap().key();
+        dns::set_cd(&mut query, true);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert!(normalized_question.key() != key);
+        let (upstream_query, _) =
+            dns::build_query_packet(&normalized_question, false, None, &[]).unwrap();
+        assert!(dns::cd(&upstream_query));
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+type = "resolver"
+[network]
+listen = "127.0.0.1:0"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Both queries are sent upstream, with their own CD bit, and get their own response
+        for &(checking_disabled, address) in &[(true, 66), (false, 1)] {
+            let mut query = query_packet("example.com", 1);
+            dns::set_cd(&mut query, checking_disabled);
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut upstream_query = [0u8; 512];
+            let (len, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+            assert_eq!(dns::cd(&upstream_query[..len]), checking_disabled);
+            let answer = rr("example.com", 1, 3600, &[192, 0, 2, address]);
+            let mut response = response_packet("example.com", 1, &[answer], &[], &[]);
+            dns::set_tid(&mut response, dns::tid(&upstream_query));
+            dns::set_cd(&mut response, checking_disabled);
+            upstream.send_to(&response, ext_addr).unwrap();
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            assert!(client_response[..len].ends_with(&[192, 0, 2, address]));
+        }
+
+        // Both responses are now cached
+        for &(checking_disabled, address) in &[(true, 66), (false, 1)] {
+            let mut query = query_packet("example.com", 1);
+            dns::set_cd(&mut query, checking_disabled);
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            assert!(client_response[..len].ends_with(&[192, 0, 2, address]));
+        }
+    }
+
+    #[test]
+    fn client_ratelimit() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+listen = "127.0.0.1:0"
+client_max_qps = 1
+client_ratelimit_action = "refused"
+"#;
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut query = query_packet("version.bind", dns::DNS_TYPE_TXT);
+        let qclass_offset = query.len() - 2;
+        query[qclass_offset + 1] = dns::DNS_CLASS_CH as u8;
+        let mut rcodes = vec![];
+        for _ in 0..2 {
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+            let mut response = [0u8; 512];
+            let len = socket.recv(&mut response).unwrap();
+            rcodes.push(dns::rcode(&response[..len]));
+        }
+        assert_eq!(rcodes, vec![dns::DNS_RCODE_NOERROR, dns::DNS_RCODE_REFUSED]);
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+client_ratelimit_action = "reject"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+    }
+
+    #[test]
+    fn client_ratelimit_slip() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+listen = "127.0.0.1:0"
+client_max_qps = 1
+client_ratelimit_action = "slip"
+client_ratelimit_slip = 2
+"#;
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut query = query_packet("version.bind", dns::DNS_TYPE_TXT);
+        let qclass_offset = query.len() - 2;
+        query[qclass_offset + 1] = dns::DNS_CLASS_CH as u8;
+        for tid in 1..6 {
+            dns::set_tid(&mut query, tid);
+            socket
+                .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                .unwrap();
+        }
+
+        // Past the first query, every other query gets a truncated response
+        let mut responses = vec![];
+        let mut response = [0u8; 512];
+        while let Ok(len) = socket.recv(&mut response) {
+            let response = &response[..len];
+            responses.push((dns::tid(response), dns::tc(response), dns::ancount(response)));
+        }
+        responses.sort();
+        assert_eq!(responses, vec![(1, false, 1), (3, true, 0), (5, true, 0)]);
+    }
+
+    #[test]
+    fn max_answers_config() {
+        for &(max_answers, valid) in &[(8, true), (65535, true), (0, false), (65536, false)] {
+            let cfg = format!(
+                r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+max_answers = {}
+"#,
+                max_answers
+            );
+            match Config::from_string(&cfg) {
+                Ok(config) => {
+                    assert!(valid);
+                    assert_eq!(config.max_answers, Some(max_answers as u16));
+                }
+                Err(_) => assert!(!valid),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_config() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[network]
+listen = "127.0.0.1:0"
+udp_ports = 1
+[global]
+threads_udp = 1
+threads_tcp = 1
+"#;
+        spawn_edgedns(&cfg);
+    }
+
+    fn query_packet(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; dns::DNS_HEADER_SIZE];
+        dns::set_tid(&mut packet, 0x1234);
+        dns::set_qdcount(&mut packet, 1);
+        packet.extend_from_slice(&dns::qname_encode(name).unwrap());
+        packet.push((qtype >> 8) as u8);
+        packet.push(qtype as u8);
+        packet.push((dns::DNS_CLASS_IN >> 8) as u8);
+        packet.push(dns::DNS_CLASS_IN as u8);
+        packet
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let packet = query_packet("example.com", 1);
+        assert!(dns::normalize(&packet, true).is_ok());
+
+        let mut response = packet.clone();
+        dns::set_qr(&mut response, true);
+        assert!(dns::normalize(&response, true).is_err());
+        assert!(dns::build_formerr_packet(&response).is_err());
+
+        let mut notify = packet.clone();
+        dns::set_opcode(&mut notify, 4);
+        assert!(dns::normalize(&notify, true).is_err());
+        let formerr = dns::build_formerr_packet(&notify).unwrap();
+        assert_eq!(dns::tid(&formerr), 0x1234);
+        assert!(dns::qr(&formerr));
+        assert_eq!(dns::opcode(&formerr), 4);
+        assert_eq!(dns::rcode(&formerr), dns::DNS_RCODE_FORMERR);
+
+        let mut no_question = packet.clone();
+        dns::set_qdcount(&mut no_question, 0);
+        assert!(dns::normalize(&no_question, true).is_err());
+
+        let mut two_questions = packet.clone();
+        dns::set_qdcount(&mut two_questions, 2);
+        two_questions.extend_from_slice(&packet[dns::DNS_HEADER_SIZE..]);
+        assert!(dns::normalize(&two_questions, true).is_err());
+        let formerr = dns::build_formerr_packet(&two_questions).unwrap();
+        assert_eq!(dns::rcode(&formerr), dns::DNS_RCODE_FORMERR);
+        assert_eq!(dns::qdcount(&formerr), 0);
+
+        let mut with_answers = packet.clone();
+        dns::set_ancount(&mut with_answers, 1);
+        assert!(dns::normalize(&with_answers, true).is_err());
+
+        assert!(dns::build_formerr_packet(&packet[..4]).is_err());
+    }
+
+    fn query_packet_with_labels(labels: &[usize]) -> Vec<u8> {
+        let mut packet = vec![0u8; dns::DNS_HEADER_SIZE];
+        dns::set_tid(&mut packet, 0x1234);
+        dns::set_qdcount(&mut packet, 1);
+        for &label_len in labels {
+            packet.push(label_len as u8);
+            packet.extend(vec![b'a'; label_len]);
+        }
+        packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+        packet
+    }
+
+    #[test]
+    fn qname_limits() {
+        // 3 * 64 + 62 bytes, plus the root label
+        let query = query_packet_with_labels(&[63, 63, 63, 61]);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.qname.len() + 1, dns::DNS_MAX_HOSTNAME_LEN);
+        let query = query_packet_with_labels(&[63, 63, 63, 62]);
+        let e = dns::normalize(&query, true).unwrap_err();
+        assert!(dns::is_name_limit_error(e));
+
+        let query = query_packet_with_labels(&[1; 127]);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.labels_count, dns::DNS_MAX_LABELS);
+        let query = query_packet_with_labels(&[1; 128]);
+        let e = dns::normalize(&query, true).unwrap_err();
+        assert!(dns::is_name_limit_error(e));
+
+        let mut response = query_packet_with_labels(&[63, 63, 63, 62]);
+        dns::set_qr(&mut response, true);
+        assert!(!dns::is_name_limit_error(dns::normalize(&response, true).unwrap_err()));
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let packet = query_packet("www.example.com", 1);
+        for len in 0..packet.len() {
+            let truncated = &packet[..len];
+            let _ = dns::normalize(truncated, true);
+            let _ = dns::normalize(truncated, false);
+            let _ = dns::min_ttl(truncated, 1, 86400, 30);
+            let _ = dns::set_ttl(&mut truncated.to_vec(), 42);
+        }
+        let mut bogus_counts = packet.clone();
+        dns::set_qr(&mut bogus_counts, true);
+        dns::set_ancount(&mut bogus_counts, 0xffff);
+        dns::set_arcount(&mut bogus_counts, 0xffff);
+        assert!(dns::min_ttl(&bogus_counts, 1, 86400, 30).is_err());
+    }
+
+    fn rr(name: &str, rr_type: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut rr = dns::qname_encode(name).unwrap();
+        rr.extend_from_slice(&[(rr_type >> 8) as u8, rr_type as u8, 0, 1]);
+        rr.extend_from_slice(&[(ttl >> 24) as u8, (ttl >> 16) as u8, (ttl >> 8) as u8, ttl as u8]);
+        rr.extend_from_slice(&[(rdata.len() >> 8) as u8, rdata.len() as u8]);
+        rr.extend_from_slice(rdata);
+        rr
+    }
+
+    fn opt_rr() -> Vec<u8> {
+        vec![0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn response_packet(
+        name: &str,
+        qtype: u16,
+        answers: &[Vec<u8>],
+        authority: &[Vec<u8>],
+        additional: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut packet = query_packet(name, qtype);
+        dns::set_qr(&mut packet, true);
+        dns::set_ancount(&mut packet, answers.len() as u16);
+        dns::set_nscount(&mut packet, authority.len() as u16);
+        dns::set_arcount(&mut packet, additional.len() as u16);
+        for rr in answers.iter().chain(authority).chain(additional) {
+            packet.extend_from_slice(rr);
+        }
+        packet
+    }
+
+    #[test]
+    fn bailiwick() {
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let ns = rr("example.com", 2, 3600, &dns::qname_encode("ns.example.com").unwrap());
+        let glue = rr("ns.example.com", 1, 3600, &[192, 0, 2, 2]);
+        let poison = rr("www.victim.com", 1, 3600, &[203, 0, 113, 66]);
+
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &[answer.clone()],
+            &[ns.clone()],
+            &[glue.clone(), opt_rr()],
+        );
+        let original = packet.clone();
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(0));
+        assert_eq!(packet, original);
+
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &[answer.clone()],
+            &[ns.clone()],
+            &[glue.clone(), poison.clone(), opt_rr()],
+        );
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(1));
+        assert_eq!(
+            packet,
+            response_packet(
+                "www.example.com",
+                1,
+                &[answer.clone()],
+                &[ns.clone()],
+                &[glue.clone(), opt_rr()],
+            )
+        );
+        assert!(dns::min_ttl(&packet, 1, 86400, 30).is_ok());
+
+        let rogue_ns = rr("victim.com", 2, 3600, &dns::qname_encode("ns.evil.com").unwrap());
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &[answer.clone()],
+            &[rogue_ns, ns.clone()],
+            &[glue.clone()],
+        );
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(3));
+        assert_eq!(dns::nscount(&packet), 0);
+        assert_eq!(dns::arcount(&packet), 0);
+        assert_eq!(packet, response_packet("www.example.com", 1, &[answer.clone()], &[], &[]));
+
+        // The zones of the CNAME chain are in bailiwick as well
+        let cname = rr("www.example.com", 5, 3600, &dns::qname_encode("www.example.net").unwrap());
+        let target = rr("www.example.net", 1, 3600, &[192, 0, 2, 3]);
+        let net_ns = rr("example.net", 2, 3600, &dns::qname_encode("ns.example.net").unwrap());
+        let net_glue = rr("ns.example.net", 1, 3600, &[192, 0, 2, 4]);
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &[cname, target],
+            &[net_ns],
+            &[net_glue, poison.clone(), opt_rr()],
+        );
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(1));
+        assert_eq!(dns::nscount(&packet), 1);
+        assert_eq!(dns::arcount(&packet), 2);
+
+        // The root zone would put every name in bailiwick
+        let root_ns = rr(".", 2, 3600, &dns::qname_encode("ns.evil.com").unwrap());
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &[answer.clone()],
+            &[root_ns],
+            &[poison.clone()],
+        );
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(2));
+        let root_glue = rr("a.root-servers.net", 1, 3600, &[198, 41, 0, 4]);
+        let mut packet = response_packet(".", 2, &[], &[], &[root_glue]);
+        assert_eq!(dns::strip_out_of_bailiwick(&mut packet), Ok(1));
+
+        // Names that can't be decompressed can't be checked
+        let packet = response_packet("www.example.com", 1, &[answer], &[ns], &[glue]);
+        let mut packet = with_forward_pointer_record(&packet, &[203, 0, 113, 66]);
+        assert!(dns::strip_out_of_bailiwick(&mut packet).is_err());
+    }
+
+    /// Appends an additional `A` record to `packet`, whose owner name is a
+    /// pointer to its own data, that follows it.
+    fn with_forward_pointer_record(packet: &[u8], address: &[u8]) -> Vec<u8> {
+        let mut packet = packet.to_vec();
+        let rdata_offset = packet.len() + 12;
+        packet.push(0xc0 | (rdata_offset >> 8) as u8);
+        packet.push(rdata_offset as u8);
+        packet.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        packet.extend_from_slice(address);
+        let arcount = dns::arcount(&packet);
+        dns::set_arcount(&mut packet, arcount + 1);
+        packet
+    }
+
+    #[test]
+    fn referrals() {
+        let ns = rr("sub.example.com", 2, 3600, &dns::qname_encode("ns.sub.example.com").unwrap());
+        let glue = rr("ns.sub.example.com", 1, 3600, &[192, 0, 2, 53]);
+        let zone = dns::qname_encode("sub.example.com").unwrap();
+
+        // Names of the authority section are compressed using pointers to the question
+        let mut compressed_ns = vec![0xc0, 16];
+        compressed_ns.extend_from_slice(&ns[zone.len()..ns.len() - zone.len() - 5]);
+        compressed_ns.extend_from_slice(&[0, 5, 2, b'n', b's', 0xc0, 16]);
+        let packet = response_packet(
+            "www.sub.example.com",
+            1,
+            &[],
+            &[compressed_ns],
+            &[glue.clone(), opt_rr()],
+        );
+        let referral = dns::referral(&packet).unwrap().unwrap();
+        assert_eq!(referral.zone, &zone[..zone.len() - 1]);
+        assert_eq!(referral.nscount, 1);
+        assert_eq!(referral.arcount, 1);
+
+        let sibling_query = query_packet("mail.SUB.example.com", 1);
+        let normalized_question = dns::normalize(&sibling_query, true).unwrap();
+        let sibling_packet = dns::build_referral_packet(&normalized_question, &referral).unwrap();
+        assert_eq!(
+            sibling_packet,
+            response_packet("mail.SUB.example.com", 1, &[], &[ns.clone()], &[glue.clone()])
+        );
+        let other_query = query_packet("www.example.com", 1);
+        let normalized_question = dns::normalize(&other_query, true).unwrap();
+        assert!(dns::build_referral_packet(&normalized_question, &referral).is_err());
+
+        let soa = rr("sub.example.com", 6, 3600, &[0; 22]);
+        let nodata = response_packet("www.sub.example.com", 1, &[], &[soa], &[]);
+        assert_eq!(dns::referral(&nodata), Ok(None));
+        let mut authoritative = packet.clone();
+        dns::set_aa(&mut authoritative, true);
+        assert_eq!(dns::referral(&authoritative), Ok(None));
+        let answer = rr("www.sub.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let not_referral = response_packet("www.sub.example.com", 1, &[answer], &[ns], &[glue]);
+        assert_eq!(dns::referral(&not_referral), Ok(None));
+    }
+
+    #[test]
+    fn decrement_ttls() {
+        let cached = response_packet(
+            "www.example.com",
+            1,
+            &[
+                rr("www.example.com", 1, 3600, &[192, 0, 2, 1]),
+                rr("www.example.com", 1, 60, &[192, 0, 2, 2]),
+            ],
+            &[],
+            &[opt_rr()],
+        );
+
+        let mut packet = cached.clone();
+        assert!(dns::decrement_ttls(&mut packet, 10, 86400).is_ok());
+        let first_read_ttl = dns::min_ttl(&packet, 0, 86400, 30).unwrap();
+        assert_eq!(first_read_ttl, 50);
+
+        let mut packet = cached.clone();
+        assert!(dns::decrement_ttls(&mut packet, 40, 86400).is_ok());
+        let second_read_ttl = dns::min_ttl(&packet, 0, 86400, 30).unwrap();
+        assert_eq!(second_read_ttl, 20);
+        assert!(second_read_ttl < first_read_ttl);
+
+        let mut packet = cached.clone();
+        assert!(dns::decrement_ttls(&mut packet, 120, 86400).is_ok());
+        assert_eq!(dns::min_ttl(&packet, 0, 86400, 30), Ok(1));
+
+        let mut packet = cached.clone();
+        assert!(dns::decrement_ttls(&mut packet, 0, 5).is_ok());
+        let expected = response_packet(
+            "www.example.com",
+            1,
+            &[
+                rr("www.example.com", 1, 5, &[192, 0, 2, 1]),
+                rr("www.example.com", 1, 5, &[192, 0, 2, 2]),
+            ],
+            &[],
+            &[opt_rr()],
+        );
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn ecs() {
+        let ecs = [0, 1, 32, 0, 192, 0, 2, 129];
+        let mut query = query_packet("example.com", 1);
+        dns::set_arcount(&mut query, 1);
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 12, 0, 8, 0, 8]);
+        query.extend_from_slice(&ecs);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.ecs, Some(ecs.to_vec()));
+
+        let truncated = dns::ecs_truncate(&ecs, 24, 56).unwrap();
+        assert_eq!(truncated, vec![0, 1, 24, 0, 192, 0, 2]);
+        let truncated = dns::ecs_truncate(&ecs, 20, 56).unwrap();
+        assert_eq!(truncated, vec![0, 1, 20, 0, 192, 0, 0]);
+        assert!(dns::ecs_truncate(&[0, 1, 24, 0, 192, 0, 2, 1], 24, 56).is_none());
+
+        let (upstream_query, _) =
+            dns::build_query_packet(&normalized_question, false, None, &[]).unwrap();
+        let stripped = dns::normalize(&upstream_query, true).unwrap();
+        assert_eq!(stripped.ecs, None);
+        let (upstream_query, _) =
+            dns::build_query_packet(&normalized_question, false, Some(&truncated), &[]).unwrap();
+        let overwritten = dns::normalize(&upstream_query, true).unwrap();
+        assert_eq!(overwritten.ecs, Some(truncated));
+
+        let mut bogus = query.clone();
+        let bogus_len = bogus.len();
+        bogus[bogus_len - 6] = 33;
+        assert_eq!(dns::normalize(&bogus, true).unwrap().ecs, None);
+    }
+
+    fn opt_rr_with_options(options: &[&[u8]]) -> Vec<u8> {
+        let options = options.concat();
+        let mut opt_rr = opt_rr();
+        let opt_rr_len = opt_rr.len();
+        opt_rr[opt_rr_len - 2] = (options.len() >> 8) as u8;
+        opt_rr[opt_rr_len - 1] = options.len() as u8;
+        opt_rr.extend_from_slice(&options);
+        opt_rr
+    }
+
+    #[test]
+    fn edns_options() {
+        let nsid: &[u8] = &[0, 3, 0, 0];
+        let cookie: &[u8] = &[0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+        let ecs: &[u8] = &[0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2];
+
+        let mut query = query_packet("example.com", 1);
+        dns::set_arcount(&mut query, 1);
+        query.extend_from_slice(&opt_rr_with_options(&[nsid, ecs, cookie]));
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.edns_options, [nsid, cookie].concat());
+        let edns_options = dns::edns_options_filter(&normalized_question.edns_options, &[3, 15]);
+        assert_eq!(edns_options, nsid);
+        let (upstream_query, _) =
+            dns::build_query_packet(&normalized_question, false, None, &edns_options).unwrap();
+        let forwarded = dns::normalize(&upstream_query, true).unwrap();
+        assert_eq!(forwarded.edns_options, nsid);
+        assert_eq!(forwarded.ecs, None);
+
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 1]);
+        let nsid_response: &[u8] = &[0, 3, 0, 2, b'n', b'1'];
+        let mut packet = response_packet(
+            "example.com",
+            1,
+            &[answer.clone()],
+            &[],
+            &[opt_rr_with_options(&[cookie, nsid_response, ecs])],
+        );
+        assert_eq!(dns::strip_edns_options(&mut packet, &[3, 10]), Ok(1));
+        assert_eq!(
+            packet,
+            response_packet(
+                "example.com",
+                1,
+                &[answer.clone()],
+                &[],
+                &[opt_rr_with_options(&[cookie, ecs])],
+            )
+        );
+        let original = packet.clone();
+        assert_eq!(dns::strip_edns_options(&mut packet, &[3, 10]), Ok(0));
+        assert_eq!(packet, original);
+        assert_eq!(dns::strip_edns_options(&mut packet, &[3, 15]), Ok(1));
+        assert_eq!(
+            packet,
+            response_packet(
+                "example.com",
+                1,
+                &[answer.clone()],
+                &[],
+                &[opt_rr_with_options(&[ecs])],
+            )
+        );
+        assert!(dns::min_ttl(&packet, 1, 86400, 30).is_ok());
+
+        let mut bogus = response_packet(
+            "example.com",
+            1,
+            &[answer],
+            &[],
+            &[opt_rr_with_options(&[&cookie[..6]])],
+        );
+        assert!(dns::strip_edns_options(&mut bogus, &[]).is_err());
+
+        assert!(dns::has_edns_option(&normalized_question.edns_options, 3));
+        assert!(!dns::has_edns_option(&normalized_question.edns_options, 15));
+        let answer = rr("example.com", 1, 3600, &[192, 0, 2, 1]);
+        let packet = response_packet("example.com", 1, &[answer.clone()], &[], &[opt_rr()]);
+        let with_nsid = dns::add_edns_option(&packet, 3, b"n1").unwrap();
+        assert_eq!(
+            with_nsid,
+            response_packet(
+                "example.com",
+                1,
+                &[answer.clone()],
+                &[],
+                &[opt_rr_with_options(&[nsid_response])],
+            )
+        );
+        let packet = response_packet("example.com", 1, &[answer], &[], &[]);
+        assert!(dns::add_edns_option(&packet, 3, b"n1").is_err());
+    }
+
+    #[test]
+    fn cookies() {
+        let cookie: &[u8] = &[0, 10, 0, 16, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let mut opt_rr = opt_rr_with_options(&[&[0, 3, 0, 0], cookie]);
+        opt_rr[5] = (dns::DNS_RCODE_BADCOOKIE >> 4) as u8;
+        let mut packet = response_packet("example.com", 1, &[], &[], &[opt_rr]);
+        dns::set_rcode(&mut packet, (dns::DNS_RCODE_BADCOOKIE & 0xf) as u8);
+        assert_eq!(dns::extended_rcode(&packet), Ok(dns::DNS_RCODE_BADCOOKIE));
+        assert_eq!(dns::rcode(&packet), 7);
+        assert_eq!(
+            dns::edns_option(&packet, dns::DNS_EDNS_OPTION_COOKIE),
+            Ok(Some(cookie[4..].to_vec()))
+        );
+        assert_eq!(dns::edns_option(&packet, dns::DNS_EDNS_OPTION_ECS), Ok(None));
+
+        let packet = response_packet("example.com", 1, &[], &[], &[]);
+        assert_eq!(dns::extended_rcode(&packet), Ok(0));
+        assert_eq!(dns::edns_option(&packet, dns::DNS_EDNS_OPTION_COOKIE), Ok(None));
+    }
+
+    #[test]
+    fn query_tids() {
+        let query = query_packet("example.com", 1);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let mut tids = HashSet::new();
+        for _ in 0..1000 {
+            let (packet, normalized_question_minimal) =
+                dns::build_query_packet(&normalized_question, false, None, &[]).unwrap();
+            assert_eq!(dns::tid(&packet), normalized_question_minimal.tid);
+            tids.insert(normalized_question_minimal.tid);
+        }
+        assert!(tids.len() > 950);
+        assert!(tids.iter().any(|&tid| tid >= 0x8000));
+        assert!(tids.iter().any(|&tid| tid < 0x8000));
+    }
+
+    #[test]
+    fn cap_answers() {
+        let answers: Vec<Vec<u8>> = (1..5)
+            .map(|i| rr("www.example.com", 1, 3600, &[192, 0, 2, i]))
+            .collect();
+        let ns = rr("example.com", 2, 3600, &dns::qname_encode("ns.example.com").unwrap());
+
+        let mut packet = response_packet("www.example.com", 1, &answers, &[ns.clone()], &[]);
+        let original = packet.clone();
+        assert_eq!(dns::cap_answers(&mut packet, 4), Ok(0));
+        assert_eq!(packet, original);
+
+        let mut packet = response_packet(
+            "www.example.com",
+            1,
+            &answers,
+            &[ns.clone()],
+            &[opt_rr()],
+        );
+        assert_eq!(dns::cap_answers(&mut packet, 2), Ok(2));
+        assert!(dns::tc(&packet));
+        let mut expected = response_packet("www.example.com", 1, &answers[..2], &[], &[opt_rr()]);
+        dns::set_tc(&mut expected, true);
+        assert_eq!(packet, expected);
+        assert!(dns::min_ttl(&packet, 1, 86400, 30).is_ok());
+
+        let mut packet = response_packet("www.example.com", 1, &answers, &[], &[]);
+        assert_eq!(dns::cap_answers(&mut packet, 0), Ok(4));
+        let mut expected = response_packet("www.example.com", 1, &[], &[], &[]);
+        dns::set_tc(&mut expected, true);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn sinkhole() {
+        let ipv4 = "192.0.2.1".parse().unwrap();
+        let ipv6 = "2001:db8::1".parse().unwrap();
+
+        let query = query_packet("www.example.com", dns::DNS_TYPE_A);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let packet = dns::build_sinkhole_packet(&normalized_question, &ipv4, &ipv6, 30).unwrap();
+        assert_eq!(dns::ancount(&packet), 1);
+        assert_eq!(&packet[packet.len() - 4..], &[192, 0, 2, 1]);
+        assert_eq!(dns::min_ttl(&packet, 1, 86400, 60), Ok(30));
+
+        let query = query_packet("www.example.com", dns::DNS_TYPE_AAAA);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let packet = dns::build_sinkhole_packet(&normalized_question, &ipv4, &ipv6, 30).unwrap();
+        assert_eq!(dns::ancount(&packet), 1);
+        assert_eq!(&packet[packet.len() - 16..], &ipv6.octets());
+
+        let query = query_packet("www.example.com", 16);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let packet = dns::build_sinkhole_packet(&normalized_question, &ipv4, &ipv6, 30).unwrap();
+        assert_eq!(dns::ancount(&packet), 0);
+        assert_eq!(dns::min_ttl(&packet, 1, 86400, 60), Ok(60));
+    }
+
+    #[test]
+    fn txt_annotation() {
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let packet = response_packet("www.example.com", 1, &[answer], &[], &[opt_rr()]);
+        let annotated = dns::add_txt_annotation(&packet, "cache").unwrap();
+        assert_eq!(dns::arcount(&annotated), 2);
+        assert_eq!(&annotated[..dns::DNS_HEADER_SIZE - 2], &packet[..dns::DNS_HEADER_SIZE - 2]);
+        assert_eq!(&annotated[packet.len()..packet.len() + 2], &[0xc0, 12]);
+        assert!(annotated.ends_with(b"\x05cache"));
+        assert_eq!(dns::min_ttl(&annotated, 0, 86400, 30), Ok(0));
+
+        assert!(dns::add_txt_annotation(&packet[..dns::DNS_HEADER_SIZE], "cache").is_err());
+    }
+
+    fn soa_rr(zone: &str, ttl: u32, minimum: u32) -> Vec<u8> {
+        let mut rdata = dns::qname_encode(&format!("ns.{}", zone)).unwrap();
+        rdata.extend_from_slice(&dns::qname_encode(&format!("hostmaster.{}", zone)).unwrap());
+        for value in &[2018010101, 7200, 3600, 1209600, minimum] {
+            rdata.extend_from_slice(&[
+                (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                *value as u8,
+            ]);
+        }
+        rr(zone, dns::DNS_TYPE_SOA, ttl, &rdata)
+    }
+
+    fn skip_name(packet: &[u8], mut offset: usize) -> usize {
+        while packet[offset] != 0 {
+            if packet[offset] & 0xc0 == 0xc0 {
+                return offset + 2;
+            }
+            offset += packet[offset] as usize + 1;
+        }
+        offset + 1
+    }
+
+    /// Returns the addresses of the `A` records of the answer section, in order
+    fn a_records(packet: &[u8]) -> Vec<[u8; 4]> {
+        let mut offset = skip_name(packet, dns::DNS_HEADER_SIZE) + 4;
+        let mut addresses = vec![];
+        for _ in 0..dns::ancount(packet) {
+            offset = skip_name(packet, offset);
+            let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+            let rdlen = (packet[offset + 8] as usize) << 8 | packet[offset + 9] as usize;
+            offset += 10;
+            if rr_type == 1 {
+                let rdata = &packet[offset..offset + rdlen];
+                addresses.push([rdata[0], rdata[1], rdata[2], rdata[3]]);
+            }
+            offset += rdlen;
+        }
+        addresses
+    }
+
+    #[test]
+    fn permute_addresses() {
+        let cname = rr(
+            "www.example.com",
+            5,
+            3600,
+            &dns::qname_encode("example.com").unwrap(),
+        );
+        let mut answers = vec![cname];
+        for i in 1..5 {
+            answers.push(rr("example.com", 1, 3600, &[192, 0, 2, i]));
+        }
+        answers.push(rr(
+            "example.com",
+            28,
+            3600,
+            &[0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ));
+        let packet = response_packet("www.example.com", 1, &answers, &[], &[]);
+
+        let mut rotated = packet.clone();
+        dns::permute_addresses(&mut rotated, |rdatas| rdatas.rotate_left(1)).unwrap();
+        assert_eq!(
+            a_records(&rotated),
+            vec![
+                [192, 0, 2, 2],
+                [192, 0, 2, 3],
+                [192, 0, 2, 4],
+                [192, 0, 2, 1]
+            ]
+        );
+        assert_eq!(rotated.len(), packet.len());
+        assert_eq!(&rotated[..60], &packet[..60]);
+        assert_eq!(&rotated[rotated.len() - 30..], &packet[packet.len() - 30..]);
+
+        let mut reversed = packet.clone();
+        dns::permute_addresses(&mut reversed, |rdatas| rdatas.reverse()).unwrap();
+        let mut addresses = a_records(&reversed);
+        assert_eq!(addresses[0], [192, 0, 2, 4]);
+        addresses.sort();
+        assert_eq!(addresses, a_records(&packet));
+        assert!(dns::decrement_ttls(&mut reversed, 0, 3600).is_ok());
+    }
+
+    #[test]
+    fn sort_addresses() {
+        let records = [
+            rr("example.com", 1, 3600, &[192, 0, 2, 3]),
+            rr("example.com", 1, 1800, &[192, 0, 2, 1]),
+            rr("example.com", 1, 900, &[192, 0, 2, 2]),
+        ];
+        let packets: Vec<Vec<u8>> = [[0, 1, 2], [2, 0, 1], [1, 2, 0]]
+            .iter()
+            .map(|order| {
+                let answers: Vec<_> = order.iter().map(|&i| records[i].clone()).collect();
+                let mut packet = response_packet("example.com", 1, &answers, &[], &[]);
+                dns::sort_addresses(&mut packet).unwrap();
+                packet
+            })
+            .collect();
+        assert_eq!(
+            packets[0],
+            response_packet(
+                "example.com",
+                1,
+                &[records[1].clone(), records[2].clone(), records[0].clone()],
+                &[],
+                &[],
+            )
+        );
+        assert_eq!(packets[0], packets[1]);
+        assert_eq!(packets[0], packets[2]);
+    }
+
+    #[test]
+    fn answer_order() {
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+answer_order = "rotate"
+"#;
+        assert_eq!(
+            Config::from_string(cfg).unwrap().answer_order,
+            AnswerOrder::Rotate
+        );
+
+        let cfg = r#"
+[upstream]
+servers = ["127.0.0.1:53"]
+[global]
+answer_order = "random"
+"#;
+        assert!(Config::from_string(cfg).is_err());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let cfg = format!(
+            r#"
+[upstream]
+servers = ["{}"]
+[network]
+listen = "127.0.0.1:0"
+[global]
+answer_order = "rotate"
+"#,
+            upstream.local_addr().unwrap()
+        );
+        let server = spawn_edgedns(&cfg);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = query_packet("example.com", 1);
+        socket
+            .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+            .unwrap();
+        let mut upstream_query = [0u8; 512];
+        let (_, ext_addr) = upstream.recv_from(&mut upstream_query).unwrap();
+        let answers: Vec<Vec<u8>> = (1..4)
+            .map(|i| rr("example.com", 1, 3600, &[192, 0, 2, i]))
+            .collect();
+        let mut response = response_packet("example.com", 1, &answers, &[], &[]);
+        dns::set_tid(&mut response, dns::tid(&upstream_query));
+        upstream.send_to(&response, ext_addr).unwrap();
+
+        // Subsequent responses come from the same cache entry
+        let mut orders = HashSet::new();
+        for i in 0..3 {
+            if i > 0 {
+                socket
+                    .send_to(&query, ("127.0.0.1", server.udp_ports[0]))
+                    .unwrap();
+            }
+            let mut client_response = [0u8; 512];
+            let len = socket.recv(&mut client_response).unwrap();
+            let mut addresses = a_records(&client_response[..len]);
+            orders.insert(addresses.clone());
+            addresses.sort();
+            assert_eq!(
+                addresses,
+                vec![[192, 0, 2, 1], [192, 0, 2, 2], [192, 0, 2, 3]]
+            );
+        }
+        assert_eq!(orders.len(), 3);
+    }
+
+    #[test]
+    fn negative_ttl() {
+        let mut packet =
+            response_packet("www.example.com", 1, &[], &[soa_rr("example.com", 3600, 300)], &[]);
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(300)));
+        dns::set_rcode(&mut packet, dns::DNS_RCODE_NXDOMAIN);
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(300)));
+
+        let packet = response_packet(
+            "www.example.com",
+            1,
+            &[],
+            &[soa_rr("example.com", 60, 300)],
+            &[opt_rr()],
+        );
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(60)));
+
+        let answer = rr("www.example.com", 1, 3600, &[192, 0, 2, 1]);
+        let packet = response_packet(
+            "www.example.com",
+            1,
+            &[answer],
+            &[soa_rr("example.com", 3600, 300)],
+            &[],
+        );
+        assert_eq!(dns::negative_ttl(&packet), Ok(None));
+
+        let packet = response_packet("www.example.com", 1, &[], &[], &[]);
+        assert_eq!(dns::negative_ttl(&packet), Ok(None));
+
+        let mut truncated_soa = soa_rr("example.com", 3600, 300);
+        truncated_soa.truncate(truncated_soa.len() - 4);
+        let rdlen = truncated_soa.len() - dns::qname_encode("example.com").unwrap().len() - 10;
+        let rdlen_offset = truncated_soa.len() - rdlen - 2;
+        truncated_soa[rdlen_offset + 1] = rdlen as u8;
+        let packet = response_packet("www.example.com", 1, &[], &[truncated_soa], &[]);
+        assert!(dns::negative_ttl(&packet).is_err());
+
+        let query = query_packet("www.nonexistent.example.com", 1);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let mname = dns::qname_encode("localhost").unwrap();
+        let rname = dns::qname_encode("nobody.invalid").unwrap();
+        let packet =
+            dns::build_nxdomain_packet_with_soa(&normalized_question, &mname, &rname, 300, 3600)
+                .unwrap();
+        assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NXDOMAIN);
+        assert_eq!(dns::nscount(&packet), 1);
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(300)));
+        let packet =
+            dns::build_nxdomain_packet_with_soa(&normalized_question, &mname, &rname, 300, 10)
+                .unwrap();
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(10)));
+    }
+
+    #[test]
+    fn strip_additional_records() {
+        let answer = rr("example.com", 15, 3600, &[0, 10, 2, b'm', b'x', 0xc0, 12]);
+        let glue_v4 = rr("mx.example.com", dns::DNS_TYPE_A, 3600, &[192, 0, 2, 1]);
+        let glue_v6 = rr("mx.example.com", dns::DNS_TYPE_AAAA, 3600, &[0x20; 16]);
+        let mut packet = response_packet(
+            "example.com",
+            15,
+            &[answer.clone()],
+            &[],
+            &[glue_v4.clone(), glue_v6.clone(), opt_rr()],
+        );
+        let original = packet.clone();
+        assert_eq!(dns::strip_additional_records(&mut packet, 15), Ok(0));
+        assert_eq!(packet, original);
+        assert_eq!(dns::strip_additional_records(&mut packet, dns::DNS_TYPE_AAAA), Ok(1));
+        let expected = response_packet(
+            "example.com",
+            15,
+            &[answer.clone()],
+            &[],
+            &[glue_v4.clone(), opt_rr()],
+        );
+        assert_eq!(packet, dns::compress_names(&expected).unwrap());
+        assert_eq!(dns::ancount(&packet), 1);
+        assert_eq!(dns::arcount(&packet), 2);
+        assert!(packet.ends_with(&opt_rr()));
+        assert!(dns::normalize(&packet, false).is_ok());
+
+        // The owner name of the A record points to the removed AAAA record
+        let glue_v6_offset = response_packet("example.com", 15, &[answer.clone()], &[], &[]).len();
+        let mut glue_v4_compressed = vec![0xc0 | (glue_v6_offset >> 8) as u8, glue_v6_offset as u8];
+        glue_v4_compressed.extend_from_slice(&glue_v4[glue_v4.len() - 14..]);
+        let mut packet = response_packet(
+            "example.com",
+            15,
+            &[answer.clone()],
+            &[],
+            &[glue_v6.clone(), glue_v4_compressed],
+        );
+        assert_eq!(dns::strip_additional_records(&mut packet, dns::DNS_TYPE_AAAA), Ok(1));
+        let expected =
+            response_packet("example.com", 15, &[answer.clone()], &[], &[glue_v4.clone()]);
+        assert_eq!(packet, dns::compress_names(&expected).unwrap());
+
+        let mut packet =
+            response_packet("example.com", 15, &[answer], &[], &[glue_v4, glue_v6]);
+        assert_eq!(dns::strip_additional_records(&mut packet, dns::DNS_TYPE_A), Ok(1));
+        assert_eq!(dns::arcount(&packet), 1);
+
+        let query = query_packet("example.com", dns::DNS_TYPE_AAAA);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        let mname = dns::qname_encode("localhost").unwrap();
+        let rname = dns::qname_encode("nobody.invalid").unwrap();
+        let packet =
+            dns::build_nodata_packet_with_soa(&normalized_question, &mname, &rname, 300, 300)
+                .unwrap();
+        assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOERROR);
+        assert_eq!(dns::ancount(&packet), 0);
+        assert_eq!(dns::negative_ttl(&packet), Ok(Some(300)));
+    }
+
+    #[test]
+    fn dns64() {
+        let prefix: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let ipv4 = [192, 0, 2, 33];
+        assert_eq!(
+            dns::nat64_address(&prefix, 96, &ipv4),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+        let prefix: Ipv6Addr = "2001:db8:122:344::".parse().unwrap();
+        assert_eq!(
+            dns::nat64_address(&prefix, 64, &ipv4),
+            "2001:db8:122:344:c0:2:2100:0".parse::<Ipv6Addr>().unwrap()
+        );
+        let prefix: Ipv6Addr = "2001:db8::".parse().unwrap();
+        assert_eq!(
+            dns::nat64_address(&prefix, 32, &ipv4),
+            "2001:db8:c000:221::".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let cname = rr("www.example.com", dns::DNS_TYPE_CNAME, 600, &[2, b'v', b'4', 0xc0, 16]);
+        let a = rr("v4.example.com", dns::DNS_TYPE_A, 300, &ipv4);
+        let packet = response_packet(
+            "www.example.com",
+            dns::DNS_TYPE_A,
+            &[cname, a],
+            &[soa_rr("example.com", 3600, 300)],
+            &[opt_rr()],
+        );
+        assert_eq!(dns::has_answers(&packet, dns::DNS_TYPE_A), Ok(true));
+        assert_eq!(dns::has_answers(&packet, dns::DNS_TYPE_AAAA), Ok(false));
+        let prefix: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let dns64_packet = dns::build_dns64_packet(&packet, &prefix, 96)
+            .unwrap()
+            .unwrap();
+        let normalized_question = dns::normalize(&dns64_packet, false).unwrap();
+        assert_eq!(normalized_question.qtype, dns::DNS_TYPE_AAAA);
+        assert_eq!(dns::ancount(&dns64_packet), 2);
+        assert_eq!(dns::nscount(&dns64_packet), 0);
+        assert_eq!(dns::arcount(&dns64_packet), 1);
+        assert_eq!(dns::has_answers(&dns64_packet, dns::DNS_TYPE_CNAME), Ok(true));
+        assert_eq!(dns::has_answers(&dns64_packet, dns::DNS_TYPE_A), Ok(false));
+        let address = "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap().octets();
+        let mut expected = [0, 28, 0, 1, 0, 0, 1, 44, 0, 16].to_vec();
+        expected.extend_from_slice(&address);
+        expected.extend_from_slice(&opt_rr());
+        assert!(dns64_packet.ends_with(&expected));
+
+        let nodata = response_packet(
+            "www.example.com",
+            dns::DNS_TYPE_A,
+            &[],
+            &[soa_rr("example.com", 3600, 300)],
+            &[],
+        );
+        assert_eq!(dns::build_dns64_packet(&nodata, &prefix, 96), Ok(None));
+    }
+
+    #[test]
+    fn compress_names() {
+        let mut mx_rdata = vec![0, 10];
+        mx_rdata.extend_from_slice(&dns::qname_encode("mx.example.com").unwrap());
+        let mut ns_rdata = dns::qname_encode("NS1.example.com").unwrap();
+        let answers = vec![
+            rr("www.example.com", dns::DNS_TYPE_MX, 3600, &mx_rdata),
+            rr("www.example.com", dns::DNS_TYPE_MX, 3600, &mx_rdata),
+        ];
+        let authority = vec![
+            rr("example.com", dns::DNS_TYPE_NS, 3600, &ns_rdata),
+            soa_rr("example.com", 3600, 300),
+        ];
+        let additional = vec![
+            rr("mx.example.com", dns::DNS_TYPE_A, 3600, &[192, 0, 2, 1]),
+            rr("NS1.example.com", dns::DNS_TYPE_A, 3600, &[192, 0, 2, 2]),
+            opt_rr(),
+        ];
+        let packet = response_packet(
+            "www.example.com",
+            dns::DNS_TYPE_MX,
+            &answers,
+            &authority,
+            &additional,
+        );
+        let compressed = dns::compress_names(&packet).unwrap();
+        assert!(compressed.len() < packet.len());
+        assert_eq!(&compressed[..dns::DNS_HEADER_SIZE], &packet[..dns::DNS_HEADER_SIZE]);
+        assert!(dns::normalize(&compressed, false).is_ok());
+        assert!(compressed.ends_with(&opt_rr()));
+        // The case of names is preserved
+        ns_rdata.truncate(4);
+        assert!(compressed.windows(4).any(|x| x == &ns_rdata[..]));
+
+        // Compressing again doesn't change anything
+        assert_eq!(dns::compress_names(&compressed).unwrap(), compressed);
+
+        let mut truncated = packet.clone();
+        truncated.pop();
+        assert!(dns::compress_names(&truncated).is_err());
+
+        let nodata =
+            response_packet("www.example.com", dns::DNS_TYPE_A, &[], &authority[1..], &[]);
+        let compressed = dns::compress_names(&nodata).unwrap();
+        assert!(compressed.len() < nodata.len());
+        assert_eq!(dns::negative_ttl(&compressed), Ok(Some(300)));
+    }
+
+    #[test]
+    fn replace_question() {
+        let answers = vec![
+            rr("www.example.com", 5, 3600, &dns::qname_encode("example.com").unwrap()),
+            rr("example.com", 1, 3600, &[192, 0, 2, 1]),
+        ];
+        let packet = response_packet("www.example.com", 1, &answers, &[], &[opt_rr()]);
+        let mut qname = dns::qname_encode("Alias.example.NET").unwrap();
+        qname.pop();
+        let replaced = dns::replace_question(&packet, &qname, dns::DNS_TYPE_AAAA).unwrap();
+        let normalized_question = dns::normalize(&replaced, false).unwrap();
+        assert_eq!(normalized_question.qname, qname);
+        assert_eq!(normalized_question.qtype, dns::DNS_TYPE_AAAA);
+        assert_eq!(dns::ancount(&replaced), 2);
+        assert_eq!(a_records(&replaced), vec![[192, 0, 2, 1]]);
+
+        // The records are unchanged
+        let mut qname = dns::qname_encode("www.example.com").unwrap();
+        qname.pop();
+        assert_eq!(
+            dns::replace_question(&replaced, &qname, 1),
+            dns::compress_names(&packet)
+        );
+    }
+
+    #[test]
+    fn edns_version() {
+        let mut query = query_packet("example.com", 1);
+        dns::set_arcount(&mut query, 1);
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0]);
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.edns_version, 0);
+        assert!(normalized_question.dnssec);
+
+        let query_len = query.len();
+        query[query_len - 5] = 1;
+        let normalized_question = dns::normalize(&query, true).unwrap();
+        assert_eq!(normalized_question.edns_version, 1);
+
+        let packet = dns::build_badvers_packet(&normalized_question).unwrap();
+        assert!(dns::qr(&packet));
+        assert_eq!(dns::tid(&packet), 0x1234);
+        assert_eq!(dns::rcode(&packet), 0);
+        assert_eq!(dns::arcount(&packet), 1);
+        assert_eq!(&packet[packet.len() - 6..packet.len() - 4], &[1, 0]);
+        let response = dns::normalize(&packet, false).unwrap();
+        assert_eq!(response.qname, normalized_question.qname);
+    }
+
+    #[test]
+    fn question_key() {
+        let query = query_packet("www.example.com", 1);
+        let key = dns::normalize(&query, true).unwrap().key();
+        let query_mixed_case = query_packet("WwW.ExAmPlE.CoM", 1);
+        let normalized_question = dns::normalize(&query_mixed_case, true).unwrap();
+        assert_eq!(normalized_question.key(), key);
+        assert_eq!(dns::qname_lc(&normalized_question.qname), key.qname_lc);
+
+        // "www" followed by a pointer to the first byte of the question count,
+        // that is 0, i.e. the root label
+        let mut compressed = query_packet("www", 1);
+        compressed.truncate(dns::DNS_HEADER_SIZE + 4);
+        compressed.extend_from_slice(&[0xc0, 0x04, 0, 1, 0, 1]);
+        let normalized_question = dns::normalize(&compressed, true).unwrap();
+        let uncompressed = dns::normalize(&query_packet("WWW", 1), true).unwrap();
+        assert_eq!(normalized_question.key(), uncompressed.key());
+        assert_eq!(normalized_question.labels_count, 1);
+
+        // "www" followed by a pointer to the transaction ID, that encodes the
+        // "a" label, followed by the flags, that encode the root label
+        let mut compressed = query_packet("www", 1);
+        compressed.truncate(dns::DNS_HEADER_SIZE + 4);
+        compressed.extend_from_slice(&[0xc0, 0x00, 0, 1, 0, 1]);
+        dns::set_tid(&mut compressed, 0x0161);
+        let normalized_question = dns::normalize(&compressed, true).unwrap();
+        let uncompressed = dns::normalize(&query_packet("www.a", 1), true).unwrap();
+        assert_eq!(normalized_question.key(), uncompressed.key());
+        assert_eq!(normalized_question.labels_count, 2);
+
+        // Pointers can only refer to names that have already been seen
+        let mut compressed = query_packet("www", 1);
+        compressed.truncate(dns::DNS_HEADER_SIZE + 4);
+        compressed.extend_from_slice(&[0xc0, 0x16, 0, 1, 0, 1]);
+        compressed.extend_from_slice(&dns::qname_encode("example.com").unwrap());
+        assert!(dns::normalize(&compressed, true).is_err());
+    }
+
+    #[test]
+    fn rcode_names() {
+        let nxdomain = dns::build_nxdomain_packet(
+            &dns::normalize(&query_packet("www.example.com", 1), true).unwrap(),
+        ).unwrap();
+        assert_eq!(dns::rcode_name(dns::rcode(&nxdomain)), "NXDOMAIN");
+        assert_eq!(dns::rcode_name(dns::DNS_RCODE_SERVFAIL), "SERVFAIL");
+        assert_eq!(dns::rcode_name(15), "OTHER");
     }
 }